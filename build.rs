@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// The commit the renderer is built from, for the image metadata
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    watch_head();
+
+    #[cfg(feature = "capi")]
+    write_header();
+}
+
+// Rebuilds when HEAD moves: on checkout HEAD itself changes, on commit the
+// branch it points to. Outside a checkout there is nothing to watch, and a
+// missing path would make cargo rerun the script every build
+fn watch_head() {
+    let git = Path::new(".git");
+    let Ok(head) = fs::read_to_string(git.join("HEAD")) else {
+        return;
+    };
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(reference) = head.trim().strip_prefix("ref: ") {
+        // Packed refs have no file of their own until the next commit
+        if git.join(reference).exists() {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        } else if git.join("packed-refs").exists() {
+            println!("cargo:rerun-if-changed=.git/packed-refs");
+        }
+    }
+}
+
+// The C declarations of src/capi.rs, configured in cbindgen.toml. They go
+// to the build directory; the committed include/raytracing.h is only
+// rewritten on request, so builds leave the source tree alone:
+//   RAYTRACING_UPDATE_HEADER=1 cargo build --features capi
+#[cfg(feature = "capi")]
+fn write_header() {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let bindings = cbindgen::generate(&dir).unwrap();
+    bindings.write_to_file(format!("{}/raytracing.h", out_dir));
+    if std::env::var_os("RAYTRACING_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(format!("{}/include/raytracing.h", dir));
+    }
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=RAYTRACING_UPDATE_HEADER");
+}
@@ -0,0 +1,56 @@
+#ifndef RAYTRACING_H
+#define RAYTRACING_H
+
+/* Generated from src/capi.rs by cbindgen, do not edit */
+
+#include <stddef.h>
+
+/**
+ * A scene with its options
+ */
+typedef struct RtRenderer RtRenderer;
+
+/**
+ * Loads a scene file, or returns NULL
+ */
+struct RtRenderer *rt_renderer_from_file(const char *path);
+
+/**
+ * Parses a scene from its text, or returns NULL. Relative paths in it
+ * are from the working directory
+ */
+struct RtRenderer *rt_renderer_from_memory(const char *text);
+
+void rt_renderer_free(struct RtRenderer *renderer);
+
+/**
+ * Sets a command line option, e.g. ("--accel", "bvh"), with a NULL value
+ * for flags such as "--clay". The scene is reloaded for the next render
+ */
+int rt_set_option(const struct RtRenderer *renderer, const char *name, const char *value);
+
+/**
+ * The size of the image, the buffer of rt_render holds width * height * 3
+ * floats
+ */
+int rt_image_size(const struct RtRenderer *renderer, size_t *width, size_t *height);
+
+/**
+ * Renders into the buffer the RGB colors in the order of the PPM, after
+ * the exposure and grading options, as written to the PPM before
+ * quantization. Returns -1 if the buffer is too small; a cancelled render
+ * has the sample passes finished so far
+ */
+int rt_render(const struct RtRenderer *renderer, float *pixels, size_t len);
+
+/**
+ * Of the render in progress or the last one, from 0 to 1
+ */
+float rt_progress(const struct RtRenderer *renderer);
+
+/**
+ * Stops the render in progress after the current run of pixels
+ */
+void rt_cancel(const struct RtRenderer *renderer);
+
+#endif /* RAYTRACING_H */
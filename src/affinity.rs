@@ -0,0 +1,49 @@
+// Pinning of the render threads with --pin-threads, so the scheduler does
+// not move them between CPUs and their caches. Only on Linux, elsewhere it
+// does nothing
+
+// Pins the calling thread to the index-th CPU the process may run on,
+// wrapping around when there are more threads than CPUs
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(index: usize) {
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    // SAFETY: cpu_set_t is plain data, zeroed is the empty set, and both
+    // calls get its real size
+    unsafe {
+        let mut allowed: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, size, &mut allowed) != 0 {
+            log::warn!(
+                "could not pin thread {}: {}",
+                index,
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        let cpus = (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &allowed))
+            .collect::<Vec<_>>();
+        if cpus.is_empty() {
+            return;
+        }
+
+        let cpu = cpus[index % cpus.len()];
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, size, &set) != 0 {
+            log::warn!(
+                "could not pin thread {}: {}",
+                index,
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        log::debug!("pinned thread {} to CPU {}", index, cpu);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(index: usize) {
+    if index == 0 {
+        log::warn!("--pin-threads only works on Linux");
+    }
+}
@@ -0,0 +1,143 @@
+use std::f32::consts::PI;
+use std::sync::OnceLock;
+
+use glm::{vec3, Vec3};
+
+use crate::random::Microfacet;
+use crate::trace::{refract, schilcks_coeff};
+
+// Directional albedo of the single-scattering GGX lobes, which miss the
+// light scattered between facets more than once. The tables are built on
+// first use by integrating over a grid of facet normals
+
+const N_COS: usize = 16;
+const N_ROUGHNESS: usize = 16;
+const N_ETA: usize = 16;
+// ln 3, the tables cover eta from 1/3 to 3
+const MAX_LOG_ETA: f32 = 1.0986123;
+// facet normals per table entry along u and the azimuth
+const N_SAMPLES: usize = 24;
+
+static REFLECTION: OnceLock<Table> = OnceLock::new();
+static DIELECTRIC: OnceLock<Table> = OnceLock::new();
+
+// Fraction of the light a white GGX mirror reflects from the direction
+// at cos to the normal
+pub fn reflection_albedo(cos: f32, alpha: f32) -> f32 {
+    let table = REFLECTION.get_or_init(|| Table::new(1, |cos, alpha, _| albedo(cos, alpha, None)));
+    table.lookup(cos, alpha, 0.0)
+}
+
+// The same for a clear rough dielectric, reflection and refraction
+// together, with eta = eta_from / eta_to
+pub fn dielectric_albedo(cos: f32, alpha: f32, eta: f32) -> f32 {
+    let table = DIELECTRIC
+        .get_or_init(|| Table::new(N_ETA, |cos, alpha, eta| albedo(cos, alpha, Some(eta))));
+    let t = (eta.ln() / MAX_LOG_ETA).clamp(-1.0, 1.0) * 0.5 + 0.5;
+    table.lookup(cos, alpha, t * (N_ETA - 1) as f32)
+}
+
+// The mean sampling weight, as the tracer computes it, with no
+// dielectric for a mirror
+fn albedo(cos: f32, alpha: f32, eta: Option<f32>) -> f32 {
+    let microfacet = Microfacet { alpha };
+    let n = vec3(0.0, 0.0, 1.0);
+    let to_eye = vec3((1.0 - cos * cos).sqrt(), 0.0, cos);
+
+    let mut sum = 0.0;
+    for i in 0..N_SAMPLES {
+        for j in 0..N_SAMPLES {
+            let u = (i as f32 + 0.5) / N_SAMPLES as f32;
+            let phi = 2.0 * PI * (j as f32 + 0.5) / N_SAMPLES as f32;
+            let m = microfacet.local_normal(u, phi);
+            let cos_i = glm::dot(&to_eye, &m);
+            if cos_i <= 0.0 {
+                continue;
+            }
+
+            let weight = |o: &Vec3, transmitted: bool| {
+                if (o.z < 0.0) != transmitted {
+                    0.0
+                } else {
+                    microfacet.weight(&n, &to_eye, o, &m)
+                }
+            };
+            let reflected = -to_eye + 2.0 * cos_i * m;
+            sum += match eta {
+                None => weight(&reflected, false),
+                Some(eta) => {
+                    let coeff = schilcks_coeff(eta, cos_i);
+                    match refract(&-to_eye, &m, eta) {
+                        Some(refracted) => {
+                            coeff * weight(&reflected, false)
+                                + (1.0 - coeff) * weight(&refracted, true)
+                        }
+                        None => weight(&reflected, false),
+                    }
+                }
+            };
+        }
+    }
+    sum / (N_SAMPLES * N_SAMPLES) as f32
+}
+
+// Values at cos = (i + 0.5) / N_COS and roughness = j / (N_ROUGHNESS - 1),
+// roughness being the square root of alpha
+struct Table {
+    values: Vec<f32>,
+}
+
+impl Table {
+    fn new(n_eta: usize, albedo: impl Fn(f32, f32, f32) -> f32) -> Self {
+        let mut values = Vec::with_capacity(n_eta * N_ROUGHNESS * N_COS);
+        for k in 0..n_eta {
+            let t = if n_eta > 1 {
+                k as f32 / (n_eta - 1) as f32
+            } else {
+                0.5
+            };
+            let eta = ((t * 2.0 - 1.0) * MAX_LOG_ETA).exp();
+            for j in 0..N_ROUGHNESS {
+                let roughness = j as f32 / (N_ROUGHNESS - 1) as f32;
+                let alpha = (roughness * roughness).max(1e-4);
+                for i in 0..N_COS {
+                    let cos = (i as f32 + 0.5) / N_COS as f32;
+                    values.push(albedo(cos, alpha, eta).max(1e-3));
+                }
+            }
+        }
+        Self { values }
+    }
+
+    // Linear in all three, eta given as a fractional index
+    fn lookup(&self, cos: f32, alpha: f32, eta: f32) -> f32 {
+        let n_eta = self.values.len() / (N_ROUGHNESS * N_COS);
+        let coords = [
+            (cos * N_COS as f32 - 0.5, N_COS),
+            (alpha.sqrt() * (N_ROUGHNESS - 1) as f32, N_ROUGHNESS),
+            (eta, n_eta),
+        ];
+
+        let mut result = 0.0;
+        for corner in 0..8 {
+            let mut idx = 0;
+            let mut weight = 1.0;
+            for (axis, &(x, n)) in coords.iter().enumerate().rev() {
+                let x = x.clamp(0.0, (n - 1) as f32);
+                let low = (x.floor() as usize).min(n.saturating_sub(2));
+                let t = x - low as f32;
+                let (i, w) = if corner >> axis & 1 == 1 {
+                    ((low + 1).min(n - 1), t)
+                } else {
+                    (low, 1.0 - t)
+                };
+                idx = idx * n + i;
+                weight *= w;
+            }
+            if weight > 0.0 {
+                result += weight * self.values[idx];
+            }
+        }
+        result
+    }
+}
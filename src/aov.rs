@@ -0,0 +1,181 @@
+use itertools::iproduct;
+use rayon::prelude::*;
+
+use crate::exr::write_exr;
+use crate::objects::Material;
+use crate::scene::Scene;
+use crate::trace::visible_hit;
+
+// Number of IDs per pixel in the mattes, the ones covering the most
+const MATTE_RANKS: usize = 2;
+
+// Writes the rendered radiance together with depth, normal and ID passes
+// into one EXR. The passes come from the ray through every pixel center,
+// except for the mattes, which hold the IDs covering most of the pixel
+// with their coverage (so objects can be cut out with antialiased edges),
+// the light path layers, which sum up to the radiance, and the variance
+// of the radiance estimate with the number of samples behind it
+pub fn write_aovs(scene: &Scene, path: &str, metadata: &[(String, String)]) {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let forward = scene.camera.axis.column(2).normalize();
+
+    // EXR rows go from the top, image rows from the bottom
+    let pixels = (0..width * height)
+        .map(|idx| (idx % width, height - 1 - idx / width))
+        .collect::<Vec<_>>();
+
+    let hits = pixels
+        .par_iter()
+        .map(|&(i, j)| {
+            let u = (i as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let v = (j as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+            let ray = scene.camera.ray_to_point(u, v);
+            visible_hit(scene, &ray, true)
+                .map(|(idx, hit, _)| (idx, glm::dot(&(hit.t * ray.direction), &forward), hit.n))
+        })
+        .collect::<Vec<_>>();
+
+    let mattes = pixels
+        .par_iter()
+        .map(|&(i, j)| coverage(scene, i, j))
+        .collect::<Vec<_>>();
+
+    let beauty = |c: usize| {
+        pixels
+            .iter()
+            .map(|&(i, j)| scene.image.get(i, j)[c])
+            .collect()
+    };
+    let pass = |f: &dyn Fn(&(usize, f32, glm::Vec3)) -> f32, background: f32| {
+        hits.iter()
+            .map(|hit| hit.as_ref().map_or(background, f))
+            .collect()
+    };
+
+    let matte = |kind: usize, rank: usize, is_coverage: bool| {
+        mattes
+            .iter()
+            .map(|m| {
+                m[kind].get(rank).map_or(
+                    0.0,
+                    |&(id, coverage)| {
+                        if is_coverage {
+                            coverage
+                        } else {
+                            id
+                        }
+                    },
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // IDs start from 1, 0 is the background
+    let mut channels = vec![("R", beauty(0)), ("G", beauty(1)), ("B", beauty(2))];
+    // The color is premultiplied, the background is black then
+    if let Some(alpha) = &scene.alpha {
+        let values = pixels.iter().map(|&(i, j)| alpha.get(i, j).x).collect();
+        channels.push(("A", values));
+    }
+    channels.extend([
+        ("depth.Z", pass(&|hit| hit.1, f32::INFINITY)),
+        ("normal.X", pass(&|hit| hit.2.x, 0.0)),
+        ("normal.Y", pass(&|hit| hit.2.y, 0.0)),
+        ("normal.Z", pass(&|hit| hit.2.z, 0.0)),
+        ("id.object", pass(&|hit| (hit.0 + 1) as f32, 0.0)),
+        (
+            "id.material",
+            pass(&|hit| material_id(&scene.objects[hit.0].material), 0.0),
+        ),
+    ]);
+
+    const MATTE_NAMES: [[[&str; 2]; MATTE_RANKS]; 2] = [
+        [
+            ["object_matte.id0", "object_matte.coverage0"],
+            ["object_matte.id1", "object_matte.coverage1"],
+        ],
+        [
+            ["material_matte.id0", "material_matte.coverage0"],
+            ["material_matte.id1", "material_matte.coverage1"],
+        ],
+    ];
+    for (kind, ranks) in MATTE_NAMES.iter().enumerate() {
+        for (rank, [id, coverage]) in ranks.iter().enumerate() {
+            channels.push((id, matte(kind, rank, false)));
+            channels.push((coverage, matte(kind, rank, true)));
+        }
+    }
+
+    const LIGHT_PATH_NAMES: [[&str; 3]; 4] = [
+        ["emission.R", "emission.G", "emission.B"],
+        ["diffuse_direct.R", "diffuse_direct.G", "diffuse_direct.B"],
+        [
+            "diffuse_indirect.R",
+            "diffuse_indirect.G",
+            "diffuse_indirect.B",
+        ],
+        ["specular.R", "specular.G", "specular.B"],
+    ];
+    for (image, names) in scene.light_paths.iter().zip(LIGHT_PATH_NAMES) {
+        for (c, name) in names.into_iter().enumerate() {
+            let values = pixels.iter().map(|&(i, j)| image.get(i, j)[c]).collect();
+            channels.push((name, values));
+        }
+    }
+
+    if let Some(variance) = &scene.variance {
+        for (c, name) in ["variance.R", "variance.G", "variance.B"]
+            .into_iter()
+            .enumerate()
+        {
+            let values = pixels.iter().map(|&(i, j)| variance.get(i, j)[c]).collect();
+            channels.push((name, values));
+        }
+    }
+    // Every pixel gets the same number of samples
+    channels.push(("samples.Y", vec![scene.n_samples as f32; width * height]));
+
+    write_exr(path, width, height, &channels, metadata);
+}
+
+// Object and material IDs with the fractions of the pixel they cover,
+// largest first, from a grid of about as many rays as the scene's samples
+fn coverage(scene: &Scene, i: usize, j: usize) -> [Vec<(f32, f32)>; 2] {
+    let n = (scene.n_samples as f32).sqrt().ceil() as usize;
+    let (width, height) = (scene.image.width as f32, scene.image.height as f32);
+
+    let mut coverage = [Vec::<(f32, f32)>::new(), Vec::new()];
+    for (a, b) in iproduct!(0..n, 0..n) {
+        let u = (i as f32 + (a as f32 + 0.5) / n as f32) / width * 2.0 - 1.0;
+        let v = (j as f32 + (b as f32 + 0.5) / n as f32) / height * 2.0 - 1.0;
+        let ray = scene.camera.ray_to_point(u, v);
+
+        let ids = match visible_hit(scene, &ray, true) {
+            Some((idx, ..)) => [(idx + 1) as f32, material_id(&scene.objects[idx].material)],
+            None => [0.0, 0.0],
+        };
+        for (ids_coverage, id) in coverage.iter_mut().zip(ids) {
+            match ids_coverage.iter_mut().find(|(other, _)| *other == id) {
+                Some((_, count)) => *count += 1.0,
+                None => ids_coverage.push((id, 1.0)),
+            }
+        }
+    }
+
+    for ids_coverage in &mut coverage {
+        for (_, count) in ids_coverage.iter_mut() {
+            *count /= (n * n) as f32;
+        }
+        ids_coverage.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap());
+    }
+    coverage
+}
+
+fn material_id(material: &Material) -> f32 {
+    match material {
+        Material::Diffuse => 1.0,
+        Material::Metallic { .. } => 2.0,
+        Material::Dielectric { .. } => 3.0,
+        Material::ShadowCatcher => 4.0,
+    }
+}
@@ -0,0 +1,109 @@
+use glm::vec3;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::environment::Environment;
+use crate::objects::{Geometry, Material, Object, RayIntersection};
+use crate::options::Options;
+use crate::ray::Ray;
+use crate::render::{apply_overrides, render};
+use crate::scene::{Scene, SceneBuilder};
+use crate::traversal::{Linear, TraversalBackend};
+
+const SCENE_SEED: u64 = 2024;
+const N_SPHERES: usize = 200;
+
+// Renders a fixed procedural scene and reports where the time went, the
+// speed and a checksum of the image. The checksum only changes when the
+// rendered values do, so it catches unintended changes between commits
+pub fn run_benchmark(options: &Options) {
+    let start = Instant::now();
+    let mut scene = benchmark_scene();
+    let scene_time = start.elapsed();
+
+    let start = Instant::now();
+    apply_overrides(&mut scene, options);
+    let build_time = start.elapsed();
+
+    let rays = Arc::new(AtomicU64::new(0));
+    let traversal = std::mem::replace(&mut scene.traversal, Box::new(Linear));
+    scene.traversal = Box::new(CountingTraversal {
+        inner: traversal,
+        rays: rays.clone(),
+    });
+
+    let start = Instant::now();
+    render(&mut scene, options);
+    let render_time = start.elapsed();
+
+    let rays = rays.load(Ordering::Relaxed);
+    println!("scene     {:10.1} ms", scene_time.as_secs_f64() * 1000.0);
+    println!("build     {:10.1} ms", build_time.as_secs_f64() * 1000.0);
+    println!("render    {:10.1} ms", render_time.as_secs_f64() * 1000.0);
+    println!(
+        "rays      {:10} ({:.2} Mrays/s)",
+        rays,
+        rays as f64 / render_time.as_secs_f64() / 1e6
+    );
+    println!("checksum  {:016x}", scene.image.checksum());
+}
+
+// Random spheres of all materials on a ground plane under an area light
+pub fn benchmark_scene() -> Scene {
+    let mut rng = SmallRng::seed_from_u64(SCENE_SEED);
+    let mut builder = SceneBuilder::default();
+
+    builder
+        .set_dimensions(320, 240)
+        .set_samples(16)
+        .set_ray_depth(6)
+        .set_environment(Environment::Color(vec3(0.2, 0.25, 0.3)));
+    builder.set_camera(
+        vec3(0.0, 4.0, 12.0),
+        vec3(1.0, 0.0, 0.0),
+        vec3(0.0, 0.95, -0.3).normalize(),
+        vec3(0.0, -0.3, -0.95).normalize(),
+        1.0,
+    );
+
+    builder.add_plane(vec3(0.0, 1.0, 0.0)).color = vec3(0.7, 0.7, 0.7);
+
+    for _ in 0..N_SPHERES {
+        let radius = rng.gen_range(0.15..0.5);
+        let center = vec3(rng.gen_range(-8.0..8.0), radius, rng.gen_range(-8.0..4.0));
+        let color = vec3(rng.gen(), rng.gen(), rng.gen());
+        let material = match rng.gen_range(0..3) {
+            0 => Material::Diffuse,
+            1 => Material::Metallic { fresnel: None },
+            _ => Material::Dielectric { ior: 1.5 },
+        };
+
+        let sphere = builder.add_sphere(center, radius);
+        sphere.color = color;
+        sphere.material = material;
+    }
+
+    let light = builder.add_box(vec3(3.0, 0.1, 3.0));
+    light.geometry.position = vec3(0.0, 8.0, 0.0);
+    light.emission = vec3(8.0, 8.0, 8.0);
+
+    builder.build()
+}
+
+struct CountingTraversal {
+    inner: Box<dyn TraversalBackend>,
+    rays: Arc<AtomicU64>,
+}
+
+impl TraversalBackend for CountingTraversal {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)> {
+        self.rays.fetch_add(1, Ordering::Relaxed);
+        self.inner.intersect(objects, ray)
+    }
+}
@@ -1,23 +1,234 @@
 use glm::{vec3, Vec3};
 use na::Matrix3;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
+use crate::image::Image;
+use crate::objects::Aabb;
+use crate::random::PathSampler;
 use crate::ray::Ray;
 
+// Rejection sampling of a mask gives up after this many tries and uses
+// the lens center
+const MAX_MASK_TRIES: usize = 64;
+
+// Thin lens, a pinhole while the aperture is 0
+#[derive(Clone)]
+pub struct Lens {
+    // radius
+    pub aperture: f32,
+    // distance along the forward axis that is in focus
+    pub focus_distance: f32,
+    // polygonal aperture with this many blades, round below 3
+    pub blades: usize,
+    // radians
+    pub blade_rotation: f32,
+    // aperture shape over the square around the lens, white is open
+    pub mask: Option<Arc<Image>>,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture: 0.0,
+            focus_distance: 1.0,
+            blades: 0,
+            blade_rotation: 0.0,
+            mask: None,
+        }
+    }
+}
+
+impl Lens {
+    // Uniform point of the aperture shape, within the unit disk
+    pub fn sample(&self, sampler: &mut PathSampler) -> (f32, f32) {
+        if let Some(mask) = &self.mask {
+            for _ in 0..MAX_MASK_TRIES {
+                let (x, y) = sampler.next_2d();
+                let i = ((x * mask.width as f32) as usize).min(mask.width - 1);
+                let j = ((y * mask.height as f32) as usize).min(mask.height - 1);
+                if sampler.next_1d() < mask.get(i, j).max() {
+                    return (2.0 * x - 1.0, 2.0 * y - 1.0);
+                }
+            }
+            return (0.0, 0.0);
+        }
+
+        if self.blades < 3 {
+            let (u, v) = sampler.next_2d();
+            let (r, phi) = (u.sqrt(), 2.0 * PI * v);
+            return (r * phi.cos(), r * phi.sin());
+        }
+
+        // A triangle between the center and one of the edges
+        let edge = 2.0 * PI / self.blades as f32;
+        let k = sampler.next_index(self.blades) as f32;
+        let corner = |i: f32| {
+            let phi = self.blade_rotation + i * edge;
+            vec3(phi.cos(), phi.sin(), 0.0)
+        };
+        let (a, b) = (corner(k), corner(k + 1.0));
+
+        let (s, t) = sampler.next_2d();
+        let s = s.sqrt();
+        let p = s * ((1.0 - t) * a + t * b);
+        (p.x, p.y)
+    }
+}
+
+#[derive(Clone)]
 pub struct Camera {
     pub position: Vec3,
     pub axis: Matrix3<f32>,
 
     pub tg_fov_x: f32,
     pub tg_fov_y: f32,
+
+    // Radial distortion coefficient, positive is barrel and negative is
+    // pincushion
+    pub distortion: f32,
+    // 0 is none, 1 is the full cos^4 falloff of a real lens
+    pub vignetting: f32,
+    // Relative magnification difference between the red and blue images
+    pub chromatic_aberration: f32,
+    // Horizontal offset of the image window, for off-axis stereo eyes
+    pub shift: f32,
+    pub lens: Lens,
+    // Camera rays only see what is between the distances along the axis
+    pub near: f32,
+    pub far: f32,
 }
 
 impl Camera {
     pub fn ray_to_point(&self, u: f32, v: f32) -> Ray {
         assert!(u.abs() <= 1.0 && v.abs() <= 1.0);
 
-        let direction = vec3(u * self.tg_fov_x, v * self.tg_fov_y, 1.0);
-        let direction = self.axis * direction;
+        let (x, y) = self.distorted(u, v);
+        let direction = self.axis * vec3(x + self.shift, y, 1.0);
+
+        self.clipped(Ray::new(self.position, direction))
+    }
+
+    // Ray through a point of the lens (from Lens::sample) that meets the
+    // pinhole ray at the focus distance
+    pub fn ray_through_lens(&self, u: f32, v: f32, (lx, ly): (f32, f32)) -> Ray {
+        let pinhole = self.ray_to_point(u, v);
+        let forward = self.axis.column(2).normalize();
+        let focus = pinhole.origin
+            + pinhole.direction * self.lens.focus_distance / glm::dot(&pinhole.direction, &forward);
+
+        let right = self.axis.column(0).normalize();
+        let up = self.axis.column(1).normalize();
+        let origin = self.position + self.lens.aperture * (lx * right + ly * up);
+        self.clipped(Ray::new(origin, focus - origin))
+    }
+
+    // Only hits between the near and far distances along the axis, from
+    // an origin on the lens
+    fn clipped(&self, ray: Ray) -> Ray {
+        let cos = glm::dot(&ray.direction, &self.axis.column(2).normalize());
+        ray.with_interval(self.near / cos, self.far / cos)
+    }
+
+    // Contains every point a camera ray can hit, through any point of the
+    // image and of the lens. Assumes orthogonal axes, as the lens does
+    pub fn frustum(&self) -> Frustum {
+        let right = self.axis.column(0).normalize();
+        let up = self.axis.column(1).normalize();
+        let forward = self.axis.column(2).normalize();
+
+        // The distortion scale is linear in r2, so largest at an end
+        let r2 = 1.0 + (self.tg_fov_y / self.tg_fov_x).powi(2);
+        let scale = 1.0f32.max(1.0 + self.distortion * r2);
+        // Tangents of the pinhole rays along the normalized axes
+        let norm = self.axis.column(2).norm();
+        let tg_x = self.tg_fov_x * scale * self.axis.column(0).norm() / norm;
+        let tg_y = self.tg_fov_y * scale * self.axis.column(1).norm() / norm;
+        let shift = self.shift * self.axis.column(0).norm() / norm;
+
+        // At depth z lens rays are at most aperture * (1 + z / focus)
+        // further out than the pinhole ones
+        let a = self.lens.aperture;
+        let spread = a / self.lens.focus_distance;
+        let side = |dir: Vec3, tg: f32| (dir - (tg + spread) * forward, a);
+        let mut planes = vec![
+            side(right, shift + tg_x),
+            side(-right, tg_x - shift),
+            side(up, tg_y),
+            side(-up, tg_y),
+            (-forward, -self.near),
+        ];
+        if self.far.is_finite() {
+            planes.push((forward, self.far));
+        }
+        Frustum {
+            origin: self.position,
+            planes,
+        }
+    }
+
+    // Image point (as for ray_to_point) a world point is seen at, if in
+    // the image and in front of the camera
+    pub fn project(&self, point: &Vec3) -> Option<(f32, f32)> {
+        let d = self.axis.try_inverse()? * (point - self.position);
+        if d.z <= 0.0 {
+            return None;
+        }
+        let (x, y) = (d.x / d.z - self.shift, d.y / d.z);
+
+        // The distortion is undone by fixed-point iteration
+        let (mut x0, mut y0) = (x, y);
+        for _ in 0..8 {
+            let r2 = (x0 * x0 + y0 * y0) / (self.tg_fov_x * self.tg_fov_x);
+            let scale = 1.0 + self.distortion * r2;
+            (x0, y0) = (x / scale, y / scale);
+        }
+        let (u, v) = (x0 / self.tg_fov_x, y0 / self.tg_fov_y);
+        (u.abs() <= 1.0 && v.abs() <= 1.0).then_some((u, v))
+    }
+
+    // Image point seen by the channel (0 red, 1 green, 2 blue), red is not
+    // shifted and blue is shifted the most towards the center
+    pub fn aberrated(&self, u: f32, v: f32, channel: usize) -> (f32, f32) {
+        let scale = 1.0 - self.chromatic_aberration * channel as f32 / 2.0;
+        (u * scale, v * scale)
+    }
+
+    // Weight of the radiance coming through the point of the image
+    pub fn vignetting_factor(&self, u: f32, v: f32) -> f32 {
+        let (x, y) = self.distorted(u, v);
+        let cos2 = 1.0 / (1.0 + x * x + y * y);
+        1.0 - self.vignetting + self.vignetting * cos2 * cos2
+    }
+
+    // Point of the image plane at distance 1, with r = 1 at the side edges
+    fn distorted(&self, u: f32, v: f32) -> (f32, f32) {
+        let x = u * self.tg_fov_x;
+        let y = v * self.tg_fov_y;
+        let r2 = (x * x + y * y) / (self.tg_fov_x * self.tg_fov_x);
+        let scale = 1.0 + self.distortion * r2;
+        (x * scale, y * scale)
+    }
+}
+
+// Intersection of half-spaces n . (p - origin) <= d
+pub struct Frustum {
+    origin: Vec3,
+    planes: Vec<(Vec3, f32)>,
+}
 
-        Ray::new(self.position, direction)
+impl Frustum {
+    // False only if the box is entirely outside one plane, by more than
+    // the slack
+    pub fn overlaps(&self, aabb: &Aabb, slack: f32) -> bool {
+        self.planes.iter().all(|(n, d)| {
+            // The corner furthest inside
+            let corner = vec3(
+                if n.x > 0.0 { aabb.min.x } else { aabb.max.x },
+                if n.y > 0.0 { aabb.min.y } else { aabb.max.y },
+                if n.z > 0.0 { aabb.min.z } else { aabb.max.z },
+            );
+            glm::dot(n, &(corner - self.origin)) <= d + slack * n.norm()
+        })
     }
 }
@@ -0,0 +1,160 @@
+use glm::Vec3;
+use na::Matrix3;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::interrupt::interrupted;
+use crate::options::Options;
+use crate::parser::parse_scene;
+use crate::render::{apply_overrides, render_to_file};
+#[cfg(feature = "ffmpeg")]
+use crate::render::{post_process, render};
+use crate::scene::Scene;
+#[cfg(feature = "ffmpeg")]
+use crate::video::Video;
+
+pub struct Keyframe {
+    // seconds
+    pub time: f32,
+    pub position: Vec3,
+    pub target: Vec3,
+    // radians, like CAMERA_FOV_X
+    pub fov_x: f32,
+}
+
+// One keyframe per line, sorted by time:
+//   KEYFRAME time px py pz tx ty tz fov_x
+pub fn parse_camera_path(path: &str) -> Vec<Keyframe> {
+    let file = File::open(path).unwrap();
+    let reader = BufReader::new(file);
+
+    let mut keyframes: Vec<Keyframe> = Vec::new();
+    for line in reader.lines() {
+        let tokens = line.as_ref().unwrap().split(' ').collect::<Vec<_>>();
+        if tokens[0] != "KEYFRAME" {
+            continue;
+        }
+
+        let float = |i: usize| tokens[i].parse::<f32>().unwrap();
+        let keyframe = Keyframe {
+            time: float(1),
+            position: glm::vec3(float(2), float(3), float(4)),
+            target: glm::vec3(float(5), float(6), float(7)),
+            fov_x: float(8),
+        };
+        if let Some(last) = keyframes.last() {
+            assert!(
+                keyframe.time > last.time,
+                "keyframes must be sorted by time"
+            );
+        }
+        keyframes.push(keyframe);
+    }
+
+    assert!(!keyframes.is_empty(), "camera path has no keyframes");
+    keyframes
+}
+
+// Renders every frame of the path into files named by frame_path, or
+// into the --video file
+pub fn render_camera_path(scene_path: &str, path: &str, options: &Options, output: &str) {
+    let keyframes = parse_camera_path(path);
+    let (start, end) = (keyframes[0].time, keyframes.last().unwrap().time);
+    let n_frames = ((end - start) * options.fps).floor() as usize + 1;
+
+    let mut scene = parse_scene(scene_path);
+    apply_overrides(&mut scene, options);
+
+    #[cfg(feature = "ffmpeg")]
+    let mut video = options
+        .video
+        .as_ref()
+        .map(|path| Video::new(path, options.fps));
+    #[cfg(not(feature = "ffmpeg"))]
+    assert!(options.video.is_none(), "--video needs the ffmpeg feature");
+
+    for frame in 0..n_frames {
+        // The interrupted frame is still written
+        if interrupted() {
+            break;
+        }
+        let time = start + frame as f32 / options.fps;
+        let (position, target, fov_x) = interpolate(&keyframes, time);
+        let scale = options.scene_scale;
+        look_at(&mut scene, position * scale, target * scale, fov_x);
+
+        #[cfg(feature = "ffmpeg")]
+        if let Some(video) = &mut video {
+            render(&mut scene, options);
+            post_process(&mut scene.image, options);
+            video.write_frame(&scene.image);
+            continue;
+        }
+        render_to_file(&mut scene, options, &frame_path(output, frame));
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    if let Some(video) = video {
+        video.finish();
+    }
+}
+
+// A printf-style frame number in the output (out_%04d.ppm or out_%d.ppm)
+// is replaced, otherwise it is appended to the stem: OUT_0000.ppm
+pub fn frame_path(output: &str, frame: usize) -> String {
+    if let Some((prefix, rest)) = output.split_once('%') {
+        if let Some((width, suffix)) = rest.split_once('d') {
+            if width.chars().all(|c| c.is_ascii_digit()) {
+                let width = width.parse::<usize>().unwrap_or(0);
+                return format!("{}{:0width$}{}", prefix, frame, suffix, width = width);
+            }
+        }
+    }
+
+    let (stem, extension) = output.rsplit_once('.').unwrap_or((output, "ppm"));
+    format!("{}_{:04}.{}", stem, frame, extension)
+}
+
+// Catmull-Rom spline through the keyframes, the end keyframes are repeated
+fn interpolate(keyframes: &[Keyframe], time: f32) -> (Vec3, Vec3, f32) {
+    let last = keyframes.len() - 1;
+    let i = keyframes
+        .iter()
+        .rposition(|keyframe| keyframe.time <= time)
+        .unwrap_or(0)
+        .min(last.saturating_sub(1));
+    let (a, b) = (&keyframes[i], &keyframes[(i + 1).min(last)]);
+    let t = if b.time > a.time {
+        ((time - a.time) / (b.time - a.time)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    let points =
+        [i.saturating_sub(1), i, (i + 1).min(last), (i + 2).min(last)].map(|k| &keyframes[k]);
+    let position = catmull_rom(points.map(|k| k.position), t);
+    let target = catmull_rom(points.map(|k| k.target), t);
+    let fov_x = catmull_rom(points.map(|k| Vec3::repeat(k.fov_x)), t).x;
+    (position, target, fov_x)
+}
+
+fn catmull_rom([p0, p1, p2, p3]: [Vec3; 4], t: f32) -> Vec3 {
+    let (t2, t3) = (t * t, t * t * t);
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+// Points the camera at the target with y up
+fn look_at(scene: &mut Scene, position: Vec3, target: Vec3, fov_x: f32) {
+    let forward = (target - position).normalize();
+    let right = glm::cross(&forward, &Vec3::y()).normalize();
+    let up = glm::cross(&right, &forward);
+
+    let camera = &mut scene.camera;
+    camera.position = position;
+    camera.axis = Matrix3::from_columns(&[right, up, forward]);
+    camera.tg_fov_x = (fov_x / 2.0).tan();
+    camera.tg_fov_y = scene.image.height as f32 / scene.image.width as f32 * camera.tg_fov_x;
+}
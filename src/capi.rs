@@ -0,0 +1,227 @@
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, c_int, CStr};
+use std::io::Cursor;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::controller::RenderController;
+use crate::options::Options;
+use crate::parser::parse_scene_from;
+use crate::render::{apply_overrides, load_scene, post_process, render_with};
+use crate::scene::Scene;
+
+// C API for embedding the renderer (--features capi), declared in
+// include/raytracing.h, which cbindgen writes (see build.rs). For a
+// shared library:
+//   cargo rustc --release --lib --features capi --crate-type cdylib
+// Strings are NUL-terminated UTF-8 and pointers must be valid. Functions
+// returning int give 0, or -1 with the message on stderr; the renderer
+// itself is left usable. While one thread renders, others may call
+// rt_progress and rt_cancel
+
+enum Source {
+    File(String),
+    Text(String),
+}
+
+/// A scene with its options
+pub struct RtRenderer {
+    source: Source,
+    // as on the command line, without the input and output
+    args: Mutex<Vec<String>>,
+    // reloaded when the options change, and out while rendering
+    scene: Mutex<Option<Scene>>,
+    progress: Arc<AtomicU32>,
+    controller: RenderController,
+}
+
+impl RtRenderer {
+    fn new(source: Source) -> Self {
+        let progress = Arc::new(AtomicU32::new(0));
+        let fraction = progress.clone();
+        let renderer = Self {
+            source,
+            args: Mutex::new(Vec::new()),
+            scene: Mutex::new(None),
+            progress,
+            controller: RenderController::with_progress(move |progress| {
+                fraction.store(progress.fraction().to_bits(), Ordering::Relaxed);
+            }),
+        };
+        // Errors in the scene show here rather than at the first render
+        let scene = renderer.load(&renderer.options(Vec::new()));
+        *renderer.scene.lock().unwrap() = Some(scene);
+        renderer
+    }
+
+    fn options(&self, args: Vec<String>) -> Options {
+        let mut options = Options::parse(args);
+        if let Source::File(path) = &self.source {
+            options.input = path.clone();
+        }
+        options
+    }
+
+    fn load(&self, options: &Options) -> Scene {
+        match &self.source {
+            Source::File(path) => load_scene(path, options),
+            Source::Text(text) => {
+                let mut scene = parse_scene_from(Cursor::new(text));
+                apply_overrides(&mut scene, options);
+                scene
+            }
+        }
+    }
+
+    // The loaded scene, or a new one for the current options
+    fn take_scene(&self, options: &Options) -> Scene {
+        let scene = self.scene.lock().unwrap().take();
+        scene.unwrap_or_else(|| self.load(options))
+    }
+}
+
+// Panics stop at the boundary, the default hook has printed them
+fn guard<T>(failure: T, f: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(failure)
+}
+
+unsafe fn string(s: *const c_char) -> String {
+    assert!(!s.is_null(), "null string");
+    CStr::from_ptr(s).to_str().unwrap().to_owned()
+}
+
+unsafe fn borrow<'a>(renderer: *const RtRenderer) -> &'a RtRenderer {
+    assert!(!renderer.is_null(), "null renderer");
+    &*renderer
+}
+
+fn status(ok: bool) -> c_int {
+    if ok {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Loads a scene file, or returns NULL
+#[no_mangle]
+pub unsafe extern "C" fn rt_renderer_from_file(path: *const c_char) -> *mut RtRenderer {
+    guard(std::ptr::null_mut(), || {
+        let source = Source::File(string(path));
+        Box::into_raw(Box::new(RtRenderer::new(source)))
+    })
+}
+
+/// Parses a scene from its text, or returns NULL. Relative paths in it
+/// are from the working directory
+#[no_mangle]
+pub unsafe extern "C" fn rt_renderer_from_memory(text: *const c_char) -> *mut RtRenderer {
+    guard(std::ptr::null_mut(), || {
+        let source = Source::Text(string(text));
+        Box::into_raw(Box::new(RtRenderer::new(source)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rt_renderer_free(renderer: *mut RtRenderer) {
+    if !renderer.is_null() {
+        drop(Box::from_raw(renderer));
+    }
+}
+
+/// Sets a command line option, e.g. ("--accel", "bvh"), with a NULL value
+/// for flags such as "--clay". The scene is reloaded for the next render
+#[no_mangle]
+pub unsafe extern "C" fn rt_set_option(
+    renderer: *const RtRenderer,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    status(guard(false, || {
+        let renderer = borrow(renderer);
+        let mut args = renderer.args.lock().unwrap().clone();
+        args.push(string(name));
+        if !value.is_null() {
+            args.push(string(value));
+        }
+        // Checked before it is kept
+        renderer.options(args.clone());
+        *renderer.args.lock().unwrap() = args;
+        *renderer.scene.lock().unwrap() = None;
+        true
+    }))
+}
+
+/// The size of the image, the buffer of rt_render holds width * height * 3
+/// floats
+#[no_mangle]
+pub unsafe extern "C" fn rt_image_size(
+    renderer: *const RtRenderer,
+    width: *mut usize,
+    height: *mut usize,
+) -> c_int {
+    status(guard(false, || {
+        let renderer = borrow(renderer);
+        let options = renderer.options(renderer.args.lock().unwrap().clone());
+        let scene = renderer.take_scene(&options);
+        (*width, *height) = (scene.image.width, scene.image.height);
+        *renderer.scene.lock().unwrap() = Some(scene);
+        true
+    }))
+}
+
+/// Renders into the buffer the RGB colors in the order of the PPM, after
+/// the exposure and grading options, as written to the PPM before
+/// quantization. Returns -1 if the buffer is too small; a cancelled render
+/// has the sample passes finished so far
+#[no_mangle]
+pub unsafe extern "C" fn rt_render(
+    renderer: *const RtRenderer,
+    pixels: *mut f32,
+    len: usize,
+) -> c_int {
+    status(guard(false, || {
+        let renderer = borrow(renderer);
+        let options = renderer.options(renderer.args.lock().unwrap().clone());
+        let mut scene = renderer.take_scene(&options);
+        let (width, height) = (scene.image.width, scene.image.height);
+        let needed = width * height * 3;
+        if pixels.is_null() || len < needed {
+            // Reported like the other failures, the scene is kept
+            *renderer.scene.lock().unwrap() = Some(scene);
+            assert!(!pixels.is_null(), "null buffer");
+            panic!("the buffer holds {} floats, the image {}", len, needed);
+        }
+
+        renderer.controller.reset();
+        renderer.progress.store(0, Ordering::Relaxed);
+        // A cancel lowers the sample count of the scene
+        let n_samples = scene.n_samples;
+        render_with(&mut scene, &options, &renderer.controller);
+        scene.n_samples = n_samples;
+        post_process(&mut scene.image, &options);
+
+        let pixels = std::slice::from_raw_parts_mut(pixels, needed);
+        for (pixel, color) in pixels.chunks_mut(3).zip(scene.image.pixels()) {
+            pixel.copy_from_slice(color.as_slice());
+        }
+        *renderer.scene.lock().unwrap() = Some(scene);
+        true
+    }))
+}
+
+/// Of the render in progress or the last one, from 0 to 1
+#[no_mangle]
+pub unsafe extern "C" fn rt_progress(renderer: *const RtRenderer) -> f32 {
+    guard(0.0, || {
+        f32::from_bits(borrow(renderer).progress.load(Ordering::Relaxed))
+    })
+}
+
+/// Stops the render in progress after the current run of pixels
+#[no_mangle]
+pub unsafe extern "C" fn rt_cancel(renderer: *const RtRenderer) {
+    guard((), || borrow(renderer).controller.cancel());
+}
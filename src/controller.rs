@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// For hosts embedding the renderer: render_with reports its progress to a
+// callback and stops when cancelled from another thread, keeping the
+// sample passes it finished, as the first Ctrl-C does
+pub type ProgressCallback = Box<dyn Fn(&Progress) + Send + Sync>;
+
+#[derive(Default)]
+pub struct RenderController {
+    cancelled: AtomicBool,
+    on_progress: Option<ProgressCallback>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    // sample passes finished and in all
+    pub pass: usize,
+    pub passes: usize,
+    // pixels finished of the current pass
+    pub pixels: usize,
+    pub total_pixels: usize,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    // Of the whole render, from 0 to 1
+    pub fn fraction(&self) -> f32 {
+        let pixels = self.pixels as f32 / self.total_pixels.max(1) as f32;
+        ((self.pass as f32 + pixels) / self.passes.max(1) as f32).min(1.0)
+    }
+}
+
+impl RenderController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The callback is called from the render threads, after every run of
+    // pixels and every pass
+    pub fn with_progress(on_progress: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            on_progress: Some(Box::new(on_progress)),
+        }
+    }
+
+    // Checked before every run of pixels, the pass in progress is dropped
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // To render again after a cancel
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn pass(
+        &self,
+        passes: usize,
+        total_pixels: usize,
+        start: Instant,
+    ) -> PassProgress<'_> {
+        PassProgress {
+            controller: self,
+            passes,
+            total_pixels,
+            start,
+            pixels: AtomicUsize::new(0),
+        }
+    }
+
+    fn report(&self, progress: Progress) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(&progress);
+        }
+    }
+}
+
+// Counts the pixels of a pass finished by the render threads
+pub(crate) struct PassProgress<'a> {
+    controller: &'a RenderController,
+    passes: usize,
+    total_pixels: usize,
+    start: Instant,
+    pixels: AtomicUsize,
+}
+
+impl PassProgress<'_> {
+    pub fn add(&self, pass: usize, pixels: usize) {
+        let pixels = self.pixels.fetch_add(pixels, Ordering::Relaxed) + pixels;
+        self.report(pass, pixels);
+    }
+
+    // The passes before it are finished, the last call is for all of them
+    pub fn begin(&self, pass: usize) {
+        self.pixels.store(0, Ordering::Relaxed);
+        self.report(pass, 0);
+    }
+
+    fn report(&self, pass: usize, pixels: usize) {
+        self.controller.report(Progress {
+            pass,
+            passes: self.passes,
+            pixels,
+            total_pixels: self.total_pixels,
+            elapsed: self.start.elapsed(),
+        });
+    }
+}
@@ -0,0 +1,229 @@
+use glm::{vec3, Vec3};
+use itertools::iproduct;
+use na::Matrix3;
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::camera::{Camera, Lens};
+use crate::image::Image;
+use crate::options::Options;
+use crate::output::render_metadata;
+use crate::render::{post_process, render};
+use crate::scene::Scene;
+
+// Reflection probes (--cubemap LAYOUT): six square 90 degree views from
+// --cubemap-point, the camera position by default, --cubemap-size pixels
+// wide, the image height by default. The lens effects of the scene camera
+// are left out. Layouts:
+//   cross     one 4x3 image with the front (-Z) in the middle
+//                      +Y
+//                  -X  -Z  +X  +Z
+//                      -Y
+//             each face upright as seen from the point, so they join up
+//   faces     six files, OUT_px.ppm, OUT_nx.ppm, ... OUT_nz.ppm
+//   equirect  one 2:1 latitude-longitude map resampled from the faces,
+//             -Z in the middle and +X to the right of it
+#[derive(Clone, Copy)]
+pub enum CubemapLayout {
+    Cross,
+    Faces,
+    Equirect,
+}
+
+impl FromStr for CubemapLayout {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cross" => Ok(CubemapLayout::Cross),
+            "faces" => Ok(CubemapLayout::Faces),
+            "equirect" => Ok(CubemapLayout::Equirect),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Face {
+    name: &'static str,
+    forward: [f32; 3],
+    up: [f32; 3],
+    // in the cross, counted from the bottom left
+    cell: (usize, usize),
+}
+
+const FACES: [Face; 6] = [
+    Face {
+        name: "px",
+        forward: [1.0, 0.0, 0.0],
+        up: [0.0, 1.0, 0.0],
+        cell: (2, 1),
+    },
+    Face {
+        name: "nx",
+        forward: [-1.0, 0.0, 0.0],
+        up: [0.0, 1.0, 0.0],
+        cell: (0, 1),
+    },
+    Face {
+        name: "py",
+        forward: [0.0, 1.0, 0.0],
+        up: [0.0, 0.0, 1.0],
+        cell: (1, 2),
+    },
+    Face {
+        name: "ny",
+        forward: [0.0, -1.0, 0.0],
+        up: [0.0, 0.0, -1.0],
+        cell: (1, 0),
+    },
+    Face {
+        name: "pz",
+        forward: [0.0, 0.0, 1.0],
+        up: [0.0, 1.0, 0.0],
+        cell: (3, 1),
+    },
+    Face {
+        name: "nz",
+        forward: [0.0, 0.0, -1.0],
+        up: [0.0, 1.0, 0.0],
+        cell: (1, 1),
+    },
+];
+
+// Returns the files written
+pub fn render_cubemap(
+    scene: &mut Scene,
+    options: &Options,
+    layout: CubemapLayout,
+    output: &str,
+) -> Vec<String> {
+    let start = Instant::now();
+    let camera = scene.camera.clone();
+    let (width, height) = (scene.image.width, scene.image.height);
+    let size = options.cubemap_size.unwrap_or(height);
+    let point = options
+        .cubemap_point
+        .map_or(camera.position, |point| point * options.scene_scale);
+
+    // Side by side, graded together so the faces get the same exposure
+    let mut strip = Image::new(6 * size, size);
+    for (k, face) in FACES.iter().enumerate() {
+        scene.camera = face_camera(&camera, point, face);
+        scene.image = Image::new(size, size);
+        render(scene, options);
+        for (u, v) in iproduct!(0..size, 0..size) {
+            strip.set(k * size + u, v, scene.image.get(u, v));
+        }
+    }
+    scene.camera = camera;
+    scene.image = Image::new(width, height);
+    scene.auto_exposure = post_process(&mut strip, options);
+
+    let faces = (0..6)
+        .map(|k| {
+            let mut face = Image::new(size, size);
+            for (u, v) in iproduct!(0..size, 0..size) {
+                face.set(u, v, strip.get(k * size + u, v));
+            }
+            face
+        })
+        .collect::<Vec<_>>();
+
+    let metadata = render_metadata(scene, options, start.elapsed());
+    let image = match layout {
+        CubemapLayout::Faces => {
+            let paths = face_paths(output);
+            for (face, path) in faces.iter().zip(&paths) {
+                face.write_with_metadata(path, &metadata);
+            }
+            return paths.to_vec();
+        }
+        CubemapLayout::Cross => cross(&faces),
+        CubemapLayout::Equirect => equirect(&faces),
+    };
+    image.write_with_metadata(output, &metadata);
+    vec![output.to_owned()]
+}
+
+// Files of the faces layout
+pub fn face_paths(output: &str) -> [String; 6] {
+    let (stem, extension) = output.rsplit_once('.').unwrap_or((output, "ppm"));
+    FACES.map(|face| format!("{}_{}.{}", stem, face.name, extension))
+}
+
+fn face_camera(camera: &Camera, position: Vec3, face: &Face) -> Camera {
+    let forward = Vec3::from(face.forward);
+    let up = Vec3::from(face.up);
+    Camera {
+        position,
+        axis: Matrix3::from_columns(&[forward.cross(&up), up, forward]),
+        tg_fov_x: 1.0,
+        tg_fov_y: 1.0,
+        distortion: 0.0,
+        vignetting: 0.0,
+        chromatic_aberration: 0.0,
+        shift: 0.0,
+        lens: Lens::default(),
+        ..camera.clone()
+    }
+}
+
+// The cells off the cross stay black
+fn cross(faces: &[Image]) -> Image {
+    let size = faces[0].width;
+    let mut image = Image::new(4 * size, 3 * size);
+    for (face, image_face) in FACES.iter().zip(faces) {
+        let (column, row) = face.cell;
+        for (u, v) in iproduct!(0..size, 0..size) {
+            image.set(column * size + u, row * size + v, image_face.get(u, v));
+        }
+    }
+    image
+}
+
+fn equirect(faces: &[Image]) -> Image {
+    let size = faces[0].width;
+    let (width, height) = (4 * size, 2 * size);
+    let mut image = Image::new(width, height);
+    for (i, j) in iproduct!(0..width, 0..height) {
+        // v grows upwards
+        let longitude = ((i as f32 + 0.5) / width as f32 * 2.0 - 1.0) * PI;
+        let latitude = ((j as f32 + 0.5) / height as f32 - 0.5) * PI;
+        let direction = vec3(
+            longitude.sin() * latitude.cos(),
+            latitude.sin(),
+            -longitude.cos() * latitude.cos(),
+        );
+        image.set(i, j, lookup(faces, &direction));
+    }
+    image
+}
+
+// Bilinear in the face the direction points into
+fn lookup(faces: &[Image], direction: &Vec3) -> Vec3 {
+    let (k, face) = FACES
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| {
+            let along = |face: &Face| glm::dot(direction, &Vec3::from(face.forward));
+            along(a).total_cmp(&along(b))
+        })
+        .unwrap();
+    let forward = Vec3::from(face.forward);
+    let up = Vec3::from(face.up);
+    let along = glm::dot(direction, &forward);
+    let x = glm::dot(direction, &forward.cross(&up)) / along;
+    let y = glm::dot(direction, &up) / along;
+
+    let image = &faces[k];
+    let size = image.width;
+    let to_pixel = |t: f32| ((t + 1.0) / 2.0 * size as f32 - 0.5).clamp(0.0, (size - 1) as f32);
+    let (u, v) = (to_pixel(x), to_pixel(y));
+    let (u0, v0) = (u.floor() as usize, v.floor() as usize);
+    let (u1, v1) = ((u0 + 1).min(size - 1), (v0 + 1).min(size - 1));
+    let (fu, fv) = (u - u0 as f32, v - v0 as f32);
+    let bottom = image.get(u0, v0) * (1.0 - fu) + image.get(u1, v0) * fu;
+    let top = image.get(u0, v1) * (1.0 - fu) + image.get(u1, v1) * fu;
+    bottom * (1.0 - fv) + top * fv
+}
@@ -0,0 +1,212 @@
+use glm::Vec3;
+use itertools::iproduct;
+use na::{Rotation3, Unit};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::time::Instant;
+
+use crate::camera::Camera;
+use crate::exr::write_exr;
+use crate::interrupt::interrupted;
+use crate::options::Options;
+use crate::output::render_metadata;
+use crate::random::PathSampler;
+use crate::render::{load_scene, render};
+use crate::report::{json_object, json_string, json_vec3};
+use crate::scene::Scene;
+use crate::trace::visible_hit;
+
+// Training data for denoisers (--dataset DIR): the scene from random views
+// around its camera, the first view being the camera itself. For every
+// view NNNN it writes
+//   NNNN_noisy.exr  radiance with --dataset-noisy samples, and the albedo,
+//                   normal and depth features of the first hits
+//   NNNN_clean.exr  radiance with the samples of the scene, from another
+//                   seed so its noise is independent of the noisy one
+// and lists them with the cameras in DIR/manifest.json
+
+// Largest move of a view, a fraction of the scene size, and largest turn
+// to the sides and up or down, radians
+const VIEW_MOVE: f32 = 0.1;
+const VIEW_TURN: f32 = 0.25;
+// The albedo and normal are averaged over this many rays per pixel side,
+// antialiased like the radiance
+const FEATURE_GRID: usize = 4;
+// Sampler stream of the features apart from the render steps, so the
+// mixes they pick are independent of its samples
+const FEATURE_STREAM: usize = usize::MAX - 2;
+
+pub fn render_dataset(dir: &str, options: &Options) {
+    std::fs::create_dir_all(dir).unwrap();
+    let mut scene = load_scene(&options.input, options);
+    let (camera, n_samples) = (scene.camera.clone(), scene.n_samples);
+    let size = scene.bounds().map_or(1.0, |bounds| bounds.size());
+    let clean_options = Options {
+        seed: options.seed.wrapping_add(1),
+        ..options.clone()
+    };
+
+    let mut rng = SmallRng::seed_from_u64(options.seed);
+    let mut views = Vec::new();
+    for view in 0..options.dataset_views {
+        scene.camera = if view == 0 {
+            camera.clone()
+        } else {
+            random_view(&camera, size, &mut rng)
+        };
+
+        let start = Instant::now();
+        scene.n_samples = options.dataset_noisy;
+        render(&mut scene, options);
+        let mut noisy = radiance(&scene);
+        noisy.extend(features(&scene, options.seed));
+        let noisy_metadata = render_metadata(&scene, options, start.elapsed());
+
+        let start = Instant::now();
+        scene.n_samples = n_samples;
+        render(&mut scene, &clean_options);
+        let clean = radiance(&scene);
+        let clean_metadata = render_metadata(&scene, &clean_options, start.elapsed());
+
+        // Either pass may be short of samples then
+        if interrupted() {
+            break;
+        }
+        let (width, height) = (scene.image.width, scene.image.height);
+        let (noisy_name, clean_name) = (
+            format!("{:04}_noisy.exr", view),
+            format!("{:04}_clean.exr", view),
+        );
+        let path = |name: &str| format!("{}/{}", dir, name);
+        write_exr(&path(&noisy_name), width, height, &noisy, &noisy_metadata);
+        write_exr(&path(&clean_name), width, height, &clean, &clean_metadata);
+        log::info!("wrote view {} of {}", view + 1, options.dataset_views);
+
+        let axis = &scene.camera.axis;
+        let camera = [
+            ("position", json_vec3(&scene.camera.position)),
+            ("right", json_vec3(&axis.column(0).into_owned())),
+            ("up", json_vec3(&axis.column(1).into_owned())),
+            ("forward", json_vec3(&axis.column(2).into_owned())),
+        ];
+        views.push(json_object(&[
+            ("noisy", json_string(&noisy_name)),
+            ("clean", json_string(&clean_name)),
+            ("camera", json_object(&camera)),
+        ]));
+    }
+
+    let channels = |names: &[&str]| {
+        let names = names
+            .iter()
+            .map(|name| json_string(name))
+            .collect::<Vec<_>>();
+        format!("[{}]", names.join(", "))
+    };
+    let manifest = json_object(&[
+        ("input", json_string(&options.input)),
+        ("width", scene.image.width.to_string()),
+        ("height", scene.image.height.to_string()),
+        ("noisy_samples", options.dataset_noisy.to_string()),
+        ("clean_samples", n_samples.to_string()),
+        (
+            "noisy_channels",
+            channels(&[
+                "R", "G", "B", "albedo.R", "albedo.G", "albedo.B", "normal.X", "normal.Y",
+                "normal.Z", "depth.Z",
+            ]),
+        ),
+        ("clean_channels", channels(&["R", "G", "B"])),
+        ("views", format!("[{}]", views.join(", "))),
+    ]);
+    std::fs::write(format!("{}/manifest.json", dir), manifest + "\n").unwrap();
+}
+
+// The scene camera moved within a ball and turned about the world up axis
+// and its own right axis
+fn random_view(camera: &Camera, size: f32, rng: &mut SmallRng) -> Camera {
+    let offset = loop {
+        let p = Vec3::from_fn(|_, _| rng.gen_range(-1.0..1.0));
+        if p.norm_squared() <= 1.0 {
+            break p;
+        }
+    };
+    let right = Unit::new_normalize(camera.axis.column(0).into_owned());
+    let yaw = Rotation3::from_axis_angle(&Vec3::y_axis(), rng.gen_range(-VIEW_TURN..VIEW_TURN));
+    let pitch = Rotation3::from_axis_angle(&right, rng.gen_range(-VIEW_TURN..VIEW_TURN));
+
+    let mut view = camera.clone();
+    view.position += VIEW_MOVE * size * offset;
+    view.axis = (yaw * pitch).matrix() * camera.axis;
+    view
+}
+
+// EXR rows go from the top, image rows from the bottom
+fn exr_pixels(scene: &Scene) -> Vec<(usize, usize)> {
+    let (width, height) = (scene.image.width, scene.image.height);
+    (0..width * height)
+        .map(|idx| (idx % width, height - 1 - idx / width))
+        .collect()
+}
+
+fn radiance(scene: &Scene) -> Vec<(&'static str, Vec<f32>)> {
+    let pixels = exr_pixels(scene);
+    ["R", "G", "B"]
+        .into_iter()
+        .enumerate()
+        .map(|(c, name)| {
+            let values = pixels.iter().map(|&(i, j)| scene.image.get(i, j)[c]);
+            (name, values.collect())
+        })
+        .collect()
+}
+
+// Albedo and normal averaged over the pixel, 0 where it shows the
+// background, and the depth along the camera axis of the ray through its
+// center, as in the AOVs
+fn features(scene: &Scene, seed: u64) -> Vec<(&'static str, Vec<f32>)> {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let forward = scene.camera.axis.column(2).normalize();
+    let n = FEATURE_GRID;
+
+    let features = exr_pixels(scene)
+        .par_iter()
+        .map(|&(i, j)| {
+            let (mut albedo, mut normal) = (Vec3::zeros(), Vec3::zeros());
+            for (a, b) in iproduct!(0..n, 0..n) {
+                let u = (i as f32 + (a as f32 + 0.5) / n as f32) / width as f32 * 2.0 - 1.0;
+                let v = (j as f32 + (b as f32 + 0.5) / n as f32) / height as f32 * 2.0 - 1.0;
+                let ray = scene.camera.ray_to_point(u, v);
+                if let Some((idx, hit, point)) = visible_hit(scene, &ray, true) {
+                    let pixel = PathSampler::new(seed, j * width + i, FEATURE_STREAM);
+                    let mut sampler = pixel.fork(a * n + b);
+                    albedo += scene.objects[idx]
+                        .surface_at(&point, &hit.n, &mut sampler)
+                        .1;
+                    normal += hit.n;
+                }
+            }
+
+            let u = (i as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let v = (j as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+            let ray = scene.camera.ray_to_point(u, v);
+            let depth = visible_hit(scene, &ray, true).map_or(f32::INFINITY, |(_, hit, _)| {
+                glm::dot(&(hit.t * ray.direction), &forward)
+            });
+            let samples = (n * n) as f32;
+            (albedo / samples, normal / samples, depth)
+        })
+        .collect::<Vec<_>>();
+
+    let channel = |f: &dyn Fn(&(Vec3, Vec3, f32)) -> f32| features.iter().map(f).collect();
+    vec![
+        ("albedo.R", channel(&|f| f.0.x)),
+        ("albedo.G", channel(&|f| f.0.y)),
+        ("albedo.B", channel(&|f| f.0.z)),
+        ("normal.X", channel(&|f| f.1.x)),
+        ("normal.Y", channel(&|f| f.1.y)),
+        ("normal.Z", channel(&|f| f.1.z)),
+        ("depth.Z", channel(&|f| f.2)),
+    ]
+}
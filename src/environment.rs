@@ -0,0 +1,237 @@
+use glm::{vec3, Vec3};
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::profile_scope;
+use crate::random::ToSun;
+
+// Angular radius of the sun disk, radians
+const SUN_RADIUS: f32 = 0.00465;
+// Sun radiance relative to the sky at the zenith
+const SUN_RADIANCE: f32 = 1e5;
+// Lowest zenith cosine the sky model is evaluated at, directions below
+// the horizon get the horizon color
+const MIN_COS_THETA: f32 = 0.01;
+
+// Radiance coming from directions where rays leave the scene
+pub enum Environment {
+    Color(Vec3),
+    Sky(Sky),
+    Gradient(Gradient),
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        profile_scope!("environment");
+        match self {
+            Environment::Color(color) => *color,
+            Environment::Sky(sky) => sky.radiance(direction),
+            Environment::Gradient(gradient) => gradient.radiance(direction),
+        }
+    }
+
+    // Sampling strategy for the sun disk, if there is a sun above the horizon
+    pub fn to_sun(&self) -> Option<ToSun> {
+        match self {
+            Environment::Sky(sky) if sky.sun_direction.y > 0.0 => Some(ToSun {
+                direction: sky.sun_direction,
+                cos_max: SUN_RADIUS.cos(),
+            }),
+            _ => None,
+        }
+    }
+
+    // A constant color that lights the scene from every direction
+    pub fn is_uniform_light(&self) -> bool {
+        matches!(self, Environment::Color(color) if color.max() > 0.0)
+    }
+
+    // The line of the scene format that recreates it
+    pub fn scene_line(&self) -> String {
+        match self {
+            Environment::Color(c) => format!("BG_COLOR {} {} {}", c.x, c.y, c.z),
+            Environment::Sky(sky) => {
+                let d = sky.sun_direction;
+                format!(
+                    "SKY {} {} {} {} {}",
+                    d.x, d.y, d.z, sky.turbidity, sky.intensity
+                )
+            }
+            Environment::Gradient(g) => {
+                let colors =
+                    [g.zenith, g.horizon, g.ground].map(|c| format!("{} {} {}", c.x, c.y, c.z));
+                format!("BG_GRADIENT {}", colors.join(" "))
+            }
+        }
+    }
+}
+
+// Colors at the zenith, the horizon and straight down with y up, linear
+// in the height of the direction in between. A quick studio background
+#[derive(Clone, Copy)]
+pub struct Gradient {
+    pub zenith: Vec3,
+    pub horizon: Vec3,
+    pub ground: Vec3,
+}
+
+impl Gradient {
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let y = direction.y.clamp(-1.0, 1.0);
+        if y >= 0.0 {
+            self.horizon.lerp(&self.zenith, y)
+        } else {
+            self.horizon.lerp(&self.ground, -y)
+        }
+    }
+}
+
+// zenith:horizon[:ground] with comma separated colors, the ground is the
+// horizon color when left out
+impl FromStr for Gradient {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let color = |s: &str| -> Result<Vec3, ()> {
+            let c = s
+                .split(',')
+                .map(|x| x.parse::<f32>().map_err(|_| ()))
+                .collect::<Result<Vec<_>, _>>()?;
+            match c[..] {
+                [r, g, b] => Ok(vec3(r, g, b)),
+                _ => Err(()),
+            }
+        };
+        let colors = s.split(':').map(color).collect::<Result<Vec<_>, _>>()?;
+        match colors[..] {
+            [zenith, horizon] => Ok(Gradient {
+                zenith,
+                horizon,
+                ground: horizon,
+            }),
+            [zenith, horizon, ground] => Ok(Gradient {
+                zenith,
+                horizon,
+                ground,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+// Preetham et al. "A Practical Analytic Model for Daylight" with y up,
+// scaled so that the sky at the zenith has the given luminance
+pub struct Sky {
+    sun_direction: Vec3,
+    turbidity: f32,
+    intensity: f32,
+    sun_theta: f32,
+    // Perez coefficients and zenith values for Y, x and y
+    perez: [[f32; 5]; 3],
+    zenith: [f32; 3],
+    sun_color: Vec3,
+}
+
+impl Sky {
+    pub fn new(sun_direction: Vec3, turbidity: f32, intensity: f32) -> Self {
+        let sun_direction = sun_direction.normalize();
+        let sun_theta = sun_direction.y.clamp(MIN_COS_THETA, 1.0).acos();
+        let t = turbidity;
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let (s, s2, s3) = (sun_theta, sun_theta.powi(2), sun_theta.powi(3));
+        let zenith_x = t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+        let zenith_y = t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+        let mut sky = Self {
+            sun_direction,
+            turbidity,
+            intensity,
+            sun_theta,
+            perez,
+            zenith: [1.0, zenith_x, zenith_y],
+            sun_color: Vec3::zeros(),
+        };
+
+        // The sun has the chromaticity of the sky around it
+        let [_, x, y] = sky.xyy(sun_direction.y, 0.0);
+        sky.sun_color = xyy_to_rgb(x, y, SUN_RADIANCE * intensity);
+        sky
+    }
+
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let cos_gamma = glm::dot(direction, &self.sun_direction).clamp(-1.0, 1.0);
+        if self.sun_direction.y > 0.0 && cos_gamma >= SUN_RADIUS.cos() {
+            return self.sun_color;
+        }
+
+        let [luminance, x, y] = self.xyy(direction.y, cos_gamma.acos());
+        xyy_to_rgb(x, y, luminance * self.intensity)
+    }
+
+    fn xyy(&self, cos_theta: f32, gamma: f32) -> [f32; 3] {
+        let cos_theta = cos_theta.max(MIN_COS_THETA);
+        std::array::from_fn(|i| {
+            let f = perez(&self.perez[i], cos_theta, gamma);
+            let f_zenith = perez(&self.perez[i], 1.0, self.sun_theta);
+            self.zenith[i] * f / f_zenith
+        })
+    }
+}
+
+fn perez(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+
+    let rgb = vec3(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    );
+    rgb.sup(&Vec3::zeros())
+}
+
+// Direction towards the sun, the azimuth is measured from -z towards x
+pub fn sun_direction(elevation: f32, azimuth: f32) -> Vec3 {
+    let elevation = elevation * PI / 180.0;
+    let azimuth = azimuth * PI / 180.0;
+    vec3(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        -elevation.cos() * azimuth.cos(),
+    )
+}
@@ -0,0 +1,86 @@
+use crate::options::Options;
+use crate::scene::Scene;
+
+// Every distance tolerance of the intersection code. The absolute ones
+// grow with the size of the scene, the error of the intersection solvers
+// does too; the relative offset of new rays is a number of ulps and
+// scales by itself. Each scene keeps its own, set when it is loaded, and
+// rays carry it to the objects they are tested against
+
+// Size of the scenes the absolute values below are meant for
+const REFERENCE_SIZE: f32 = 10.0;
+
+// Constants from "A Fast and Robust Method for Avoiding Self-Intersection"
+// (Wächter, Binder): points near the origin are moved by a fixed distance,
+// others by a fixed number of ulps, so the offset scales with magnitude
+const OFFSET_ORIGIN: f32 = 1.0 / 32.0;
+const OFFSET_FLOAT_SCALE: f32 = 1.0 / 65536.0;
+const OFFSET_INT_SCALE: f32 = 256.0;
+
+// Distance at which sphere tracing counts a hit on an SDF
+const SDF_HIT: f32 = 1e-4;
+
+// Thresholds without a unit, the same at every scale: sampled directions
+// with a lower pdf are dropped, cosines are kept above MIN_COS where they
+// divide, and irradiance records weigh at most 1 / MIN_RECORD_ERROR
+pub const MIN_PDF: f32 = 1e-6;
+pub const MIN_COS: f32 = 1e-6;
+pub const MIN_RECORD_ERROR: f32 = 1e-4;
+// Bound on the relative rounding error of one float operation
+pub const UNIT_ROUNDOFF: f32 = f32::EPSILON / 2.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Epsilon {
+    // of the absolute tolerances
+    pub scale: f32,
+    // ulps the ray offset moves points away from the origin by
+    pub offset_int_scale: f32,
+}
+
+impl Default for Epsilon {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            offset_int_scale: OFFSET_INT_SCALE,
+        }
+    }
+}
+
+impl Epsilon {
+    // From the bounded objects of the scene
+    pub fn of_scene(scene: &Scene) -> Self {
+        Self {
+            scale: scene_scale(scene),
+            ..Default::default()
+        }
+    }
+
+    // With --eps-scale and --ray-offset-ulps
+    pub fn with_options(self, options: &Options) -> Self {
+        Self {
+            scale: options.eps_scale.unwrap_or(self.scale),
+            offset_int_scale: options.ray_offset_ulps.unwrap_or(self.offset_int_scale),
+        }
+    }
+
+    // Below it the ray offset is a distance rather than ulps
+    pub fn offset_origin(&self) -> f32 {
+        OFFSET_ORIGIN * self.scale
+    }
+
+    pub fn offset_float_scale(&self) -> f32 {
+        OFFSET_FLOAT_SCALE * self.scale
+    }
+
+    pub fn sdf_hit(&self) -> f32 {
+        SDF_HIT * self.scale
+    }
+}
+
+// Size of the scene relative to the reference one
+pub fn scene_scale(scene: &Scene) -> f32 {
+    match scene.bounds() {
+        Some(bounds) if bounds.size() > 0.0 => bounds.size() / REFERENCE_SIZE,
+        _ => 1.0,
+    }
+}
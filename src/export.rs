@@ -0,0 +1,169 @@
+use glm::Vec3;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::objects::{Fresnel, Geometry, Material};
+use crate::scene::Scene;
+
+// Writes the scene in the scene format, to see what the parser and the
+// overrides produced or to keep generated geometry (point clouds, pbrt
+// imports) as a plain scene file
+pub fn export_scene(scene: &Scene, path: &str) {
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    for line in scene_lines(scene) {
+        writeln!(file, "{}", line).unwrap();
+    }
+}
+
+pub fn scene_lines(scene: &Scene) -> Vec<String> {
+    let camera = &scene.camera;
+    let axis = |i: usize| vec3_line(&camera.axis.column(i).into_owned());
+
+    let mut lines = vec![
+        format!("DIMENSIONS {} {}", scene.image.width, scene.image.height),
+        format!("RAY_DEPTH {}", scene.ray_depth),
+        format!("SAMPLES {}", scene.n_samples),
+        scene.environment.scene_line(),
+        format!("CAMERA_POSITION {}", vec3_line(&camera.position)),
+        format!("CAMERA_RIGHT {}", axis(0)),
+        format!("CAMERA_UP {}", axis(1)),
+        format!("CAMERA_FORWARD {}", axis(2)),
+        format!("CAMERA_FOV_X {}", 2.0 * camera.tg_fov_x.atan()),
+        format!("CAMERA_DISTORTION {}", camera.distortion),
+        format!("CAMERA_VIGNETTING {}", camera.vignetting),
+        format!(
+            "CAMERA_CHROMATIC_ABERRATION {}",
+            camera.chromatic_aberration
+        ),
+        format!(
+            "CAMERA_APERTURE {} {}",
+            camera.lens.aperture, camera.lens.focus_distance
+        ),
+        format!(
+            "CAMERA_APERTURE_BLADES {} {}",
+            camera.lens.blades, camera.lens.blade_rotation
+        ),
+        format!("CAMERA_CLIP {} {}", camera.near, camera.far),
+    ];
+    let max = &scene.max_bounces;
+    for (keyword, limit) in [
+        ("RAY_DEPTH_DIFFUSE", max.diffuse),
+        ("RAY_DEPTH_GLOSSY", max.glossy),
+        ("RAY_DEPTH_TRANSMISSION", max.transmission),
+    ] {
+        if limit != usize::MAX {
+            lines.push(format!("{} {}", keyword, limit));
+        }
+    }
+    if camera.lens.mask.is_some() {
+        log::warn!("export: the aperture mask is not written");
+    }
+
+    for obj in &scene.objects {
+        let figure = obj.geometry.scene_lines();
+        if figure.is_empty() {
+            log::warn!("export: skipping an object the scene format can't describe");
+            continue;
+        }
+
+        lines.push("NEW_PRIMITIVE".to_owned());
+        lines.extend(figure);
+        if let Some(name) = &obj.name {
+            lines.push(format!("NAME {}", name));
+        }
+        lines.push(format!("COLOR {}", vec3_line(&obj.color)));
+        if let Some(checker) = &obj.checker {
+            lines.push(format!(
+                "CHECKER {} {}",
+                vec3_line(&checker.color),
+                checker.size
+            ));
+        }
+        if glm::length2(&obj.emission) > 0.0 {
+            lines.push(format!("EMISSION {}", vec3_line(&obj.emission)));
+            if obj.single_sided {
+                lines.push("SINGLE_SIDED".to_owned());
+            }
+            if let Some(names) = &obj.links.illuminates {
+                lines.push(format!("LIGHT_LINK {}", names.join(" ")));
+            }
+            if let Some(names) = &obj.links.shadowed_by {
+                lines.push(format!("SHADOW_LINK {}", names.join(" ")));
+            }
+        }
+        let hidden_from = obj.visibility.hidden_from();
+        if !hidden_from.is_empty() {
+            lines.push(format!("INVISIBLE {}", hidden_from.join(" ")));
+        }
+        match obj.material {
+            Material::Diffuse => {}
+            Material::Metallic { fresnel } => {
+                lines.push("METALLIC".to_owned());
+                match fresnel {
+                    Some(Fresnel::Conductor { eta, k }) => lines.push(format!(
+                        "CONDUCTOR_IOR {} {}",
+                        vec3_line(&eta),
+                        vec3_line(&k)
+                    )),
+                    Some(Fresnel::F82 { tint }) => {
+                        lines.push(format!("F82_TINT {}", vec3_line(&tint)))
+                    }
+                    None => {}
+                }
+            }
+            Material::Dielectric { ior } => {
+                lines.push("DIELECTRIC".to_owned());
+                lines.push(format!("IOR {}", ior));
+            }
+            Material::ShadowCatcher => lines.push("SHADOW_CATCHER".to_owned()),
+        }
+        if obj.roughness > 0.0 {
+            lines.push(format!("ROUGHNESS {}", obj.roughness));
+        }
+        if let Some(mix) = &obj.mix {
+            let (kind, ior) = match mix.material {
+                Material::Diffuse => ("DIFFUSE", String::new()),
+                Material::Metallic { .. } => ("METALLIC", String::new()),
+                Material::Dielectric { ior } => ("DIELECTRIC", format!(" {}", ior)),
+                Material::ShadowCatcher => ("SHADOW_CATCHER", String::new()),
+            };
+            lines.push(format!(
+                "MIX {} {} {}{}",
+                kind,
+                vec3_line(&mix.color),
+                mix.factor,
+                ior
+            ));
+            if let Some(size) = mix.mask_size {
+                lines.push(format!("MIX_MASK {}", size));
+            }
+        }
+    }
+
+    for plane in &scene.clip_planes {
+        lines.push(format!(
+            "CLIP_PLANE {} {}",
+            vec3_line(&plane.point),
+            vec3_line(&plane.normal)
+        ));
+    }
+    for portal in &scene.portals {
+        let (s, q) = (portal.figure.sizes, portal.rotation.coords);
+        lines.push(format!(
+            "PORTAL {} {} {} {} {} {} {}",
+            s.x,
+            s.y,
+            vec3_line(&portal.position),
+            q.x,
+            q.y,
+            q.z,
+            q.w
+        ));
+    }
+
+    lines
+}
+
+fn vec3_line(v: &Vec3) -> String {
+    format!("{} {} {}", v.x, v.y, v.z)
+}
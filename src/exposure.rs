@@ -0,0 +1,189 @@
+use glm::Vec3;
+use std::str::FromStr;
+
+use crate::image::{luminance, Image, MIDDLE_GRAY};
+
+// Picks the exposure from the rendered radiance, for scenes where the
+// brightness isn't known in advance. --exposure is added on top of it
+#[derive(Clone, Copy)]
+pub enum AutoExposure {
+    // log average weighted towards the image center
+    CenterWeighted,
+    // log average of the pixels between the 10th and 90th percentile, so
+    // emitters and black background don't pull it
+    Percentile,
+}
+
+impl FromStr for AutoExposure {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "center-weighted" => Ok(AutoExposure::CenterWeighted),
+            "percentile" => Ok(AutoExposure::Percentile),
+            _ => Err(()),
+        }
+    }
+}
+
+// Diagnostic views of the graded image, for judging the exposure
+#[derive(Clone, Copy)]
+pub enum ExposureCheck {
+    // bands of luminance before tonemapping, see FALSE_COLORS
+    FalseColor,
+    // diagonal stripes over what comes out near white after tonemapping
+    Zebra,
+}
+
+impl FromStr for ExposureCheck {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "false-color" => Ok(ExposureCheck::FalseColor),
+            "zebra" => Ok(ExposureCheck::Zebra),
+            _ => Err(()),
+        }
+    }
+}
+
+// One stop wide bands around middle gray (green), from 4 stops under and
+// below (purple) to 4 over and above (red, close to white after the
+// tonemap): purple, blue, light blue, teal, dark gray, green, light gray,
+// pink, yellow, red
+const FALSE_COLORS: [[f32; 3]; 10] = [
+    [0.4, 0.0, 0.6],
+    [0.0, 0.0, 1.0],
+    [0.0, 0.5, 1.0],
+    [0.0, 0.6, 0.6],
+    [0.3, 0.3, 0.3],
+    [0.0, 0.8, 0.0],
+    [0.6, 0.6, 0.6],
+    [1.0, 0.6, 0.7],
+    [1.0, 1.0, 0.0],
+    [1.0, 0.0, 0.0],
+];
+// Display value of the brightest channel from which zebra stripes show,
+// and their width in pixels
+pub const ZEBRA_LEVEL: f32 = 0.95;
+pub const ZEBRA_WIDTH: usize = 4;
+
+// Band of a graded luminance
+pub fn false_color(luminance: f32) -> Vec3 {
+    let stops = (luminance / MIDDLE_GRAY).log2();
+    let band = (stops + 5.5).floor().clamp(0.0, 9.0) as usize;
+    Vec3::from(FALSE_COLORS[band])
+}
+
+// Luminance bins in stops
+const MIN_EV: f32 = -12.0;
+const MAX_EV: f32 = 12.0;
+const BINS_PER_EV: usize = 4;
+// in half diagonals
+const CENTER_SIGMA: f32 = 0.5;
+const HISTOGRAM_WIDTH: usize = 40;
+
+pub struct Histogram {
+    // pixel weight per bin, pixels darker than MIN_EV are left out
+    bins: Vec<f32>,
+}
+
+impl Histogram {
+    pub fn new(image: &Image, center_weighted: bool) -> Self {
+        let n_bins = ((MAX_EV - MIN_EV) as usize) * BINS_PER_EV;
+        let mut bins = vec![0.0; n_bins];
+
+        let center = (image.width as f32 / 2.0, image.height as f32 / 2.0);
+        let half_diagonal = center.0.hypot(center.1);
+        for v in 0..image.height {
+            for u in 0..image.width {
+                let l = luminance(&image.get(u, v));
+                if l.is_nan() || l <= MIN_EV.exp2() {
+                    continue;
+                }
+                let bin = ((l.log2() - MIN_EV) * BINS_PER_EV as f32) as usize;
+
+                let weight = if center_weighted {
+                    let dx = u as f32 + 0.5 - center.0;
+                    let dy = v as f32 + 0.5 - center.1;
+                    let r = dx.hypot(dy) / half_diagonal;
+                    (-r * r / (2.0 * CENTER_SIGMA * CENTER_SIGMA)).exp()
+                } else {
+                    1.0
+                };
+                bins[bin.min(n_bins - 1)] += weight;
+            }
+        }
+
+        Self { bins }
+    }
+
+    fn bin_ev(bin: usize) -> f32 {
+        MIN_EV + (bin as f32 + 0.5) / BINS_PER_EV as f32
+    }
+
+    // Weighted mean of the log luminance between two percentiles
+    pub fn mean_ev(&self, low: f32, high: f32) -> Option<f32> {
+        let total = self.bins.iter().sum::<f32>();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let (low, high) = (low * total, high * total);
+        let mut below = 0.0;
+        let (mut sum, mut weight) = (0.0, 0.0);
+        for (bin, &count) in self.bins.iter().enumerate() {
+            // The part of the bin inside the percentile range
+            let inside = (below + count).min(high) - below.max(low);
+            below += count;
+            if inside > 0.0 {
+                sum += inside * Self::bin_ev(bin);
+                weight += inside;
+            }
+        }
+        (weight > 0.0).then(|| sum / weight)
+    }
+
+    // One line per stop, from the darkest to the brightest occupied one
+    pub fn lines(&self) -> Vec<String> {
+        let stops = self
+            .bins
+            .chunks(BINS_PER_EV)
+            .map(|chunk| chunk.iter().sum::<f32>())
+            .collect::<Vec<_>>();
+        let max = stops.iter().cloned().fold(0.0, f32::max);
+        let Some(first) = stops.iter().position(|&count| count > 0.0) else {
+            return Vec::new();
+        };
+        let last = stops.iter().rposition(|&count| count > 0.0).unwrap();
+
+        (first..=last)
+            .map(|stop| {
+                let width = (stops[stop] / max * HISTOGRAM_WIDTH as f32).round() as usize;
+                format!(
+                    "{:+4} EV {}",
+                    MIN_EV as i32 + stop as i32,
+                    "#".repeat(width)
+                )
+            })
+            .collect()
+    }
+}
+
+// Stops that bring the image key to middle gray
+pub fn auto_exposure(image: &Image, mode: AutoExposure) -> f32 {
+    let histogram = Histogram::new(image, matches!(mode, AutoExposure::CenterWeighted));
+    for line in histogram.lines() {
+        log::debug!("{}", line);
+    }
+
+    let key = match mode {
+        AutoExposure::CenterWeighted => histogram.mean_ev(0.0, 1.0),
+        AutoExposure::Percentile => histogram.mean_ev(0.1, 0.9),
+    };
+    let Some(key) = key else {
+        log::warn!("the image is black, auto exposure is skipped");
+        return 0.0;
+    };
+    MIDDLE_GRAY.log2() - key
+}
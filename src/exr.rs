@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Minimal OpenEXR writer: one part, uncompressed scanlines, 32-bit float
+// channels. Channels are (name, values row by row from the top), names with
+// a dot ("normal.X") become layers in compositors. The metadata becomes
+// string attributes of the header
+pub fn write_exr(
+    path: &str,
+    width: usize,
+    height: usize,
+    channels: &[(&str, Vec<f32>)],
+    metadata: &[(String, String)],
+) {
+    let mut channels = channels.iter().collect::<Vec<_>>();
+    channels.sort_by_key(|(name, _)| *name);
+    for (name, values) in &channels {
+        assert!(
+            values.len() == width * height,
+            "wrong size of channel {}",
+            name
+        );
+    }
+
+    let mut header = Vec::new();
+    header.extend([0x76, 0x2f, 0x31, 0x01]);
+    header.extend(2_u32.to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for (name, _) in &channels {
+        chlist.extend(name.as_bytes());
+        chlist.push(0);
+        // FLOAT, pLinear and reserved, x and y sampling
+        chlist.extend(2_i32.to_le_bytes());
+        chlist.extend([0, 0, 0, 0]);
+        chlist.extend(1_i32.to_le_bytes());
+        chlist.extend(1_i32.to_le_bytes());
+    }
+    chlist.push(0);
+    attribute(&mut header, "channels", "chlist", &chlist);
+
+    attribute(&mut header, "compression", "compression", &[0]);
+    let window = [0, 0, width as i32 - 1, height as i32 - 1]
+        .into_iter()
+        .flat_map(i32::to_le_bytes)
+        .collect::<Vec<_>>();
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    for (key, value) in metadata {
+        attribute(&mut header, key, "string", value.as_bytes());
+    }
+    header.push(0);
+
+    // Every block is one scanline: y, size, then every channel's row
+    let line_size = 4 * width * channels.len();
+    let block_size = 8 + line_size;
+    let first_block = header.len() + 8 * height;
+
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    file.write_all(&header).unwrap();
+    for y in 0..height {
+        let offset = (first_block + y * block_size) as u64;
+        file.write_all(&offset.to_le_bytes()).unwrap();
+    }
+
+    for y in 0..height {
+        file.write_all(&(y as i32).to_le_bytes()).unwrap();
+        file.write_all(&(line_size as i32).to_le_bytes()).unwrap();
+        for (_, values) in &channels {
+            for value in &values[y * width..(y + 1) * width] {
+                file.write_all(&value.to_le_bytes()).unwrap();
+            }
+        }
+    }
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    header.extend(name.as_bytes());
+    header.push(0);
+    header.extend(type_name.as_bytes());
+    header.push(0);
+    header.extend((value.len() as i32).to_le_bytes());
+    header.extend(value);
+}
@@ -0,0 +1,164 @@
+use glm::{vec3, Vec3};
+use std::path::Path;
+
+use crate::environment::Environment;
+use crate::image::{Grading, Image};
+use crate::objects::*;
+use crate::options::Options;
+use crate::render::{post_process, render};
+use crate::scene::{Scene, SceneBuilder};
+use crate::traversal::{build_traversal, Accel};
+
+const SEED: u64 = 0;
+// Root mean square difference of the displayed values, in [0, 1]
+const TOLERANCE: f32 = 0.01;
+const ACCELS: [Accel; 6] = [
+    Accel::Linear,
+    Accel::KdTree,
+    Accel::Grid,
+    Accel::Bvh,
+    Accel::QuantizedBvh,
+    Accel::Lbvh,
+];
+
+// Renders the tiny built-in scenes with every traversal backend and
+// compares them with DIR/<scene>.ppm, or writes these references (from the
+// linear backend) when updating. Returns whether all renders match
+pub fn run_golden(dir: &str, update: bool, options: &Options) -> bool {
+    let options = Options {
+        seed: SEED,
+        aov: None,
+        bloom: 0.0,
+        grading: Grading::default(),
+        auto_exposure: None,
+        irradiance_cache: None,
+        reproject: None,
+        ..options.clone()
+    };
+
+    let mut passed = true;
+    for (name, build) in SCENES {
+        let path = Path::new(dir).join(format!("{}.ppm", name));
+        let path = path.to_str().unwrap();
+
+        if update {
+            let image = render_golden(build(), Accel::Linear, &options);
+            image.write(path);
+            println!("{:12} written to {}", name, path);
+            continue;
+        }
+
+        let reference = Image::read(path);
+        for accel in ACCELS {
+            let rmse = render_golden(build(), accel, &options).rmse(&reference);
+            let ok = rmse <= TOLERANCE;
+            println!(
+                "{:12} {:8} rmse {:.5} {}",
+                name,
+                accel.name(),
+                rmse,
+                if ok { "ok" } else { "FAILED" }
+            );
+            passed &= ok;
+        }
+    }
+    passed
+}
+
+fn render_golden(mut scene: Scene, accel: Accel, options: &Options) -> Image {
+    scene.traversal = build_traversal(accel, &scene.objects, &options.bvh, None);
+    scene.epsilon = scene.epsilon.with_options(options);
+    render(&mut scene, options);
+    post_process(&mut scene.image, options);
+    scene.image
+}
+
+type SceneFn = fn() -> Scene;
+
+const SCENES: [(&str, SceneFn); 4] = [
+    ("diffuse", diffuse_scene),
+    ("specular", specular_scene),
+    ("csg_sdf", csg_sdf_scene),
+    ("nested_glass", nested_glass_scene),
+];
+
+// Settings and camera shared by the scenes, with a ground and a light
+fn base_builder() -> SceneBuilder {
+    let mut builder = SceneBuilder::default();
+    builder
+        .set_dimensions(48, 36)
+        .set_samples(16)
+        .set_ray_depth(4)
+        .set_environment(Environment::Color(vec3(0.2, 0.25, 0.3)));
+    builder.set_camera(
+        vec3(0.0, 1.5, 6.0),
+        vec3(1.0, 0.0, 0.0),
+        vec3(0.0, 0.97, -0.24).normalize(),
+        vec3(0.0, -0.24, -0.97).normalize(),
+        1.0,
+    );
+
+    let ground = builder.add_plane(Vec3::y());
+    ground.geometry.position = vec3(0.0, -1.0, 0.0);
+    ground.color = vec3(0.8, 0.8, 0.8);
+    builder.add_light(vec3(0.0, 4.0, 1.0), 1.0, vec3(6.0, 6.0, 6.0));
+    builder
+}
+
+fn diffuse_scene() -> Scene {
+    let mut builder = base_builder();
+    builder.add_sphere(vec3(-1.2, 0.0, 0.0), 1.0).color = vec3(0.9, 0.3, 0.2);
+    builder.add_box(vec3(0.6, 0.6, 0.6)).geometry.position = vec3(1.3, -0.4, 0.0);
+    builder.last_object().color = vec3(0.2, 0.5, 0.9);
+    builder.build()
+}
+
+fn specular_scene() -> Scene {
+    let mut builder = base_builder();
+    let metal = builder.add_sphere(vec3(-1.2, 0.0, 0.0), 1.0);
+    metal.color = vec3(0.9, 0.8, 0.5);
+    metal.material = Material::Metallic { fresnel: None };
+    let glass = builder.add_sphere(vec3(1.2, 0.0, 0.0), 1.0);
+    glass.color = vec3(1.0, 1.0, 1.0);
+    glass.material = Material::Dielectric { ior: 1.5 };
+    builder.build()
+}
+
+fn csg_sdf_scene() -> Scene {
+    let mut builder = base_builder();
+
+    let operands: Vec<Box<dyn Solid>> = vec![
+        Box::new(PositionedFigure::new(Parallelipiped {
+            sizes: vec3(0.8, 0.8, 0.8),
+        })),
+        Box::new(PositionedFigure::new(Ellipsoid {
+            radiuses: vec3(1.0, 1.0, 1.0),
+        })),
+    ];
+    let csg = builder.add_csg(Csg::from_operands(CsgOp::Difference, operands));
+    csg.geometry.position = vec3(-1.3, -0.2, 0.0);
+    csg.color = vec3(0.9, 0.3, 0.2);
+
+    let sdf = Sdf::sphere(0.6).smooth_union(Sdf::torus(0.8, 0.2), 0.2);
+    let sdf = builder.add_sdf(sdf);
+    sdf.geometry.position = vec3(1.3, -0.2, 0.0);
+    sdf.color = vec3(0.3, 0.8, 0.4);
+
+    builder.build()
+}
+
+// Glass inside glass away from the origin, whose refraction depends on
+// telling the entering hits from the leaving ones
+fn nested_glass_scene() -> Scene {
+    let mut builder = base_builder();
+    builder.add_sphere(vec3(-1.6, 0.0, -1.5), 1.0).color = vec3(0.9, 0.3, 0.2);
+
+    let outer = builder.add_box(vec3(0.9, 0.9, 0.9));
+    outer.geometry.position = vec3(0.6, -0.1, 0.5);
+    outer.color = vec3(0.8, 0.9, 1.0);
+    outer.material = Material::Dielectric { ior: 1.5 };
+    let inner = builder.add_sphere(vec3(0.6, -0.1, 0.5), 0.6);
+    inner.color = vec3(1.0, 1.0, 1.0);
+    inner.material = Material::Dielectric { ior: 1.33 };
+    builder.build()
+}
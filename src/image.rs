@@ -1,42 +1,321 @@
 use glm::{vec3, Vec3};
+use itertools::iproduct;
 use na::SimdPartialOrd;
 use std::fs::File;
 use std::io::Write;
 
+use crate::exposure::{false_color, ExposureCheck, ZEBRA_LEVEL, ZEBRA_WIDTH};
+
+// Applied to linear radiance before tonemapping, the default changes nothing
+#[derive(Clone)]
+pub struct Grading {
+    // stops
+    pub exposure: f32,
+    // Kelvin of the light that should look white
+    pub temperature: f32,
+    // positive is towards magenta, negative towards green
+    pub tint: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+}
+
+impl Default for Grading {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            temperature: NEUTRAL_TEMPERATURE,
+            tint: 0.0,
+            contrast: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+const NEUTRAL_TEMPERATURE: f32 = 6500.0;
+const TINT_SCALE: f32 = 0.3;
+pub const MIDDLE_GRAY: f32 = 0.18;
+const BLOOM_THRESHOLD: f32 = 1.0;
+
+// Storage of the channel values. Compensated images keep the rounding
+// errors of accumulate() and add(), so means of many samples don't stop
+// changing, at twice the memory
+#[derive(Clone, Copy, PartialEq)]
+pub enum Precision {
+    Full { compensated: bool },
+    // f16, half the memory for films of very large renders
+    Half { compensated: bool },
+}
+
+#[derive(Clone)]
+enum Data {
+    // the values are missing the errors
+    Full {
+        values: Vec<f32>,
+        errors: Option<Vec<f32>>,
+    },
+    // f16 bits
+    Half {
+        values: Vec<u16>,
+        errors: Option<Vec<u16>>,
+    },
+}
+
+#[derive(Clone)]
 pub struct Image {
     pub width: usize,
     pub height: usize,
-    data: Vec<Vec3>,
+    // per pixel, a single one is repeated in all colors (alpha)
+    channels: usize,
+    data: Data,
 }
 
 impl Image {
     pub fn new(width: usize, height: usize) -> Self {
+        Self::with_layout(width, height, 3, Precision::Full { compensated: false })
+    }
+
+    pub fn with_layout(width: usize, height: usize, channels: usize, precision: Precision) -> Self {
+        assert!(channels == 1 || channels == 3);
+        let len = width * height * channels;
+        let data = match precision {
+            Precision::Full { compensated } => Data::Full {
+                values: vec![0.0; len],
+                errors: compensated.then(|| vec![0.0; len]),
+            },
+            Precision::Half { compensated } => Data::Half {
+                values: vec![0; len],
+                errors: compensated.then(|| vec![0; len]),
+            },
+        };
         Self {
             width,
             height,
-            data: vec![Vec3::zeros(); width * height],
+            channels,
+            data,
         }
     }
 
-    pub fn get(&self, u: usize, v: usize) -> Vec3 {
+    fn offset(&self, u: usize, v: usize) -> usize {
         let v = self.height - 1 - v;
-        self.data[self.width * v + u]
+        (self.width * v + u) * self.channels
+    }
+
+    pub fn get(&self, u: usize, v: usize) -> Vec3 {
+        self.pixel(self.offset(u, v))
     }
 
     pub fn set(&mut self, u: usize, v: usize, color: Vec3) {
-        let v = self.height - 1 - v;
-        self.data[self.width * v + u] = color;
+        let offset = self.offset(u, v);
+        self.set_pixel(offset, color);
+    }
+
+    // Adds a sample to the running mean of that many
+    pub fn accumulate(&mut self, u: usize, v: usize, sample: Vec3, n: f32) {
+        let offset = self.offset(u, v);
+        match &mut self.data {
+            Data::Full {
+                values,
+                errors: Some(errors),
+            } => {
+                for c in 0..self.channels {
+                    let (i, x) = (offset + c, sample[c]);
+                    let change = (x - (values[i] + errors[i])) / (n + 1.0);
+                    compensated_add(&mut values[i], &mut errors[i], change);
+                }
+            }
+            Data::Half {
+                values,
+                errors: Some(errors),
+            } => {
+                for c in 0..self.channels {
+                    let (i, x) = (offset + c, sample[c]);
+                    let mean = f16_to_f32(values[i]) + f16_to_f32(errors[i]);
+                    let mean = mean + (x - mean) / (n + 1.0);
+                    values[i] = f32_to_f16(mean);
+                    errors[i] = f32_to_f16(mean - f16_to_f32(values[i]));
+                }
+            }
+            _ => {
+                let old = self.pixel(offset);
+                self.set_pixel(offset, (old * n + sample) / (n + 1.0));
+            }
+        }
+    }
+
+    pub fn add(&mut self, u: usize, v: usize, color: Vec3) {
+        let offset = self.offset(u, v);
+        match &mut self.data {
+            Data::Full {
+                values,
+                errors: Some(errors),
+            } => {
+                for c in 0..self.channels {
+                    let i = offset + c;
+                    compensated_add(&mut values[i], &mut errors[i], color[c]);
+                }
+            }
+            _ => {
+                let old = self.pixel(offset);
+                self.set_pixel(offset, old + color);
+            }
+        }
+    }
+
+    fn pixel(&self, offset: usize) -> Vec3 {
+        let value = |c: usize| match &self.data {
+            Data::Full { values, errors } => {
+                values[offset + c] + errors.as_ref().map_or(0.0, |e| e[offset + c])
+            }
+            Data::Half { values, errors } => {
+                f16_to_f32(values[offset + c])
+                    + errors.as_ref().map_or(0.0, |e| f16_to_f32(e[offset + c]))
+            }
+        };
+        if self.channels == 1 {
+            Vec3::repeat(value(0))
+        } else {
+            vec3(value(0), value(1), value(2))
+        }
+    }
+
+    fn set_pixel(&mut self, offset: usize, color: Vec3) {
+        for c in 0..self.channels {
+            match &mut self.data {
+                Data::Full { values, errors } => {
+                    values[offset + c] = color[c];
+                    if let Some(errors) = errors {
+                        errors[offset + c] = 0.0;
+                    }
+                }
+                Data::Half { values, errors } => {
+                    values[offset + c] = f32_to_f16(color[c]);
+                    if let Some(errors) = errors {
+                        let error = color[c] - f16_to_f32(values[offset + c]);
+                        errors[offset + c] = f32_to_f16(error);
+                    }
+                }
+            }
+        }
+    }
+
+    // In storage order, rows from the bottom
+    pub fn pixels(&self) -> Vec<Vec3> {
+        (0..self.width * self.height)
+            .map(|idx| self.pixel(idx * self.channels))
+            .collect()
+    }
+
+    fn set_pixels(&mut self, pixels: Vec<Vec3>) {
+        for (idx, color) in pixels.into_iter().enumerate() {
+            self.set_pixel(idx * self.channels, color);
+        }
+    }
+
+    pub fn side_by_side(left: &Image, right: &Image) -> Self {
+        let mut image = Image::new(left.width + right.width, left.height);
+        for v in 0..left.height {
+            for u in 0..left.width {
+                image.set(u, v, left.get(u, v));
+                image.set(left.width + u, v, right.get(u, v));
+            }
+        }
+        image
+    }
+
+    pub fn over_under(top: &Image, bottom: &Image) -> Self {
+        let mut image = Image::new(top.width, top.height + bottom.height);
+        for v in 0..top.height {
+            for u in 0..top.width {
+                // v grows upwards
+                image.set(u, bottom.height + v, top.get(u, v));
+                image.set(u, v, bottom.get(u, v));
+            }
+        }
+        image
+    }
+
+    // FNV-1a over the bits of the values
+    pub fn checksum(&self) -> u64 {
+        let mut hash = 0xcbf29ce484222325_u64;
+        for value in self.pixels().iter().flat_map(|color| color.iter()) {
+            for byte in value.to_bits().to_le_bytes() {
+                hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    // Reads a binary PPM as written by write()
+    pub fn read(path: &str) -> Self {
+        let bytes = std::fs::read(path).unwrap();
+        let mut rest = &bytes[..];
+        // Comments only come before the pixels
+        let mut header = || loop {
+            let end = rest.iter().position(|&b| b == b'\n').unwrap();
+            let line = std::str::from_utf8(&rest[..end]).unwrap().to_owned();
+            rest = &rest[end + 1..];
+            if !line.starts_with('#') {
+                return line;
+            }
+        };
+
+        assert!(header() == "P6", "{} is not a binary PPM", path);
+        let size = header();
+        let (width, height) = size.split_once(' ').unwrap();
+        let (width, height) = (width.parse().unwrap(), height.parse().unwrap());
+        assert!(header() == "255", "{} is not an 8-bit PPM", path);
+
+        let data = rest
+            .chunks_exact(3)
+            .map(|rgb| vec3(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32) / 255.0)
+            .collect::<Vec<_>>();
+        assert!(data.len() == width * height, "{} is truncated", path);
+
+        let mut image = Self::new(width, height);
+        image.set_pixels(data);
+        image
+    }
+
+    // Root mean square difference of the channel values
+    pub fn rmse(&self, other: &Image) -> f32 {
+        assert!(self.width == other.width && self.height == other.height);
+        let sum = self
+            .pixels()
+            .iter()
+            .zip(&other.pixels())
+            .map(|(a, b)| glm::length2(&(a - b)))
+            .sum::<f32>();
+        (sum / (3 * self.width * self.height) as f32).sqrt()
     }
 
     pub fn write(&self, path: &str) {
+        self.write_with_metadata(path, &[]);
+    }
+
+    // With a comment line in the header for every key and value
+    pub fn write_with_metadata(&self, path: &str, metadata: &[(String, String)]) {
         let mut file = File::create(path).unwrap();
+        self.write_ppm_with_comments(&mut file, metadata);
+    }
+
+    // Binary PPM, also used for piping frames to other programs
+    pub fn write_ppm<W: Write>(&self, file: &mut W) {
+        self.write_ppm_with_comments(file, &[]);
+    }
+
+    fn write_ppm_with_comments<W: Write>(&self, file: &mut W, comments: &[(String, String)]) {
         file.write_all("P6\n".as_bytes()).unwrap();
+        for (key, value) in comments {
+            let value = value.replace('\n', " ");
+            file.write_all(format!("# {}: {}\n", key, value).as_bytes())
+                .unwrap();
+        }
         file.write_all(format!("{} {}\n", self.width, self.height).as_bytes())
             .unwrap();
         file.write_all("255\n".as_bytes()).unwrap();
 
         let data = self
-            .data
+            .pixels()
             .iter()
             .flat_map(|color| {
                 [color.x, color.y, color.z]
@@ -48,15 +327,149 @@ impl Image {
         file.write_all(&data).unwrap();
     }
 
-    pub fn color_correction(&mut self) {
-        for color in &mut self.data {
-            let c = aces_tonemap(color);
+    // Adds the blurred part of the image brighter than the threshold, so
+    // bright emitters glow. The radius is the Gaussian sigma in pixels
+    pub fn bloom(&mut self, strength: f32, radius: f32) {
+        let mut pixels = self.pixels();
+        let bright = pixels
+            .iter()
+            .map(|color| {
+                let l = luminance(color);
+                if l > BLOOM_THRESHOLD {
+                    color * ((l - BLOOM_THRESHOLD) / l)
+                } else {
+                    Vec3::zeros()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let kernel = gaussian_kernel(radius);
+        let blurred = self.blur(&bright, &kernel, (1, 0));
+        let blurred = self.blur(&blurred, &kernel, (0, 1));
+
+        for (color, glow) in pixels.iter_mut().zip(blurred) {
+            *color += strength * glow;
+        }
+        self.set_pixels(pixels);
+    }
+
+    // One pass of a separable blur along step, clamped at the borders
+    fn blur(&self, data: &[Vec3], kernel: &[f32], step: (isize, isize)) -> Vec<Vec3> {
+        let half = (kernel.len() / 2) as isize;
+        let (w, h) = (self.width as isize, self.height as isize);
+
+        let mut result = vec![Vec3::zeros(); data.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = Vec3::zeros();
+                for (k, weight) in kernel.iter().enumerate() {
+                    let offset = k as isize - half;
+                    let sx = (x + offset * step.0).clamp(0, w - 1);
+                    let sy = (y + offset * step.1).clamp(0, h - 1);
+                    sum += *weight * data[(sy * w + sx) as usize];
+                }
+                result[(y * w + x) as usize] = sum;
+            }
+        }
+        result
+    }
+
+    // Instead of color_correction, to judge the exposure of the grading
+    pub fn exposure_check(&mut self, grading: &Grading, check: ExposureCheck) {
+        match check {
+            ExposureCheck::FalseColor => {
+                let gains = white_balance_gains(grading.temperature, grading.tint);
+                let mut pixels = self.pixels();
+                for color in &mut pixels {
+                    *color = false_color(luminance(&grade(color, grading, &gains)));
+                }
+                self.set_pixels(pixels);
+            }
+            ExposureCheck::Zebra => {
+                self.color_correction(grading);
+                for (u, v) in iproduct!(0..self.width, 0..self.height) {
+                    let stripe = ((u + v) / ZEBRA_WIDTH).is_multiple_of(2);
+                    if stripe && self.get(u, v).max() >= ZEBRA_LEVEL {
+                        self.set(u, v, Vec3::zeros());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn color_correction(&mut self, grading: &Grading) {
+        let gains = white_balance_gains(grading.temperature, grading.tint);
+
+        let mut pixels = self.pixels();
+        for color in &mut pixels {
+            let c = grade(color, grading, &gains);
+            let c = aces_tonemap(&c);
             let c = gamma_correction(&c);
             *color = c;
         }
+        self.set_pixels(pixels);
     }
 }
 
+fn grade(color: &Vec3, grading: &Grading, gains: &Vec3) -> Vec3 {
+    let c = color * 2.0_f32.powf(grading.exposure);
+    let c = c.component_mul(gains);
+
+    // Power curve around middle gray, so it stays in place
+    let c = c.map(|x| MIDDLE_GRAY * (x.max(0.0) / MIDDLE_GRAY).powf(grading.contrast));
+
+    let l = luminance(&c);
+    let c = c.add_scalar(-l) * grading.saturation;
+    c.add_scalar(l).sup(&Vec3::zeros())
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let half = (3.0 * sigma).ceil().max(1.0) as i32;
+    let kernel = (-half..=half)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let sum = kernel.iter().sum::<f32>();
+    kernel.into_iter().map(|w| w / sum).collect()
+}
+
+pub fn luminance(color: &Vec3) -> f32 {
+    glm::dot(color, &vec3(0.2126, 0.7152, 0.0722))
+}
+
+// Channel gains that make light of the given temperature neutral, with
+// the luminance of white kept
+fn white_balance_gains(temperature: f32, tint: f32) -> Vec3 {
+    let gains = blackbody_color(NEUTRAL_TEMPERATURE).component_div(&blackbody_color(temperature));
+    let gains = gains.component_mul(&vec3(1.0, 1.0 - tint * TINT_SCALE, 1.0));
+    gains / luminance(&gains)
+}
+
+// Linear color of a black body, fit by Tanner Helland, valid for 1000-40000K
+fn blackbody_color(temperature: f32) -> Vec3 {
+    let t = temperature.clamp(1000.0, 40000.0) / 100.0;
+
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let g = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12217 * (t - 60.0).powf(-0.07551485)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+
+    // The fit is gamma encoded; blue is kept above zero for the gains
+    vec3(r, g, b).map(|x| (x.clamp(1.0, 255.0) / 255.0).powf(2.2))
+}
+
 fn gamma_correction(color: &Vec3) -> Vec3 {
     let pow = 1.0 / 2.2;
     Vec3::from_iterator(color.iter().map(|x| x.powf(pow)))
@@ -81,3 +494,140 @@ fn aces_tonemap(x: &Vec3) -> Vec3 {
 fn saturate(color: Vec3) -> Vec3 {
     color.simd_clamp(Vec3::zeros(), vec3(1.0, 1.0, 1.0))
 }
+
+// Neumaier's summation, the sum is value + error
+fn compensated_add(value: &mut f32, error: &mut f32, x: f32) {
+    let sum = *value + x;
+    *error += if value.abs() >= x.abs() {
+        (*value - sum) + x
+    } else {
+        (x - sum) + *value
+    };
+    *value = sum;
+}
+
+// Rounded to the nearest, ties to even
+fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = (bits >> 16) as u16 & 0x8000;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7fffff;
+    if exponent == 0xff {
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    // Subnormals shift the mantissa further, with the implicit bit
+    let exponent = exponent - 127 + 15;
+    let (half, shift) = if exponent > 0 {
+        ((exponent as u32) << 10 | mantissa >> 13, 13)
+    } else if exponent >= -10 {
+        let shift = (14 - exponent) as u32;
+        ((mantissa | 0x800000) >> shift, shift)
+    } else {
+        return sign;
+    };
+    let rest = (mantissa | 0x800000) & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let round = rest > halfway || (rest == halfway && half & 1 == 1);
+    // A carry out of the mantissa increments the exponent, up to infinity
+    sign | (half + round as u32).min(0x7c00) as u16
+}
+
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+    match exponent {
+        0 => {
+            let magnitude = mantissa as f32 * (-24.0_f32).exp2();
+            f32::from_bits(sign | magnitude.to_bits())
+        }
+        0x1f => f32::from_bits(sign | 0x7f800000 | mantissa << 13),
+        _ => f32::from_bits(sign | (exponent + 112) << 23 | mantissa << 13),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f16_round_trip() {
+        for h in 0..=u16::MAX {
+            let x = f16_to_f32(h);
+            if x.is_nan() {
+                assert!(f32_to_f16(x) & 0x7c00 == 0x7c00 && f32_to_f16(x) & 0x3ff != 0);
+            } else {
+                assert_eq!(f32_to_f16(x), h, "{:#06x} is {}", h, x);
+            }
+        }
+    }
+
+    #[test]
+    fn f16_values() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        // Smallest normal
+        assert_eq!(f32_to_f16((-14.0_f32).exp2()), 0x0400);
+        assert_eq!(f16_to_f32(0x0400), (-14.0_f32).exp2());
+    }
+
+    #[test]
+    fn f16_rounding() {
+        let ulp = (-10.0_f32).exp2();
+        // Halfway rounds to the even mantissa, anything more rounds up
+        assert_eq!(f32_to_f16(1.0 + ulp / 2.0), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 1.5 * ulp), 0x3c02);
+        assert_eq!(f32_to_f16(1.0 + ulp / 2.0 + ulp / 1024.0), 0x3c01);
+        assert_eq!(f32_to_f16(1.0 + ulp / 2.0 - ulp / 1024.0), 0x3c00);
+        // A carry out of the mantissa goes to the next exponent
+        assert_eq!(f32_to_f16(2.0 - ulp / 4.0), 0x4000);
+    }
+
+    #[test]
+    fn f16_denormals() {
+        let unit = (-24.0_f32).exp2();
+        assert_eq!(f32_to_f16(unit), 0x0001);
+        assert_eq!(f32_to_f16(1023.0 * unit), 0x03ff);
+        assert_eq!(f16_to_f32(0x0001), unit);
+        assert_eq!(f16_to_f32(0x83ff), -1023.0 * unit);
+        // Halfway cases round to even, down to zero
+        assert_eq!(f32_to_f16(0.5 * unit), 0x0000);
+        assert_eq!(f32_to_f16(1.5 * unit), 0x0002);
+        assert_eq!(f32_to_f16(2.5 * unit), 0x0002);
+        assert_eq!(f32_to_f16(0.75 * unit), 0x0001);
+        // The largest denormal rounds up to the smallest normal
+        assert_eq!(f32_to_f16(1023.75 * unit), 0x0400);
+        assert_eq!(f32_to_f16(1e-10), 0x0000);
+        assert_eq!(f32_to_f16(-1e-10), 0x8000);
+        assert_eq!(f32_to_f16(f32::MIN_POSITIVE), 0x0000);
+    }
+
+    #[test]
+    fn f16_overflow() {
+        assert_eq!(f32_to_f16(65519.0), 0x7bff);
+        // Halfway to the next power of two rounds to even, infinity
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(-1e6), 0xfc00);
+        assert_eq!(f32_to_f16(f32::MAX), 0x7c00);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn f16_nan() {
+        let h = f32_to_f16(f32::NAN);
+        assert_eq!(h & 0x7c00, 0x7c00);
+        assert_ne!(h & 0x3ff, 0);
+        assert!(f16_to_f32(h).is_nan());
+        // A payload only in the low bits stays a NaN, not infinity
+        assert!(f16_to_f32(f32_to_f16(f32::from_bits(0x7f800001))).is_nan());
+    }
+}
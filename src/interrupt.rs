@@ -0,0 +1,19 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The first Ctrl-C lets the render stop after the current sample pass and
+// write what it has, the second one quits right away
+pub fn install_handler() {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        log::warn!("interrupted, writing the image after this pass (Ctrl-C again to quit)");
+    })
+    .unwrap();
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
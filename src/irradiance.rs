@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use glm::{vec3, Vec3};
+use rayon::prelude::*;
+
+use crate::epsilon::MIN_RECORD_ERROR;
+use crate::objects::Material;
+use crate::random::{Cosine, PathSampler};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::trace::{trace_bounce, visible_hit};
+
+// Ward's irradiance cache (Ward, Heckbert 1992) for the light arriving at
+// diffuse surfaces after the first bounce. Records are placed before the
+// render at the points the first bounces from the camera reach, so the
+// render stays repeatable, and are read-only while rendering. Points no
+// record covers are path traced as usual
+
+// Hemisphere strata of a record, in theta and phi
+const THETA_STRATA: usize = 6;
+const PHI_STRATA: usize = 18;
+// Camera rays go through every CANDIDATE_STRIDE-th pixel in both
+// directions, each with that many bounces
+const CANDIDATE_STRIDE: usize = 4;
+const CANDIDATE_BOUNCES: usize = 2;
+// Record radius limits, in pixels at the record's distance from the camera
+const MIN_SPACING: f32 = 3.0;
+const MAX_SPACING: f32 = 60.0;
+// Records computed together before checking whether they overlap
+const BATCH: usize = 256;
+// Sampler streams apart from the render steps
+const CANDIDATE_STREAM: usize = usize::MAX;
+const RECORD_STREAM: usize = usize::MAX - 1;
+
+struct Record {
+    object: usize,
+    point: Vec3,
+    normal: Vec3,
+    irradiance: Vec3,
+    // harmonic mean distance to the surfaces around, clamped
+    radius: f32,
+    // per channel, with respect to rotating the normal and to moving
+    rotation: [Vec3; 3],
+    translation: [Vec3; 3],
+}
+
+impl Record {
+    // Ward's weight where the estimated error is below the accuracy
+    fn weight(&self, point: &Vec3, normal: &Vec3, accuracy: f32) -> Option<f32> {
+        let offset = point - self.point;
+        let error =
+            offset.norm() / self.radius + (1.0 - glm::dot(normal, &self.normal)).max(0.0).sqrt();
+        // Points in front of the record see light it doesn't
+        let in_front = glm::dot(&offset, &(normal + self.normal)) / 2.0 < -0.05 * self.radius;
+        (error < accuracy && !in_front).then(|| 1.0 / error.max(MIN_RECORD_ERROR))
+    }
+
+    fn extrapolate(&self, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let rotation = glm::cross(&self.normal, normal);
+        let offset = point - self.point;
+        Vec3::from_fn(|c, _| {
+            let change =
+                glm::dot(&self.rotation[c], &rotation) + glm::dot(&self.translation[c], &offset);
+            (self.irradiance[c] + change).max(0.0)
+        })
+    }
+}
+
+pub struct IrradianceCache {
+    records: Vec<Record>,
+    // Ward's a, the largest error a record is used with
+    accuracy: f32,
+    // Records by the level of their validity radius, rounded up to a power
+    // of two, and a cell of that size they overlap
+    cells: HashMap<(i32, [i32; 3]), Vec<usize>>,
+    levels: Vec<i32>,
+}
+
+struct Candidate {
+    object: usize,
+    point: Vec3,
+    normal: Vec3,
+}
+
+impl IrradianceCache {
+    pub fn build(scene: &Scene, accuracy: f32, seed: u64) -> Self {
+        let mut cache = Self {
+            records: Vec::new(),
+            accuracy,
+            cells: HashMap::new(),
+            levels: Vec::new(),
+        };
+
+        let candidates = candidates(scene, seed);
+        for (batch_idx, batch) in candidates.chunks(BATCH).enumerate() {
+            let records = batch
+                .par_iter()
+                .enumerate()
+                .filter(|(_, c)| cache.irradiance(c.object, &c.point, &c.normal).is_none())
+                .map(|(i, c)| {
+                    let sampler = PathSampler::new(seed, batch_idx * BATCH + i, RECORD_STREAM);
+                    record(scene, c, &sampler)
+                })
+                .collect::<Vec<_>>();
+            for record in records {
+                if cache
+                    .irradiance(record.object, &record.point, &record.normal)
+                    .is_none()
+                {
+                    cache.insert(record);
+                }
+            }
+        }
+
+        log::info!(
+            "irradiance cache: {} records from {} candidates",
+            cache.records.len(),
+            candidates.len()
+        );
+        cache
+    }
+
+    // Interpolated irradiance at a point of the object, if records cover it
+    pub fn irradiance(&self, object: usize, point: &Vec3, normal: &Vec3) -> Option<Vec3> {
+        let mut sum = Vec3::zeros();
+        let mut total = 0.0;
+        for &level in &self.levels {
+            let Some(indices) = self.cells.get(&(level, cell(level, point))) else {
+                continue;
+            };
+            for &i in indices {
+                let record = &self.records[i];
+                if record.object != object {
+                    continue;
+                }
+                if let Some(weight) = record.weight(point, normal, self.accuracy) {
+                    sum += weight * record.extrapolate(point, normal);
+                    total += weight;
+                }
+            }
+        }
+        (total > 0.0).then(|| sum / total)
+    }
+
+    fn insert(&mut self, record: Record) {
+        // A cell at least as large as the validity radius, so the sphere
+        // overlaps at most two cells along each axis
+        let reach = self.accuracy * record.radius;
+        let level = reach.log2().ceil() as i32;
+        if !self.levels.contains(&level) {
+            self.levels.push(level);
+        }
+
+        let low = cell(level, &record.point.add_scalar(-reach));
+        let high = cell(level, &record.point.add_scalar(reach));
+        let idx = self.records.len();
+        for x in low[0]..=high[0] {
+            for y in low[1]..=high[1] {
+                for z in low[2]..=high[2] {
+                    self.cells.entry((level, [x, y, z])).or_default().push(idx);
+                }
+            }
+        }
+        self.records.push(record);
+    }
+}
+
+fn cell(level: i32, point: &Vec3) -> [i32; 3] {
+    let size = (level as f32).exp2();
+    [0, 1, 2].map(|i| (point[i] / size).floor() as i32)
+}
+
+// Points the first diffuse bounces of camera rays reach on diffuse objects
+fn candidates(scene: &Scene, seed: u64) -> Vec<Candidate> {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let pixels = (0..height)
+        .step_by(CANDIDATE_STRIDE)
+        .flat_map(|j| (0..width).step_by(CANDIDATE_STRIDE).map(move |i| (i, j)))
+        .collect::<Vec<_>>();
+
+    pixels
+        .into_par_iter()
+        .flat_map_iter(|(i, j)| {
+            let sampler = &mut PathSampler::new(seed, j * width + i, CANDIDATE_STREAM);
+            let u = (i as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let v = (j as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+            let ray = scene.camera.ray_to_point(u, v);
+
+            let mut found = Vec::new();
+            let Some((idx, hit, point)) = visible_hit(scene, &ray, true) else {
+                return found;
+            };
+            if !is_cached(scene, idx) {
+                return found;
+            }
+            for _ in 0..CANDIDATE_BOUNCES {
+                let direction = Cosine::sample(&hit.n, sampler);
+                let ray = Ray::new_offset(point, &hit.n, direction, &scene.epsilon);
+                if let Some((object, hit, point)) = visible_hit(scene, &ray, false) {
+                    if is_cached(scene, object) {
+                        found.push(Candidate {
+                            object,
+                            point,
+                            normal: hit.n,
+                        });
+                    }
+                }
+            }
+            found
+        })
+        .collect()
+}
+
+// Lambertian objects, Oren-Nayar reflection depends on the view
+pub fn is_cached(scene: &Scene, idx: usize) -> bool {
+    let obj = &scene.objects[idx];
+    matches!(obj.material, Material::Diffuse) && obj.roughness == 0.0
+}
+
+fn record(scene: &Scene, candidate: &Candidate, sampler: &PathSampler) -> Record {
+    let (m, n) = (THETA_STRATA, PHI_STRATA);
+    let normal = candidate.normal;
+    let (tangent, bitangent) = frame(&normal);
+    let planar = |phi: f32| tangent * phi.cos() + bitangent * phi.sin();
+
+    // Radiance and hit distance per stratum, cosine distributed
+    let mut radiance = vec![Vec3::zeros(); m * n];
+    let mut distance = vec![f32::INFINITY; m * n];
+    for j in 0..m {
+        for k in 0..n {
+            // Every ray a path of its own
+            let sampler = &mut sampler.fork(j * n + k);
+            let (u, v) = sampler.next_2d();
+            let sin_theta = ((j as f32 + u) / m as f32).sqrt();
+            let phi = 2.0 * PI * (k as f32 + v) / n as f32;
+            let cos_theta = (1.0 - sin_theta * sin_theta).max(0.0).sqrt();
+            let direction = planar(phi) * sin_theta + normal * cos_theta;
+
+            let ray = Ray::new_offset(candidate.point, &normal, direction, &scene.epsilon);
+            if let Some((_, hit, _)) = visible_hit(scene, &ray, false) {
+                distance[j * n + k] = hit.t;
+            }
+            radiance[j * n + k] = trace_bounce(scene, &ray, candidate.object, sampler);
+        }
+    }
+    let at = |j: usize, k: usize| (radiance[j * n + k], distance[j * n + k]);
+
+    let irradiance = radiance.iter().sum::<Vec3>() * PI / (m * n) as f32;
+    let inverse_sum = distance.iter().map(|d| 1.0 / d).sum::<f32>();
+    let footprint =
+        glm::distance(&candidate.point, &scene.camera.position) * 2.0 * scene.camera.tg_fov_x
+            / scene.image.width as f32;
+    let radius =
+        ((m * n) as f32 / inverse_sum).clamp(MIN_SPACING * footprint, MAX_SPACING * footprint);
+
+    // Ward and Heckbert's gradients for this stratification
+    let theta = |x: f32| (x / m as f32).sqrt().asin();
+    let mut rotation = [Vec3::zeros(); 3];
+    let mut translation = [Vec3::zeros(); 3];
+    let add = |gradient: &mut [Vec3; 3], direction: Vec3, change: Vec3| {
+        for c in 0..3 {
+            gradient[c] += direction * change[c];
+        }
+    };
+    for k in 0..n {
+        let phi_low = 2.0 * PI * k as f32 / n as f32;
+        let phi_mid = 2.0 * PI * (k as f32 + 0.5) / n as f32;
+        let u_k = planar(phi_low);
+        let v_k = planar(phi_low + PI / 2.0);
+        let v_mid = planar(phi_mid + PI / 2.0);
+
+        for j in 0..m {
+            let (l, r) = at(j, k);
+            let theta_low = theta(j as f32);
+            let theta_high = theta(j as f32 + 1.0);
+            let theta_mid = theta(j as f32 + 0.5);
+
+            add(
+                &mut rotation,
+                v_mid,
+                -l * theta_mid.tan() * PI / (m * n) as f32,
+            );
+
+            if j > 0 {
+                let (l_prev, r_prev) = at(j - 1, k);
+                let coeff =
+                    2.0 * PI / n as f32 * theta_low.sin() * theta_low.cos().powi(2) / r.min(r_prev);
+                add(&mut translation, u_k, (l - l_prev) * coeff);
+            }
+            let (l_prev, r_prev) = at(j, (k + n - 1) % n);
+            let coeff = (theta_low.cos() - theta_high.cos()) / (theta_mid.sin() * r.min(r_prev));
+            add(&mut translation, v_k, (l - l_prev) * coeff);
+        }
+    }
+
+    Record {
+        object: candidate.object,
+        point: candidate.point,
+        normal,
+        irradiance,
+        radius,
+        rotation,
+        translation,
+    }
+}
+
+// Two unit vectors perpendicular to n and to each other
+fn frame(n: &Vec3) -> (Vec3, Vec3) {
+    let other = if n.x.abs() > 0.9 {
+        vec3(0.0, 1.0, 0.0)
+    } else {
+        vec3(1.0, 0.0, 0.0)
+    };
+    let tangent = glm::cross(&other, n).normalize();
+    (tangent, glm::cross(n, &tangent))
+}
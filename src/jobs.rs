@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::interrupt::interrupted;
+use crate::options::Options;
+use crate::render::{load_scene, render_to_file};
+use crate::scene::Scene;
+
+pub struct Job {
+    pub scene: String,
+    pub output: String,
+    pub dimensions: Option<(usize, usize)>,
+    pub n_samples: Option<usize>,
+}
+
+// Same line-based syntax as scene files, e.g.
+//   JOB
+//   SCENE assets/scene.txt
+//   DIMENSIONS 256 256
+//   SAMPLES 64
+//   OUTPUT /tmp/scene_64.ppm
+pub fn parse_jobs(path: &str) -> Vec<Job> {
+    let mut jobs: Vec<Job> = Vec::new();
+
+    let file = File::open(path).unwrap();
+    let reader = BufReader::new(file);
+    for line in reader.lines() {
+        let tokens = line.as_ref().unwrap().split(' ').collect::<Vec<_>>();
+
+        match tokens[0] {
+            "JOB" => jobs.push(Job {
+                scene: String::new(),
+                output: String::new(),
+                dimensions: None,
+                n_samples: None,
+            }),
+            "SCENE" => jobs.last_mut().unwrap().scene = tokens[1].into(),
+            "OUTPUT" => jobs.last_mut().unwrap().output = tokens[1].into(),
+            "DIMENSIONS" => {
+                let width = tokens[1].parse::<usize>().unwrap();
+                let height = tokens[2].parse::<usize>().unwrap();
+                jobs.last_mut().unwrap().dimensions = Some((width, height));
+            }
+            "SAMPLES" => {
+                jobs.last_mut().unwrap().n_samples = Some(tokens[1].parse::<usize>().unwrap());
+            }
+            _ => {}
+        }
+    }
+
+    jobs
+}
+
+pub fn run_jobs(path: &str, options: &Options) {
+    let mut scenes: HashMap<String, Scene> = HashMap::new();
+
+    for job in parse_jobs(path) {
+        if interrupted() {
+            break;
+        }
+        let scene = scenes
+            .entry(job.scene.clone())
+            .or_insert_with(|| load_scene(&job.scene, options));
+
+        let old_dimensions = (scene.image.width, scene.image.height);
+        let (width, height) = job.dimensions.unwrap_or(old_dimensions);
+        let n_samples = job.n_samples.unwrap_or(scene.n_samples);
+
+        scene.set_dimensions(width, height);
+        let old_samples = std::mem::replace(&mut scene.n_samples, n_samples);
+
+        render_to_file(scene, options, &job.output);
+        // The next job of the scene starts from its own settings
+        scene.set_dimensions(old_dimensions.0, old_dimensions.1);
+        scene.n_samples = old_samples;
+    }
+}
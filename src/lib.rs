@@ -0,0 +1,45 @@
+pub mod affinity;
+pub mod albedo;
+pub mod aov;
+pub mod benchmark;
+pub mod camera;
+pub mod camera_path;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod controller;
+pub mod cubemap;
+pub mod dataset;
+pub mod environment;
+pub mod epsilon;
+pub mod export;
+pub mod exposure;
+pub mod exr;
+pub mod golden;
+pub mod image;
+pub mod interrupt;
+pub mod irradiance;
+pub mod jobs;
+pub mod logging;
+pub mod network;
+pub mod objects;
+pub mod options;
+pub mod output;
+pub mod overrides;
+pub mod parser;
+pub mod pbrt;
+pub mod points;
+pub mod probes;
+pub mod profile;
+pub mod random;
+pub mod ray;
+pub mod render;
+pub mod report;
+pub mod scene;
+pub mod stereo;
+pub mod temporal;
+pub mod tiles;
+pub mod trace;
+pub mod traversal;
+#[cfg(feature = "ffmpeg")]
+pub mod video;
+pub mod wavefront;
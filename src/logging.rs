@@ -0,0 +1,30 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Messages of the binary go to stderr, library users can install another
+// logger for the log crate instead
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{}] {}",
+                record.level().as_str().to_lowercase(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(level);
+}
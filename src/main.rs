@@ -1,42 +1,70 @@
-mod camera;
-mod image;
-mod objects;
-mod parser;
-mod random;
-mod ray;
-mod trace;
+use raytracing::options::Options;
+use raytracing::{
+    affinity, benchmark, camera_path, dataset, export, golden, interrupt, jobs, logging, network,
+    probes, profile, render, report,
+};
+use std::time::Instant;
 
-use parser::*;
-use rand::Rng;
-use trace::trace_ray;
-
-fn render(scene: &mut Scene) {
-    for step in 0..scene.n_samples {
-        for i in 0..scene.image.width {
-            for j in 0..scene.image.height {
-                let du = scene.generator.gen::<f32>();
-                let dv = scene.generator.gen::<f32>();
-                let u = (i as f32 + du) / scene.image.width as f32 * 2.0 - 1.0;
-                let v = (j as f32 + dv) / scene.image.height as f32 * 2.0 - 1.0;
-                let ray = scene.camera.ray_to_point(u, v);
+fn main() {
+    let options = Options::from_args();
+    logging::init(options.log_level);
+    profile::start(options.profile.as_deref());
 
-                let old_color = scene.image.get(i, j);
-                let color = trace_ray(scene, &ray, 0);
-                let step_f = step as f32;
-                let new_color = (old_color * step_f + color) / (step_f + 1.0);
-                scene.image.set(i, j, new_color);
-            }
-        }
+    // Without --threads rayon sizes the pool itself, honoring RAYON_NUM_THREADS
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = options.threads {
+        pool = pool.num_threads(threads);
     }
-}
+    if options.pin_threads {
+        pool = pool.start_handler(affinity::pin_current_thread);
+    }
+    let pool = pool.build().unwrap();
 
-fn main() {
-    let input = std::env::args().nth(1).unwrap_or("assets/scene.txt".into());
-    let output = std::env::args().nth(2).unwrap_or("/tmp/out.ppm".into());
+    pool.install(|| {
+        if let Some(dir) = &options.golden {
+            if !golden::run_golden(dir, options.update_golden, &options) {
+                std::process::exit(1);
+            }
+        } else if options.benchmark {
+            benchmark::run_benchmark(&options);
+        } else if let Some(jobs) = &options.jobs {
+            interrupt::install_handler();
+            jobs::run_jobs(jobs, &options);
+        } else if let Some(addr) = &options.serve {
+            if let Err(err) = network::serve(addr, &options) {
+                log::error!("network render failed: {}", err);
+                std::process::exit(1);
+            }
+        } else if let Some(addr) = &options.worker {
+            if let Err(err) = network::work(addr, &options) {
+                log::error!("worker failed: {}", err);
+                std::process::exit(1);
+            }
+        } else if let Some(path) = &options.camera_path {
+            interrupt::install_handler();
+            camera_path::render_camera_path(&options.input, path, &options, &options.output);
+        } else if let Some(dir) = &options.dataset {
+            interrupt::install_handler();
+            dataset::render_dataset(dir, &options);
+        } else if let Some(path) = &options.sh_probes {
+            probes::bake_probes(path, &options);
+        } else if let Some(path) = &options.export {
+            let scene = render::load_scene(&options.input, &options);
+            export::export_scene(&scene, path);
+        } else {
+            interrupt::install_handler();
+            let start = Instant::now();
+            let mut scene = render::load_scene(&options.input, &options);
+            let load_time = start.elapsed();
 
-    let mut scene = parse_scene(&input);
-    render(&mut scene);
+            let start = Instant::now();
+            let outputs = render::render_to_file(&mut scene, &options, &options.output);
+            let render_time = start.elapsed();
 
-    scene.image.color_correction();
-    scene.image.write(&output);
+            if let Some(path) = &options.report {
+                report::write_report(path, &scene, &options, &outputs, load_time, render_time);
+            }
+        }
+    });
+    profile::finish(options.profile.as_deref());
 }
@@ -0,0 +1,400 @@
+use glm::{vec3, Vec3};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::options::Options;
+use crate::parser::{file_paths, parse_scene_from, replace_file_paths};
+use crate::pbrt::is_pbrt;
+use crate::render::{apply_overrides, post_process, render_tile};
+use crate::scene::Scene;
+use crate::tiles::{sort_tiles, split_into_tiles, Tile};
+
+// Protocol, all numbers are little-endian u32/f32, strings and files are
+// their length followed by the bytes:
+//   coordinator -> worker: number of arguments, the coordinator command line
+//   coordinator -> worker: scene source
+//   coordinator -> worker: number of files, path and contents of every file
+//                          the scene and the options read
+//   coordinator -> worker: 1, x0, y0, x1, y1 for every tile, 0 when done
+//   worker -> coordinator: averaged radiance of every tile pixel, in the
+//                          Z-order of Tile::pixels (by Morton code of the
+//                          image coordinates)
+const TAG_DONE: u32 = 0;
+const TAG_TILE: u32 = 1;
+// How often the coordinator checks for workers while no tile arrives
+const IDLE_CHECK: Duration = Duration::from_secs(1);
+
+// Everything a worker is sent before the tiles
+struct Job {
+    args: Vec<String>,
+    source: String,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+// Tiles not handed out yet, the number of tiles not rendered yet and
+// the workers connected. A worker that finds no tile waits for the rest: a
+// worker that disconnects puts its tile back
+struct TileQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+struct QueueState {
+    tiles: Vec<Tile>,
+    pending: usize,
+    workers: usize,
+    // since when no worker is connected
+    idle_since: Option<Instant>,
+}
+
+impl TileQueue {
+    fn new(tiles: Vec<Tile>) -> Self {
+        let pending = tiles.len();
+        Self {
+            state: Mutex::new(QueueState {
+                tiles,
+                pending,
+                workers: 0,
+                idle_since: Some(Instant::now()),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // None once every tile is rendered
+    fn take(&self) -> Option<Tile> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(tile) = state.tiles.pop() {
+                return Some(tile);
+            }
+            if state.pending == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn done(&self) {
+        self.state.lock().unwrap().pending -= 1;
+        self.changed.notify_all();
+    }
+
+    fn put_back(&self, tile: Tile) {
+        self.state.lock().unwrap().tiles.push(tile);
+        self.changed.notify_all();
+    }
+
+    fn connect(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.workers += 1;
+        state.idle_since = None;
+    }
+
+    fn disconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.workers -= 1;
+        if state.workers == 0 {
+            state.idle_since = Some(Instant::now());
+        }
+    }
+
+    fn idle_time(&self) -> Option<Duration> {
+        self.state.lock().unwrap().idle_since.map(|t| t.elapsed())
+    }
+}
+
+// Fails when no worker is connected for --serve-timeout seconds while
+// tiles are left
+pub fn serve(addr: &str, options: &Options) -> io::Result<()> {
+    assert!(
+        !is_pbrt(&options.input),
+        "--serve needs a scene in the scene format"
+    );
+    let source = std::fs::read_to_string(&options.input).unwrap();
+    let mut scene = parse_scene_from(source.as_bytes());
+    apply_overrides(&mut scene, options);
+
+    let paths = file_paths(&source)
+        .into_iter()
+        .chain(options.overrides.clone());
+    let files = paths
+        .map(|path| {
+            let contents = std::fs::read(&path).unwrap();
+            (path, contents)
+        })
+        .collect();
+    let job = Arc::new(Job {
+        args: options.args.clone(),
+        source,
+        files,
+    });
+
+    let (width, height) = (scene.image.width, scene.image.height);
+    let mut tiles = split_into_tiles(width, height);
+    sort_tiles(&mut tiles, options.tile_order, width, height);
+    // Workers take them from the end
+    tiles.reverse();
+    let n_tiles = tiles.len();
+    let queue = Arc::new(TileQueue::new(tiles));
+    let (sender, receiver) = mpsc::channel();
+
+    let listener = TcpListener::bind(addr)?;
+    let worker_queue = queue.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let queue = worker_queue.clone();
+            let sender = sender.clone();
+            let job = job.clone();
+            std::thread::spawn(move || serve_worker(stream, &job, &queue, &sender));
+        }
+    });
+
+    let timeout = Duration::from_secs_f32(options.serve_timeout);
+    let mut received = 0;
+    while received < n_tiles {
+        let (tile, colors) = match receiver.recv_timeout(IDLE_CHECK) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                if queue.idle_time().is_some_and(|idle| idle > timeout) {
+                    let message = format!(
+                        "no workers for {} s with {} of {} tiles left",
+                        options.serve_timeout,
+                        n_tiles - received,
+                        n_tiles
+                    );
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::other("the listener stopped"));
+            }
+        };
+        for ((i, j), color) in tile.pixels().into_iter().zip(colors) {
+            scene.image.set(i, j, color);
+        }
+        received += 1;
+    }
+
+    post_process(&mut scene.image, options);
+    scene.image.write(&options.output);
+    Ok(())
+}
+
+fn serve_worker(
+    stream: TcpStream,
+    job: &Job,
+    queue: &TileQueue,
+    results: &Sender<(Tile, Vec<Vec3>)>,
+) {
+    queue.connect();
+    hand_out_tiles(stream, job, queue, results);
+    queue.disconnect();
+}
+
+fn hand_out_tiles(
+    stream: TcpStream,
+    job: &Job,
+    queue: &TileQueue,
+    results: &Sender<(Tile, Vec<Vec3>)>,
+) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = BufWriter::new(stream);
+
+    if send_job(&mut writer, job).is_err() {
+        return;
+    }
+
+    loop {
+        let Some(tile) = queue.take() else {
+            let _ = write_u32(&mut writer, TAG_DONE).and_then(|_| writer.flush());
+            return;
+        };
+
+        match request_tile(&mut reader, &mut writer, &tile) {
+            Ok(colors) => {
+                // The coordinator has stopped when it fails
+                let _ = results.send((tile, colors));
+                queue.done();
+            }
+            Err(_) => {
+                // The worker is gone, let somebody else render its tile
+                log::warn!("a worker disconnected, its tile is handed out again");
+                queue.put_back(tile);
+                return;
+            }
+        }
+    }
+}
+
+fn send_job(writer: &mut impl Write, job: &Job) -> io::Result<()> {
+    write_u32(writer, job.args.len() as u32)?;
+    for arg in &job.args {
+        write_bytes(writer, arg.as_bytes())?;
+    }
+    write_bytes(writer, job.source.as_bytes())?;
+    write_u32(writer, job.files.len() as u32)?;
+    for (path, contents) in &job.files {
+        write_bytes(writer, path.as_bytes())?;
+        write_bytes(writer, contents)?;
+    }
+    writer.flush()
+}
+
+fn request_tile(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    tile: &Tile,
+) -> io::Result<Vec<Vec3>> {
+    write_u32(writer, TAG_TILE)?;
+    for x in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        write_u32(writer, x as u32)?;
+    }
+    writer.flush()?;
+
+    let n_pixels = (tile.x1 - tile.x0) * (tile.y1 - tile.y0);
+    (0..n_pixels)
+        .map(|_| {
+            Ok(vec3(
+                read_f32(reader)?,
+                read_f32(reader)?,
+                read_f32(reader)?,
+            ))
+        })
+        .collect()
+}
+
+pub fn work(addr: &str, options: &Options) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let n_args = read_u32(&mut reader)?;
+    let args = (0..n_args)
+        .map(|_| read_string(&mut reader))
+        .collect::<io::Result<Vec<_>>>()?;
+    let source = read_string(&mut reader)?;
+    let n_files = read_u32(&mut reader)?;
+    let files = (0..n_files)
+        .map(|_| Ok((read_string(&mut reader)?, read_bytes(&mut reader)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Copies of the coordinator files, only needed while loading
+    let dir = std::env::temp_dir().join(format!("raytracing-worker-{}", std::process::id()));
+    let loaded = load_job(&dir, args, &source, files, options);
+    let _ = std::fs::remove_dir_all(&dir);
+    let (scene, options) = loaded?;
+
+    let (width, height) = (scene.image.width, scene.image.height);
+    loop {
+        match read_u32(&mut reader) {
+            Ok(TAG_TILE) => {}
+            Ok(TAG_DONE) => return Ok(()),
+            // The coordinator exits as soon as the last tile arrives, so a
+            // closed connection means the same as TAG_DONE
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(tag) => {
+                let message = format!("unknown tag {}", tag);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            Err(err) => return Err(err),
+        }
+
+        let mut coords = [0; 4];
+        for x in &mut coords {
+            *x = read_u32(&mut reader)? as usize;
+        }
+        let [x0, y0, x1, y1] = coords;
+        if x0 >= x1 || y0 >= y1 || x1 > width || y1 > height {
+            let message = format!("tile {:?} is not in the {}x{} image", coords, width, height);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        let colors = render_tile(&scene, &Tile { x0, y0, x1, y1 }, &options);
+        for color in colors {
+            for c in color.iter() {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+// The scene and options of the coordinator, its files copied to dir
+fn load_job(
+    dir: &Path,
+    args: Vec<String>,
+    source: &str,
+    files: Vec<(String, Vec<u8>)>,
+    options: &Options,
+) -> io::Result<(Scene, Options)> {
+    std::fs::create_dir_all(dir)?;
+    // By their path on the coordinator
+    let mut copies = HashMap::new();
+    for (k, (path, contents)) in files.into_iter().enumerate() {
+        // The readers go by the extension
+        let name = Path::new(&path).file_name().unwrap_or_default();
+        let copy = dir.join(format!("{}-{}", k, name.to_string_lossy()));
+        std::fs::write(&copy, contents)?;
+        copies.insert(path, copy.to_string_lossy().into_owned());
+    }
+    let copy_of = |path: &str| {
+        copies.get(path).cloned().ok_or_else(|| {
+            let message = format!("the coordinator did not send {}", path);
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })
+    };
+
+    // The image is the coordinator's, only the cache stays local
+    let cache_dir = options.cache_dir.clone();
+    let mut options = Options::parse(args);
+    options.cache_dir = cache_dir;
+    options.overrides = options.overrides.as_deref().map(copy_of).transpose()?;
+
+    for path in file_paths(source) {
+        copy_of(&path)?;
+    }
+    let source = replace_file_paths(source, |path| copy_of(path).unwrap());
+    let mut scene = parse_scene_from(source.as_bytes());
+    apply_overrides(&mut scene, &options);
+    Ok((scene, options))
+}
+
+fn write_u32(writer: &mut impl Write, x: u32) -> io::Result<()> {
+    writer.write_all(&x.to_le_bytes())
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_u32(writer, bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; read_u32(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader)?)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    read_u32(reader).map(f32::from_bits)
+}
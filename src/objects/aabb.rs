@@ -0,0 +1,198 @@
+use glm::{vec3, Vec3};
+use na::UnitQuaternion;
+
+use crate::epsilon::UNIT_ROUNDOFF;
+use crate::ray::Ray;
+
+// 1 + 2 gamma(3), the bound on the rounding of a slab distance relative
+// to it (Pharr et al., "Physically Based Rendering", 3.9)
+const FAR_ROUNDING: f32 = 1.0 + 2.0 * (3.0 * UNIT_ROUNDOFF / (1.0 - 3.0 * UNIT_ROUNDOFF));
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::repeat(f32::INFINITY),
+            max: Vec3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    // Largest extent
+    pub fn size(&self) -> f32 {
+        (self.max - self.min).max()
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.x * d.z)
+    }
+
+    // Bounds of the box after rotating and then moving it
+    pub fn transformed(&self, rotation: &UnitQuaternion<f32>, position: &Vec3) -> Self {
+        (0..8)
+            .map(|corner| {
+                let p = vec3(
+                    if corner & 1 == 0 {
+                        self.min.x
+                    } else {
+                        self.max.x
+                    },
+                    if corner & 2 == 0 {
+                        self.min.y
+                    } else {
+                        self.max.y
+                    },
+                    if corner & 4 == 0 {
+                        self.min.z
+                    } else {
+                        self.max.z
+                    },
+                );
+                rotation * p + position
+            })
+            .fold(Aabb::empty(), |aabb, p| {
+                aabb.union(&Aabb { min: p, max: p })
+            })
+    }
+
+    // Returns the part of the ray's interval inside the box. For rays
+    // parallel to a slab the bounds are infinite, or NaN when the ray lies
+    // in the plane of a face; the comparisons skip NaN, which counts such
+    // rays as inside. The far bound is rounded up as in pbrt, so the
+    // interval is never short of the real one
+    pub fn intersect(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let o = ray.origin;
+        let inv_d = ray.inv_direction;
+        let bounds = [self.min, self.max];
+
+        let mut t1 = ray.t_min;
+        let mut t2 = ray.t_max;
+        for i in 0..3 {
+            let near = (bounds[ray.sign[i]][i] - o[i]) * inv_d[i];
+            let far = (bounds[1 - ray.sign[i]][i] - o[i]) * inv_d[i] * FAR_ROUNDING;
+            if near > t1 {
+                t1 = near;
+            }
+            if far < t2 {
+                t2 = far;
+            }
+        }
+
+        if t1 > t2 {
+            None
+        } else {
+            Some((t1, t2))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: Vec3::zeros(),
+            max: Vec3::repeat(1.0),
+        }
+    }
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+        Ray::from_unit(origin.into(), direction.into())
+    }
+
+    // The far bound is only ever rounded up
+    fn assert_interval(hit: Option<(f32, f32)>, t_near: f32, t_far: f32) {
+        let (t1, t2) = hit.unwrap();
+        assert_eq!(t1, t_near);
+        assert!(t2 >= t_far && t2 - t_far < 1e-5, "{} is not {}", t2, t_far);
+    }
+
+    #[test]
+    fn crossing() {
+        let hit = unit_box().intersect(&ray([-1.0, 0.5, 0.5], [1.0, 0.0, 0.0]));
+        assert_interval(hit, 1.0, 2.0);
+    }
+
+    #[test]
+    fn in_face_plane() {
+        let hit = unit_box().intersect(&ray([-1.0, 0.5, 1.0], [1.0, 0.0, 0.0]));
+        assert_interval(hit, 1.0, 2.0);
+        let hit = unit_box().intersect(&ray([0.5, -1.0, 0.0], [0.0, 1.0, 0.0]));
+        assert_interval(hit, 1.0, 2.0);
+    }
+
+    #[test]
+    fn along_edge() {
+        let hit = unit_box().intersect(&ray([-1.0, 1.0, 1.0], [1.0, 0.0, 0.0]));
+        assert_interval(hit, 1.0, 2.0);
+        let hit = unit_box().intersect(&ray([0.0, 0.0, 3.0], [0.0, 0.0, -1.0]));
+        assert_interval(hit, 2.0, 3.0);
+    }
+
+    #[test]
+    fn negative_zero_direction() {
+        for direction in [[1.0, -0.0, 0.0], [1.0, 0.0, -0.0], [1.0, -0.0, -0.0]] {
+            let hit = unit_box().intersect(&ray([-1.0, 0.5, 0.5], direction));
+            assert_interval(hit, 1.0, 2.0);
+            // Outside the slabs, whatever the sign of the zeros
+            assert!(unit_box()
+                .intersect(&ray([-1.0, 2.0, 2.0], direction))
+                .is_none());
+            assert!(unit_box()
+                .intersect(&ray([-1.0, -1.0, -1.0], direction))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn parallel_outside_slab() {
+        assert!(unit_box()
+            .intersect(&ray([-1.0, 2.0, 0.5], [1.0, 0.0, 0.0]))
+            .is_none());
+        assert!(unit_box()
+            .intersect(&ray([0.5, 0.5, -1e-3], [0.0, 1.0, 0.0]))
+            .is_none());
+        assert!(unit_box()
+            .intersect(&ray([0.5, 0.5, 1.0 + 1e-3], [-1.0, 0.0, 0.0]))
+            .is_none());
+    }
+
+    #[test]
+    fn origin_inside() {
+        let hit = unit_box().intersect(&ray([0.5, 0.25, 0.5], [0.0, -1.0, 0.0]));
+        assert_interval(hit, 0.0, 0.25);
+    }
+
+    #[test]
+    fn interval_clips_box() {
+        let forward = ray([-1.0, 0.5, 0.5], [1.0, 0.0, 0.0]);
+        // Inside the box on both ends
+        let hit = unit_box().intersect(&forward.clone().with_interval(1.5, 1.75));
+        assert_eq!(hit, Some((1.5, 1.75)));
+        // Starts before the box or ends after it
+        let hit = unit_box().intersect(&forward.clone().with_interval(0.5, 1.5));
+        assert_eq!(hit, Some((1.0, 1.5)));
+        let hit = unit_box().intersect(&forward.clone().with_interval(1.5, 10.0));
+        assert_interval(hit, 1.5, 2.0);
+        // Before and after the box
+        assert!(unit_box()
+            .intersect(&forward.clone().with_interval(0.0, 0.5))
+            .is_none());
+        assert!(unit_box()
+            .intersect(&forward.with_interval(2.5, f32::INFINITY))
+            .is_none());
+    }
+}
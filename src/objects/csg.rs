@@ -0,0 +1,335 @@
+use glm::Vec3;
+
+use super::{
+    box_interval, Aabb, Ellipsoid, Geometry, Parallelipiped, Plane, PositionedFigure,
+    RayIntersection,
+};
+use crate::ray::Ray;
+
+// Part of a ray inside a solid with the outward normals at both ends,
+// ends may be infinite (and then have zero normals)
+#[derive(Clone, Copy)]
+pub struct Span {
+    pub t_in: f32,
+    pub n_in: Vec3,
+    pub t_out: f32,
+    pub n_out: Vec3,
+}
+
+pub trait Solid: Geometry {
+    // Sorted, disjoint spans along the whole line of the ray,
+    // including negative parameters
+    fn spans(&self, ray: &Ray) -> Vec<Span>;
+}
+
+#[derive(Clone, Copy)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+pub struct Csg {
+    pub op: CsgOp,
+    pub a: Box<dyn Solid>,
+    pub b: Box<dyn Solid>,
+}
+
+impl Csg {
+    // Applies the operation left to right: a - b - c for a difference
+    pub fn from_operands(op: CsgOp, operands: Vec<Box<dyn Solid>>) -> Self {
+        assert!(operands.len() >= 2, "CSG needs at least two operands");
+
+        let mut operands = operands.into_iter();
+        let a = operands.next().unwrap();
+        let b = operands.next().unwrap();
+        operands.fold(Csg { op, a, b }, |csg, b| Csg {
+            op,
+            a: Box::new(csg),
+            b,
+        })
+    }
+}
+
+impl Solid for Csg {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let a = self.a.spans(ray);
+        let b = self.b.spans(ray);
+
+        match self.op {
+            CsgOp::Union => union(&a, &b),
+            CsgOp::Intersection => intersection(&a, &b),
+            CsgOp::Difference => intersection(&a, &complement(&b)),
+        }
+    }
+}
+
+impl Geometry for Csg {
+    fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
+        first_boundary(&self.spans(ray), ray)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match self.op {
+            CsgOp::Union => Some(self.a.bounds()?.union(&self.b.bounds()?)),
+            CsgOp::Intersection => match (self.a.bounds(), self.b.bounds()) {
+                (Some(a), Some(b)) => Some(Aabb {
+                    min: a.min.sup(&b.min),
+                    max: a.max.inf(&b.max),
+                }),
+                (a, b) => a.or(b),
+            },
+            CsgOp::Difference => self.a.bounds(),
+        }
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        let op = match self.op {
+            CsgOp::Union => "UNION",
+            CsgOp::Intersection => "INTERSECTION",
+            CsgOp::Difference => "DIFFERENCE",
+        };
+        let mut lines = vec![format!("CSG_BEGIN {}", op)];
+        lines.extend(self.a.scene_lines());
+        lines.extend(self.b.scene_lines());
+        lines.push("CSG_END".to_owned());
+        lines
+    }
+}
+
+// In the interval of the ray
+fn first_boundary(spans: &[Span], ray: &Ray) -> Option<RayIntersection> {
+    for span in spans {
+        if span.t_in > ray.t_min {
+            return ray.contains(span.t_in).then_some(RayIntersection {
+                t: span.t_in,
+                n: span.n_in,
+                is_inside: false,
+            });
+        }
+        if span.t_out > ray.t_min {
+            return ray.contains(span.t_out).then_some(RayIntersection {
+                t: span.t_out,
+                n: span.n_out,
+                is_inside: true,
+            });
+        }
+    }
+    None
+}
+
+fn union(a: &[Span], b: &[Span]) -> Vec<Span> {
+    let mut all = a.iter().chain(b).copied().collect::<Vec<_>>();
+    all.sort_by(|x, y| x.t_in.partial_cmp(&y.t_in).unwrap());
+
+    let mut result: Vec<Span> = Vec::new();
+    for span in all {
+        match result.last_mut() {
+            Some(last) if span.t_in <= last.t_out => {
+                if span.t_out > last.t_out {
+                    last.t_out = span.t_out;
+                    last.n_out = span.n_out;
+                }
+            }
+            _ => result.push(span),
+        }
+    }
+    result
+}
+
+fn intersection(a: &[Span], b: &[Span]) -> Vec<Span> {
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        let (x, y) = (&a[i], &b[j]);
+        let (t_in, n_in) = if x.t_in > y.t_in {
+            (x.t_in, x.n_in)
+        } else {
+            (y.t_in, y.n_in)
+        };
+        let (t_out, n_out) = if x.t_out < y.t_out {
+            (x.t_out, x.n_out)
+        } else {
+            (y.t_out, y.n_out)
+        };
+
+        if t_in < t_out {
+            result.push(Span {
+                t_in,
+                n_in,
+                t_out,
+                n_out,
+            });
+        }
+
+        if x.t_out < y.t_out {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+// Outside of a solid becomes inside, so the normals flip
+fn complement(spans: &[Span]) -> Vec<Span> {
+    let mut result = Vec::new();
+    let mut t_in = f32::NEG_INFINITY;
+    let mut n_in = Vec3::zeros();
+
+    for span in spans {
+        if span.t_in > t_in {
+            result.push(Span {
+                t_in,
+                n_in,
+                t_out: span.t_in,
+                n_out: -span.n_in,
+            });
+        }
+        t_in = span.t_out;
+        n_in = -span.n_out;
+    }
+
+    if t_in < f32::INFINITY {
+        result.push(Span {
+            t_in,
+            n_in,
+            t_out: f32::INFINITY,
+            n_out: Vec3::zeros(),
+        });
+    }
+    result
+}
+
+impl Geometry for Box<dyn Solid> {
+    fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
+        self.as_ref().intersect(ray)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.as_ref().bounds()
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        self.as_ref().scene_lines()
+    }
+}
+
+impl Solid for Box<dyn Solid> {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        self.as_ref().spans(ray)
+    }
+}
+
+impl<F: Solid> Solid for PositionedFigure<F> {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let transformed_ray = Ray::from_unit(
+            self.rotation.inverse() * (ray.origin - self.position),
+            self.rotation.inverse() * ray.direction,
+        )
+        .with_interval(ray.t_min, ray.t_max)
+        .with_epsilon(ray.epsilon);
+
+        let mut spans = self.figure.spans(&transformed_ray);
+        for span in &mut spans {
+            span.n_in = (self.rotation * span.n_in).normalize();
+            span.n_out = (self.rotation * span.n_out).normalize();
+            if !span.n_in.iter().all(|x| x.is_finite()) {
+                span.n_in = Vec3::zeros();
+            }
+            if !span.n_out.iter().all(|x| x.is_finite()) {
+                span.n_out = Vec3::zeros();
+            }
+        }
+        spans
+    }
+}
+
+impl Solid for Plane {
+    // Inside is the half-space the normal points away from
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let along = glm::dot(&ray.direction, &self.normal);
+        let height = glm::dot(&ray.origin, &self.normal);
+        let t = -height / along;
+
+        if along > 0.0 {
+            vec![Span {
+                t_in: f32::NEG_INFINITY,
+                n_in: Vec3::zeros(),
+                t_out: t,
+                n_out: self.normal,
+            }]
+        } else if along < 0.0 {
+            vec![Span {
+                t_in: t,
+                n_in: self.normal,
+                t_out: f32::INFINITY,
+                n_out: Vec3::zeros(),
+            }]
+        } else if height < 0.0 {
+            vec![Span {
+                t_in: f32::NEG_INFINITY,
+                n_in: Vec3::zeros(),
+                t_out: f32::INFINITY,
+                n_out: Vec3::zeros(),
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl Solid for Ellipsoid {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let u = ray.origin.component_div(&self.radiuses);
+        let v = ray.direction.component_div(&self.radiuses);
+
+        let a = glm::length2(&v);
+        let b = glm::dot(&u, &v);
+        let c = glm::length2(&u) - 1.0;
+
+        let det = b * b - a * c;
+        if det < 0.0 {
+            return Vec::new();
+        }
+
+        let t1 = (-b - det.sqrt()) / a;
+        let t2 = (-b + det.sqrt()) / a;
+        let normal = |t: f32| (u + t * v).component_div(&self.radiuses);
+
+        vec![Span {
+            t_in: t1,
+            n_in: normal(t1),
+            t_out: t2,
+            n_out: normal(t2),
+        }]
+    }
+}
+
+impl Solid for Parallelipiped {
+    fn spans(&self, ray: &Ray) -> Vec<Span> {
+        let o = ray.origin;
+        let d = ray.direction;
+        let (t1, t2) = box_interval(&self.sizes, ray);
+
+        if t1 > t2 {
+            return Vec::new();
+        }
+
+        let normal = |t: f32| {
+            let mut n = (o + t * d).component_div(&self.sizes);
+            let (i, _) = n.abs().argmax();
+            n[(i + 1) % 3] = 0.0;
+            n[(i + 2) % 3] = 0.0;
+            n
+        };
+
+        vec![Span {
+            t_in: t1,
+            n_in: normal(t1),
+            t_out: t2,
+            n_out: normal(t2),
+        }]
+    }
+}
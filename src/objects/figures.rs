@@ -1,4 +1,4 @@
-use glm::Vec3;
+use glm::{Vec2, Vec3};
 use na::UnitQuaternion;
 
 pub struct Plane {
@@ -16,6 +16,21 @@ pub struct Parallelipiped {
     pub sizes: Vec3,
 }
 
+// Another figure made larger by the factor in every direction, for
+// figures that have no sizes of their own to change (CSG and SDFs)
+pub struct Scaled<F> {
+    pub figure: F,
+    pub factor: f32,
+}
+
+// In the xy plane, visible from both sides
+#[derive(Clone)]
+pub struct Rectangle {
+    // center is 0
+    pub sizes: Vec2,
+}
+
+#[derive(Clone)]
 pub struct PositionedFigure<F> {
     pub figure: F,
     pub position: Vec3,
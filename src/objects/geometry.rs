@@ -1,9 +1,9 @@
 use glm::Vec3;
-use itertools::MultiUnzip;
+use std::sync::Arc;
 
 use super::{
-    figures::{Ellipsoid, Parallelipiped, Plane},
-    LightSource, PositionedFigure,
+    figures::{Ellipsoid, Parallelipiped, Plane, Rectangle, Scaled},
+    Aabb, PositionedFigure,
 };
 use crate::ray::Ray;
 
@@ -11,20 +11,39 @@ use crate::ray::Ray;
 pub struct RayIntersection {
     pub t: f32,
     pub n: Vec3,
+    // The ray leaves the figure at the hit: it goes along the outward
+    // normal, the side a plane or rectangle faces for flat figures
     pub is_inside: bool,
 }
 
-pub trait Geometry {
+pub trait Geometry: Send + Sync {
     fn intersect(&self, ray: &Ray) -> Option<RayIntersection>;
+
+    // None for unbounded figures
+    fn bounds(&self) -> Option<Aabb>;
+
+    // Lines of the scene format that recreate the figure, empty when the
+    // format can't describe it
+    fn scene_lines(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Makes the figure larger by the factor in every direction, false when
+    // it can't change its own sizes and has to be wrapped in Scaled
+    fn scale(&mut self, _factor: f32) -> bool {
+        false
+    }
 }
 
-// TODO: fix!
-impl Geometry for PositionedFigure<Box<dyn Geometry>> {
+impl<F: Geometry> Geometry for PositionedFigure<F> {
     fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
-        let transformed_ray = Ray {
-            origin: self.rotation.inverse() * (ray.origin - self.position),
-            direction: self.rotation.inverse() * ray.direction,
-        };
+        // rotations keep the lengths, and so the interval
+        let transformed_ray = Ray::from_unit(
+            self.rotation.inverse() * (ray.origin - self.position),
+            self.rotation.inverse() * ray.direction,
+        )
+        .with_interval(ray.t_min, ray.t_max)
+        .with_epsilon(ray.epsilon);
         let mut intersection = self.figure.intersect(&transformed_ray)?;
 
         intersection.n = (self.rotation * intersection.n).normalize();
@@ -34,40 +53,108 @@ impl Geometry for PositionedFigure<Box<dyn Geometry>> {
 
         Some(intersection)
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let aabb = self.figure.bounds()?;
+        Some(aabb.transformed(&self.rotation, &self.position))
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        let (p, q) = (self.position, self.rotation.coords);
+        let mut lines = self.figure.scene_lines();
+        lines.push(format!("POSITION {} {} {}", p.x, p.y, p.z));
+        lines.push(format!("ROTATION {} {} {} {}", q.x, q.y, q.z, q.w));
+        lines
+    }
 }
 
-impl<F: Geometry> Geometry for PositionedFigure<F> {
+impl Geometry for Box<dyn Geometry> {
     fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
-        let transformed_ray = Ray {
-            origin: self.rotation.inverse() * (ray.origin - self.position),
-            direction: self.rotation.inverse() * ray.direction,
-        };
-        let mut intersection = self.figure.intersect(&transformed_ray)?;
+        self.as_ref().intersect(ray)
+    }
 
-        intersection.n = (self.rotation * intersection.n).normalize();
-        if glm::dot(&intersection.n, &ray.direction) > 0.0 {
-            intersection.n = -intersection.n;
-        }
+    fn bounds(&self) -> Option<Aabb> {
+        self.as_ref().bounds()
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        self.as_ref().scene_lines()
+    }
+
+    fn scale(&mut self, factor: f32) -> bool {
+        self.as_mut().scale(factor)
+    }
+}
 
+// Figure shared by duplicated objects
+impl Geometry for Arc<dyn Geometry> {
+    fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
+        self.as_ref().intersect(ray)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.as_ref().bounds()
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        self.as_ref().scene_lines()
+    }
+}
+
+// Distances along the ray scale with the figure, the normals stay
+impl Geometry for Scaled<Box<dyn Geometry>> {
+    fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
+        let scaled_ray = Ray::from_unit(ray.origin / self.factor, ray.direction)
+            .with_interval(ray.t_min / self.factor, ray.t_max / self.factor)
+            .with_epsilon(ray.epsilon);
+        let mut intersection = self.figure.intersect(&scaled_ray)?;
+        intersection.t *= self.factor;
         Some(intersection)
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let aabb = self.figure.bounds()?;
+        Some(Aabb {
+            min: aabb.min * self.factor,
+            max: aabb.max * self.factor,
+        })
+    }
+
+    fn scale(&mut self, factor: f32) -> bool {
+        self.factor *= factor;
+        true
+    }
 }
 
 impl Geometry for Plane {
     fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
         let t = -glm::dot(&ray.origin, &self.normal) / glm::dot(&ray.direction, &self.normal);
-        let is_inside = glm::dot(&self.normal, &ray.origin) < 0.0;
+        let is_inside = glm::dot(&self.normal, &ray.direction) > 0.0;
 
-        if t < 0.0 {
-            None
-        } else {
+        if ray.contains(t) {
             Some(RayIntersection {
                 t,
                 n: self.normal,
                 is_inside,
             })
+        } else {
+            None
         }
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        let n = self.normal;
+        vec![format!("PLANE {} {} {}", n.x, n.y, n.z)]
+    }
+
+    // through 0, so the same plane
+    fn scale(&mut self, _factor: f32) -> bool {
+        true
+    }
 }
 
 impl Geometry for Ellipsoid {
@@ -89,49 +176,44 @@ impl Geometry for Ellipsoid {
         let t2 = (-b - det.sqrt()) / a;
 
         let (t1, t2) = (t1.min(t2), t1.max(t2));
+        let t = [t1, t2].into_iter().find(|&t| ray.contains(t))?;
 
-        let t = if t1 > 0.0 {
-            Some(t1)
-        } else if t2 > 0.0 {
-            Some(t2)
-        } else {
-            None
-        }?;
-
+        let n = (u + t * v).component_div(&self.radiuses);
         Some(RayIntersection {
             t,
-            is_inside: glm::length2(&u) < 1.0,
-            n: (u + t * v).component_div(&self.radiuses),
+            is_inside: glm::dot(&n, &ray.direction) > 0.0,
+            n,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb {
+            min: -self.radiuses,
+            max: self.radiuses,
         })
     }
+
+    fn scene_lines(&self) -> Vec<String> {
+        let r = self.radiuses;
+        vec![format!("ELLIPSOID {} {} {}", r.x, r.y, r.z)]
+    }
+
+    fn scale(&mut self, factor: f32) -> bool {
+        self.radiuses *= factor;
+        true
+    }
 }
 
 impl Geometry for Parallelipiped {
     fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
         let o = ray.origin;
         let d = ray.direction;
+        let (t1, t2) = box_interval(&self.sizes, ray);
 
-        let (l, r): (Vec<_>, Vec<_>) = (0..3)
-            .map(|i| {
-                let t1 = (self.sizes[i] - o[i]) / d[i];
-                let t2 = (-self.sizes[i] - o[i]) / d[i];
-
-                (t1.min(t2), t1.max(t2))
-            })
-            .multiunzip();
-
-        let t1 = l[0].max(l[1]).max(l[2]);
-        let t2 = r[0].min(r[1]).min(r[2]);
-
-        let t = if t1 > t2 {
-            None
-        } else if t1 >= 0.0 {
-            Some(t1)
-        } else if t2 >= 0.0 {
-            Some(t2)
-        } else {
-            None
-        }?;
+        if t1 > t2 {
+            return None;
+        }
+        let t = [t1, t2].into_iter().find(|&t| ray.contains(t))?;
 
         let mut n = (o + t * d).component_div(&self.sizes);
         let (i, _) = n.abs().argmax();
@@ -140,8 +222,71 @@ impl Geometry for Parallelipiped {
 
         Some(RayIntersection {
             t,
-            is_inside: o.component_div(&self.sizes).abs().max() < 1.0,
+            is_inside: glm::dot(&n, &d) > 0.0,
             n,
         })
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb {
+            min: -self.sizes,
+            max: self.sizes,
+        })
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        let s = self.sizes;
+        vec![format!("BOX {} {} {}", s.x, s.y, s.z)]
+    }
+
+    fn scale(&mut self, factor: f32) -> bool {
+        self.sizes *= factor;
+        true
+    }
+}
+
+// Parameter interval of the ray in the box from -sizes to sizes. Empty
+// when the start is past the end
+pub fn box_interval(sizes: &Vec3, ray: &Ray) -> (f32, f32) {
+    let mut t1 = f32::NEG_INFINITY;
+    let mut t2 = f32::INFINITY;
+    for i in 0..3 {
+        // the near side is the negative one for a positive direction
+        let near = if ray.sign[i] == 0 {
+            -sizes[i]
+        } else {
+            sizes[i]
+        };
+        let inv_d = ray.inv_direction[i];
+        t1 = t1.max((near - ray.origin[i]) * inv_d);
+        t2 = t2.min((-near - ray.origin[i]) * inv_d);
+    }
+    (t1, t2)
+}
+
+impl Geometry for Rectangle {
+    fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
+        let t = -ray.origin.z * ray.inv_direction.z;
+        if !ray.contains(t) {
+            return None;
+        }
+
+        let p = ray.origin + t * ray.direction;
+        if p.x.abs() > self.sizes.x || p.y.abs() > self.sizes.y {
+            return None;
+        }
+
+        Some(RayIntersection {
+            t,
+            n: Vec3::z(),
+            is_inside: ray.direction.z > 0.0,
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb {
+            min: -self.sizes.push(0.0),
+            max: self.sizes.push(0.0),
+        })
+    }
 }
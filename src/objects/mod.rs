@@ -1,12 +1,18 @@
+mod aabb;
+mod csg;
 mod figures;
 mod geometry;
 mod object;
 mod sample;
+mod sdf;
 
+pub use aabb::*;
+pub use csg::*;
 pub use figures::*;
 pub use geometry::*;
 pub use object::*;
 pub use sample::*;
+pub use sdf::*;
 
 pub trait LightSource: Geometry + Sample {}
 impl<T> LightSource for T where T: Geometry + Sample {}
@@ -1,19 +1,155 @@
-use glm::Vec3;
-
 use super::PositionedFigure;
+use crate::profile_scope;
+use crate::random::PathSampler;
+use glm::{vec3, Vec3};
 
+#[derive(Clone, Copy)]
 pub enum Material {
     Diffuse,
-    Metallic,
+    // Without fresnel it reflects the object color at all angles
+    Metallic { fresnel: Option<Fresnel> },
     Dielectric { ior: f32 },
+    // Transparent to the camera apart from the shadows it receives, for
+    // compositing onto photos. Diffuse for other rays
+    ShadowCatcher,
+}
+
+// Reflectance of a metal over the angle of incidence
+#[derive(Clone, Copy)]
+pub enum Fresnel {
+    // complex index of refraction n + ik per channel, the object color
+    // is not used
+    Conductor { eta: Vec3, k: Vec3 },
+    // Schlick's curve from the object color at normal incidence, bent to
+    // the tint times it at about 82 degrees
+    F82 { tint: Vec3 },
+}
+
+impl Fresnel {
+    // Measured metals, at 650, 550 and 450 nm
+    pub fn preset(name: &str) -> Option<Self> {
+        let (eta, k) = match name {
+            "gold" => (vec3(0.143, 0.374, 1.442), vec3(3.983, 2.385, 1.603)),
+            "silver" => (vec3(0.155, 0.117, 0.138), vec3(4.828, 3.122, 2.147)),
+            "copper" => (vec3(0.200, 0.924, 1.102), vec3(3.912, 2.452, 2.142)),
+            "aluminum" => (vec3(1.657, 0.880, 0.521), vec3(9.224, 6.270, 4.837)),
+            _ => return None,
+        };
+        Some(Fresnel::Conductor { eta, k })
+    }
+}
+
+// Alternates the object color with another one in cubes of the given size
+#[derive(Clone)]
+pub struct Checker {
+    pub color: Vec3,
+    pub size: f32,
+}
+
+// A second material over part of the surface, e.g. diffuse patches on
+// worn metal. Each hit takes it with the probability of its coverage
+#[derive(Clone)]
+pub struct Mix {
+    pub material: Material,
+    pub color: Vec3,
+    // fraction of the surface
+    pub factor: f32,
+    // feature size of the noise mask, which covers roughly the factor
+    // with patches; without it the blend is uniform
+    pub mask_size: Option<f32>,
+}
+
+// Objects an emitter lights and the objects that shadow it, by name.
+// Without a list every object is included. Only light reaching diffuse
+// surfaces is linked, reflections and refractions show every emitter
+#[derive(Clone, Default)]
+pub struct LightLinks {
+    pub illuminates: Option<Vec<String>>,
+    pub shadowed_by: Option<Vec<String>>,
+}
+
+impl LightLinks {
+    pub fn illuminates(&self, name: Option<&str>) -> bool {
+        linked(&self.illuminates, name)
+    }
+
+    pub fn shadowed_by(&self, name: Option<&str>) -> bool {
+        linked(&self.shadowed_by, name)
+    }
+}
+
+fn linked(names: &Option<Vec<String>>, name: Option<&str>) -> bool {
+    let Some(names) = names else {
+        return true;
+    };
+    name.is_some_and(|name| names.iter().any(|n| n == name))
+}
+
+// Kinds of rays that see the object. Without shadow it still shows in
+// reflections and GI, but emitters are seen through it
+#[derive(Clone)]
+pub struct Visibility {
+    pub camera: bool,
+    pub shadow: bool,
+    // reflections, refractions and diffuse bounces
+    pub indirect: bool,
+}
+
+impl Visibility {
+    // CAMERA, SHADOW or INDIRECT, as in the scene format
+    pub fn hide_from(&mut self, kind: &str) {
+        match kind {
+            "CAMERA" => self.camera = false,
+            "SHADOW" => self.shadow = false,
+            "INDIRECT" => self.indirect = false,
+            _ => panic!("unknown ray kind: {}", kind),
+        }
+    }
+
+    pub fn hidden_from(&self) -> Vec<&'static str> {
+        [
+            ("CAMERA", self.camera),
+            ("SHADOW", self.shadow),
+            ("INDIRECT", self.indirect),
+        ]
+        .into_iter()
+        .filter(|(_, visible)| !visible)
+        .map(|(kind, _)| kind)
+        .collect()
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self {
+            camera: true,
+            shadow: true,
+            indirect: true,
+        }
+    }
 }
 
 pub struct Object<G> {
     pub geometry: PositionedFigure<G>,
 
     pub color: Vec3,
+    pub checker: Option<Checker>,
     pub emission: Vec3,
+    // Emits only from the outside, or the normal side of a plane
+    pub single_sided: bool,
+    pub links: LightLinks,
+    pub visibility: Visibility,
     pub material: Material,
+    pub mix: Option<Mix>,
+    // Spread of the microfacet normals, 0 for a smooth surface. Frosts
+    // dielectrics, blurs metal reflections and makes diffuse surfaces
+    // Oren-Nayar
+    pub roughness: f32,
+
+    // For filtering the scene by name
+    pub name: Option<String>,
+    // Hidden objects are left out of the built scene
+    pub hidden: bool,
 }
 
 impl<G> Object<G> {
@@ -21,8 +157,90 @@ impl<G> Object<G> {
         Self {
             geometry: PositionedFigure::new(geometry),
             color: Vec3::zeros(),
+            checker: None,
             emission: Vec3::zeros(),
+            single_sided: false,
+            links: LightLinks::default(),
+            visibility: Visibility::default(),
             material: Material::Diffuse,
+            mix: None,
+            roughness: 0.0,
+            name: None,
+            hidden: false,
+        }
+    }
+}
+
+impl<G> Object<G> {
+    pub fn color_at(&self, point: &Vec3, normal: &Vec3) -> Vec3 {
+        let Some(checker) = &self.checker else {
+            return self.color;
+        };
+        profile_scope!("texture");
+
+        let rotation = self.geometry.rotation.inverse();
+        let p = rotation * (point - self.geometry.position) / checker.size;
+        // The coordinate along the normal is nearly constant on the surface
+        // and would flicker between cells, so it is skipped
+        let (skipped, _) = (rotation * normal).abs().argmax();
+
+        let cell = (0..3)
+            .filter(|&i| i != skipped)
+            .map(|i| p[i].floor() as i64)
+            .sum::<i64>();
+
+        if cell.rem_euclid(2) == 0 {
+            self.color
+        } else {
+            checker.color
+        }
+    }
+
+    // The material and color at the point, with the mix picked at random
+    pub fn surface_at(
+        &self,
+        point: &Vec3,
+        normal: &Vec3,
+        sampler: &mut PathSampler,
+    ) -> (Material, Vec3) {
+        if let Some(mix) = &self.mix {
+            let coverage = match mix.mask_size {
+                Some(size) => {
+                    let p = self.geometry.rotation.inverse() * (point - self.geometry.position);
+                    if value_noise(&(p / size)) < mix.factor {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+                None => mix.factor,
+            };
+            if sampler.next_1d() < coverage {
+                return (mix.material, mix.color);
+            }
+        }
+        (self.material, self.color_at(point, normal))
+    }
+}
+
+// Smoothly interpolated random values at the integer lattice, in 0..1
+fn value_noise(p: &Vec3) -> f32 {
+    let cell = p.map(|x| x.floor());
+    let t = (p - cell).map(|x| x * x * (3.0 - 2.0 * x));
+
+    let mut result = 0.0;
+    for corner in 0..8 {
+        let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+        let mut weight = 1.0;
+        let mut hash: u64 = 0x9e3779b97f4a7c15;
+        for i in 0..3 {
+            let k = offset[i] as f32;
+            weight *= if offset[i] == 1 { t[i] } else { 1.0 - t[i] };
+            hash ^= (cell[i] + k) as i64 as u64;
+            hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+            hash ^= hash >> 33;
         }
+        result += weight * (hash >> 40) as f32 / (1u64 << 24) as f32;
     }
+    result
 }
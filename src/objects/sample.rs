@@ -1,18 +1,17 @@
 use std::f32::consts::PI;
 
+use super::{Ellipsoid, Parallelipiped, PositionedFigure, Rectangle};
+use crate::random::PathSampler;
 use glm::{vec3, Vec3};
-use rand::{rngs::ThreadRng, Rng};
-
-use super::{Ellipsoid, Parallelipiped, PositionedFigure};
 
 pub trait Sample {
-    fn sample(&self, rng: &mut ThreadRng) -> Vec3;
+    fn sample(&self, sampler: &mut PathSampler) -> Vec3;
     fn pdf(&self, p: &Vec3) -> f32;
 }
 
 impl<F: Sample> Sample for PositionedFigure<F> {
-    fn sample(&self, rng: &mut ThreadRng) -> Vec3 {
-        let point = self.figure.sample(rng);
+    fn sample(&self, sampler: &mut PathSampler) -> Vec3 {
+        let point = self.figure.sample(sampler);
         self.rotation * point + self.position
     }
 
@@ -23,11 +22,11 @@ impl<F: Sample> Sample for PositionedFigure<F> {
 }
 
 impl Sample for Parallelipiped {
-    fn sample(&self, rng: &mut ThreadRng) -> Vec3 {
+    fn sample(&self, sampler: &mut PathSampler) -> Vec3 {
         let (a, b, c) = (self.sizes.x, self.sizes.y, self.sizes.z);
         let area = a * b + b * c + a * c;
 
-        let x = rng.gen_range(0.0..area);
+        let x = sampler.next_1d() * area;
         let mut p = if x < a * b {
             Vec3::z()
         } else if x < a * b + a * c {
@@ -36,14 +35,14 @@ impl Sample for Parallelipiped {
             Vec3::z()
         };
 
-        if rng.gen_bool(0.5) {
+        if sampler.next_1d() < 0.5 {
             p = -p;
         }
         p = p.component_mul(&self.sizes);
 
         for i in 0..3 {
             if p[i] == 0.0 {
-                p[i] = rng.gen_range(-self.sizes[i]..self.sizes[i]);
+                p[i] = (2.0 * sampler.next_1d() - 1.0) * self.sizes[i];
             }
         }
 
@@ -57,9 +56,22 @@ impl Sample for Parallelipiped {
     }
 }
 
+impl Sample for Rectangle {
+    fn sample(&self, sampler: &mut PathSampler) -> Vec3 {
+        let (u, v) = sampler.next_2d();
+        let x = (2.0 * u - 1.0) * self.sizes.x;
+        let y = (2.0 * v - 1.0) * self.sizes.y;
+        vec3(x, y, 0.0)
+    }
+
+    fn pdf(&self, _p: &Vec3) -> f32 {
+        1.0 / (4.0 * self.sizes.x * self.sizes.y)
+    }
+}
+
 impl Sample for Ellipsoid {
-    fn sample(&self, rng: &mut ThreadRng) -> Vec3 {
-        let p_sphere = sphere_uniform(rng);
+    fn sample(&self, sampler: &mut PathSampler) -> Vec3 {
+        let p_sphere = sphere_uniform(sampler);
         p_sphere.component_mul(&self.radiuses)
     }
 
@@ -75,9 +87,10 @@ impl Sample for Ellipsoid {
 }
 
 // TODO: remove copy paste
-fn sphere_uniform(rng: &mut ThreadRng) -> Vec3 {
-    let phi = rng.gen::<f32>() * std::f32::consts::PI;
-    let z = rng.gen::<f32>() * 2.0 - 1.0;
+fn sphere_uniform(sampler: &mut PathSampler) -> Vec3 {
+    let (u, v) = sampler.next_2d();
+    let phi = u * std::f32::consts::PI;
+    let z = v * 2.0 - 1.0;
     let x = (1.0 - z * z).sqrt() * phi.cos();
     let y = (1.0 - z * z).sqrt() * phi.sin();
 
@@ -0,0 +1,282 @@
+use glm::{vec3, Vec3};
+
+use super::{Aabb, Geometry, RayIntersection};
+use crate::ray::Ray;
+
+const MAX_STEPS: usize = 512;
+const MAX_ESCAPE_STEPS: usize = 64;
+const MANDELBULB_RADIUS: f32 = 1.2;
+
+// Signed distance field, negative inside. Built from shapes centered at 0
+// and combinators, e.g. Sdf::sphere(1.0).smooth_union(Sdf::torus(1.5, 0.3), 0.2)
+pub enum Sdf {
+    Sphere { radius: f32 },
+    Cube { sizes: Vec3 },
+    // lies in the xz plane
+    Torus { major: f32, minor: f32 },
+    Mandelbulb { power: f32, iterations: usize },
+    Translate { offset: Vec3, sdf: Box<Sdf> },
+    // larger by the factor in every direction
+    Scale { factor: f32, sdf: Box<Sdf> },
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    Difference(Box<Sdf>, Box<Sdf>),
+    SmoothUnion { k: f32, a: Box<Sdf>, b: Box<Sdf> },
+}
+
+impl Sdf {
+    pub fn sphere(radius: f32) -> Self {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cube(sizes: Vec3) -> Self {
+        Sdf::Cube { sizes }
+    }
+
+    pub fn torus(major: f32, minor: f32) -> Self {
+        Sdf::Torus { major, minor }
+    }
+
+    pub fn mandelbulb(power: f32, iterations: usize) -> Self {
+        Sdf::Mandelbulb { power, iterations }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        Sdf::Translate {
+            offset,
+            sdf: Box::new(self),
+        }
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        Sdf::Scale {
+            factor,
+            sdf: Box::new(self),
+        }
+    }
+
+    pub fn union(self, other: Sdf) -> Self {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Sdf) -> Self {
+        Sdf::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: Sdf) -> Self {
+        Sdf::Difference(Box::new(self), Box::new(other))
+    }
+
+    // Blends the surfaces within distance k of each other
+    pub fn smooth_union(self, other: Sdf, k: f32) -> Self {
+        Sdf::SmoothUnion {
+            k,
+            a: Box::new(self),
+            b: Box::new(other),
+        }
+    }
+
+    pub fn distance(&self, p: &Vec3) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => glm::length(p) - radius,
+            Sdf::Cube { sizes } => {
+                let q = p.abs() - sizes;
+                glm::length(&q.sup(&Vec3::zeros())) + q.max().min(0.0)
+            }
+            Sdf::Torus { major, minor } => {
+                let ring = glm::length(&glm::vec2(p.x, p.z)) - major;
+                glm::length(&glm::vec2(ring, p.y)) - minor
+            }
+            Sdf::Mandelbulb { power, iterations } => mandelbulb(p, *power, *iterations),
+            Sdf::Translate { offset, sdf } => sdf.distance(&(p - offset)),
+            Sdf::Scale { factor, sdf } => sdf.distance(&(p / *factor)) * factor,
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::Difference(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion { k, a, b } => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+                b + (a - b) * h - k * h * (1.0 - h)
+            }
+        }
+    }
+
+    // The stack commands of the SDF block that build this shape
+    fn commands(&self, lines: &mut Vec<String>) {
+        let mut binary = |a: &Sdf, b: &Sdf, command: String| {
+            a.commands(lines);
+            b.commands(lines);
+            lines.push(command);
+        };
+
+        match self {
+            Sdf::Sphere { radius } => lines.push(format!("SPHERE {}", radius)),
+            Sdf::Cube { sizes: s } => lines.push(format!("CUBE {} {} {}", s.x, s.y, s.z)),
+            Sdf::Torus { major, minor } => lines.push(format!("TORUS {} {}", major, minor)),
+            Sdf::Mandelbulb { power, iterations } => {
+                lines.push(format!("MANDELBULB {} {}", power, iterations))
+            }
+            Sdf::Translate { offset: o, sdf } => {
+                sdf.commands(lines);
+                lines.push(format!("TRANSLATE {} {} {}", o.x, o.y, o.z));
+            }
+            Sdf::Scale { factor, sdf } => {
+                sdf.commands(lines);
+                lines.push(format!("SCALE {}", factor));
+            }
+            Sdf::Union(a, b) => binary(a, b, "UNION".to_owned()),
+            Sdf::Intersection(a, b) => binary(a, b, "INTERSECTION".to_owned()),
+            Sdf::Difference(a, b) => binary(a, b, "DIFFERENCE".to_owned()),
+            Sdf::SmoothUnion { k, a, b } => binary(a, b, format!("SMOOTH_UNION {}", k)),
+        }
+    }
+
+    fn normal(&self, p: &Vec3, hit_eps: f32) -> Vec3 {
+        let gradient = Vec3::from_fn(|i, _| {
+            let mut h = Vec3::zeros();
+            h[i] = hit_eps;
+            self.distance(&(p + h)) - self.distance(&(p - h))
+        });
+        gradient.normalize()
+    }
+}
+
+fn mandelbulb(p: &Vec3, power: f32, iterations: usize) -> f32 {
+    let mut z = *p;
+    let mut dr = 1.0;
+    let mut r = glm::length(&z);
+
+    for _ in 0..iterations {
+        if r > 2.0 {
+            break;
+        }
+
+        let theta = (z.z / r).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+
+        let zr = r.powf(power);
+        z =
+            zr * vec3(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            ) + p;
+        r = glm::length(&z);
+    }
+
+    0.5 * r.ln() * r / dr
+}
+
+impl Geometry for Sdf {
+    // Sphere tracing inside the bounds. Rays starting on the surface
+    // first step off it, so they do not hit it again right away
+    fn intersect(&self, ray: &Ray) -> Option<RayIntersection> {
+        let hit_eps = ray.epsilon.sdf_hit();
+        let aabb = self.bounds()?;
+        let padding = Vec3::repeat(2.0 * hit_eps);
+        let aabb = Aabb {
+            min: aabb.min - padding,
+            max: aabb.max + padding,
+        };
+        let (t_enter, t_exit) = aabb.intersect(ray)?;
+
+        let at = |t: f32| ray.origin + t * ray.direction;
+        let mut t = t_enter;
+        // marches towards the surface from the side the ray starts on
+        let sign = if self.distance(&at(t)) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+
+        let mut escape_steps = 0;
+        while sign * self.distance(&at(t)) < 2.0 * hit_eps {
+            escape_steps += 1;
+            if escape_steps > MAX_ESCAPE_STEPS {
+                return None;
+            }
+            t += 2.0 * hit_eps;
+        }
+
+        for _ in 0..MAX_STEPS {
+            if t > t_exit {
+                return None;
+            }
+
+            let d = sign * self.distance(&at(t));
+            if d < hit_eps {
+                let n = self.normal(&at(t), hit_eps);
+                return Some(RayIntersection {
+                    t,
+                    is_inside: glm::dot(&n, &ray.direction) > 0.0,
+                    n,
+                });
+            }
+            t += d;
+        }
+
+        None
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let centered = |half: Vec3| Aabb {
+            min: -half,
+            max: half,
+        };
+
+        let aabb = match self {
+            Sdf::Sphere { radius } => centered(Vec3::repeat(*radius)),
+            Sdf::Cube { sizes } => centered(*sizes),
+            Sdf::Torus { major, minor } => centered(vec3(major + minor, *minor, major + minor)),
+            Sdf::Mandelbulb { .. } => centered(Vec3::repeat(MANDELBULB_RADIUS)),
+            Sdf::Translate { offset, sdf } => {
+                let aabb = sdf.bounds()?;
+                Aabb {
+                    min: aabb.min + offset,
+                    max: aabb.max + offset,
+                }
+            }
+            Sdf::Scale { factor, sdf } => {
+                let aabb = sdf.bounds()?;
+                Aabb {
+                    min: aabb.min * *factor,
+                    max: aabb.max * *factor,
+                }
+            }
+            Sdf::Union(a, b) => a.bounds()?.union(&b.bounds()?),
+            Sdf::Intersection(a, b) => {
+                let (a, b) = (a.bounds()?, b.bounds()?);
+                Aabb {
+                    min: a.min.sup(&b.min),
+                    max: a.max.inf(&b.max),
+                }
+            }
+            Sdf::Difference(a, _) => a.bounds()?,
+            Sdf::SmoothUnion { k, a, b } => {
+                let aabb = a.bounds()?.union(&b.bounds()?);
+                Aabb {
+                    min: aabb.min.add_scalar(-k),
+                    max: aabb.max.add_scalar(*k),
+                }
+            }
+        };
+
+        Some(aabb)
+    }
+
+    fn scene_lines(&self) -> Vec<String> {
+        let mut lines = vec!["SDF_BEGIN".to_owned()];
+        self.commands(&mut lines);
+        lines.push("SDF_END".to_owned());
+        lines
+    }
+
+    // Rather than in Scaled, where the hit distance would be in the
+    // unscaled units
+    fn scale(&mut self, factor: f32) -> bool {
+        let sdf = std::mem::replace(self, Sdf::sphere(0.0));
+        *self = sdf.scale(factor);
+        true
+    }
+}
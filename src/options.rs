@@ -0,0 +1,408 @@
+use glm::{vec3, Vec3};
+use log::LevelFilter;
+
+use crate::cubemap::CubemapLayout;
+use crate::environment::Gradient;
+use crate::exposure::{AutoExposure, ExposureCheck};
+use crate::image::Grading;
+use crate::probes::ProbeGrid;
+use crate::stereo::StereoLayout;
+use crate::tiles::TileOrder;
+use crate::traversal::{Accel, BvhBuildOptions};
+
+#[derive(Clone)]
+pub struct Options {
+    pub input: String,
+    pub output: String,
+
+    pub clay: bool,
+    pub nan_debug: bool,
+    pub threads: Option<usize>,
+    // keeps each render thread on one CPU, on Linux
+    pub pin_threads: bool,
+    pub jobs: Option<String>,
+    pub serve: Option<String>,
+    pub worker: Option<String>,
+    // seconds --serve waits without workers before it fails
+    pub serve_timeout: f32,
+    // tiles rendered first, locally and with --serve
+    pub tile_order: TileOrder,
+    pub accel: Accel,
+    pub ground: Option<f32>,
+    pub ground_checker: Option<f32>,
+    // sky turbidity, replaces the scene environment
+    pub sky: Option<f32>,
+    // zenith:horizon[:ground] colors, replaces the scene environment
+    pub gradient: Option<Gradient>,
+    // degrees
+    pub sun_elevation: f32,
+    pub sun_azimuth: f32,
+    pub grading: Grading,
+    // picks the exposure before tonemapping, --exposure is added to it.
+    // The pick is the auto_exposure of --report, and logged with --verbose
+    pub auto_exposure: Option<AutoExposure>,
+    // false color or zebra view instead of the graded image
+    pub exposure_check: Option<ExposureCheck>,
+    // 0 disables bloom
+    pub bloom: f32,
+    // pixels
+    pub bloom_radius: f32,
+    pub stereo: Option<StereoLayout>,
+    // distance between the eyes and to the point they converge at
+    pub interocular: f32,
+    pub convergence: f32,
+    // six 90 degree views from the point instead of the camera view
+    pub cubemap: Option<CubemapLayout>,
+    pub cubemap_point: Option<Vec3>,
+    // pixels along the side of a face, the image height by default
+    pub cubemap_size: Option<usize>,
+    // EXR file for the radiance with depth, normal and ID passes
+    pub aov: Option<String>,
+    // renders with the same seed are identical
+    pub seed: u64,
+    pub benchmark: bool,
+    // directory with the reference images of the golden test scenes
+    pub golden: Option<String>,
+    pub update_golden: bool,
+    // writes the loaded scene in the scene format instead of rendering
+    pub export: Option<String>,
+    // object names, when any are included all others are left out
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // file with edits of named objects, see overrides.rs
+    pub overrides: Option<String>,
+    // keyframe file, renders a frame sequence
+    pub camera_path: Option<String>,
+    pub fps: f32,
+    // encodes the camera path frames with ffmpeg, with the ffmpeg feature
+    pub video: Option<String>,
+    // directory for noisy and converged renders of random views with
+    // their features, see dataset.rs
+    pub dataset: Option<String>,
+    pub dataset_views: usize,
+    // samples per pixel of the noisy renders
+    pub dataset_noisy: usize,
+    // JSON file for SH light probes on a grid, see probes.rs
+    pub sh_probes: Option<String>,
+    pub probe_grid: ProbeGrid,
+    // rays per probe
+    pub probe_rays: usize,
+    // --verbose and --quiet change it from warnings
+    pub log_level: LevelFilter,
+    // JSON summary of the render
+    pub report: Option<String>,
+    // image point to focus on, 0..1 from the top left. The distance it
+    // picks is the focus_distance of --report, and logged with --verbose
+    pub focus_point: Option<(f32, f32)>,
+    // ends paths by throughput instead of the scene ray depth
+    pub min_throughput: Option<f32>,
+    // light samples at every diffuse hit, more than 1 traces shadow rays
+    // besides the path
+    pub light_samples: usize,
+    // least roughness of metal and glass after a diffuse bounce
+    pub regularize: Option<f32>,
+    // accuracy of the irradiance cache, without it there is none
+    pub irradiance_cache: Option<f32>,
+    // samples the previous camera path frame counts as where it is reused
+    pub reproject: Option<f32>,
+    // built BVHs are kept here and reused by later renders
+    pub cache_dir: Option<String>,
+    // rebuilds the BVH from the nodes a warm-up render visits
+    pub bvh_tune: bool,
+    pub bvh: BvhBuildOptions,
+    // camera rays use a BVH over the objects in the camera frustum
+    pub frustum_cull: bool,
+    // puffin recording of the render, with the puffin feature
+    pub profile: Option<String>,
+    // f16 film for very large renders, the AOV passes take half the memory
+    pub half_film: bool,
+    // traces the paths of a pass together instead of one by one
+    pub wavefront: bool,
+    // Row by row instead of in Z-order, to compare
+    pub scanline: bool,
+    // Replace the size of the scene relative to the one the epsilons are
+    // meant for, and the ulps new rays are moved off surfaces
+    pub eps_scale: Option<f32>,
+    pub ray_offset_ulps: Option<f32>,
+    // Every length of the scene (and the camera path) is multiplied by
+    // it, e.g. 0.01 for scenes in centimeters
+    pub scene_scale: f32,
+    // The command line these were parsed from, --serve sends it to workers
+    pub args: Vec<String>,
+}
+
+impl Options {
+    pub fn from_args() -> Self {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    // The command line without the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut positional = Vec::new();
+        let mut clay = false;
+        let mut nan_debug = false;
+        let mut threads = None;
+        let mut pin_threads = false;
+        let mut jobs = None;
+        let mut serve = None;
+        let mut worker = None;
+        let mut serve_timeout = 60.0;
+        let mut tile_order = TileOrder::ZOrder;
+        let mut accel = Accel::Linear;
+        let mut ground = None;
+        let mut ground_checker = None;
+        let mut gradient = None;
+        let mut sky = None;
+        let mut sun_elevation = 45.0;
+        let mut sun_azimuth = 0.0;
+        let mut grading = Grading::default();
+        let mut auto_exposure = None;
+        let mut exposure_check = None;
+        let mut bloom = 0.0;
+        let mut bloom_radius = 8.0;
+        let mut stereo = None;
+        let mut interocular = 0.065;
+        let mut convergence = 5.0;
+        let mut cubemap = None;
+        let mut cubemap_point = None;
+        let mut cubemap_size = None;
+        let mut aov = None;
+        let mut seed = 0;
+        let mut benchmark = false;
+        let mut golden = None;
+        let mut update_golden = false;
+        let mut export = None;
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        let mut overrides = None;
+        let mut camera_path = None;
+        let mut fps = 24.0;
+        let mut video = None;
+        let mut dataset = None;
+        let mut dataset_views = 16;
+        let mut dataset_noisy = 4;
+        let mut sh_probes = None;
+        let mut probe_grid = ProbeGrid([4; 3]);
+        let mut probe_rays = 1024;
+        let mut log_level = LevelFilter::Warn;
+        let mut report = None;
+        let mut focus_point = None;
+        let mut min_throughput = None;
+        let mut light_samples = 1;
+        let mut regularize = None;
+        let mut irradiance_cache = None;
+        let mut reproject = None;
+        let mut cache_dir = None;
+        let mut bvh_tune = false;
+        let mut frustum_cull = false;
+        let mut bvh = BvhBuildOptions::default();
+        let mut profile = None;
+        let mut half_film = false;
+        let mut wavefront = false;
+        let mut scanline = false;
+        let mut eps_scale = None;
+        let mut ray_offset_ulps = None;
+        let mut scene_scale = 1.0;
+
+        let all_args = args.into_iter().collect::<Vec<_>>();
+        let mut args = all_args.iter().cloned();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--clay" => clay = true,
+                "--nan-debug" => nan_debug = true,
+                "--benchmark" => benchmark = true,
+                "--golden" => golden = Some(parse_value(&arg, args.next())),
+                "--update-golden" => update_golden = true,
+                "--threads" => threads = Some(parse_value(&arg, args.next())),
+                "--pin-threads" => pin_threads = true,
+                "--jobs" => jobs = Some(parse_value(&arg, args.next())),
+                "--serve" => serve = Some(parse_value(&arg, args.next())),
+                "--worker" => worker = Some(parse_value(&arg, args.next())),
+                "--serve-timeout" => serve_timeout = parse_value(&arg, args.next()),
+                "--tile-order" => tile_order = parse_value(&arg, args.next()),
+                "--accel" => accel = parse_value(&arg, args.next()),
+                "--ground" => ground = Some(parse_value(&arg, args.next())),
+                "--ground-checker" => ground_checker = Some(parse_value(&arg, args.next())),
+                "--sky" => sky = Some(parse_value(&arg, args.next())),
+                "--gradient" => gradient = Some(parse_value(&arg, args.next())),
+                "--sun-elevation" => sun_elevation = parse_value(&arg, args.next()),
+                "--sun-azimuth" => sun_azimuth = parse_value(&arg, args.next()),
+                "--exposure" => grading.exposure = parse_value(&arg, args.next()),
+                "--auto-exposure" => auto_exposure = Some(parse_value(&arg, args.next())),
+                "--exposure-check" => exposure_check = Some(parse_value(&arg, args.next())),
+                "--temperature" => grading.temperature = parse_value(&arg, args.next()),
+                "--tint" => grading.tint = parse_value(&arg, args.next()),
+                "--contrast" => grading.contrast = parse_value(&arg, args.next()),
+                "--saturation" => grading.saturation = parse_value(&arg, args.next()),
+                "--bloom" => bloom = parse_value(&arg, args.next()),
+                "--bloom-radius" => bloom_radius = parse_value(&arg, args.next()),
+                "--stereo" => stereo = Some(parse_value(&arg, args.next())),
+                "--interocular" => interocular = parse_value(&arg, args.next()),
+                "--convergence" => convergence = parse_value(&arg, args.next()),
+                "--cubemap" => cubemap = Some(parse_value(&arg, args.next())),
+                "--cubemap-point" => {
+                    let (x, y, z) = parse_triple(&arg, args.next());
+                    cubemap_point = Some(vec3(x, y, z));
+                }
+                "--cubemap-size" => cubemap_size = Some(parse_value(&arg, args.next())),
+                "--aov" => aov = Some(parse_value(&arg, args.next())),
+                "--seed" => seed = parse_value(&arg, args.next()),
+                "--export" => export = Some(parse_value(&arg, args.next())),
+                "--include" => include.push(parse_value(&arg, args.next())),
+                "--exclude" => exclude.push(parse_value(&arg, args.next())),
+                "--overrides" => overrides = Some(parse_value(&arg, args.next())),
+                "--camera-path" => camera_path = Some(parse_value(&arg, args.next())),
+                "--fps" => fps = parse_value(&arg, args.next()),
+                "--dataset" => dataset = Some(parse_value(&arg, args.next())),
+                "--dataset-views" => dataset_views = parse_value(&arg, args.next()),
+                "--dataset-noisy" => dataset_noisy = parse_value(&arg, args.next()),
+                "--sh-probes" => sh_probes = Some(parse_value(&arg, args.next())),
+                "--probe-grid" => probe_grid = parse_value(&arg, args.next()),
+                "--probe-rays" => probe_rays = parse_value(&arg, args.next()),
+                "--video" => video = Some(parse_value(&arg, args.next())),
+                "--verbose" => log_level = LevelFilter::Debug,
+                "--quiet" => log_level = LevelFilter::Error,
+                "--report" => report = Some(parse_value(&arg, args.next())),
+                "--focus-point" => focus_point = Some(parse_pair(&arg, args.next())),
+                "--min-throughput" => min_throughput = Some(parse_value(&arg, args.next())),
+                "--light-samples" => light_samples = parse_value(&arg, args.next()),
+                "--regularize" => regularize = Some(parse_value(&arg, args.next())),
+                "--irradiance-cache" => irradiance_cache = Some(parse_value(&arg, args.next())),
+                "--reproject" => reproject = Some(parse_value(&arg, args.next())),
+                "--cache-dir" => cache_dir = Some(parse_value(&arg, args.next())),
+                "--bvh-tune" => bvh_tune = true,
+                "--frustum-cull" => frustum_cull = true,
+                "--bvh-max-leaf" => bvh.max_leaf = parse_value(&arg, args.next()),
+                "--bvh-costs" => {
+                    (bvh.traversal_cost, bvh.intersection_cost) = parse_pair(&arg, args.next())
+                }
+                "--bvh-bins" => bvh.split_attempts = parse_value(&arg, args.next()),
+                "--profile" => profile = Some(parse_value(&arg, args.next())),
+                "--half-film" => half_film = true,
+                "--wavefront" => wavefront = true,
+                "--scanline" => scanline = true,
+                "--eps-scale" => eps_scale = Some(parse_value(&arg, args.next())),
+                "--ray-offset-ulps" => ray_offset_ulps = Some(parse_value(&arg, args.next())),
+                "--scene-scale" => scene_scale = parse_value(&arg, args.next()),
+                _ if arg.starts_with("--") => panic!("unknown option: {}", arg),
+                _ => positional.push(arg),
+            }
+        }
+
+        bvh.check();
+        assert!(
+            serve_timeout >= 0.0,
+            "--serve-timeout is a number of seconds"
+        );
+        assert!(light_samples >= 1, "--light-samples must be at least 1");
+        assert!(dataset_noisy >= 1, "--dataset-noisy must be at least 1");
+        assert!(
+            regularize.is_none_or(|r: f32| (0.0..=1.0).contains(&r)),
+            "--regularize is a roughness in 0..1"
+        );
+
+        let mut positional = positional.into_iter();
+        Self {
+            input: positional.next().unwrap_or("assets/scene.txt".into()),
+            output: positional.next().unwrap_or("/tmp/out.ppm".into()),
+            clay,
+            nan_debug,
+            threads,
+            pin_threads,
+            jobs,
+            serve,
+            worker,
+            serve_timeout,
+            tile_order,
+            accel,
+            ground,
+            ground_checker,
+            gradient,
+            sky,
+            sun_elevation,
+            sun_azimuth,
+            grading,
+            auto_exposure,
+            exposure_check,
+            bloom,
+            bloom_radius,
+            stereo,
+            interocular,
+            convergence,
+            cubemap,
+            cubemap_point,
+            cubemap_size,
+            aov,
+            seed,
+            benchmark,
+            golden,
+            update_golden,
+            export,
+            include,
+            exclude,
+            overrides,
+            camera_path,
+            fps,
+            video,
+            dataset,
+            dataset_views,
+            dataset_noisy,
+            sh_probes,
+            probe_grid,
+            probe_rays,
+            log_level,
+            report,
+            focus_point,
+            min_throughput,
+            light_samples,
+            regularize,
+            irradiance_cache,
+            reproject,
+            cache_dir,
+            bvh_tune,
+            frustum_cull,
+            bvh,
+            profile,
+            half_film,
+            wavefront,
+            scanline,
+            eps_scale,
+            ray_offset_ulps,
+            scene_scale,
+            args: all_args,
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+    let Some(value) = value else {
+        panic!("missing value for {}", name);
+    };
+    match value.parse() {
+        Ok(value) => value,
+        Err(_) => panic!("invalid value for {}: {}", name, value),
+    }
+}
+
+// Two comma-separated values, as in --focus-point 0.5,0.4
+fn parse_pair<T: std::str::FromStr>(name: &str, value: Option<String>) -> (T, T) {
+    let value: String = parse_value(name, value);
+    let Some((a, b)) = value.split_once(',') else {
+        panic!("invalid value for {}: {}", name, value);
+    };
+    (
+        parse_value(name, Some(a.to_owned())),
+        parse_value(name, Some(b.to_owned())),
+    )
+}
+
+// Three comma-separated values, as in --cubemap-point 0,1.5,0
+fn parse_triple<T: std::str::FromStr>(name: &str, value: Option<String>) -> (T, T, T) {
+    let value: String = parse_value(name, value);
+    let values = value.split(',').collect::<Vec<_>>();
+    let [a, b, c] = values[..] else {
+        panic!("invalid value for {}: {}", name, value);
+    };
+    let parse = |x: &str| parse_value(name, Some(x.to_owned()));
+    (parse(a), parse(b), parse(c))
+}
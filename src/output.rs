@@ -0,0 +1,69 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::options::Options;
+use crate::scene::Scene;
+
+// Output paths may name the render, e.g. out_{scene}_{spp}spp_{w}x{h}.ppm:
+//   {scene}  stem of the input file
+//   {spp}    samples per pixel
+//   {w} {h}  image size
+//   {seed}   --seed
+//   {accel}  traversal backend
+pub fn expand_path(template: &str, scene: &Scene, options: &Options) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let value = match &rest[start + 1..start + end] {
+            "scene" => scene_name(&options.input).to_owned(),
+            "spp" => scene.n_samples.to_string(),
+            "w" => scene.image.width.to_string(),
+            "h" => scene.image.height.to_string(),
+            "seed" => options.seed.to_string(),
+            "accel" => options.accel.name().to_owned(),
+            name => panic!("unknown {{{}}} in output path {}", name, template),
+        };
+        result.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn scene_name(input: &str) -> &str {
+    let name = input.rsplit('/').next().unwrap();
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+// How the image was made, for the PPM comments and the EXR header, so
+// renders can be traced back to their settings
+pub fn render_metadata(
+    scene: &Scene,
+    options: &Options,
+    render: Duration,
+) -> Vec<(String, String)> {
+    let date = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let command = std::env::args().collect::<Vec<_>>().join(" ");
+    [
+        (
+            "software",
+            format!("raytracing {}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("commit", env!("GIT_COMMIT").to_owned()),
+        ("command", command),
+        ("input", options.input.clone()),
+        ("samples", scene.n_samples.to_string()),
+        ("seed", options.seed.to_string()),
+        ("accel", options.accel.name().to_owned()),
+        ("render_ms", format!("{:.0}", render.as_secs_f64() * 1000.0)),
+        // seconds since the Unix epoch
+        ("date", date.to_string()),
+    ]
+    .map(|(key, value)| (key.to_owned(), value))
+    .to_vec()
+}
@@ -0,0 +1,57 @@
+use glm::vec3;
+use na::{Unit, UnitQuaternion};
+
+use crate::scene::Scene;
+
+// Edits of named objects applied at load time, so a layout can be tweaked
+// without changing the scene file. One per line, in order:
+//   TRANSLATE name x y z
+//   ROTATE name x y z degrees  (around the object's position)
+//   SCALE name x y z           (ellipsoids and boxes)
+//   DUPLICATE name new_name
+//   INVISIBLE name CAMERA|SHADOW|INDIRECT...
+// Every object with the name is edited, names can't contain spaces here
+pub fn apply_override_file(scene: &mut Scene, path: &str) {
+    let source = std::fs::read_to_string(path).unwrap();
+
+    for line in source.lines() {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let Some(&command) = tokens.first() else {
+            continue;
+        };
+        let float = |i: usize| tokens[i].parse::<f32>().unwrap();
+
+        let name = tokens[1];
+        let indices = (0..scene.objects.len())
+            .filter(|&idx| scene.objects[idx].name.as_deref() == Some(name))
+            .collect::<Vec<_>>();
+        assert!(!indices.is_empty(), "no object named {}", name);
+
+        for idx in indices {
+            match command {
+                "TRANSLATE" => {
+                    scene.objects[idx].geometry.position += vec3(float(2), float(3), float(4));
+                }
+                "ROTATE" => {
+                    let axis = Unit::new_normalize(vec3(float(2), float(3), float(4)));
+                    let rotation = UnitQuaternion::from_axis_angle(&axis, float(5).to_radians());
+                    let geometry = &mut scene.objects[idx].geometry;
+                    geometry.rotation = rotation * geometry.rotation;
+                }
+                "SCALE" => scene.scale_object(idx, vec3(float(2), float(3), float(4))),
+                "DUPLICATE" => {
+                    let copy = scene.duplicate_object(idx);
+                    scene.objects[copy].name = Some(tokens[2].to_owned());
+                }
+                "INVISIBLE" => {
+                    for kind in &tokens[2..] {
+                        scene.objects[idx].visibility.hide_from(kind);
+                    }
+                }
+                _ => panic!("unknown override: {}", command),
+            }
+        }
+    }
+
+    scene.update_lights();
+}
@@ -1,201 +1,407 @@
 use glm::{vec3, Vec3};
-use itertools::izip;
-use na::{Matrix3, UnitQuaternion};
-use rand::rngs::ThreadRng;
+use na::UnitQuaternion;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-use crate::camera::Camera;
-use crate::image::*;
+use crate::environment::{Environment, Gradient, Sky};
+use crate::image::Image;
 use crate::objects::*;
+use crate::pbrt::{is_pbrt, parse_pbrt};
+use crate::points::load_points;
+use crate::scene::{Scene, SceneBuilder};
 
-pub struct Scene {
-    pub ray_depth: usize,
-    pub n_samples: usize,
+type CsgOperands = Vec<PositionedFigure<Box<dyn Solid>>>;
 
-    pub image: Image,
-    pub background_color: Vec3,
-    pub camera: Camera,
-
-    pub objects: Vec<Object<Box<dyn Geometry>>>,
-    pub lights: Vec<Box<dyn LightSource>>,
+pub fn parse_scene(path: &str) -> Scene {
+    if is_pbrt(path) {
+        return parse_pbrt(path);
+    }
 
-    pub generator: ThreadRng,
+    let file = File::open(path).unwrap();
+    parse_scene_from(BufReader::new(file))
 }
 
-#[derive(Default)]
-pub struct SceneParser {
-    image_width: Option<usize>,
-    image_height: Option<usize>,
-    background_color: Option<Vec3>,
-
-    camera_position: Option<Vec3>,
-    camera_axis: [Option<Vec3>; 3],
-    camera_fov_x: Option<f32>,
-
-    objects: Vec<Object<Box<dyn Geometry>>>,
-    figure_types: Vec<FigureType>,
-    // mb_lights: Vec<(Box<dyn LightSource>, usize)>,
-    ray_depth: Option<usize>,
-    n_samples: Option<usize>,
-}
+// Commands that read the file named by their first argument
+const FILE_COMMANDS: [&str; 2] = ["CAMERA_APERTURE_MASK", "POINT_CLOUD"];
 
-enum FigureType {
-    Plane(Vec3),
-    Parallelipiped(Vec3),
-    Ellipsoid(Vec3),
+// The files a scene source reads besides itself
+pub fn file_paths(source: &str) -> Vec<String> {
+    let mut paths = Vec::new();
+    replace_file_paths(source, |path| {
+        paths.push(path.to_owned());
+        path.to_owned()
+    });
+    paths
 }
 
-impl SceneParser {
-    pub fn create_scene(self) -> Scene {
-        let image = Image::new(self.image_width.unwrap(), self.image_height.unwrap());
-
-        let tg_fov_x = (self.camera_fov_x.unwrap() / 2.0).tan();
-        let aspect = image.height as f32 / image.width as f32;
-        let tg_fov_y = aspect * tg_fov_x;
-        let axis = self
-            .camera_axis
-            .into_iter()
-            .map(Option::unwrap)
-            .collect::<Vec<_>>();
-
-        let camera = Camera {
-            position: self.camera_position.unwrap(),
-            axis: Matrix3::from_columns(&axis),
-            tg_fov_x,
-            tg_fov_y,
-        };
-
-        let lights = izip!(self.figure_types.into_iter(), self.objects.iter())
-            .filter_map(|(fig_type, obj)| {
-                if glm::length2(&obj.emission) == 0.0 {
-                    return None;
-                }
-                match fig_type {
-                    FigureType::Plane(_) => None,
-                    FigureType::Ellipsoid(radiuses) => Some(Box::new(PositionedFigure {
-                        figure: Ellipsoid { radiuses },
-                        position: obj.geometry.position,
-                        rotation: obj.geometry.rotation,
-                    })
-                        as Box<dyn LightSource>),
-                    FigureType::Parallelipiped(sizes) => Some(Box::new(PositionedFigure {
-                        figure: Parallelipiped { sizes },
-                        position: obj.geometry.position,
-                        rotation: obj.geometry.rotation,
-                    })),
-                }
-            })
-            .collect::<Vec<_>>();
-
-        Scene {
-            ray_depth: self.ray_depth.unwrap(),
-            n_samples: self.n_samples.unwrap(),
-            image,
-            background_color: self.background_color.unwrap(),
-            camera,
-            objects: self.objects,
-            lights,
-            generator: rand::thread_rng(),
-        }
-    }
+// The source with every file it reads renamed, e.g. to a local copy
+pub fn replace_file_paths(source: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    source
+        .lines()
+        .map(|line| {
+            let mut tokens = line.split(' ').map(str::to_owned).collect::<Vec<_>>();
+            if tokens.len() > 1 && FILE_COMMANDS.contains(&tokens[0].as_str()) {
+                tokens[1] = replace(&tokens[1]);
+            }
+            tokens.join(" ") + "\n"
+        })
+        .collect()
 }
 
-pub fn parse_scene(path: &str) -> Scene {
-    let mut parser = SceneParser::default();
+pub fn parse_scene_from<R: BufRead>(reader: R) -> Scene {
+    let mut builder = SceneBuilder::default();
+    let mut csg_stack: Vec<(CsgOp, CsgOperands)> = Vec::new();
+    let mut sdf_stack: Option<Vec<Sdf>> = None;
 
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
     for line in reader.lines() {
         let tokens = line.as_ref().unwrap().split(' ').collect::<Vec<_>>();
 
+        if let Some(stack) = &mut sdf_stack {
+            if tokens[0] == "SDF_END" {
+                let mut stack = sdf_stack.take().unwrap();
+                assert!(stack.len() == 1, "SDF block must leave exactly one shape");
+                builder.add_sdf(stack.pop().unwrap());
+            } else {
+                parse_sdf_command(&tokens, stack);
+            }
+            continue;
+        }
+
+        if let Some((_, operands)) = csg_stack.last_mut() {
+            if parse_csg_operand(&tokens, operands) {
+                continue;
+            }
+        }
+
         match tokens[0] {
             "DIMENSIONS" => {
-                parser.image_width = Some(tokens[1].parse::<usize>().unwrap());
-                parser.image_height = Some(tokens[2].parse::<usize>().unwrap());
+                let width = tokens[1].parse::<usize>().unwrap();
+                let height = tokens[2].parse::<usize>().unwrap();
+                builder.set_dimensions(width, height);
             }
             "RAY_DEPTH" => {
-                parser.ray_depth = Some(tokens[1].parse::<usize>().unwrap());
+                builder.set_ray_depth(tokens[1].parse::<usize>().unwrap());
+            }
+            "RAY_DEPTH_DIFFUSE" => {
+                builder.max_bounces().diffuse = tokens[1].parse::<usize>().unwrap();
+            }
+            "RAY_DEPTH_GLOSSY" => {
+                builder.max_bounces().glossy = tokens[1].parse::<usize>().unwrap();
+            }
+            "RAY_DEPTH_TRANSMISSION" => {
+                builder.max_bounces().transmission = tokens[1].parse::<usize>().unwrap();
             }
             "SAMPLES" => {
-                parser.n_samples = Some(tokens[1].parse::<usize>().unwrap());
+                builder.set_samples(tokens[1].parse::<usize>().unwrap());
+            }
+            "BG_COLOR" => {
+                builder.set_environment(Environment::Color(parse_vec3(&tokens[1..])));
+            }
+            "BG_GRADIENT" => {
+                let zenith = parse_vec3(&tokens[1..]);
+                let horizon = parse_vec3(&tokens[4..]);
+                // the ground is optional
+                let ground = if tokens.len() > 7 {
+                    parse_vec3(&tokens[7..])
+                } else {
+                    horizon
+                };
+                builder.set_environment(Environment::Gradient(Gradient {
+                    zenith,
+                    horizon,
+                    ground,
+                }));
+            }
+            "SKY" => {
+                let sun_direction = parse_vec3(&tokens[1..]);
+                let turbidity = tokens[4].parse::<f32>().unwrap();
+                let intensity = tokens[5].parse::<f32>().unwrap();
+                builder.set_environment(Environment::Sky(Sky::new(
+                    sun_direction,
+                    turbidity,
+                    intensity,
+                )));
             }
-            "BG_COLOR" => parser.background_color = Some(parse_vec3(&tokens[1..])),
             "CAMERA_POSITION" => {
-                parser.camera_position = Some(parse_vec3(&tokens[1..]));
+                builder.set_camera_position(parse_vec3(&tokens[1..]));
             }
             "CAMERA_RIGHT" => {
-                parser.camera_axis[0] = Some(parse_vec3(&tokens[1..]));
+                builder.set_camera_right(parse_vec3(&tokens[1..]));
             }
             "CAMERA_UP" => {
-                parser.camera_axis[1] = Some(parse_vec3(&tokens[1..]));
+                builder.set_camera_up(parse_vec3(&tokens[1..]));
             }
             "CAMERA_FORWARD" => {
-                parser.camera_axis[2] = Some(parse_vec3(&tokens[1..]));
+                builder.set_camera_forward(parse_vec3(&tokens[1..]));
             }
             "CAMERA_FOV_X" => {
-                parser.camera_fov_x = Some(tokens[1].parse::<f32>().unwrap());
+                builder.set_camera_fov_x(tokens[1].parse::<f32>().unwrap());
+            }
+            "CAMERA_DISTORTION" => {
+                builder.set_camera_distortion(tokens[1].parse::<f32>().unwrap());
+            }
+            "CAMERA_VIGNETTING" => {
+                builder.set_camera_vignetting(tokens[1].parse::<f32>().unwrap());
+            }
+            "CAMERA_CHROMATIC_ABERRATION" => {
+                builder.set_camera_chromatic_aberration(tokens[1].parse::<f32>().unwrap());
+            }
+            "CAMERA_APERTURE" => {
+                let radius = tokens[1].parse::<f32>().unwrap();
+                let focus_distance = tokens[2].parse::<f32>().unwrap();
+                builder.set_camera_aperture(radius, focus_distance);
+            }
+            "CAMERA_APERTURE_BLADES" => {
+                let blades = tokens[1].parse::<usize>().unwrap();
+                let rotation = tokens.get(2).map_or(0.0, |r| r.parse::<f32>().unwrap());
+                builder.set_camera_blades(blades, rotation);
+            }
+            "CAMERA_APERTURE_MASK" => {
+                builder.set_camera_aperture_mask(Image::read(tokens[1]));
+            }
+            "CAMERA_CLIP" => {
+                let near = tokens[1].parse::<f32>().unwrap();
+                let far = tokens[2].parse::<f32>().unwrap();
+                builder.set_camera_clip(near, far);
+            }
+            // A point on the plane and the normal towards the part cut away
+            "CLIP_PLANE" => {
+                builder.add_clip_plane(parse_vec3(&tokens[1..]), parse_vec3(&tokens[4..]));
             }
             "NEW_PRIMITIVE" => {}
             "PLANE" => {
-                let normal = parse_vec3(&tokens[1..]);
-                parser.objects.push(Object::new(Box::new(Plane { normal })));
-                parser.figure_types.push(FigureType::Plane(normal));
+                builder.add_plane(parse_vec3(&tokens[1..]));
             }
             "ELLIPSOID" => {
-                let radiuses = parse_vec3(&tokens[1..]);
-                parser
-                    .objects
-                    .push(Object::new(Box::new(Ellipsoid { radiuses })));
-                parser.figure_types.push(FigureType::Ellipsoid(radiuses));
+                builder.add_ellipsoid(parse_vec3(&tokens[1..]));
             }
             "BOX" => {
-                let sizes = parse_vec3(&tokens[1..]);
-                parser
-                    .objects
-                    .push(Object::new(Box::new(Parallelipiped { sizes })));
-                parser.figure_types.push(FigureType::Parallelipiped(sizes));
+                builder.add_box(parse_vec3(&tokens[1..]));
+            }
+            "CSG_BEGIN" => {
+                let op = match tokens[1] {
+                    "UNION" => CsgOp::Union,
+                    "INTERSECTION" => CsgOp::Intersection,
+                    "DIFFERENCE" => CsgOp::Difference,
+                    op => panic!("unknown CSG operation: {}", op),
+                };
+                csg_stack.push((op, Vec::new()));
+            }
+            "CSG_END" => {
+                let (op, operands) = csg_stack.pop().unwrap();
+                let operands = operands
+                    .into_iter()
+                    .map(|operand| Box::new(operand) as Box<dyn Solid>)
+                    .collect();
+                let csg = Csg::from_operands(op, operands);
+
+                match csg_stack.last_mut() {
+                    Some((_, outer)) => outer.push(PositionedFigure::new(Box::new(csg))),
+                    None => {
+                        builder.add_csg(csg);
+                    }
+                }
+            }
+            "SDF_BEGIN" => {
+                sdf_stack = Some(Vec::new());
+            }
+            "PORTAL" => {
+                let sizes = glm::vec2(
+                    tokens[1].parse::<f32>().unwrap(),
+                    tokens[2].parse::<f32>().unwrap(),
+                );
+                let position = parse_vec3(&tokens[3..]);
+                let rotation = if tokens.len() > 6 {
+                    parse_quaternion(&tokens[6..])
+                } else {
+                    UnitQuaternion::identity()
+                };
+                builder.add_portal(sizes, position, rotation);
+            }
+            "POINT_CLOUD" => {
+                let points = load_points(tokens[1]);
+                let radius = tokens[2].parse::<f32>().unwrap();
+                builder.add_point_cloud(&points, radius);
             }
             "POSITION" => {
                 let position = parse_vec3(&tokens[1..]);
-                let idx = parser.objects.len() - 1;
-                parser.objects[idx].geometry.position = position;
+                builder.last_object().geometry.position = position;
             }
             "ROTATION" => {
                 let rotation = parse_quaternion(&tokens[1..]);
-                let idx = parser.objects.len() - 1;
-                parser.objects[idx].geometry.rotation = rotation;
+                builder.last_object().geometry.rotation = rotation;
             }
             "COLOR" => {
                 let color = parse_vec3(&tokens[1..]);
-                let idx = parser.objects.len() - 1;
-                parser.objects[idx].color = color;
+                builder.last_object().color = color;
+            }
+            "CHECKER" => {
+                let color = parse_vec3(&tokens[1..]);
+                let size = tokens[4].parse::<f32>().unwrap();
+                builder.last_object().checker = Some(Checker { color, size });
             }
             "EMISSION" => {
                 let color = parse_vec3(&tokens[1..]);
-                let idx = parser.objects.len() - 1;
-                parser.objects[idx].emission = color;
+                builder.last_object().emission = color;
+            }
+            "SINGLE_SIDED" => {
+                builder.last_object().single_sided = true;
+            }
+            // Names of the objects the emitter lights or is shadowed by,
+            // they can't contain spaces here
+            "LIGHT_LINK" => {
+                let names = tokens[1..].iter().map(|name| name.to_string()).collect();
+                builder.last_object().links.illuminates = Some(names);
+            }
+            "SHADOW_LINK" => {
+                let names = tokens[1..].iter().map(|name| name.to_string()).collect();
+                builder.last_object().links.shadowed_by = Some(names);
+            }
+            // INVISIBLE CAMERA|SHADOW|INDIRECT...
+            "INVISIBLE" => {
+                for kind in &tokens[1..] {
+                    builder.last_object().visibility.hide_from(kind);
+                }
+            }
+            "NAME" => {
+                builder.last_object().name = Some(tokens[1..].join(" "));
+            }
+            "HIDDEN" => {
+                builder.last_object().hidden = true;
             }
             "METALLIC" => {
-                let idx = parser.objects.len() - 1;
-                parser.objects[idx].material = Material::Metallic;
+                builder.last_object().material = Material::Metallic { fresnel: None };
             }
             "DIELECTRIC" => {
-                let idx = parser.objects.len() - 1;
-                parser.objects[idx].material = Material::Dielectric { ior: 1.0 };
+                builder.last_object().material = Material::Dielectric { ior: 1.0 };
+            }
+            "SHADOW_CATCHER" => {
+                builder.last_object().material = Material::ShadowCatcher;
             }
             "IOR" => {
                 let ior = tokens[1].parse::<f32>().unwrap();
-                let idx = parser.objects.len() - 1;
-                if let Material::Dielectric { .. } = parser.objects[idx].material {
-                    parser.objects[idx].material = Material::Dielectric { ior };
+                if let Material::Dielectric { .. } = builder.last_object().material {
+                    builder.last_object().material = Material::Dielectric { ior };
+                }
+            }
+            "ROUGHNESS" => {
+                builder.last_object().roughness = tokens[1].parse::<f32>().unwrap();
+            }
+            // CONDUCTOR gold|silver|copper|aluminum
+            "CONDUCTOR" => {
+                let Some(fresnel) = Fresnel::preset(tokens[1]) else {
+                    panic!("unknown conductor: {}", tokens[1]);
+                };
+                set_fresnel(builder.last_object(), fresnel);
+            }
+            // CONDUCTOR_IOR n_r n_g n_b k_r k_g k_b
+            "CONDUCTOR_IOR" => {
+                let eta = parse_vec3(&tokens[1..]);
+                let k = parse_vec3(&tokens[4..]);
+                set_fresnel(builder.last_object(), Fresnel::Conductor { eta, k });
+            }
+            "F82_TINT" => {
+                let tint = parse_vec3(&tokens[1..]);
+                set_fresnel(builder.last_object(), Fresnel::F82 { tint });
+            }
+            // MIX DIFFUSE|METALLIC|DIELECTRIC|SHADOW_CATCHER r g b factor [ior]
+            "MIX" => {
+                let material = match tokens[1] {
+                    "DIFFUSE" => Material::Diffuse,
+                    "METALLIC" => Material::Metallic { fresnel: None },
+                    "DIELECTRIC" => Material::Dielectric {
+                        ior: tokens[6].parse::<f32>().unwrap(),
+                    },
+                    "SHADOW_CATCHER" => Material::ShadowCatcher,
+                    kind => panic!("unknown mix material: {}", kind),
+                };
+                let color = parse_vec3(&tokens[2..]);
+                let factor = tokens[5].parse::<f32>().unwrap();
+                builder.last_object().mix = Some(Mix {
+                    material,
+                    color,
+                    factor,
+                    mask_size: None,
+                });
+            }
+            "MIX_MASK" => {
+                let size = tokens[1].parse::<f32>().unwrap();
+                if let Some(mix) = &mut builder.last_object().mix {
+                    mix.mask_size = Some(size);
                 }
             }
             _ => {}
         }
     }
 
-    parser.create_scene()
+    builder.build()
+}
+
+// Figures and their transforms inside CSG_BEGIN/CSG_END become operands
+fn parse_csg_operand(
+    tokens: &[&str],
+    operands: &mut Vec<PositionedFigure<Box<dyn Solid>>>,
+) -> bool {
+    let solid: Box<dyn Solid> = match tokens[0] {
+        "PLANE" => Box::new(Plane {
+            normal: parse_vec3(&tokens[1..]),
+        }),
+        "ELLIPSOID" => Box::new(Ellipsoid {
+            radiuses: parse_vec3(&tokens[1..]),
+        }),
+        "BOX" => Box::new(Parallelipiped {
+            sizes: parse_vec3(&tokens[1..]),
+        }),
+        "POSITION" => {
+            operands.last_mut().unwrap().position = parse_vec3(&tokens[1..]);
+            return true;
+        }
+        "ROTATION" => {
+            operands.last_mut().unwrap().rotation = parse_quaternion(&tokens[1..]);
+            return true;
+        }
+        _ => return false,
+    };
+
+    operands.push(PositionedFigure::new(solid));
+    true
+}
+
+// Commands inside SDF_BEGIN/SDF_END work on a stack: shapes are pushed,
+// TRANSLATE and SCALE change the top one, binary operations pop two shapes
+fn parse_sdf_command(tokens: &[&str], stack: &mut Vec<Sdf>) {
+    let float = |i: usize| tokens[i].parse::<f32>().unwrap();
+
+    let sdf = match tokens[0] {
+        "SPHERE" => Sdf::sphere(float(1)),
+        "CUBE" => Sdf::cube(parse_vec3(&tokens[1..])),
+        "TORUS" => Sdf::torus(float(1), float(2)),
+        "MANDELBULB" => Sdf::mandelbulb(float(1), tokens[2].parse::<usize>().unwrap()),
+        "TRANSLATE" => stack.pop().unwrap().translate(parse_vec3(&tokens[1..])),
+        "SCALE" => stack.pop().unwrap().scale(float(1)),
+        "UNION" | "INTERSECTION" | "DIFFERENCE" | "SMOOTH_UNION" => {
+            let b = stack.pop().unwrap();
+            let a = stack.pop().unwrap();
+            match tokens[0] {
+                "UNION" => a.union(b),
+                "INTERSECTION" => a.intersection(b),
+                "DIFFERENCE" => a.difference(b),
+                _ => a.smooth_union(b, float(1)),
+            }
+        }
+        _ => return,
+    };
+
+    stack.push(sdf);
+}
+
+// Like IOR, only for objects already marked METALLIC
+fn set_fresnel(obj: &mut Object<Box<dyn Geometry>>, fresnel: Fresnel) {
+    if let Material::Metallic { .. } = obj.material {
+        obj.material = Material::Metallic {
+            fresnel: Some(fresnel),
+        };
+    }
 }
 
 fn parse_vec3(tokens: &[&str]) -> Vec3 {
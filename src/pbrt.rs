@@ -0,0 +1,521 @@
+use glm::{vec3, Mat4, Vec3};
+use na::{Matrix3, Rotation3, UnitQuaternion};
+use std::path::Path;
+
+use crate::environment::Environment;
+use crate::objects::{Fresnel, Material, Mix};
+use crate::scene::{Scene, SceneBuilder};
+
+// Subset of the pbrt-v3 format: perspective camera, film resolution,
+// sample count, path depth, transforms, attribute blocks, matte, plastic,
+// metal, mirror, glass and mix materials (also named), spheres, diffuse area
+// lights and a constant infinite light. Everything else is skipped with
+// a warning
+pub fn parse_pbrt(path: &str) -> Scene {
+    let source = std::fs::read_to_string(path).unwrap();
+    let tokens = tokenize(&source);
+    let directives = split_directives(&tokens);
+
+    let mut builder = SceneBuilder::default();
+    let mut settings = Settings::default();
+    let mut state = State::default();
+    let mut stack: Vec<(State, bool)> = Vec::new();
+    let mut named_materials: Vec<(String, Surface)> = Vec::new();
+
+    for (name, args) in directives {
+        let numbers = || {
+            args.iter()
+                .filter_map(|token| match token {
+                    Token::Number(x) => Some(*x),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        match name {
+            "LookAt" => {
+                let v = numbers();
+                let look_at = glm::look_at_lh(
+                    &vec3(v[0], v[1], v[2]),
+                    &vec3(v[3], v[4], v[5]),
+                    &vec3(v[6], v[7], v[8]),
+                );
+                state.ctm *= look_at;
+            }
+            "Translate" => {
+                let v = numbers();
+                state.ctm = glm::translate(&state.ctm, &vec3(v[0], v[1], v[2]));
+            }
+            "Rotate" => {
+                let v = numbers();
+                let axis = vec3(v[1], v[2], v[3]);
+                state.ctm = glm::rotate(&state.ctm, v[0].to_radians(), &axis);
+            }
+            "Scale" => {
+                let v = numbers();
+                state.ctm = glm::scale(&state.ctm, &vec3(v[0], v[1], v[2]));
+            }
+            "Identity" => state.ctm = Mat4::identity(),
+            // pbrt matrices are written column by column
+            "Transform" => state.ctm = Mat4::from_column_slice(&numbers()),
+            "ConcatTransform" => state.ctm *= Mat4::from_column_slice(&numbers()),
+            "Camera" => {
+                let params = Params::new(&args);
+                settings.fov = params.float("fov").unwrap_or(90.0);
+                settings.camera = state.ctm.try_inverse().unwrap();
+            }
+            "Film" => {
+                let params = Params::new(&args);
+                settings.width = params.float("xresolution").map_or(640, |x| x as usize);
+                settings.height = params.float("yresolution").map_or(480, |y| y as usize);
+            }
+            "Sampler" => {
+                let params = Params::new(&args);
+                settings.samples = params.float("pixelsamples").map_or(16, |n| n as usize);
+            }
+            "Integrator" => {
+                let params = Params::new(&args);
+                settings.max_depth = params.float("maxdepth").map_or(5, |n| n as usize);
+            }
+            "WorldBegin" => state.ctm = Mat4::identity(),
+            "AttributeBegin" => stack.push((state.clone(), false)),
+            "TransformBegin" => stack.push((state.clone(), true)),
+            "AttributeEnd" | "TransformEnd" => {
+                let (saved, transform_only) = stack.pop().unwrap();
+                if transform_only {
+                    state.ctm = saved.ctm;
+                } else {
+                    state = saved;
+                }
+            }
+            "Material" => {
+                let params = Params::new(&args);
+                state.surface = surface(params.kind(), &params, &named_materials);
+            }
+            "MakeNamedMaterial" => {
+                let params = Params::new(&args);
+                let kind = params.string("type").unwrap_or("matte");
+                let surface = surface(kind, &params, &named_materials);
+                named_materials.push((params.kind().to_owned(), surface));
+            }
+            "NamedMaterial" => {
+                let params = Params::new(&args);
+                state.surface = named_surface(params.kind(), &named_materials).clone();
+            }
+            "AreaLightSource" => {
+                let params = Params::new(&args);
+                let scale = params.rgb("scale").unwrap_or(Vec3::repeat(1.0));
+                let emission = params.rgb("L").unwrap_or(Vec3::repeat(1.0));
+                state.emission = emission.component_mul(&scale);
+                // pbrt lights emit from the front only by default
+                state.single_sided = !params.bool("twosided").unwrap_or(false);
+            }
+            "LightSource" => {
+                let params = Params::new(&args);
+                match params.kind() {
+                    "infinite" => {
+                        let scale = params.rgb("scale").unwrap_or(Vec3::repeat(1.0));
+                        let radiance = params.rgb("L").unwrap_or(Vec3::repeat(1.0));
+                        settings.environment = radiance.component_mul(&scale);
+                    }
+                    kind => log::warn!("pbrt: skipping unsupported light {}", kind),
+                }
+            }
+            "Shape" => {
+                let params = Params::new(&args);
+                if params.kind() != "sphere" {
+                    log::warn!("pbrt: skipping unsupported shape {}", params.kind());
+                    continue;
+                }
+
+                // Shears are not representable and get lost here
+                let radius = params.float("radius").unwrap_or(1.0);
+                let linear = state.ctm.fixed_view::<3, 3>(0, 0).into_owned();
+                let scale = Vec3::from_fn(|i, _| linear.column(i).norm());
+                let rotation = Matrix3::from_columns(&[
+                    linear.column(0) / scale.x,
+                    linear.column(1) / scale.y,
+                    linear.column(2) / scale.z,
+                ]);
+
+                let obj = builder.add_ellipsoid(scale * radius);
+                obj.geometry.position = state.ctm.column(3).xyz();
+                obj.geometry.rotation =
+                    UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix(&rotation));
+                obj.material = state.surface.material;
+                obj.color = state.surface.color;
+                obj.mix = state.surface.mix.clone();
+                obj.roughness = state.surface.roughness;
+                obj.emission = state.emission;
+                obj.single_sided = state.single_sided;
+            }
+            "WorldEnd" => {}
+            _ => log::warn!("pbrt: skipping unsupported directive {}", name),
+        }
+    }
+
+    settings.apply(&mut builder);
+    builder.build()
+}
+
+pub fn is_pbrt(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "pbrt")
+}
+
+#[derive(Clone)]
+struct Surface {
+    material: Material,
+    color: Vec3,
+    mix: Option<Mix>,
+    roughness: f32,
+}
+
+#[derive(Clone)]
+struct State {
+    ctm: Mat4,
+    surface: Surface,
+    emission: Vec3,
+    single_sided: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            ctm: Mat4::identity(),
+            surface: Surface {
+                material: Material::Diffuse,
+                color: Vec3::repeat(0.5),
+                mix: None,
+                roughness: 0.0,
+            },
+            emission: Vec3::zeros(),
+            single_sided: false,
+        }
+    }
+}
+
+struct Settings {
+    width: usize,
+    height: usize,
+    samples: usize,
+    max_depth: usize,
+    fov: f32,
+    // camera to world
+    camera: Mat4,
+    environment: Vec3,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            samples: 16,
+            max_depth: 5,
+            fov: 90.0,
+            camera: Mat4::identity(),
+            environment: Vec3::zeros(),
+        }
+    }
+}
+
+impl Settings {
+    fn apply(&self, builder: &mut SceneBuilder) {
+        // pbrt's fov is for the shorter side, and its depth counts bounces
+        let tg_fov = (self.fov.to_radians() / 2.0).tan();
+        let aspect = self.width as f32 / self.height as f32;
+        let fov_x = if aspect > 1.0 {
+            2.0 * (tg_fov * aspect).atan()
+        } else {
+            self.fov.to_radians()
+        };
+
+        let axis = |i: usize| self.camera.column(i).xyz().normalize();
+        builder
+            .set_dimensions(self.width, self.height)
+            .set_samples(self.samples)
+            .set_ray_depth(self.max_depth + 1)
+            .set_environment(Environment::Color(self.environment))
+            .set_camera(
+                self.camera.column(3).xyz(),
+                axis(0),
+                axis(1),
+                axis(2),
+                fov_x,
+            );
+    }
+}
+
+fn surface(kind: &str, params: &Params, named: &[(String, Surface)]) -> Surface {
+    if kind != "mix" {
+        let (material, color) = material(kind, params);
+        return Surface {
+            material,
+            color,
+            mix: None,
+            roughness: roughness(params),
+        };
+    }
+
+    // pbrt-v3 names the two materials separately, v4 in a list. The
+    // amount is the weight of the second one
+    let names = match params.strings("materials") {
+        Some(names) => names,
+        None => ["namedmaterial1", "namedmaterial2"]
+            .iter()
+            .map(|name| params.string(name).unwrap_or(""))
+            .collect(),
+    };
+    let [first, second] = names[..] else {
+        panic!("pbrt: mix needs two materials");
+    };
+    let amount = params
+        .rgb("amount")
+        .map_or(0.5, |amount| amount.sum() / 3.0);
+
+    let second = named_surface(second, named);
+    Surface {
+        mix: Some(Mix {
+            material: second.material,
+            color: second.color,
+            factor: amount,
+            mask_size: None,
+        }),
+        ..named_surface(first, named).clone()
+    }
+}
+
+// pbrt takes the microfacet alpha, after a square root unless
+// remaproughness is off. Ours is the square root of alpha
+fn roughness(params: &Params) -> f32 {
+    let Some(roughness) = params.float("roughness").or(params.float("uroughness")) else {
+        return 0.0;
+    };
+    if params.bool("remaproughness") == Some(false) {
+        roughness.sqrt()
+    } else {
+        roughness.sqrt().sqrt()
+    }
+}
+
+// Named spectra of the measured metals map to the presets, both pbrt
+// versions default to copper
+fn conductor(params: &Params) -> Fresnel {
+    if params.get("reflectance").is_some() {
+        return Fresnel::F82 {
+            tint: Vec3::repeat(1.0),
+        };
+    }
+    if let (Some(eta), Some(k)) = (params.rgb("eta"), params.rgb("k")) {
+        return Fresnel::Conductor { eta, k };
+    }
+    let preset = match params.string("eta") {
+        Some("metal-Au-eta") => "gold",
+        Some("metal-Ag-eta") => "silver",
+        Some("metal-Al-eta") => "aluminum",
+        Some("metal-Cu-eta") | None => "copper",
+        Some(name) => {
+            log::warn!("pbrt: replacing unsupported spectrum {} with copper", name);
+            "copper"
+        }
+    };
+    Fresnel::preset(preset).unwrap()
+}
+
+fn named_surface<'a>(name: &str, named: &'a [(String, Surface)]) -> &'a Surface {
+    let Some((_, surface)) = named.iter().rev().find(|(n, _)| n == name) else {
+        panic!("unknown material: {}", name);
+    };
+    surface
+}
+
+fn material(kind: &str, params: &Params) -> (Material, Vec3) {
+    match kind {
+        "matte" | "plastic" | "substrate" => {
+            let color = params.rgb("Kd").unwrap_or(Vec3::repeat(0.5));
+            (Material::Diffuse, color)
+        }
+        "mirror" => {
+            let color = params.rgb("Kr").unwrap_or(Vec3::repeat(0.9));
+            (Material::Metallic { fresnel: None }, color)
+        }
+        "metal" | "conductor" => {
+            let fresnel = conductor(params);
+            let color = params.rgb("reflectance").unwrap_or(Vec3::repeat(1.0));
+            (
+                Material::Metallic {
+                    fresnel: Some(fresnel),
+                },
+                color,
+            )
+        }
+        "glass" => {
+            let ior = params.float("eta").or(params.float("index")).unwrap_or(1.5);
+            let color = params.rgb("Kt").unwrap_or(Vec3::repeat(1.0));
+            (Material::Dielectric { ior }, color)
+        }
+        _ => {
+            log::warn!("pbrt: replacing unsupported material {} with matte", kind);
+            (Material::Diffuse, Vec3::repeat(0.5))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(f32),
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            while chars.next().is_some_and(|c| c != '\n') {}
+        } else if c == '[' || c == ']' {
+            chars.next();
+            tokens.push(if c == '[' { Token::Open } else { Token::Close });
+        } else if c == '"' {
+            chars.next();
+            let string = chars.by_ref().take_while(|&c| c != '"').collect();
+            tokens.push(Token::String(string));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || "[]\"#".contains(c) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(match word.parse::<f32>() {
+                Ok(x) => Token::Number(x),
+                Err(_) => Token::Identifier(word),
+            });
+        }
+    }
+
+    tokens
+}
+
+// Every directive with the tokens up to the next one
+fn split_directives(tokens: &[Token]) -> Vec<(&str, Vec<Token>)> {
+    let mut directives: Vec<(&str, Vec<Token>)> = Vec::new();
+    for token in tokens {
+        match (token, directives.last_mut()) {
+            (Token::Identifier(name), _) => directives.push((name, Vec::new())),
+            (_, Some((_, args))) => args.push(token.clone()),
+            (_, None) => panic!("pbrt: values before the first directive"),
+        }
+    }
+    directives
+}
+
+// The leading string (shape, material or light kind) and the
+// "type name" value lists after it
+struct Params {
+    kind: String,
+    values: Vec<(String, Vec<Token>)>,
+}
+
+impl Params {
+    fn new(args: &[Token]) -> Self {
+        let mut args = args.iter().peekable();
+        let kind = match args.next() {
+            Some(Token::String(kind)) => kind.clone(),
+            _ => String::new(),
+        };
+
+        let mut values = Vec::new();
+        while let Some(token) = args.next() {
+            let Token::String(declaration) = token else {
+                continue;
+            };
+            let name = declaration
+                .split_whitespace()
+                .last()
+                .unwrap_or("")
+                .to_owned();
+
+            let mut list = Vec::new();
+            if args.peek() == Some(&&Token::Open) {
+                args.next();
+                for token in args.by_ref() {
+                    if *token == Token::Close {
+                        break;
+                    }
+                    list.push(token.clone());
+                }
+            } else if let Some(token) = args.next() {
+                list.push(token.clone());
+            }
+            values.push((name, list));
+        }
+
+        Self { kind, values }
+    }
+
+    fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    fn get(&self, name: &str) -> Option<&[Token]> {
+        self.values
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, list)| list.as_slice())
+    }
+
+    fn float(&self, name: &str) -> Option<f32> {
+        match self.get(name)?.first()? {
+            Token::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.get(name)?.first()? {
+            Token::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // pbrt-v3 writes bools as strings
+    fn bool(&self, name: &str) -> Option<bool> {
+        self.string(name)?.parse().ok()
+    }
+
+    fn strings(&self, name: &str) -> Option<Vec<&str>> {
+        self.get(name)?
+            .iter()
+            .map(|token| match token {
+                Token::String(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // A single value is used for all three channels
+    fn rgb(&self, name: &str) -> Option<Vec3> {
+        let numbers = self
+            .get(name)?
+            .iter()
+            .filter_map(|token| match token {
+                Token::Number(x) => Some(*x),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        match numbers.as_slice() {
+            [x] => Some(Vec3::repeat(*x)),
+            [r, g, b] => Some(vec3(*r, *g, *b)),
+            _ => None,
+        }
+    }
+}
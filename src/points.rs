@@ -0,0 +1,82 @@
+use glm::{vec3, Vec3};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+pub struct Point {
+    pub position: Vec3,
+    pub color: Option<Vec3>,
+}
+
+// Reads ".ply" files in the ASCII flavour and whitespace separated
+// "x y z [r g b]" lines otherwise. Colors above 1 are taken as 0..255
+pub fn load_points(path: &str) -> Vec<Point> {
+    let file = File::open(path).unwrap();
+    let mut lines = BufReader::new(file).lines().map(Result::unwrap);
+
+    let mut n_points = None;
+    let mut color_columns = None;
+    if path.ends_with(".ply") {
+        let (count, columns) = parse_ply_header(&mut lines);
+        n_points = Some(count);
+        color_columns = columns;
+    }
+
+    let mut points = lines
+        .map(|line| {
+            line.split_whitespace()
+                .map(|x| x.parse::<f32>().unwrap())
+                .collect::<Vec<_>>()
+        })
+        .filter(|values| values.len() >= 3)
+        .take(n_points.unwrap_or(usize::MAX))
+        .map(|values| {
+            let [r, g, b] = color_columns.unwrap_or([3, 4, 5]);
+            Point {
+                position: vec3(values[0], values[1], values[2]),
+                color: (values.len() > r.max(g).max(b))
+                    .then(|| vec3(values[r], values[g], values[b])),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let is_8bit = points.iter().filter_map(|p| p.color).any(|c| c.max() > 1.0);
+    if is_8bit {
+        for p in &mut points {
+            p.color = p.color.map(|c| c / 255.0);
+        }
+    }
+
+    points
+}
+
+// Returns the vertex count and the columns of red, green and blue
+fn parse_ply_header(lines: &mut impl Iterator<Item = String>) -> (usize, Option<[usize; 3]>) {
+    let mut n_vertices = 0;
+    let mut in_vertex = false;
+    let mut properties = Vec::new();
+
+    for line in lines.by_ref() {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        match tokens.as_slice() {
+            ["format", format, ..] => {
+                assert!(*format == "ascii", "only ASCII PLY files are supported");
+            }
+            ["element", name, count] => {
+                in_vertex = *name == "vertex";
+                if in_vertex {
+                    n_vertices = count.parse().unwrap();
+                }
+            }
+            ["property", .., name] if in_vertex => properties.push(name.to_string()),
+            ["end_header"] => break,
+            _ => {}
+        }
+    }
+
+    let column = |name: &str| properties.iter().position(|p| p == name);
+    let colors = match (column("red"), column("green"), column("blue")) {
+        (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+        _ => None,
+    };
+    (n_vertices, colors)
+}
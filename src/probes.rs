@@ -0,0 +1,143 @@
+use glm::{vec3, Vec3};
+use itertools::iproduct;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::options::Options;
+use crate::random::PathSampler;
+use crate::ray::Ray;
+use crate::render::load_scene;
+use crate::report::{json_object, json_string, json_vec3};
+use crate::scene::Scene;
+use crate::trace::{trace_path, visible_hit};
+
+// Light probes for real-time engines (--sh-probes FILE): a grid over the
+// bounded objects of the scene, the incoming radiance at every point
+// projected on the real spherical harmonics up to band 2 (9 RGB
+// coefficients, the first 4 are the L1 set). Probes see the scene as
+// indirect rays do, invisible-to-camera objects included
+
+// Real SH basis in world axes (y up), in the order of the coefficients
+const BASIS: [&str; 9] = ["1", "y", "z", "x", "xy", "yz", "3z^2-1", "xz", "x^2-y^2"];
+
+// Probes along x, y and z, one number for all three
+#[derive(Clone, Copy)]
+pub struct ProbeGrid(pub [usize; 3]);
+
+impl FromStr for ProbeGrid {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let counts = s
+            .split(',')
+            .map(|count| count.parse::<usize>().map_err(|_| ()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match counts[..] {
+            [n] if n > 0 => Ok(ProbeGrid([n; 3])),
+            [x, y, z] if x > 0 && y > 0 && z > 0 => Ok(ProbeGrid([x, y, z])),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn bake_probes(path: &str, options: &Options) {
+    let scene = load_scene(&options.input, options);
+    let Some(bounds) = scene.bounds() else {
+        panic!("the scene has no bounded objects to place probes around");
+    };
+    let ProbeGrid(counts) = options.probe_grid;
+
+    // At the centers of the grid cells, x first
+    let positions = iproduct!(0..counts[2], 0..counts[1], 0..counts[0])
+        .map(|(k, j, i)| {
+            let cell = vec3(i as f32, j as f32, k as f32).add_scalar(0.5);
+            let counts = vec3(counts[0] as f32, counts[1] as f32, counts[2] as f32);
+            bounds.min + (bounds.max - bounds.min).component_mul(&cell.component_div(&counts))
+        })
+        .collect::<Vec<_>>();
+
+    let probes = positions
+        .par_iter()
+        .enumerate()
+        .map(|(idx, position)| {
+            let (sh, backfaces) = probe(&scene, position, idx, options);
+            let sh = sh.iter().map(json_vec3).collect::<Vec<_>>();
+            json_object(&[
+                ("position", json_vec3(position)),
+                ("backfaces", backfaces.to_string()),
+                ("sh", format!("[{}]", sh.join(", "))),
+            ])
+        })
+        .collect::<Vec<_>>();
+    log::info!("baked {} probes", probes.len());
+
+    let basis = BASIS.map(json_string);
+    let probes_json = json_object(&[
+        ("input", json_string(&options.input)),
+        ("order", "2".to_owned()),
+        ("basis", format!("[{}]", basis.join(", "))),
+        (
+            "grid",
+            format!("[{}, {}, {}]", counts[0], counts[1], counts[2]),
+        ),
+        ("min", json_vec3(&bounds.min)),
+        ("max", json_vec3(&bounds.max)),
+        ("rays", strata(options).pow(2).to_string()),
+        ("probes", format!("[{}]", probes.join(", "))),
+    ]);
+    std::fs::write(path, probes_json + "\n").unwrap();
+}
+
+// Along each side of the square of strata, --probe-rays is rounded up
+fn strata(options: &Options) -> usize {
+    (options.probe_rays as f32).sqrt().ceil() as usize
+}
+
+// SH coefficients of the radiance arriving at the point, from rays
+// stratified in z and azimuth, and the fraction of them hitting the inside
+// of a figure, high when the probe is buried in geometry
+fn probe(scene: &Scene, position: &Vec3, idx: usize, options: &Options) -> ([Vec3; 9], f32) {
+    let n = strata(options);
+    let mut sh = [Vec3::zeros(); 9];
+    let mut backfaces = 0;
+    for (a, b) in iproduct!(0..n, 0..n) {
+        let mut sampler = PathSampler::new(options.seed, idx, a * n + b);
+        let (u, v) = sampler.next_2d();
+        let z = 1.0 - 2.0 * (a as f32 + u) / n as f32;
+        let phi = 2.0 * PI * (b as f32 + v) / n as f32;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let direction = vec3(r * phi.cos(), r * phi.sin(), z);
+
+        let ray = Ray::from_unit(*position, direction);
+        if visible_hit(scene, &ray, false).is_some_and(|(_, hit, _)| hit.is_inside) {
+            backfaces += 1;
+        }
+        // As after a bounce, not as a camera ray
+        let radiance = trace_path(scene, &ray, 1, &mut sampler).total();
+        for (coefficient, y) in sh.iter_mut().zip(sh_basis(&direction)) {
+            *coefficient += y * radiance;
+        }
+    }
+
+    let rays = (n * n) as f32;
+    (
+        sh.map(|coefficient| coefficient * 4.0 * PI / rays),
+        backfaces as f32 / rays,
+    )
+}
+
+fn sh_basis(d: &Vec3) -> [f32; 9] {
+    let (x, y, z) = (d.x, d.y, d.z);
+    [
+        0.282095,
+        0.488603 * y,
+        0.488603 * z,
+        0.488603 * x,
+        1.092548 * x * y,
+        1.092548 * y * z,
+        0.315392 * (3.0 * z * z - 1.0),
+        1.092548 * x * z,
+        0.546274 * (x * x - y * y),
+    ]
+}
@@ -0,0 +1,53 @@
+// Scopes for frame profilers, recorded with the puffin feature (written
+// to the --profile file, for puffin_viewer) or the tracy feature (sent to
+// a connected Tracy viewer). Without either they compile to nothing. Every
+// sample pass of a render is a frame
+
+// Times the rest of the enclosing block
+#[macro_export]
+macro_rules! profile_scope {
+    ($name:expr) => {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!($name);
+        #[cfg(feature = "tracy")]
+        let _span = tracy_client::span!($name);
+    };
+}
+
+#[cfg(feature = "puffin")]
+static FRAMES: std::sync::OnceLock<puffin::GlobalFrameView> = std::sync::OnceLock::new();
+
+// Before anything is profiled
+pub fn start(output: Option<&str>) {
+    #[cfg(feature = "puffin")]
+    if output.is_some() {
+        FRAMES.get_or_init(puffin::GlobalFrameView::default);
+        puffin::set_scopes_on(true);
+    }
+    #[cfg(not(feature = "puffin"))]
+    if output.is_some() {
+        log::warn!("--profile needs the puffin feature, nothing is recorded");
+    }
+    #[cfg(feature = "tracy")]
+    tracy_client::Client::start();
+}
+
+pub fn end_frame() {
+    #[cfg(feature = "puffin")]
+    puffin::GlobalProfiler::lock().new_frame();
+    #[cfg(feature = "tracy")]
+    tracy_client::frame_mark();
+}
+
+// Writes the recorded frames
+pub fn finish(output: Option<&str>) {
+    #[cfg(feature = "puffin")]
+    if let (Some(path), Some(frames)) = (output, FRAMES.get()) {
+        puffin::GlobalProfiler::lock().new_frame();
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        frames.lock().write(&mut file).unwrap();
+        log::info!("wrote the profile to {}", path);
+    }
+    #[cfg(not(feature = "puffin"))]
+    let _ = output;
+}
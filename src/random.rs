@@ -1,19 +1,91 @@
 use glm::{vec3, Vec3};
 use na::Matrix3;
-use rand::{rngs::ThreadRng, Rng};
 use std::f32::consts::PI;
 
+use crate::epsilon::Epsilon;
 use crate::objects::{LightSource, RayIntersection};
 use crate::ray::Ray;
 
-const EPS: f32 = 1e-4;
+// The random numbers of one path, a sample of one pixel, so renders are
+// repeatable and do not depend on the number of threads or on tiling.
+// Every number has a dimension: the stage of the path (the camera, then
+// one per bounce) and its place among the numbers drawn at that stage.
+// Each stage starts from its first dimension whatever the earlier ones
+// drew, so a decision keeps its dimension across the samples of a pixel
+// and a stratified sampler can replace this one stage by stage. The
+// numbers are independent here, a hash of the path and the dimension
+pub struct PathSampler {
+    key: u64,
+    stage: u64,
+    dimension: u64,
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+impl PathSampler {
+    pub fn new(seed: u64, pixel: usize, step: usize) -> Self {
+        let stream = ((step as u64) << 32) | pixel as u64;
+        Self {
+            key: mix(mix(seed) ^ stream),
+            stage: 0,
+            dimension: 0,
+        }
+    }
+
+    // The first dimension of the hit at this depth, the camera is before
+    // depth 0
+    pub fn start_bounce(&mut self, depth: usize) {
+        self.stage = depth as u64 + 1;
+        self.dimension = 0;
+    }
+
+    // Another path from the same point, e.g. one of several rays of a
+    // record, starting at the camera stage
+    pub fn fork(&self, index: usize) -> Self {
+        Self {
+            key: mix(self.key ^ mix(self.position()) ^ index as u64),
+            stage: 0,
+            dimension: 0,
+        }
+    }
+
+    // Uniform in 0..1
+    pub fn next_1d(&mut self) -> f32 {
+        // SplitMix64 at the position of the dimension
+        let position = self.position();
+        self.dimension += 1;
+        let bits = mix(self.key.wrapping_add(position.wrapping_mul(GOLDEN_GAMMA)));
+        (bits >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn next_2d(&mut self) -> (f32, f32) {
+        (self.next_1d(), self.next_1d())
+    }
+
+    // Uniform in 0..n, from one dimension
+    pub fn next_index(&mut self, n: usize) -> usize {
+        ((self.next_1d() * n as f32) as usize).min(n - 1)
+    }
+
+    fn position(&self) -> u64 {
+        (self.stage << 32) | self.dimension
+    }
+}
 
+// Finalizer of SplitMix64
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// Over the hemisphere around n
 pub struct Uniform;
 pub struct Cosine;
 
 impl Uniform {
-    pub fn sample(n: &Vec3, rng: &mut ThreadRng) -> Vec3 {
-        let mut d = sphere_uniform(rng);
+    pub fn sample(n: &Vec3, sampler: &mut PathSampler) -> Vec3 {
+        let mut d = sphere_uniform(sampler);
         if glm::dot(&d, n) <= 0.0 {
             d = -d;
         };
@@ -21,7 +93,7 @@ impl Uniform {
     }
 
     pub fn pdf(n: &Vec3, d: &Vec3) -> f32 {
-        if glm::dot(&d, n) <= 0.0 {
+        if glm::dot(d, n) <= 0.0 {
             0.0
         } else {
             0.5 / PI
@@ -30,26 +102,16 @@ impl Uniform {
 }
 
 impl Cosine {
-    pub fn sample(n: &Vec3, rng: &mut ThreadRng) -> Vec3 {
-        let theta = rng.gen_range(0.0..2.0 * PI);
-        let r = rng.gen_range(0.0_f32..1.0).sqrt();
+    pub fn sample(n: &Vec3, sampler: &mut PathSampler) -> Vec3 {
+        let (u, v) = sampler.next_2d();
+        let theta = 2.0 * PI * u;
+        let r = v.sqrt();
 
         let x = r * theta.cos();
         let y = r * theta.sin();
         let z = (1.0 - x * x - y * y).sqrt();
 
-        let z_image = *n;
-        let min_abs_coord = n.x.abs().min(n.y.abs()).min(n.z.abs());
-        let x_image =
-            Vec3::from_iterator(
-                n.iter()
-                    .map(|x| if x.abs() > min_abs_coord { 0.0 } else { 1.0 }),
-            );
-        let x_image = (x_image - n * glm::dot(&x_image, &z_image)).normalize();
-        let y_image = glm::cross(&x_image, &z_image).normalize();
-
-        let rot = Matrix3::from_columns(&[x_image, y_image, z_image]);
-        rot * vec3(x, y, z)
+        to_basis(n, &vec3(x, y, z))
     }
 
     pub fn pdf(n: &Vec3, d: &Vec3) -> f32 {
@@ -57,9 +119,65 @@ impl Cosine {
     }
 }
 
-fn sphere_uniform(rng: &mut ThreadRng) -> Vec3 {
-    let phi = rng.gen_range(0.0..PI);
-    let z = rng.gen_range(-1.0_f32..1.0);
+// GGX distribution of microfacet normals (Walter et al. 2007), sampled
+// proportionally to D(m) |m.n|
+pub struct Microfacet {
+    pub alpha: f32,
+}
+
+impl Microfacet {
+    pub fn sample(&self, n: &Vec3, sampler: &mut PathSampler) -> Vec3 {
+        let (u, v) = sampler.next_2d();
+        let phi = 2.0 * PI * v;
+        to_basis(n, &self.local_normal(u, phi)).normalize()
+    }
+
+    // The facet normal for a uniform u in 0..1 and azimuth, around z
+    pub fn local_normal(&self, u: f32, phi: f32) -> Vec3 {
+        let tan_theta = self.alpha * (u / (1.0 - u)).sqrt();
+        let cos_theta = 1.0 / (1.0 + tan_theta * tan_theta).sqrt();
+        let sin_theta = tan_theta * cos_theta;
+        vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    // BSDF times cosine over pdf for a sampled facet m, without the
+    // Fresnel term, from i to o in either hemisphere
+    pub fn weight(&self, n: &Vec3, i: &Vec3, o: &Vec3, m: &Vec3) -> f32 {
+        let g = self.g1(n, i, m) * self.g1(n, o, m);
+        glm::dot(i, m) * g / (glm::dot(i, n) * glm::dot(m, n))
+    }
+
+    // Smith shadowing of the facet m seen from v
+    pub fn g1(&self, n: &Vec3, v: &Vec3, m: &Vec3) -> f32 {
+        let cos = glm::dot(v, n);
+        if glm::dot(v, m) * cos <= 0.0 {
+            return 0.0;
+        }
+        let tan2 = (1.0 - cos * cos).max(0.0) / (cos * cos);
+        2.0 / (1.0 + (1.0 + self.alpha * self.alpha * tan2).sqrt())
+    }
+}
+
+// Rotates v so that the z axis goes to n
+fn to_basis(n: &Vec3, v: &Vec3) -> Vec3 {
+    let z_image = *n;
+    let min_abs_coord = n.x.abs().min(n.y.abs()).min(n.z.abs());
+    let x_image =
+        Vec3::from_iterator(
+            n.iter()
+                .map(|x| if x.abs() > min_abs_coord { 0.0 } else { 1.0 }),
+        );
+    let x_image = (x_image - n * glm::dot(&x_image, &z_image)).normalize();
+    let y_image = glm::cross(&x_image, &z_image).normalize();
+
+    let rot = Matrix3::from_columns(&[x_image, y_image, z_image]);
+    rot * v
+}
+
+fn sphere_uniform(sampler: &mut PathSampler) -> Vec3 {
+    let (u, v) = sampler.next_2d();
+    let phi = 2.0 * PI * u;
+    let z = 2.0 * v - 1.0;
     let x = (1.0 - z * z).sqrt() * phi.cos();
     let y = (1.0 - z * z).sqrt() * phi.sin();
     vec3(x, y, z)
@@ -67,15 +185,16 @@ fn sphere_uniform(rng: &mut ThreadRng) -> Vec3 {
 
 pub struct ToLight<'a> {
     pub lights: &'a [Box<dyn LightSource>],
+    pub epsilon: Epsilon,
 }
 
 impl<'a> ToLight<'a> {
-    pub fn sample(&self, p: &Vec3, rng: &mut ThreadRng) -> Vec3 {
+    pub fn sample(&self, p: &Vec3, sampler: &mut PathSampler) -> Vec3 {
         assert!(!self.lights.is_empty());
 
-        let idx = rng.gen_range(0..self.lights.len());
+        let idx = sampler.next_index(self.lights.len());
         let obj = &self.lights[idx];
-        let p_light = obj.sample(rng);
+        let p_light = obj.sample(sampler);
 
         (p_light - p).normalize()
     }
@@ -85,23 +204,26 @@ impl<'a> ToLight<'a> {
             return 0.0;
         }
 
-        let ray = Ray::new(*p, *d);
+        let ray = Ray::new(*p, *d).with_epsilon(self.epsilon);
         let mut pdf = 0.0;
 
         for obj in self.lights.iter() {
             let Some(i1) = obj.intersect(&ray) else {
                 continue;
             };
-            pdf += calc_intersection_pdf(obj, &ray, &i1, p);
+            pdf += calc_intersection_pdf(obj.as_ref(), &ray, &i1, p);
 
-            let ray2 = Ray::new_shifted(
-                ray.origin + i1.t * ray.direction, ray.direction
+            let ray2 = Ray::new_offset(
+                ray.origin + i1.t * ray.direction,
+                &i1.n,
+                ray.direction,
+                &self.epsilon,
             );
 
             let Some(i2) = obj.intersect(&ray2) else {
                 continue;
             };
-            pdf += calc_intersection_pdf(obj, &ray2, &i2, p);
+            pdf += calc_intersection_pdf(obj.as_ref(), &ray2, &i2, p);
         }
 
         pdf /= self.lights.len() as f32;
@@ -110,7 +232,7 @@ impl<'a> ToLight<'a> {
 }
 
 fn calc_intersection_pdf(
-    obj: &Box<dyn LightSource>,
+    obj: &dyn LightSource,
     ray: &Ray,
     intersection: &RayIntersection,
     initial_point: &Vec3,
@@ -127,35 +249,76 @@ fn calc_intersection_pdf(
     pdf
 }
 
+// Uniform over the cone of directions towards the sun disk
+pub struct ToSun {
+    pub direction: Vec3,
+    pub cos_max: f32,
+}
+
+impl ToSun {
+    pub fn sample(&self, sampler: &mut PathSampler) -> Vec3 {
+        let (u, v) = sampler.next_2d();
+        let cos_theta = 1.0 - u * (1.0 - self.cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = 2.0 * PI * v;
+
+        let d = vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        to_basis(&self.direction, &d).normalize()
+    }
+
+    pub fn pdf(&self, d: &Vec3) -> f32 {
+        if glm::dot(d, &self.direction) < self.cos_max {
+            0.0
+        } else {
+            1.0 / (2.0 * PI * (1.0 - self.cos_max))
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct MIS<'a> {
     pub to_light: ToLight<'a>,
+    pub to_sun: Option<ToSun>,
+    // The constant background as a light around the whole scene, sampled
+    // uniformly over the hemisphere
+    pub uniform_sky: bool,
 }
 
 impl<'a> MIS<'a> {
-    pub fn sample(&self, p: &Vec3, n: &Vec3, rng: &mut ThreadRng) -> Vec3 {
-        if rng.gen_bool(self.cosine_probability()) {
-            Cosine::sample(n, rng)
+    pub fn sample(&self, p: &Vec3, n: &Vec3, sampler: &mut PathSampler) -> Vec3 {
+        let k = sampler.next_index(self.n_strategies());
+        self.sample_strategy(k, p, n, sampler)
+    }
+
+    // With the k-th strategy, the pdf is still that of the mixture
+    pub fn sample_strategy(&self, k: usize, p: &Vec3, n: &Vec3, sampler: &mut PathSampler) -> Vec3 {
+        // the sky is the last one
+        if k == 0 {
+            Cosine::sample(n, sampler)
+        } else if k == 1 && !self.to_light.lights.is_empty() {
+            self.to_light.sample(p, sampler)
+        } else if self.uniform_sky && k + 1 == self.n_strategies() {
+            Uniform::sample(n, sampler)
         } else {
-            self.to_light.sample(p, rng)
+            self.to_sun.as_ref().unwrap().sample(sampler)
         }
     }
 
+    // Every available strategy is picked with the same probability
     pub fn pdf(&self, p: &Vec3, n: &Vec3, d: &Vec3) -> f32 {
-        let a = self.cosine_probability() as f32;
-        let mut pdf =
-            Cosine::pdf(n, &d) * a + self.to_light.pdf(p, &d) * (1.0 - a);
-
-        // if !(pdf > 0.0) {
-        //     pdf = f32::INFINITY;
-        // }
-        pdf
+        let sun_pdf = self.to_sun.as_ref().map_or(0.0, |to_sun| to_sun.pdf(d));
+        let sky_pdf = if self.uniform_sky {
+            Uniform::pdf(n, d)
+        } else {
+            0.0
+        };
+        let pdf = Cosine::pdf(n, d) + self.to_light.pdf(p, d) + sun_pdf + sky_pdf;
+        pdf / self.n_strategies() as f32
     }
 
-    fn cosine_probability(&self) -> f64 {
-        if self.to_light.lights.is_empty() {
-            1.0
-        } else {
-            0.5
-        }
+    pub fn n_strategies(&self) -> usize {
+        1 + !self.to_light.lights.is_empty() as usize
+            + self.to_sun.is_some() as usize
+            + self.uniform_sky as usize
     }
 }
@@ -1,25 +1,84 @@
 use glm::Vec3;
 
-const EPS: f32 = 1e-4;
+use crate::epsilon::Epsilon;
 
+#[derive(Clone)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    // infinite along the axes the ray is parallel to
+    pub inv_direction: Vec3,
+    // 1 where the direction is negative (-0 included), to pick the near
+    // and far sides of boxes without comparing
+    pub sign: [usize; 3],
+    // Distances along the ray hits are looked for in, t_min excluded
+    pub t_min: f32,
+    pub t_max: f32,
+    // Of the scene the ray is traced in, for the objects it meets
+    pub epsilon: Epsilon,
 }
 
 impl Ray {
     pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self::from_unit(origin, direction.normalize())
+    }
+
+    // For directions that are already normalized, e.g. rotated ones
+    pub fn from_unit(origin: Vec3, direction: Vec3) -> Self {
+        let inv_direction = direction.map(|x| 1.0 / x);
         Self {
             origin,
-            direction: direction.normalize(),
+            direction,
+            inv_direction,
+            sign: [0, 1, 2].map(|i| (inv_direction[i] < 0.0) as usize),
+            t_min: 0.0,
+            t_max: f32::INFINITY,
+            epsilon: Epsilon::default(),
         }
     }
 
-    pub fn new_shifted(origin: Vec3, direction: Vec3) -> Self {
-        let direction = direction.normalize();
+    pub fn with_interval(self, t_min: f32, t_max: f32) -> Self {
         Self {
-            origin: origin + EPS * direction,
-            direction,
+            t_min,
+            t_max,
+            ..self
         }
     }
+
+    pub fn with_epsilon(self, epsilon: Epsilon) -> Self {
+        Self { epsilon, ..self }
+    }
+
+    pub fn contains(&self, t: f32) -> bool {
+        t > self.t_min && t <= self.t_max
+    }
+
+    // Starts a ray at a surface point, moving the origin to the side
+    // of the surface the ray leaves to
+    pub fn new_offset(point: Vec3, normal: &Vec3, direction: Vec3, epsilon: &Epsilon) -> Self {
+        let direction = direction.normalize();
+        let normal = if glm::dot(normal, &direction) < 0.0 {
+            -normal
+        } else {
+            *normal
+        };
+
+        let (origin, float_scale) = (epsilon.offset_origin(), epsilon.offset_float_scale());
+        let int_scale = epsilon.offset_int_scale;
+        let origin = point.zip_map(&normal, |p, n| {
+            offset_coordinate(p, n, origin, float_scale, int_scale)
+        });
+        Self::from_unit(origin, direction).with_epsilon(*epsilon)
+    }
+}
+
+// Offsets from the scene epsilon
+fn offset_coordinate(p: f32, n: f32, origin: f32, float_scale: f32, int_scale: f32) -> f32 {
+    if p.abs() < origin {
+        return p + float_scale * n;
+    }
+
+    let offset = (int_scale * n) as i32;
+    let offset = if p < 0.0 { -offset } else { offset };
+    f32::from_bits((p.to_bits() as i32 + offset) as u32)
 }
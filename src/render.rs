@@ -0,0 +1,480 @@
+use glm::Vec3;
+use itertools::iproduct;
+use rayon::prelude::*;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::aov::write_aovs;
+use crate::controller::RenderController;
+use crate::cubemap::render_cubemap;
+use crate::environment::{sun_direction, Environment, Sky};
+use crate::epsilon::{self, Epsilon};
+use crate::exposure::auto_exposure;
+use crate::image::{Image, Precision};
+use crate::interrupt::interrupted;
+use crate::irradiance::IrradianceCache;
+use crate::options::Options;
+use crate::output::{expand_path, render_metadata};
+use crate::overrides::apply_override_file;
+use crate::parser::parse_scene;
+use crate::profile;
+use crate::profile_scope;
+use crate::random::PathSampler;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stereo::render_stereo;
+use crate::temporal::{primary_hits, reproject, History};
+use crate::tiles::{morton_order, tile_order, Tile, TileOrder, TILE_SIZE};
+use crate::trace::{trace_path, visible_hit, Radiance};
+use crate::traversal::{build_bvh, build_traversal, Accel, Bvh, TraversalBackend, VisitCounter};
+use crate::wavefront::trace_pass;
+
+// Zenith luminance of the --sky environment
+const SKY_INTENSITY: f32 = 0.15;
+// Fractions of the scene size for the hints logged at load: a camera
+// path crossing the scene in ten seconds, and a lens that blurs the
+// background without hiding it
+const CAMERA_SPEED_HINT: f32 = 0.1;
+const APERTURE_HINT: f32 = 0.005;
+// Pixels along the larger side of the --bvh-tune warm-up render
+const BVH_TUNE_RESOLUTION: usize = 64;
+
+pub fn render(scene: &mut Scene, options: &Options) {
+    render_with(scene, options, &RenderController::new());
+}
+
+// Renders with progress reports and checks for a cancel between runs of
+// pixels the size of a tile
+pub fn render_with(scene: &mut Scene, options: &Options, controller: &RenderController) {
+    let start = Instant::now();
+    let width = scene.image.width;
+    let height = scene.image.height;
+
+    // With --half-film only the image keeps the rounding errors of the mean
+    let (image, passes) = if options.half_film {
+        (
+            Precision::Half { compensated: true },
+            Precision::Half { compensated: false },
+        )
+    } else {
+        (
+            Precision::Full { compensated: true },
+            Precision::Full { compensated: true },
+        )
+    };
+    scene.image = Image::with_layout(width, height, 3, image);
+    // The split by light path is only needed for the EXR passes
+    scene.light_paths = if options.aov.is_some() {
+        (0..4)
+            .map(|_| Image::with_layout(width, height, 3, passes))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    // Sums of squared deviations until the end, then the variance of the
+    // mean. They soon exceed the f16 range
+    scene.variance = options
+        .aov
+        .as_ref()
+        .map(|_| Image::with_layout(width, height, 3, Precision::Full { compensated: true }));
+    scene.alpha = (options.aov.is_some() && scene.shadow_catcher)
+        .then(|| Image::with_layout(width, height, 1, passes));
+
+    scene.camera_traversal = None;
+    if options.frustum_cull {
+        scene.camera_traversal = Some(camera_traversal(scene, options));
+    }
+
+    // Rebuilt for every frame, the records depend on the camera
+    scene.irradiance_cache = None;
+    if let Some(accuracy) = options.irradiance_cache {
+        let cache_start = Instant::now();
+        scene.irradiance_cache = Some(IrradianceCache::build(scene, accuracy, options.seed));
+        log::info!(
+            "built the irradiance cache in {:.1} ms",
+            cache_start.elapsed().as_secs_f64() * 1000.0
+        );
+    }
+
+    // Threads get runs of the order, nearby pixels share more of the BVH.
+    // With --tile-order every pass starts with the tiles it puts first
+    let order = match options.tile_order {
+        _ if options.scanline => (0..width * height).collect(),
+        TileOrder::ZOrder => morton_order(width, height),
+        order => tile_order(width, height, order),
+    };
+
+    let progress = controller.pass(scene.n_samples, order.len(), start);
+    for step in 0..scene.n_samples {
+        // The mean of the passes so far is a complete, just noisier, image;
+        // the sample count is lowered so the passes report the real one
+        if interrupted() || controller.is_cancelled() {
+            scene.n_samples = step.max(1);
+            break;
+        }
+        progress.begin(step);
+
+        // Runs of the order, a cancel drops the pass
+        let samples = if options.wavefront {
+            let samples = trace_pass(scene, &order, step, options);
+            progress.add(step, order.len());
+            Some(vec![samples])
+        } else {
+            order
+                .par_chunks(TILE_SIZE * TILE_SIZE)
+                .map(|run| {
+                    if controller.is_cancelled() {
+                        return None;
+                    }
+                    let samples = run
+                        .par_iter()
+                        .map(|&idx| sample_pixel(scene, idx % width, idx / width, step, options))
+                        .collect::<Vec<_>>();
+                    progress.add(step, run.len());
+                    Some(samples)
+                })
+                .collect::<Option<Vec<_>>>()
+        };
+        let Some(samples) = samples else {
+            scene.n_samples = step.max(1);
+            break;
+        };
+
+        let step_f = step as f32;
+        for (&idx, radiance) in order.iter().zip(samples.into_iter().flatten()) {
+            let (i, j) = (idx % width, idx / width);
+            let (old_mean, color) = (scene.image.get(i, j), radiance.total());
+            scene.image.accumulate(i, j, color, step_f);
+            let new_mean = scene.image.get(i, j);
+
+            // Welford's update
+            if let Some(variance) = &mut scene.variance {
+                let m2 = (color - old_mean).component_mul(&(color - new_mean));
+                variance.add(i, j, m2);
+            }
+            for (image, part) in scene.light_paths.iter_mut().zip(radiance.parts()) {
+                image.accumulate(i, j, part, step_f);
+            }
+            if let Some(alpha) = &mut scene.alpha {
+                alpha.accumulate(i, j, Vec3::repeat(radiance.alpha), step_f);
+            }
+        }
+        profile::end_frame();
+    }
+    progress.begin(scene.n_samples);
+
+    let n = scene.n_samples as f32;
+    if let Some(variance) = &mut scene.variance {
+        for (i, j) in iproduct!(0..width, 0..height) {
+            variance.set(i, j, variance.get(i, j) / (n * (n - 1.0)).max(1.0));
+        }
+    }
+
+    // Only the image gets the history, the passes have this frame's samples
+    if let Some(samples) = options.reproject {
+        let hits = primary_hits(scene);
+        if let Some(history) = scene.history.take() {
+            reproject(scene, &history, &hits, samples);
+        }
+        scene.history = Some(History::new(scene, hits));
+    }
+
+    log::info!(
+        "rendered {}x{} with {} samples in {:.1} ms",
+        width,
+        height,
+        scene.n_samples,
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+}
+
+// Averages all of the scene's samples for every pixel of the tile, in the
+// order of Tile::pixels
+pub fn render_tile(scene: &Scene, tile: &Tile, options: &Options) -> Vec<Vec3> {
+    profile_scope!("tile");
+    tile.pixels()
+        .into_par_iter()
+        .map(|(i, j)| {
+            let sum = (0..scene.n_samples)
+                .map(|step| sample_pixel(scene, i, j, step, options).total())
+                .sum::<Vec3>();
+            sum / scene.n_samples as f32
+        })
+        .collect()
+}
+
+fn sample_pixel(scene: &Scene, i: usize, j: usize, step: usize, options: &Options) -> Radiance {
+    profile_scope!("sample");
+    let mut sample = CameraSample::new(scene, i, j, step, options);
+    let radiance = trace_path(scene, &sample.ray, 0, &mut sample.sampler);
+    sample.finish(radiance, options)
+}
+
+// The camera ray of a sample of the pixel and the sampler of the
+// rest of its path
+pub struct CameraSample {
+    pub ray: Ray,
+    pub sampler: PathSampler,
+    i: usize,
+    j: usize,
+    step: usize,
+    mask: Vec3,
+    vignetting: f32,
+    // of the camera ray, for --nan-debug
+    origin: Vec3,
+    direction: Vec3,
+}
+
+impl CameraSample {
+    pub fn new(scene: &Scene, i: usize, j: usize, step: usize, options: &Options) -> Self {
+        let mut sampler = PathSampler::new(options.seed, j * scene.image.width + i, step);
+        let (du, dv) = sampler.next_2d();
+        let u = (i as f32 + du) / scene.image.width as f32 * 2.0 - 1.0;
+        let v = (j as f32 + dv) / scene.image.height as f32 * 2.0 - 1.0;
+
+        // With chromatic aberration every sample traces a single channel
+        let (u, v, mask) = if scene.camera.chromatic_aberration > 0.0 {
+            let channel = sampler.next_index(3);
+            let (u, v) = scene.camera.aberrated(u, v, channel);
+            let mut mask = Vec3::zeros();
+            mask[channel] = 3.0;
+            (u, v, mask)
+        } else {
+            (u, v, Vec3::repeat(1.0))
+        };
+        let ray = if scene.camera.lens.aperture > 0.0 {
+            let lens = scene.camera.lens.sample(&mut sampler);
+            scene.camera.ray_through_lens(u, v, lens)
+        } else {
+            scene.camera.ray_to_point(u, v)
+        };
+
+        Self {
+            origin: ray.origin,
+            direction: ray.direction,
+            ray,
+            sampler,
+            i,
+            j,
+            step,
+            mask,
+            vignetting: scene.camera.vignetting_factor(u, v),
+        }
+    }
+
+    // The radiance of the sample from what the path brought back
+    pub fn finish(&self, radiance: Radiance, options: &Options) -> Radiance {
+        let radiance = radiance.map(|c| c.component_mul(&self.mask) * self.vignetting);
+
+        let color = radiance.total();
+        if !color.iter().all(|c| c.is_finite()) {
+            if options.nan_debug {
+                log::warn!(
+                    "non-finite radiance {:?} at pixel ({}, {}), sample {}, ray {:?} -> {:?}",
+                    color,
+                    self.i,
+                    self.j,
+                    self.step,
+                    self.origin,
+                    self.direction
+                );
+            }
+            return Radiance::default();
+        }
+
+        radiance
+    }
+}
+
+pub fn load_scene(path: &str, options: &Options) -> Scene {
+    let start = Instant::now();
+    let mut scene = parse_scene(path);
+    log::info!(
+        "parsed {} ({} objects, {} lights) in {:.1} ms",
+        path,
+        scene.objects.len(),
+        scene.lights.len(),
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+
+    apply_overrides(&mut scene, options);
+    scene
+}
+
+// Counts the BVH nodes a coarse warm-up render visits, then rebuilds the
+// BVH around them
+fn tuned_bvh(scene: &mut Scene, options: &Options) -> Box<dyn TraversalBackend> {
+    let quantized = matches!(options.accel, Accel::QuantizedBvh);
+    // Counting needs full nodes, the tuned tree is quantized afterwards
+    let bvh = Arc::new(build_bvh(
+        &scene.objects,
+        false,
+        &options.bvh,
+        options.cache_dir.as_deref(),
+    ));
+    let counter = VisitCounter::new(bvh.clone());
+    scene.traversal = Box::new(counter.clone());
+
+    let (width, height) = (scene.image.width, scene.image.height);
+    let stride = (width.max(height) / BVH_TUNE_RESOLUTION).max(1);
+    let pixels =
+        iproduct!((0..height).step_by(stride), (0..width).step_by(stride)).collect::<Vec<_>>();
+    let scene = &*scene;
+    pixels.into_par_iter().for_each(|(j, i)| {
+        sample_pixel(scene, i, j, 0, options);
+    });
+
+    Box::new(bvh.tuned(&scene.objects, &counter.visits(), quantized, &options.bvh))
+}
+
+// BVH over the objects in the camera frustum, whatever the --accel
+fn camera_traversal(scene: &Scene, options: &Options) -> Box<dyn TraversalBackend> {
+    let start = Instant::now();
+    let quantized = matches!(options.accel, Accel::QuantizedBvh);
+    let frustum = scene.camera.frustum();
+    let slack = scene.epsilon.offset_origin();
+    let bvh = Bvh::culled(&scene.objects, &frustum, slack, quantized, &options.bvh);
+    log::info!(
+        "built the camera BVH over {} of {} objects in {:.1} ms",
+        bvh.n_objects(),
+        scene.objects.len(),
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+    Box::new(bvh)
+}
+
+pub fn apply_overrides(scene: &mut Scene, options: &Options) {
+    if let Some(path) = &options.overrides {
+        apply_override_file(scene, path);
+    }
+    // The options below are in the scaled units
+    if options.scene_scale != 1.0 {
+        scene.scale(options.scene_scale);
+    }
+    log_scale_hints(scene);
+    if !options.include.is_empty() || !options.exclude.is_empty() {
+        scene.retain_objects(|obj| {
+            let name = obj.name.as_deref().unwrap_or("");
+            let included = options.include.is_empty() || options.include.iter().any(|n| n == name);
+            included && !options.exclude.iter().any(|n| n == name)
+        });
+    }
+    if let Some(height) = options.ground {
+        scene.add_ground_plane(height, options.ground_checker);
+    }
+    if let Some(turbidity) = options.sky {
+        let sun_direction = sun_direction(options.sun_elevation, options.sun_azimuth);
+        scene.environment = Environment::Sky(Sky::new(sun_direction, turbidity, SKY_INTENSITY));
+    }
+    if let Some(gradient) = options.gradient {
+        scene.environment = Environment::Gradient(gradient);
+    }
+    if options.clay {
+        scene.apply_clay_materials();
+    }
+    // After the changes to the objects, before the first rays
+    scene.epsilon = Epsilon::of_scene(scene).with_options(options);
+    scene.min_throughput = options.min_throughput;
+    scene.light_samples = options.light_samples;
+    scene.regularize = options.regularize;
+    let start = Instant::now();
+    profile_scope!("build traversal");
+    scene.traversal = match options.accel {
+        Accel::Bvh | Accel::QuantizedBvh if options.bvh_tune => tuned_bvh(scene, options),
+        accel => build_traversal(
+            accel,
+            &scene.objects,
+            &options.bvh,
+            options.cache_dir.as_deref(),
+        ),
+    };
+    log::info!(
+        "built the traversal in {:.1} ms",
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+
+    if let Some((u, v)) = options.focus_point {
+        autofocus(scene, u, v);
+    }
+}
+
+// The bounds of the scene, with settings that usually suit its size
+fn log_scale_hints(scene: &Scene) {
+    let Some(bounds) = scene.bounds() else {
+        return;
+    };
+    let size = bounds.size();
+    log::info!(
+        "scene bounds {:?} to {:?}, size {}: about --eps-scale {}, camera paths at {} per \
+         second, apertures up to {}",
+        bounds.min.as_slice(),
+        bounds.max.as_slice(),
+        size,
+        epsilon::scene_scale(scene),
+        size * CAMERA_SPEED_HINT,
+        size * APERTURE_HINT
+    );
+}
+
+// Focuses the lens on what the image point sees. The distance is in the
+// report, at the default log level it is not shown
+fn autofocus(scene: &mut Scene, u: f32, v: f32) {
+    let ray = scene.camera.ray_to_point(2.0 * u - 1.0, 1.0 - 2.0 * v);
+    let Some((_, hit, _)) = visible_hit(scene, &ray, true) else {
+        log::warn!("nothing at the focus point, the focus distance stays the same");
+        return;
+    };
+
+    let forward = scene.camera.axis.column(2).normalize();
+    let distance = hit.t * glm::dot(&ray.direction, &forward);
+    scene.camera.lens.focus_distance = distance;
+    log::info!("focus distance {:.4}", distance);
+}
+
+// Returns the files written, the paths may be templates (see
+// output::expand_path)
+pub fn render_to_file(scene: &mut Scene, options: &Options, output: &str) -> Vec<String> {
+    let output = expand_path(output, scene, options);
+    if let Some(layout) = options.cubemap {
+        return render_cubemap(scene, options, layout, &output);
+    }
+    if let Some(layout) = options.stereo {
+        return render_stereo(scene, options, layout, &output);
+    }
+    let aov = options
+        .aov
+        .as_ref()
+        .map(|path| expand_path(path, scene, options));
+
+    let start = Instant::now();
+    render(scene, options);
+    let metadata = render_metadata(scene, options, start.elapsed());
+    if let Some(path) = &aov {
+        write_aovs(scene, path, &metadata);
+    }
+
+    scene.auto_exposure = post_process(&mut scene.image, options);
+    scene.image.write_with_metadata(&output, &metadata);
+    log::debug!("wrote {}", output);
+    [output].into_iter().chain(aov).collect()
+}
+
+// Turns the radiance into displayable colors. Returns the exposure
+// --auto-exposure picked, in EV
+pub fn post_process(image: &mut Image, options: &Options) -> Option<f32> {
+    if options.bloom > 0.0 {
+        image.bloom(options.bloom, options.bloom_radius);
+    }
+
+    let mut grading = options.grading.clone();
+    let exposure = options.auto_exposure.map(|mode| auto_exposure(image, mode));
+    if let Some(exposure) = exposure {
+        log::info!("exposure {:+.2} EV", exposure);
+        grading.exposure += exposure;
+    }
+    match options.exposure_check {
+        Some(check) => image.exposure_check(&grading, check),
+        None => image.color_correction(&grading),
+    }
+    exposure
+}
@@ -0,0 +1,160 @@
+use glm::Vec3;
+use std::time::Duration;
+
+use crate::options::Options;
+use crate::scene::Scene;
+
+// Machine-readable summary of a render for render farms and CI, e.g.
+//   {"scene": {"input": "assets/scene.txt", "width": 800, ...},
+//    "settings": {...}, "timing_ms": {...}, "samples": {...},
+//    "outputs": [{"path": "/tmp/out.ppm", "bytes": 1920015, "fnv1a64": "..."}]}
+pub fn write_report(
+    path: &str,
+    scene: &Scene,
+    options: &Options,
+    outputs: &[String],
+    load: Duration,
+    render: Duration,
+) {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+    let scene_fields = [
+        ("input", json_string(&options.input)),
+        ("width", width.to_string()),
+        ("height", height.to_string()),
+        ("objects", scene.objects.len().to_string()),
+        ("lights", scene.lights.len().to_string()),
+        ("portals", scene.portals.len().to_string()),
+        // of the bounded objects, after --scene-scale
+        (
+            "bounds",
+            scene.bounds().map_or("null".to_owned(), |bounds| {
+                json_object(&[
+                    ("min", json_vec3(&bounds.min)),
+                    ("max", json_vec3(&bounds.max)),
+                ])
+            }),
+        ),
+    ];
+    let settings = [
+        ("samples", scene.n_samples.to_string()),
+        ("ray_depth", scene.ray_depth.to_string()),
+        (
+            "min_throughput",
+            scene
+                .min_throughput
+                .map_or("null".to_owned(), |min| min.to_string()),
+        ),
+        ("light_samples", scene.light_samples.to_string()),
+        (
+            "regularize",
+            scene
+                .regularize
+                .map_or("null".to_owned(), |min| min.to_string()),
+        ),
+        ("accel", json_string(options.accel.name())),
+        ("bvh_tune", options.bvh_tune.to_string()),
+        ("frustum_cull", options.frustum_cull.to_string()),
+        ("bvh_max_leaf", options.bvh.max_leaf.to_string()),
+        ("bvh_traversal_cost", options.bvh.traversal_cost.to_string()),
+        (
+            "bvh_intersection_cost",
+            options.bvh.intersection_cost.to_string(),
+        ),
+        ("bvh_bins", options.bvh.split_attempts.to_string()),
+        ("seed", options.seed.to_string()),
+        ("aperture", scene.camera.lens.aperture.to_string()),
+        (
+            "focus_distance",
+            scene.camera.lens.focus_distance.to_string(),
+        ),
+        // picked by --auto-exposure, in EV
+        (
+            "auto_exposure",
+            scene
+                .auto_exposure
+                .map_or("null".to_owned(), |ev| ev.to_string()),
+        ),
+        (
+            "irradiance_cache",
+            options
+                .irradiance_cache
+                .map_or("null".to_owned(), |accuracy| accuracy.to_string()),
+        ),
+        ("half_film", options.half_film.to_string()),
+        ("wavefront", options.wavefront.to_string()),
+        ("scanline", options.scanline.to_string()),
+        // as used by the render
+        ("eps_scale", scene.epsilon.scale.to_string()),
+        (
+            "ray_offset_ulps",
+            scene.epsilon.offset_int_scale.to_string(),
+        ),
+        ("scene_scale", options.scene_scale.to_string()),
+        ("threads", rayon::current_num_threads().to_string()),
+    ];
+    let timing = [
+        ("load", format!("{:.3}", ms(load))),
+        ("render", format!("{:.3}", ms(render))),
+        ("total", format!("{:.3}", ms(load + render))),
+    ];
+    // Fewer than requested after Ctrl-C
+    let samples = [
+        ("per_pixel", scene.n_samples.to_string()),
+        ("total", (scene.n_samples * width * height).to_string()),
+    ];
+
+    let outputs = outputs
+        .iter()
+        .filter_map(|path| {
+            let bytes = std::fs::read(path).ok()?;
+            Some(json_object(&[
+                ("path", json_string(path)),
+                ("bytes", bytes.len().to_string()),
+                ("fnv1a64", json_string(&format!("{:016x}", fnv1a(&bytes)))),
+            ]))
+        })
+        .collect::<Vec<_>>();
+
+    let report = json_object(&[
+        ("scene", json_object(&scene_fields)),
+        ("settings", json_object(&settings)),
+        ("timing_ms", json_object(&timing)),
+        ("samples", json_object(&samples)),
+        ("outputs", format!("[{}]", outputs.join(", "))),
+    ]);
+    std::fs::write(path, report + "\n").unwrap();
+}
+
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn json_object(fields: &[(&str, String)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(name, value)| format!("{}: {}", json_string(name), value))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(", "))
+}
+
+pub fn json_vec3(v: &Vec3) -> String {
+    format!("[{}, {}, {}]", v.x, v.y, v.z)
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
@@ -0,0 +1,677 @@
+use glm::{vec3, Vec2, Vec3};
+use itertools::izip;
+use na::{Matrix3, UnitQuaternion};
+use std::sync::Arc;
+
+use crate::camera::{Camera, Lens};
+use crate::environment::Environment;
+use crate::epsilon::Epsilon;
+use crate::image::*;
+use crate::irradiance::IrradianceCache;
+use crate::objects::*;
+use crate::points::Point;
+use crate::random::PathSampler;
+use crate::temporal::History;
+use crate::trace::visible_hit;
+use crate::traversal::{Linear, TraversalBackend};
+
+pub struct Scene {
+    pub ray_depth: usize,
+    pub max_bounces: MaxBounces,
+    // Paths go on while their throughput is above it instead of up to
+    // ray_depth, for biased but less noisy previews
+    pub min_throughput: Option<f32>,
+    // Light samples at every diffuse hit, see trace::sampled_light
+    pub light_samples: usize,
+    // Least roughness of metal and glass after a diffuse bounce, see
+    // trace::specular_roughness
+    pub regularize: Option<f32>,
+    pub n_samples: usize,
+
+    pub image: Image,
+    pub environment: Environment,
+    pub camera: Camera,
+
+    pub objects: Vec<Object<Box<dyn Geometry>>>,
+    // Kind of every object, to rebuild the lights when objects are removed
+    figure_types: Vec<FigureType>,
+    // Emitters and portals, both are sampled directly
+    pub lights: Vec<Box<dyn LightSource>>,
+    pub portals: Vec<PositionedFigure<Rectangle>>,
+    pub clip_planes: Vec<ClipPlane>,
+    // Some emitter is shadowed only by certain objects, or some object
+    // casts no shadows, so diffuse rays look past the objects they hit
+    pub shadow_linking: bool,
+    // Some object is a shadow catcher, so the background is transparent
+    pub shadow_catcher: bool,
+    pub traversal: Box<dyn TraversalBackend>,
+    // Tolerances of the intersection code for the size of the scene
+    pub epsilon: Epsilon,
+    // Over what the camera sees, for camera rays with --frustum-cull.
+    // Rebuilt for every frame
+    pub camera_traversal: Option<Box<dyn TraversalBackend>>,
+    // Built before rendering with --irradiance-cache
+    pub irradiance_cache: Option<IrradianceCache>,
+    // The previous frame, with --reproject
+    pub history: Option<History>,
+    // EV --auto-exposure picked for the last image written, for the report
+    pub auto_exposure: Option<f32>,
+
+    // Radiance by light path (see trace::Radiance::parts), when rendered
+    pub light_paths: Vec<Image>,
+    // Per channel variance of the pixel values, when rendered
+    pub variance: Option<Image>,
+    // Coverage in all channels, when rendered with a shadow catcher
+    pub alpha: Option<Image>,
+}
+
+// Limits of the bounces of each kind along a path, under ray_depth, so
+// glass can refract deeply without as many diffuse bounces
+#[derive(Clone, Copy)]
+pub struct MaxBounces {
+    pub diffuse: usize,
+    pub glossy: usize,
+    pub transmission: usize,
+}
+
+impl Default for MaxBounces {
+    fn default() -> Self {
+        Self {
+            diffuse: usize::MAX,
+            glossy: usize::MAX,
+            transmission: usize::MAX,
+        }
+    }
+}
+
+// Geometry on the side the normal points to is cut away, for all rays
+#[derive(Clone)]
+pub struct ClipPlane {
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+// What the camera sees first through a pixel, see Scene::probe
+pub struct HitInfo {
+    // index into the objects
+    pub object: usize,
+    pub name: Option<String>,
+    pub material: Material,
+    pub albedo: Vec3,
+    pub position: Vec3,
+    // facing the camera
+    pub normal: Vec3,
+    // from the camera position
+    pub distance: f32,
+}
+
+const CLAY_COLOR: f32 = 0.8;
+const POINT_COLOR: f32 = 0.8;
+const GROUND_COLOR: f32 = 0.8;
+const GROUND_CHECKER_COLOR: f32 = 0.2;
+
+impl Scene {
+    // Replaces the image, so the scene can be rendered again from scratch
+    pub fn set_dimensions(&mut self, width: usize, height: usize) {
+        self.image = Image::new(width, height);
+        self.camera.tg_fov_y = height as f32 / width as f32 * self.camera.tg_fov_x;
+    }
+
+    // For picking and measuring in hosts: the first hit of the pinhole ray
+    // through the center of pixel (x, y), counted from the top left, or
+    // None where the background shows or the pixel is outside the image.
+    // Mixed materials are picked as in the first sample of a render with
+    // seed 0
+    pub fn probe(&self, x: usize, y: usize) -> Option<HitInfo> {
+        let (width, height) = (self.image.width, self.image.height);
+        if x >= width || y >= height {
+            return None;
+        }
+        let u = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+        let v = 1.0 - (y as f32 + 0.5) / height as f32 * 2.0;
+        let ray = self.camera.ray_to_point(u, v);
+
+        let (idx, hit, position) = visible_hit(self, &ray, true)?;
+        let object = &self.objects[idx];
+        let mut sampler = PathSampler::new(0, (height - 1 - y) * width + x, 0);
+        sampler.start_bounce(0);
+        let (material, albedo) = object.surface_at(&position, &hit.n, &mut sampler);
+        let normal = if glm::dot(&hit.n, &ray.direction) > 0.0 {
+            -hit.n
+        } else {
+            hit.n
+        };
+        Some(HitInfo {
+            object: idx,
+            name: object.name.clone(),
+            material,
+            albedo,
+            position,
+            normal,
+            distance: hit.t,
+        })
+    }
+
+    // Diffuse horizontal plane, optionally with a checkerboard of the given size
+    pub fn add_ground_plane(&mut self, height: f32, checker_size: Option<f32>) {
+        let mut ground = Object::new(Box::new(Plane { normal: Vec3::y() }) as Box<dyn Geometry>);
+        ground.geometry.position = vec3(0.0, height, 0.0);
+        ground.color = vec3(GROUND_COLOR, GROUND_COLOR, GROUND_COLOR);
+        ground.checker = checker_size.map(|size| Checker {
+            color: vec3(
+                GROUND_CHECKER_COLOR,
+                GROUND_CHECKER_COLOR,
+                GROUND_CHECKER_COLOR,
+            ),
+            size,
+        });
+        self.objects.push(ground);
+        self.figure_types.push(FigureType::Plane);
+    }
+
+    // Removes the objects, and the lights of the emitters among them,
+    // for which keep returns false
+    pub fn retain_objects(&mut self, keep: impl Fn(&Object<Box<dyn Geometry>>) -> bool) {
+        let (objects, figure_types) = std::mem::take(&mut self.objects)
+            .into_iter()
+            .zip(std::mem::take(&mut self.figure_types))
+            .filter(|(obj, _)| keep(obj))
+            .unzip();
+        self.objects = objects;
+        self.figure_types = figure_types;
+        self.update_lights();
+    }
+
+    // Adds a copy of the object that shares its figure, returns its index
+    pub fn duplicate_object(&mut self, idx: usize) -> usize {
+        let obj = &mut self.objects[idx];
+        let placeholder = Box::new(Plane {
+            normal: Vec3::zeros(),
+        });
+        let figure: Arc<dyn Geometry> =
+            Arc::from(std::mem::replace(&mut obj.geometry.figure, placeholder));
+        obj.geometry.figure = Box::new(figure.clone());
+
+        let copy = Object {
+            geometry: PositionedFigure {
+                figure: Box::new(figure) as Box<dyn Geometry>,
+                position: obj.geometry.position,
+                rotation: obj.geometry.rotation,
+            },
+            color: obj.color,
+            checker: obj.checker.clone(),
+            emission: obj.emission,
+            single_sided: obj.single_sided,
+            links: obj.links.clone(),
+            visibility: obj.visibility.clone(),
+            material: obj.material,
+            mix: obj.mix.clone(),
+            roughness: obj.roughness,
+            name: obj.name.clone(),
+            hidden: obj.hidden,
+        };
+        self.objects.push(copy);
+        self.figure_types.push(self.figure_types[idx]);
+        self.update_lights();
+        self.objects.len() - 1
+    }
+
+    // Along the object's own axes, only ellipsoids and boxes can be scaled
+    pub fn scale_object(&mut self, idx: usize, scale: Vec3) {
+        let figure: Box<dyn Geometry> = match &mut self.figure_types[idx] {
+            FigureType::Ellipsoid(radiuses) => {
+                *radiuses = radiuses.component_mul(&scale);
+                Box::new(Ellipsoid {
+                    radiuses: *radiuses,
+                })
+            }
+            FigureType::Parallelipiped(sizes) => {
+                *sizes = sizes.component_mul(&scale);
+                Box::new(Parallelipiped { sizes: *sizes })
+            }
+            _ => panic!("only ellipsoids and boxes can be scaled"),
+        };
+        self.objects[idx].geometry.figure = figure;
+        self.update_lights();
+    }
+
+    // Whether a hit at the point is cut away by the clipping planes or,
+    // for camera rays, the near and far distances
+    pub fn is_clipped(&self, point: &Vec3, camera: bool) -> bool {
+        let cut = self
+            .clip_planes
+            .iter()
+            .any(|plane| glm::dot(&(point - plane.point), &plane.normal) > 0.0);
+        if cut || !camera {
+            return cut;
+        }
+
+        let forward = self.camera.axis.column(2);
+        let depth = glm::dot(&(point - self.camera.position), &forward.normalize());
+        depth < self.camera.near || depth > self.camera.far
+    }
+
+    // Has to be called after emitters or the object list are changed
+    pub fn update_lights(&mut self) {
+        self.lights = lights(&self.figure_types, &self.objects, &self.portals);
+        self.shadow_linking = shadow_linking(&self.objects);
+        self.shadow_catcher = shadow_catcher(&self.objects);
+    }
+
+    // Of the objects that have bounds
+    pub fn bounds(&self) -> Option<Aabb> {
+        let bounds = self
+            .objects
+            .iter()
+            .filter_map(|obj| obj.geometry.bounds())
+            .reduce(|bounds, aabb| bounds.union(&aabb))?;
+        bounds.min.iter().all(|x| x.is_finite()).then_some(bounds)
+    }
+
+    // Multiplies every length by the factor, for scenes authored in other
+    // units. Emission is radiance, which does not change with size
+    pub fn scale(&mut self, factor: f32) {
+        assert!(factor > 0.0, "the scene scale must be positive");
+        for (obj, figure_type) in self.objects.iter_mut().zip(&mut self.figure_types) {
+            let geometry = &mut obj.geometry;
+            geometry.position *= factor;
+            if !geometry.figure.scale(factor) {
+                let placeholder = Box::new(Plane {
+                    normal: Vec3::zeros(),
+                });
+                let figure = std::mem::replace(&mut geometry.figure, placeholder);
+                geometry.figure = Box::new(Scaled { figure, factor });
+            }
+            // the lights are made from these
+            match figure_type {
+                FigureType::Ellipsoid(radiuses) => *radiuses *= factor,
+                FigureType::Parallelipiped(sizes) => *sizes *= factor,
+                FigureType::Plane | FigureType::Csg | FigureType::Sdf => {}
+            }
+            if let Some(checker) = &mut obj.checker {
+                checker.size *= factor;
+            }
+            if let Some(size) = obj.mix.as_mut().and_then(|mix| mix.mask_size.as_mut()) {
+                *size *= factor;
+            }
+        }
+        for portal in &mut self.portals {
+            portal.figure.sizes *= factor;
+            portal.position *= factor;
+        }
+        for plane in &mut self.clip_planes {
+            plane.point *= factor;
+        }
+
+        let camera = &mut self.camera;
+        camera.position *= factor;
+        camera.near *= factor;
+        camera.far *= factor;
+        camera.lens.aperture *= factor;
+        camera.lens.focus_distance *= factor;
+        self.update_lights();
+    }
+
+    // Lights keep their materials, so the lighting stays the same, and
+    // shadow catchers stay
+    pub fn apply_clay_materials(&mut self) {
+        for obj in &mut self.objects {
+            let is_catcher = matches!(obj.material, Material::ShadowCatcher);
+            if glm::length2(&obj.emission) == 0.0 && !is_catcher {
+                obj.material = Material::Diffuse;
+                obj.color = vec3(CLAY_COLOR, CLAY_COLOR, CLAY_COLOR);
+                obj.checker = None;
+                obj.mix = None;
+                obj.roughness = 0.0;
+            }
+        }
+    }
+}
+
+// Collects scene settings and objects, either from a scene file or from code:
+//
+//     let mut builder = SceneBuilder::default();
+//     builder.set_dimensions(64, 64).set_samples(16).set_ray_depth(4);
+//     builder.set_camera(vec3(0.0, 0.0, 5.0), right, up, forward, 1.0);
+//     builder.add_sphere(Vec3::zeros(), 1.0).color = vec3(1.0, 0.5, 0.5);
+//     let scene = builder.build();
+#[derive(Default)]
+pub struct SceneBuilder {
+    image_width: Option<usize>,
+    image_height: Option<usize>,
+    environment: Option<Environment>,
+
+    camera_position: Option<Vec3>,
+    camera_axis: [Option<Vec3>; 3],
+    camera_fov_x: Option<f32>,
+    camera_distortion: f32,
+    camera_vignetting: f32,
+    camera_chromatic_aberration: f32,
+    camera_lens: Lens,
+    camera_clip: Option<(f32, f32)>,
+
+    objects: Vec<Object<Box<dyn Geometry>>>,
+    figure_types: Vec<FigureType>,
+    portals: Vec<PositionedFigure<Rectangle>>,
+    clip_planes: Vec<ClipPlane>,
+    ray_depth: Option<usize>,
+    max_bounces: MaxBounces,
+    n_samples: Option<usize>,
+}
+
+#[derive(Clone, Copy)]
+enum FigureType {
+    Plane,
+    Csg,
+    Sdf,
+    Parallelipiped(Vec3),
+    Ellipsoid(Vec3),
+}
+
+impl SceneBuilder {
+    pub fn set_dimensions(&mut self, width: usize, height: usize) -> &mut Self {
+        self.image_width = Some(width);
+        self.image_height = Some(height);
+        self
+    }
+
+    pub fn set_ray_depth(&mut self, ray_depth: usize) -> &mut Self {
+        self.ray_depth = Some(ray_depth);
+        self
+    }
+
+    pub fn max_bounces(&mut self) -> &mut MaxBounces {
+        &mut self.max_bounces
+    }
+
+    pub fn set_samples(&mut self, n_samples: usize) -> &mut Self {
+        self.n_samples = Some(n_samples);
+        self
+    }
+
+    pub fn set_environment(&mut self, environment: Environment) -> &mut Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn set_camera(
+        &mut self,
+        position: Vec3,
+        right: Vec3,
+        up: Vec3,
+        forward: Vec3,
+        fov_x: f32,
+    ) -> &mut Self {
+        self.set_camera_position(position)
+            .set_camera_right(right)
+            .set_camera_up(up)
+            .set_camera_forward(forward)
+            .set_camera_fov_x(fov_x)
+    }
+
+    pub fn set_camera_position(&mut self, position: Vec3) -> &mut Self {
+        self.camera_position = Some(position);
+        self
+    }
+
+    pub fn set_camera_right(&mut self, right: Vec3) -> &mut Self {
+        self.camera_axis[0] = Some(right);
+        self
+    }
+
+    pub fn set_camera_up(&mut self, up: Vec3) -> &mut Self {
+        self.camera_axis[1] = Some(up);
+        self
+    }
+
+    pub fn set_camera_forward(&mut self, forward: Vec3) -> &mut Self {
+        self.camera_axis[2] = Some(forward);
+        self
+    }
+
+    pub fn set_camera_fov_x(&mut self, fov_x: f32) -> &mut Self {
+        self.camera_fov_x = Some(fov_x);
+        self
+    }
+
+    pub fn set_camera_distortion(&mut self, distortion: f32) -> &mut Self {
+        self.camera_distortion = distortion;
+        self
+    }
+
+    pub fn set_camera_vignetting(&mut self, vignetting: f32) -> &mut Self {
+        self.camera_vignetting = vignetting;
+        self
+    }
+
+    pub fn set_camera_chromatic_aberration(&mut self, strength: f32) -> &mut Self {
+        self.camera_chromatic_aberration = strength;
+        self
+    }
+
+    pub fn set_camera_aperture(&mut self, radius: f32, focus_distance: f32) -> &mut Self {
+        self.camera_lens.aperture = radius;
+        self.camera_lens.focus_distance = focus_distance;
+        self
+    }
+
+    pub fn set_camera_blades(&mut self, blades: usize, rotation: f32) -> &mut Self {
+        self.camera_lens.blades = blades;
+        self.camera_lens.blade_rotation = rotation;
+        self
+    }
+
+    pub fn set_camera_aperture_mask(&mut self, mask: Image) -> &mut Self {
+        self.camera_lens.mask = Some(Arc::new(mask));
+        self
+    }
+
+    pub fn set_camera_clip(&mut self, near: f32, far: f32) -> &mut Self {
+        self.camera_clip = Some((near, far));
+        self
+    }
+
+    pub fn add_clip_plane(&mut self, point: Vec3, normal: Vec3) -> &mut Self {
+        self.clip_planes.push(ClipPlane {
+            point,
+            normal: normal.normalize(),
+        });
+        self
+    }
+
+    // The add_* functions return the new object, so that its position,
+    // material and emission can be set in place
+
+    pub fn add_plane(&mut self, normal: Vec3) -> &mut Object<Box<dyn Geometry>> {
+        self.figure_types.push(FigureType::Plane);
+        self.push_object(Box::new(Plane { normal }))
+    }
+
+    pub fn add_ellipsoid(&mut self, radiuses: Vec3) -> &mut Object<Box<dyn Geometry>> {
+        self.figure_types.push(FigureType::Ellipsoid(radiuses));
+        self.push_object(Box::new(Ellipsoid { radiuses }))
+    }
+
+    pub fn add_box(&mut self, sizes: Vec3) -> &mut Object<Box<dyn Geometry>> {
+        self.figure_types.push(FigureType::Parallelipiped(sizes));
+        self.push_object(Box::new(Parallelipiped { sizes }))
+    }
+
+    pub fn add_csg(&mut self, csg: Csg) -> &mut Object<Box<dyn Geometry>> {
+        self.figure_types.push(FigureType::Csg);
+        self.push_object(Box::new(csg))
+    }
+
+    pub fn add_sdf(&mut self, sdf: Sdf) -> &mut Object<Box<dyn Geometry>> {
+        self.figure_types.push(FigureType::Sdf);
+        self.push_object(Box::new(sdf))
+    }
+
+    pub fn add_sphere(&mut self, center: Vec3, radius: f32) -> &mut Object<Box<dyn Geometry>> {
+        let obj = self.add_ellipsoid(vec3(radius, radius, radius));
+        obj.geometry.position = center;
+        obj
+    }
+
+    // Spherical area light
+    pub fn add_light(
+        &mut self,
+        center: Vec3,
+        radius: f32,
+        emission: Vec3,
+    ) -> &mut Object<Box<dyn Geometry>> {
+        let obj = self.add_sphere(center, radius);
+        obj.emission = emission;
+        obj
+    }
+
+    // Every point becomes a diffuse sphere, gray unless it has a color
+    pub fn add_point_cloud(&mut self, points: &[Point], radius: f32) -> &mut Self {
+        for point in points {
+            let color = point
+                .color
+                .unwrap_or(vec3(POINT_COLOR, POINT_COLOR, POINT_COLOR));
+            self.add_sphere(point.position, radius).color = color;
+        }
+        self
+    }
+
+    // Opening (e.g. a window) through which the environment lights the
+    // scene. It is not an object, rays pass through it, but diffuse
+    // surfaces send part of their samples towards it
+    pub fn add_portal(
+        &mut self,
+        sizes: Vec2,
+        position: Vec3,
+        rotation: UnitQuaternion<f32>,
+    ) -> &mut Self {
+        self.portals.push(PositionedFigure {
+            figure: Rectangle { sizes },
+            position,
+            rotation,
+        });
+        self
+    }
+
+    pub fn last_object(&mut self) -> &mut Object<Box<dyn Geometry>> {
+        self.objects.last_mut().unwrap()
+    }
+
+    fn push_object(&mut self, geometry: Box<dyn Geometry>) -> &mut Object<Box<dyn Geometry>> {
+        self.objects.push(Object::new(geometry));
+        self.last_object()
+    }
+
+    pub fn build(self) -> Scene {
+        let image = Image::new(self.image_width.unwrap(), self.image_height.unwrap());
+
+        let tg_fov_x = (self.camera_fov_x.unwrap() / 2.0).tan();
+        let aspect = image.height as f32 / image.width as f32;
+        let tg_fov_y = aspect * tg_fov_x;
+        let axis = self
+            .camera_axis
+            .into_iter()
+            .map(Option::unwrap)
+            .collect::<Vec<_>>();
+
+        let camera = Camera {
+            position: self.camera_position.unwrap(),
+            axis: Matrix3::from_columns(&axis),
+            tg_fov_x,
+            tg_fov_y,
+            distortion: self.camera_distortion,
+            vignetting: self.camera_vignetting,
+            chromatic_aberration: self.camera_chromatic_aberration,
+            shift: 0.0,
+            lens: self.camera_lens,
+            near: self.camera_clip.map_or(0.0, |(near, _)| near),
+            far: self.camera_clip.map_or(f32::INFINITY, |(_, far)| far),
+        };
+
+        let (objects, figure_types): (Vec<_>, Vec<_>) = self
+            .objects
+            .into_iter()
+            .zip(self.figure_types)
+            .filter(|(obj, _)| !obj.hidden)
+            .unzip();
+        let lights = lights(&figure_types, &objects, &self.portals);
+        let shadow_linking = shadow_linking(&objects);
+        let shadow_catcher = shadow_catcher(&objects);
+
+        let mut scene = Scene {
+            ray_depth: self.ray_depth.unwrap(),
+            max_bounces: self.max_bounces,
+            min_throughput: None,
+            light_samples: 1,
+            regularize: None,
+            n_samples: self.n_samples.unwrap(),
+            image,
+            environment: self.environment.unwrap(),
+            camera,
+            objects,
+            figure_types,
+            lights,
+            portals: self.portals,
+            clip_planes: self.clip_planes,
+            shadow_linking,
+            shadow_catcher,
+            traversal: Box::new(Linear),
+            epsilon: Epsilon::default(),
+            camera_traversal: None,
+            irradiance_cache: None,
+            history: None,
+            auto_exposure: None,
+            light_paths: Vec::new(),
+            variance: None,
+            alpha: None,
+        };
+        scene.epsilon = Epsilon::of_scene(&scene);
+        scene
+    }
+}
+
+// Emitters among the objects, then the portals
+fn lights(
+    figure_types: &[FigureType],
+    objects: &[Object<Box<dyn Geometry>>],
+    portals: &[PositionedFigure<Rectangle>],
+) -> Vec<Box<dyn LightSource>> {
+    izip!(figure_types, objects)
+        .filter_map(|(fig_type, obj)| {
+            if glm::length2(&obj.emission) == 0.0 {
+                return None;
+            }
+            match *fig_type {
+                FigureType::Plane | FigureType::Csg | FigureType::Sdf => None,
+                FigureType::Ellipsoid(radiuses) => Some(Box::new(PositionedFigure {
+                    figure: Ellipsoid { radiuses },
+                    position: obj.geometry.position,
+                    rotation: obj.geometry.rotation,
+                }) as Box<dyn LightSource>),
+                FigureType::Parallelipiped(sizes) => Some(Box::new(PositionedFigure {
+                    figure: Parallelipiped { sizes },
+                    position: obj.geometry.position,
+                    rotation: obj.geometry.rotation,
+                })),
+            }
+        })
+        .chain(
+            portals
+                .iter()
+                .map(|portal| Box::new(portal.clone()) as Box<dyn LightSource>),
+        )
+        .collect()
+}
+
+fn shadow_linking(objects: &[Object<Box<dyn Geometry>>]) -> bool {
+    objects.iter().any(|obj| {
+        !obj.visibility.shadow
+            || glm::length2(&obj.emission) > 0.0 && obj.links.shadowed_by.is_some()
+    })
+}
+
+fn shadow_catcher(objects: &[Object<Box<dyn Geometry>>]) -> bool {
+    objects
+        .iter()
+        .any(|obj| matches!(obj.material, Material::ShadowCatcher))
+}
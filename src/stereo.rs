@@ -0,0 +1,82 @@
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::camera::Camera;
+use crate::image::Image;
+use crate::options::Options;
+use crate::output::render_metadata;
+use crate::render::{post_process, render};
+use crate::scene::Scene;
+
+#[derive(Clone, Copy)]
+pub enum StereoLayout {
+    // OUT_left.ppm and OUT_right.ppm
+    Separate,
+    SideBySide,
+    OverUnder,
+}
+
+impl FromStr for StereoLayout {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "separate" => Ok(StereoLayout::Separate),
+            "side-by-side" => Ok(StereoLayout::SideBySide),
+            "over-under" => Ok(StereoLayout::OverUnder),
+            _ => Err(()),
+        }
+    }
+}
+
+// Renders the scene from both eyes, left eye first (left or top), and
+// returns the files written
+pub fn render_stereo(
+    scene: &mut Scene,
+    options: &Options,
+    layout: StereoLayout,
+    output: &str,
+) -> Vec<String> {
+    let start = Instant::now();
+    let camera = scene.camera.clone();
+    let (width, height) = (scene.image.width, scene.image.height);
+
+    let [left, right] = [-0.5, 0.5].map(|side| {
+        scene.camera = eye(&camera, side * options.interocular, options.convergence);
+        scene.image = Image::new(width, height);
+        render(scene, options);
+        scene.auto_exposure = post_process(&mut scene.image, options);
+        std::mem::replace(&mut scene.image, Image::new(width, height))
+    });
+    scene.camera = camera;
+
+    let metadata = render_metadata(scene, options, start.elapsed());
+    let image = match layout {
+        StereoLayout::Separate => {
+            let paths = eye_paths(output);
+            left.write_with_metadata(&paths[0], &metadata);
+            right.write_with_metadata(&paths[1], &metadata);
+            return paths.to_vec();
+        }
+        StereoLayout::SideBySide => Image::side_by_side(&left, &right),
+        StereoLayout::OverUnder => Image::over_under(&left, &right),
+    };
+    image.write_with_metadata(output, &metadata);
+    vec![output.to_owned()]
+}
+
+// Files of the separate layout
+pub fn eye_paths(output: &str) -> [String; 2] {
+    let (stem, extension) = output.rsplit_once('.').unwrap_or((output, "ppm"));
+    ["left", "right"].map(|eye| format!("{}_{}.{}", stem, eye, extension))
+}
+
+// Camera moved along its right axis. The image window is shifted back, so
+// both eyes see the same window at the convergence distance (off-axis
+// stereo, no vertical parallax)
+fn eye(camera: &Camera, offset: f32, convergence: f32) -> Camera {
+    let mut eye = camera.clone();
+    eye.position += camera.axis.column(0).normalize() * offset;
+    eye.shift = -offset / convergence;
+    eye
+}
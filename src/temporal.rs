@@ -0,0 +1,118 @@
+use glm::Vec3;
+use rayon::prelude::*;
+
+use crate::camera::Camera;
+use crate::image::Image;
+use crate::objects::Material;
+use crate::scene::Scene;
+use crate::trace::visible_hit;
+
+// Previous frames of a camera path reprojected into the current one as a
+// starting estimate (--reproject), for previews with fewer samples per
+// frame. Only view-independent surfaces are reused, where the same object
+// is seen at about the same point with about the same normal
+
+// Largest distance between the reprojected points, in pixels at their
+// distance from the camera
+const MAX_POINT_OFFSET: f32 = 2.0;
+const MIN_NORMAL_COS: f32 = 0.9;
+
+#[derive(Clone, Copy)]
+pub struct Hit {
+    object: usize,
+    point: Vec3,
+    normal: Vec3,
+}
+
+// The last frame, with its accumulated radiance before post-processing
+pub struct History {
+    camera: Camera,
+    image: Image,
+    hits: Vec<Option<Hit>>,
+}
+
+impl History {
+    pub fn new(scene: &Scene, hits: Vec<Option<Hit>>) -> Self {
+        Self {
+            camera: scene.camera.clone(),
+            image: scene.image.clone(),
+            hits,
+        }
+    }
+}
+
+// First hits through the pixel centers
+pub fn primary_hits(scene: &Scene) -> Vec<Option<Hit>> {
+    let (width, height) = (scene.image.width, scene.image.height);
+    (0..width * height)
+        .into_par_iter()
+        .map(|idx| {
+            let u = ((idx % width) as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let v = ((idx / width) as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+            let ray = scene.camera.ray_to_point(u, v);
+            let (object, hit, point) = visible_hit(scene, &ray, true)?;
+            Some(Hit {
+                object,
+                point,
+                normal: hit.n,
+            })
+        })
+        .collect()
+}
+
+// Blends the history into the rendered image where it is valid, counting
+// it as that many samples
+pub fn reproject(scene: &mut Scene, history: &History, hits: &[Option<Hit>], samples: f32) {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let n = scene.n_samples as f32;
+    let mut reused = 0;
+    for (idx, hit) in hits.iter().enumerate() {
+        let Some(hit) = hit.filter(|hit| is_view_independent(scene, hit.object)) else {
+            continue;
+        };
+        let Some(previous) = history_color(scene, history, &hit) else {
+            continue;
+        };
+        let (i, j) = (idx % width, idx / width);
+        let blended = (scene.image.get(i, j) * n + previous * samples) / (n + samples);
+        scene.image.set(i, j, blended);
+        reused += 1;
+    }
+    log::info!("reprojected {} of {} pixels", reused, width * height);
+}
+
+// Bilinear over the neighboring pixels that pass the tests
+fn history_color(scene: &Scene, history: &History, hit: &Hit) -> Option<Vec3> {
+    let (width, height) = (history.image.width, history.image.height);
+    let (u, v) = history.camera.project(&hit.point)?;
+    let x = ((u + 1.0) / 2.0 * width as f32 - 0.5).clamp(0.0, (width - 1) as f32);
+    let y = ((v + 1.0) / 2.0 * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+    let (i0, j0) = (x as usize, y as usize);
+    let (tx, ty) = (x - i0 as f32, y - j0 as f32);
+
+    let footprint = glm::distance(&hit.point, &scene.camera.position) * 2.0 * scene.camera.tg_fov_x
+        / scene.image.width as f32;
+    let is_valid = |previous: &Hit| {
+        previous.object == hit.object
+            && glm::distance(&previous.point, &hit.point) < MAX_POINT_OFFSET * footprint
+            && glm::dot(&previous.normal, &hit.normal) > MIN_NORMAL_COS
+    };
+
+    let mut sum = Vec3::zeros();
+    let mut total = 0.0;
+    for (di, dj) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        let (i, j) = ((i0 + di).min(width - 1), (j0 + dj).min(height - 1));
+        let weight = (if di == 1 { tx } else { 1.0 - tx }) * (if dj == 1 { ty } else { 1.0 - ty });
+        if weight > 0.0 && history.hits[j * width + i].is_some_and(|previous| is_valid(&previous)) {
+            sum += history.image.get(i, j) * weight;
+            total += weight;
+        }
+    }
+    (total > 0.0).then(|| sum / total)
+}
+
+// Lambertian objects without a second material look the same from any side
+fn is_view_independent(scene: &Scene, idx: usize) -> bool {
+    let obj = &scene.objects[idx];
+    matches!(obj.material, Material::Diffuse) && obj.mix.is_none() && obj.roughness == 0.0
+}
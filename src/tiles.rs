@@ -0,0 +1,165 @@
+use std::str::FromStr;
+
+pub const TILE_SIZE: usize = 32;
+
+#[derive(Clone, Copy)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    // In Z-order of the image coordinates, which the network protocol
+    // relies on
+    pub fn pixels(&self) -> Vec<(usize, usize)> {
+        let mut pixels = (self.y0..self.y1)
+            .flat_map(|j| (self.x0..self.x1).map(move |i| (i, j)))
+            .collect::<Vec<_>>();
+        pixels.sort_unstable_by_key(|&(i, j)| morton(i, j));
+        pixels
+    }
+}
+
+// In Z-order of the tiles
+pub fn split_into_tiles(width: usize, height: usize) -> Vec<Tile> {
+    let mut tiles = (0..height)
+        .step_by(TILE_SIZE)
+        .flat_map(|y0| {
+            (0..width).step_by(TILE_SIZE).map(move |x0| Tile {
+                x0,
+                y0,
+                x1: (x0 + TILE_SIZE).min(width),
+                y1: (y0 + TILE_SIZE).min(height),
+            })
+        })
+        .collect::<Vec<_>>();
+    tiles.sort_unstable_by_key(|tile| morton(tile.x0 / TILE_SIZE, tile.y0 / TILE_SIZE));
+    tiles
+}
+
+// Which tiles are rendered first, locally and by the workers of a
+// network render, so the important part of the image is done early
+#[derive(Clone, Copy)]
+pub enum TileOrder {
+    ZOrder,
+    // outwards from the image center
+    Center,
+    // outwards from an image point, 0..1 from the top left
+    Point(f32, f32),
+}
+
+impl FromStr for TileOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zorder" => Ok(TileOrder::ZOrder),
+            "center" => Ok(TileOrder::Center),
+            _ => {
+                let (u, v) = s.split_once(',').ok_or(())?;
+                let u = u.parse::<f32>().map_err(|_| ())?;
+                let v = v.parse::<f32>().map_err(|_| ())?;
+                Ok(TileOrder::Point(u, v))
+            }
+        }
+    }
+}
+
+// Stable, so tiles as far from the point stay in Z-order
+pub fn sort_tiles(tiles: &mut [Tile], order: TileOrder, width: usize, height: usize) {
+    let (u, v) = match order {
+        TileOrder::ZOrder => return,
+        TileOrder::Center => (0.5, 0.5),
+        TileOrder::Point(u, v) => (u, v),
+    };
+    // Image rows go from the bottom
+    let (x, y) = (u * width as f32, (1.0 - v) * height as f32);
+    let distance = |tile: &Tile| {
+        let dx = (tile.x0 + tile.x1) as f32 / 2.0 - x;
+        let dy = (tile.y0 + tile.y1) as f32 / 2.0 - y;
+        dx * dx + dy * dy
+    };
+    tiles.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+}
+
+// Z-order (Morton) key, neighbors in the image mostly stay close in it
+fn morton(i: usize, j: usize) -> u64 {
+    fn spread(x: usize) -> u64 {
+        let mut x = x as u64 & 0xffff_ffff;
+        x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+        x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+        x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+        (x | (x << 1)) & 0x5555_5555_5555_5555
+    }
+    spread(i) | (spread(j) << 1)
+}
+
+// Indices of all the pixels in Z-order, so the rays each thread gets are
+// bunched together rather than in long rows, and reuse more of the same
+// BVH nodes while they are in cache
+pub fn morton_order(width: usize, height: usize) -> Vec<usize> {
+    let mut order = (0..width * height).collect::<Vec<_>>();
+    order.sort_unstable_by_key(|&idx| morton(idx % width, idx / width));
+    order
+}
+
+// Indices of all the pixels tile by tile, the tiles sorted by the order
+// and the pixels of each in Z-order
+pub fn tile_order(width: usize, height: usize, order: TileOrder) -> Vec<usize> {
+    let mut tiles = split_into_tiles(width, height);
+    sort_tiles(&mut tiles, order, width, height);
+    tiles
+        .iter()
+        .flat_map(|tile| tile.pixels().into_iter().map(|(i, j)| j * width + i))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn center_first() {
+        // 3x3 tiles, the middle one is the fifth in Z-order
+        let (width, height) = (3 * TILE_SIZE, 3 * TILE_SIZE);
+        let mut tiles = split_into_tiles(width, height);
+        sort_tiles(&mut tiles, TileOrder::Center, width, height);
+        assert_eq!((tiles[0].x0, tiles[0].y0), (TILE_SIZE, TILE_SIZE));
+
+        let order = tile_order(width, height, TileOrder::Center);
+        let first = order[..TILE_SIZE * TILE_SIZE]
+            .iter()
+            .map(|&idx| (idx % width, idx / width));
+        for (i, j) in first {
+            assert!((TILE_SIZE..2 * TILE_SIZE).contains(&i));
+            assert!((TILE_SIZE..2 * TILE_SIZE).contains(&j));
+        }
+    }
+
+    #[test]
+    fn point_first() {
+        // Image rows go from the bottom, so the top left point is in the
+        // last row of tiles
+        let (width, height) = (4 * TILE_SIZE, 2 * TILE_SIZE);
+        let mut tiles = split_into_tiles(width, height);
+        sort_tiles(&mut tiles, TileOrder::Point(0.0, 0.0), width, height);
+        assert_eq!((tiles[0].x0, tiles[0].y0), (0, TILE_SIZE));
+    }
+
+    #[test]
+    fn every_pixel_once() {
+        let (width, height) = (100, 70);
+        for order in [
+            TileOrder::ZOrder,
+            TileOrder::Center,
+            TileOrder::Point(0.9, 0.2),
+        ] {
+            let mut pixels = tile_order(width, height, order);
+            pixels.sort();
+            assert_eq!(pixels, (0..width * height).collect::<Vec<_>>());
+        }
+    }
+}
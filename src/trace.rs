@@ -1,131 +1,816 @@
 use std::f32::consts::PI;
 
 use glm::Vec3;
-use rand::Rng;
+use na::{Complex, ComplexField};
 
-use crate::objects::{Geometry, Material, Object, RayIntersection};
-use crate::random::{ToLight, MIS};
+use crate::albedo::{dielectric_albedo, reflection_albedo};
+use crate::epsilon::{MIN_COS, MIN_PDF};
+use crate::image::luminance;
+use crate::irradiance::is_cached;
+use crate::objects::{Fresnel, Material, RayIntersection};
+use crate::profile_scope;
+use crate::random::{Microfacet, PathSampler, ToLight, MIS};
 use crate::ray::Ray;
-use crate::Scene;
+use crate::scene::Scene;
 
-pub fn trace_ray(scene: &mut Scene, ray: &Ray, depth: usize) -> Vec3 {
-    if depth >= scene.ray_depth {
-        return Vec3::zeros();
+// Radiance split by the light path it came along. The first hit decides
+// the category: light emitted there (or the environment), light of the
+// next hit's emission reflected diffusely, the rest reflected diffusely,
+// and everything reflected or refracted specularly
+#[derive(Clone, Copy, Default)]
+pub struct Radiance {
+    pub emitted: Vec3,
+    pub diffuse_direct: Vec3,
+    pub diffuse_indirect: Vec3,
+    pub specular: Vec3,
+    // Coverage of the first hit: 1 for objects, 0 for the background and
+    // the blocked fraction of the light for shadow catchers
+    pub alpha: f32,
+}
+
+impl Radiance {
+    pub fn total(&self) -> Vec3 {
+        self.emitted + self.diffuse_direct + self.diffuse_indirect + self.specular
     }
 
-    let Some((idx, intersection)) = intersect_with_objects(&scene.objects, ray, f32::INFINITY)
-    else {
-        return scene.background_color;
+    pub fn parts(&self) -> [Vec3; 4] {
+        [
+            self.emitted,
+            self.diffuse_direct,
+            self.diffuse_indirect,
+            self.specular,
+        ]
+    }
+
+    pub fn map(&self, f: impl Fn(&Vec3) -> Vec3) -> Self {
+        Self {
+            emitted: f(&self.emitted),
+            diffuse_direct: f(&self.diffuse_direct),
+            diffuse_indirect: f(&self.diffuse_indirect),
+            specular: f(&self.specular),
+            alpha: self.alpha,
+        }
+    }
+
+    // This radiance arriving at the previous hit and reflected there
+    fn reflected(&self, weight: &Vec3, is_specular: bool) -> Self {
+        let mut result = Self::default();
+        if is_specular {
+            result.specular = self.total().component_mul(weight);
+        } else {
+            result.diffuse_direct = self.emitted.component_mul(weight);
+            result.diffuse_indirect = (self.total() - self.emitted).component_mul(weight);
+        }
+        result
+    }
+}
+
+// Bounces of a path so far, in total and by kind, for the depth limits
+#[derive(Clone, Copy)]
+pub struct PathState {
+    pub depth: usize,
+    pub diffuse: usize,
+    // metal and dielectric reflections
+    pub glossy: usize,
+    pub transmission: usize,
+    // product of the bounce weights so far
+    pub throughput: Vec3,
+    // The light emitted at the next hit was estimated by light samples at
+    // the previous one and isn't added again
+    pub light_sampled: bool,
+}
+
+impl Default for PathState {
+    fn default() -> Self {
+        Self {
+            depth: 0,
+            diffuse: 0,
+            glossy: 0,
+            transmission: 0,
+            throughput: Vec3::repeat(1.0),
+            light_sampled: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Bounce {
+    Diffuse,
+    Glossy,
+    Transmission,
+}
+
+impl Bounce {
+    pub fn is_specular(&self) -> bool {
+        !matches!(self, Bounce::Diffuse)
+    }
+}
+
+impl PathState {
+    pub fn bounce(self, kind: Bounce, weight: &Vec3) -> Self {
+        let mut next = Self {
+            depth: self.depth + 1,
+            throughput: self.throughput.component_mul(weight),
+            light_sampled: false,
+            ..self
+        };
+        match kind {
+            Bounce::Diffuse => next.diffuse += 1,
+            Bounce::Glossy => next.glossy += 1,
+            Bounce::Transmission => next.transmission += 1,
+        }
+        next
+    }
+
+    pub fn is_done(&self, scene: &Scene) -> bool {
+        let too_deep = match scene.min_throughput {
+            // Mirrors facing each other never lose throughput
+            Some(min) => self.depth >= MAX_THROUGHPUT_DEPTH || self.throughput.max() < min,
+            None => self.depth >= scene.ray_depth,
+        };
+        let max = &scene.max_bounces;
+        too_deep
+            || self.diffuse > max.diffuse
+            || self.glossy > max.glossy
+            || self.transmission > max.transmission
+    }
+}
+
+// Radiance arriving along a ray that leaves the object after a diffuse
+// bounce from the camera, for the irradiance cache
+pub fn trace_bounce(scene: &Scene, ray: &Ray, source: usize, sampler: &mut PathSampler) -> Vec3 {
+    let path = PathState {
+        depth: 2,
+        diffuse: 2,
+        ..Default::default()
+    };
+    trace_from(scene, ray, path, Some(source), sampler).total()
+}
+
+pub fn trace_ray(scene: &Scene, ray: &Ray, depth: usize, sampler: &mut PathSampler) -> Vec3 {
+    trace_path(scene, ray, depth, sampler).total()
+}
+
+pub fn trace_path(scene: &Scene, ray: &Ray, depth: usize, sampler: &mut PathSampler) -> Radiance {
+    let path = PathState {
+        depth,
+        ..Default::default()
+    };
+    trace_from(scene, ray, path, None, sampler)
+}
+
+// Light samples per camera sample of a shadow catcher
+const SHADOW_CATCHER_SAMPLES: usize = 4;
+
+// Bounces at most when paths end by throughput
+const MAX_THROUGHPUT_DEPTH: usize = 64;
+
+// Objects that shadow-linked emitters and emitters behind objects that
+// cast no shadows are seen through, per ray
+const MAX_UNSHADOWED_HITS: usize = 8;
+
+// source is the diffuse object the ray leaves, for light linking
+fn trace_from(
+    scene: &Scene,
+    ray: &Ray,
+    path: PathState,
+    source: Option<usize>,
+    sampler: &mut PathSampler,
+) -> Radiance {
+    if path.is_done(scene) {
+        return Radiance::default();
+    }
+    let depth = path.depth;
+    sampler.start_bounce(depth);
+
+    let Some((idx, intersection, point)) = visible_hit(scene, ray, depth == 0) else {
+        // Left transparent to composite the objects and shadows over
+        if depth == 0 && scene.shadow_catcher {
+            return Radiance::default();
+        }
+        if path.light_sampled {
+            return Radiance::default();
+        }
+        return Radiance {
+            emitted: scene.environment.radiance(&ray.direction),
+            ..Default::default()
+        };
     };
 
-    let point = ray.origin + intersection.t * ray.direction;
+    profile_scope!("shading");
     let normal = intersection.n;
-    let emitted = scene.objects[idx].emission;
+    let emitted = if path.light_sampled {
+        Vec3::zeros()
+    } else {
+        hit_emission(scene, ray, idx, &intersection, point, source)
+    };
+    let (material, albedo) = scene.objects[idx].surface_at(&point, &normal, sampler);
 
-    let color = match scene.objects[idx].material {
-        Material::Diffuse => {
-            let color_obj = scene.objects[idx].color / PI;
+    if depth == 0 && matches!(material, Material::ShadowCatcher) {
+        return Radiance {
+            alpha: shadow_density(scene, &point, &normal, sampler),
+            ..Default::default()
+        };
+    }
 
-            let distribution = MIS {
-                to_light: ToLight {
-                    lights: &scene.lights,
-                },
-            };
+    if let Some(reflected) = cached_light(scene, &path, idx, material, &point, &normal, &albedo) {
+        return Radiance {
+            emitted,
+            diffuse_indirect: reflected,
+            alpha: 1.0,
+            ..Default::default()
+        };
+    }
 
-            let new_dir = distribution.sample(&point, &normal, &mut scene.generator);
-            if glm::dot(&new_dir, &normal) < 0.0 {
-                Vec3::zeros()
-            } else {
-                let pdf = distribution.pdf(&point, &normal, &new_dir);
-                if !pdf.is_finite() || pdf < 1e-6 {
-                    Vec3::zeros()
-                } else {
-                    let new_ray = Ray::new_shifted(point, new_dir);
-                    let cos = glm::dot(&normal, &new_ray.direction);
+    let color = match scatter(
+        scene,
+        ray,
+        &path,
+        idx,
+        &intersection,
+        &point,
+        material,
+        &albedo,
+        sampler,
+    ) {
+        Some(scatter) => {
+            let source = matches!(scatter.kind, Bounce::Diffuse).then_some(idx);
+            let next = path.bounce(scatter.kind, &scatter.weight);
+            let direct = sampled_light(
+                scene, ray, idx, &scatter, &next, &point, &normal, &albedo, sampler,
+            );
+            let next = PathState {
+                light_sampled: direct.is_some(),
+                ..next
+            };
+            let color_in = trace_from(scene, &scatter.ray, next, source, sampler);
+            let mut color = color_in.reflected(&scatter.weight, scatter.kind.is_specular());
+            color.diffuse_direct += direct.unwrap_or_default();
+            color
+        }
+        None => Radiance::default(),
+    };
 
-                    let color_in = trace_ray(scene, &new_ray, depth + 1);
+    Radiance {
+        emitted,
+        alpha: 1.0,
+        ..color
+    }
+}
 
-                    color_in.component_mul(&color_obj) * cos / pdf
-                }
+// Where a path goes on from a hit, with the weight of the radiance coming
+// back along the new ray. None ends it
+pub struct Scatter {
+    pub ray: Ray,
+    pub weight: Vec3,
+    pub kind: Bounce,
+}
 
-            }
+#[allow(clippy::too_many_arguments)]
+pub fn scatter(
+    scene: &Scene,
+    ray: &Ray,
+    path: &PathState,
+    idx: usize,
+    intersection: &RayIntersection,
+    point: &Vec3,
+    material: Material,
+    albedo: &Vec3,
+    sampler: &mut PathSampler,
+) -> Option<Scatter> {
+    let normal = intersection.n;
+    let roughness = specular_roughness(scene, path, idx);
+    // Camera rays come without the epsilon, the new rays take it from here
+    let ray = &ray.clone().with_epsilon(scene.epsilon);
+    match material {
+        Material::Diffuse | Material::ShadowCatcher => {
+            let distribution = light_distribution(scene);
+            let strategy = sampler.next_index(distribution.n_strategies());
+            let (ray, weight) =
+                diffuse_sample(scene, ray, idx, point, &normal, albedo, strategy, sampler)?;
+            Some(Scatter {
+                ray,
+                weight,
+                kind: Bounce::Diffuse,
+            })
+        }
+        Material::Metallic { fresnel } if roughness > 0.0 => {
+            let microfacet = Microfacet {
+                alpha: roughness.powi(2),
+            };
+            scatter_rough_metal(
+                ray,
+                point,
+                &normal,
+                fresnel.as_ref(),
+                albedo,
+                &microfacet,
+                sampler,
+            )
         }
-        Material::Metallic => {
-            let reflected_ray = get_reflected_ray(&ray.direction, &point, &normal);
-            let color = trace_ray(scene, &reflected_ray, depth + 1);
-            color.component_mul(&scene.objects[idx].color)
+        Material::Metallic { fresnel } => {
+            let reflected_ray = get_reflected_ray(ray, point, &normal);
+            let weight = match fresnel {
+                Some(fresnel) => {
+                    let cos = glm::dot(&ray.direction, &normal).abs();
+                    metal_reflectance(&fresnel, albedo, cos)
+                }
+                None => *albedo,
+            };
+            Some(Scatter {
+                ray: reflected_ray,
+                weight,
+                kind: Bounce::Glossy,
+            })
         }
-        Material::Dielectric { ior } => calc_dielectric_color(
-            scene,
+        Material::Dielectric { ior } => scatter_dielectric(
             ray,
-            &point,
+            point,
             &normal,
             intersection.is_inside,
             ior,
-            idx,
-            depth,
+            roughness,
+            albedo,
+            sampler,
         ),
+    }
+}
+
+// Roughness of metal and glass, at least --regularize once the path has
+// bounced diffusely. Blurring them there keeps the caustics that only
+// mirror-like paths reach from turning into fireflies, at some bias
+fn specular_roughness(scene: &Scene, path: &PathState, idx: usize) -> f32 {
+    let roughness = scene.objects[idx].roughness;
+    match scene.regularize {
+        Some(min) if path.diffuse > 0 => roughness.max(min),
+        _ => roughness,
+    }
+}
+
+// A direction off a diffuse surface from a strategy of the light
+// distribution, with the weight of the light coming back along it
+#[allow(clippy::too_many_arguments)]
+fn diffuse_sample(
+    scene: &Scene,
+    ray: &Ray,
+    idx: usize,
+    point: &Vec3,
+    normal: &Vec3,
+    albedo: &Vec3,
+    strategy: usize,
+    sampler: &mut PathSampler,
+) -> Option<(Ray, Vec3)> {
+    let color_obj = albedo / PI;
+    let distribution = light_distribution(scene);
+
+    let new_dir = distribution.sample_strategy(strategy, point, normal, sampler);
+    if glm::dot(&new_dir, normal) < 0.0 {
+        return None;
+    }
+    let pdf = distribution.pdf(point, normal, &new_dir);
+    if !pdf.is_finite() || pdf < MIN_PDF {
+        return None;
+    }
+    let new_ray = Ray::new_offset(*point, normal, new_dir, &scene.epsilon);
+    let cos = glm::dot(normal, &new_ray.direction);
+
+    let mut weight = color_obj * cos / pdf;
+    let roughness = scene.objects[idx].roughness;
+    if roughness > 0.0 {
+        weight *= oren_nayar(normal, &-ray.direction, &new_dir, roughness);
+    }
+    Some((new_ray, weight))
+}
+
+// With --light-samples above 1, the light a diffuse bounce would find
+// emitted at the next hit, reflected and averaged over that many shadow
+// rays from the same mixture of strategies. The strategies go round from
+// a random one, so every strategy gets its share of the rays. None when
+// the path collects the emission itself
+#[allow(clippy::too_many_arguments)]
+pub fn sampled_light(
+    scene: &Scene,
+    ray: &Ray,
+    idx: usize,
+    scatter: &Scatter,
+    next: &PathState,
+    point: &Vec3,
+    normal: &Vec3,
+    albedo: &Vec3,
+    sampler: &mut PathSampler,
+) -> Option<Vec3> {
+    let sampled = scene.light_samples > 1 && matches!(scatter.kind, Bounce::Diffuse);
+    if !sampled || next.is_done(scene) {
+        return None;
+    }
+    profile_scope!("light samples");
+    let n_strategies = light_distribution(scene).n_strategies();
+    let first = sampler.next_index(n_strategies);
+
+    let mut sum = Vec3::zeros();
+    for k in 0..scene.light_samples {
+        let strategy = (first + k) % n_strategies;
+        let Some((shadow_ray, weight)) =
+            diffuse_sample(scene, ray, idx, point, normal, albedo, strategy, sampler)
+        else {
+            continue;
+        };
+        // What trace_from would add at the next hit
+        let light = match visible_hit(scene, &shadow_ray, false) {
+            Some((hit_idx, hit, hit_point)) => {
+                hit_emission(scene, &shadow_ray, hit_idx, &hit, hit_point, Some(idx))
+            }
+            None => scene.environment.radiance(&shadow_ray.direction),
+        };
+        sum += weight.component_mul(&light);
+    }
+    Some(sum / scene.light_samples as f32)
+}
+
+// Light the hit object sends back along the ray, source is the diffuse
+// object the ray leaves
+pub fn hit_emission(
+    scene: &Scene,
+    ray: &Ray,
+    idx: usize,
+    intersection: &RayIntersection,
+    point: Vec3,
+    source: Option<usize>,
+) -> Vec3 {
+    match source {
+        Some(source) if scene.shadow_linking => {
+            emission(scene, idx, intersection, Some(source))
+                + unshadowed_emission(scene, &ray.direction, idx, intersection, point, source)
+        }
+        _ => emission(scene, idx, intersection, source),
+    }
+}
+
+// Light the surface reflects from the irradiance cache, if it covers the
+// point. The first bounce is always traced, so the interpolation is blurred
+#[allow(clippy::too_many_arguments)]
+pub fn cached_light(
+    scene: &Scene,
+    path: &PathState,
+    idx: usize,
+    material: Material,
+    point: &Vec3,
+    normal: &Vec3,
+    albedo: &Vec3,
+) -> Option<Vec3> {
+    let cache = scene.irradiance_cache.as_ref()?;
+    let cached = path.diffuse > 0 && matches!(material, Material::Diffuse) && is_cached(scene, idx);
+    if !cached {
+        return None;
+    }
+    let irradiance = cache.irradiance(idx, point, normal)?;
+    Some(albedo.component_mul(&irradiance) / PI)
+}
+
+// Directions towards the lights, the sun and a constant background,
+// mixed with cosine ones. The background gets its own share of the
+// directions next to emitters, alone the cosine ones suit it best.
+// Portals already sample it where it comes in
+fn light_distribution(scene: &Scene) -> MIS<'_> {
+    let emitters = !scene.lights.is_empty() && scene.portals.is_empty();
+    MIS {
+        to_light: ToLight {
+            lights: &scene.lights,
+            epsilon: scene.epsilon,
+        },
+        to_sun: scene.environment.to_sun(),
+        uniform_sky: emitters && scene.environment.is_uniform_light(),
+    }
+}
+
+// Fraction of the emitter and environment light reaching the point that
+// objects block, from a few samples of it
+pub fn shadow_density(
+    scene: &Scene,
+    point: &Vec3,
+    normal: &Vec3,
+    sampler: &mut PathSampler,
+) -> f32 {
+    let distribution = light_distribution(scene);
+    let (mut unblocked, mut total) = (0.0, 0.0);
+    for _ in 0..SHADOW_CATCHER_SAMPLES {
+        let dir = distribution.sample(point, normal, sampler);
+        let cos = glm::dot(&dir, normal);
+        let pdf = distribution.pdf(point, normal, &dir);
+        if cos <= 0.0 || !pdf.is_finite() || pdf < MIN_PDF {
+            continue;
+        }
+
+        let ray = Ray::new_offset(*point, normal, dir, &scene.epsilon);
+        let (light, blocked) = light_behind_objects(scene, &ray);
+        let weight = luminance(&light) * cos / pdf;
+        total += weight;
+        if !blocked {
+            unblocked += weight;
+        }
+    }
+
+    if total > 0.0 {
+        1.0 - unblocked / total
+    } else {
+        0.0
+    }
+}
+
+// The emitter or environment light along the ray with the objects left
+// out, and whether any that casts shadows is in the way
+fn light_behind_objects(scene: &Scene, ray: &Ray) -> (Vec3, bool) {
+    let mut blocked = false;
+    let mut next = Ray::new(ray.origin, ray.direction);
+    loop {
+        let Some((idx, hit, point)) = visible_hit(scene, &next, false) else {
+            return (scene.environment.radiance(&ray.direction), blocked);
+        };
+        let obj = &scene.objects[idx];
+        if glm::length2(&obj.emission) > 0.0 {
+            return (emission(scene, idx, &hit, None), blocked);
+        }
+        let is_catcher = matches!(obj.material, Material::ShadowCatcher);
+        blocked |= obj.visibility.shadow && !is_catcher;
+        next = Ray::new_offset(point, &hit.n, ray.direction, &scene.epsilon);
+    }
+}
+
+// The closest hit of an object visible to the ray that isn't clipped away
+// and its point, camera rays and the others see different objects. t is
+// along the given ray, the point may be off it past skipped hits
+pub fn visible_hit(
+    scene: &Scene,
+    ray: &Ray,
+    camera: bool,
+) -> Option<(usize, RayIntersection, Vec3)> {
+    profile_scope!("traversal");
+    let ray = &ray.clone().with_epsilon(scene.epsilon);
+    let traversal = match &scene.camera_traversal {
+        Some(culled) if camera => culled,
+        _ => &scene.traversal,
+    };
+    let (idx, hit) = traversal.intersect(&scene.objects, ray)?;
+    let visible = |idx: usize, point: &Vec3| {
+        let visibility = &scene.objects[idx].visibility;
+        let kind = (camera && visibility.camera) || (!camera && visibility.indirect);
+        kind && !scene.is_clipped(point, camera)
     };
+    let mut point = ray.origin + hit.t * ray.direction;
+    if visible(idx, &point) {
+        return Some((idx, hit, point));
+    }
 
-    color + emitted
+    let mut next = Ray::new_offset(point, &hit.n, ray.direction, &scene.epsilon);
+    loop {
+        let (idx, mut hit) = traversal.intersect(&scene.objects, &next)?;
+        point = next.origin + hit.t * next.direction;
+        if visible(idx, &point) {
+            hit.t = glm::dot(&(point - ray.origin), &ray.direction);
+            return Some((idx, hit, point));
+        }
+        next = Ray::new_offset(point, &hit.n, ray.direction, &scene.epsilon);
+    }
+}
+
+// Light the object emits towards the ray, source is the diffuse object
+// the ray leaves
+fn emission(
+    scene: &Scene,
+    idx: usize,
+    intersection: &RayIntersection,
+    source: Option<usize>,
+) -> Vec3 {
+    let obj = &scene.objects[idx];
+    let back = obj.single_sided && intersection.is_inside;
+    let unlinked =
+        source.is_some_and(|source| !obj.links.illuminates(scene.objects[source].name.as_deref()));
+    if back || unlinked {
+        Vec3::zeros()
+    } else {
+        obj.emission
+    }
 }
 
-fn calc_dielectric_color(
-    scene: &mut Scene,
+// Emission of an emitter behind the hit object, when none of the objects
+// in between shadow it
+fn unshadowed_emission(
+    scene: &Scene,
+    direction: &Vec3,
+    idx: usize,
+    intersection: &RayIntersection,
+    point: Vec3,
+    source: usize,
+) -> Vec3 {
+    let is_emitter = |idx: usize| glm::length2(&scene.objects[idx].emission) > 0.0;
+    // An emitter hit first is lit normally
+    if is_emitter(idx) {
+        return Vec3::zeros();
+    }
+
+    let mut occluders = Vec::new();
+    let (mut idx, mut intersection, mut point) = (idx, intersection.clone(), point);
+    for _ in 0..MAX_UNSHADOWED_HITS {
+        if !occluders.contains(&idx) {
+            occluders.push(idx);
+        }
+
+        let ray = Ray::new_offset(point, &intersection.n, *direction, &scene.epsilon);
+        let Some(hit) = visible_hit(scene, &ray, false) else {
+            return Vec3::zeros();
+        };
+        (idx, intersection, point) = hit;
+
+        if is_emitter(idx) {
+            let links = &scene.objects[idx].links;
+            let unshadowed = occluders.iter().all(|&o| {
+                let occluder = &scene.objects[o];
+                !occluder.visibility.shadow || !links.shadowed_by(occluder.name.as_deref())
+            });
+            return if unshadowed {
+                emission(scene, idx, &intersection, Some(source))
+            } else {
+                Vec3::zeros()
+            };
+        }
+    }
+    Vec3::zeros()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn scatter_dielectric(
     ray: &Ray,
     point: &Vec3,
     normal: &Vec3,
     is_inside: bool,
     ior: f32,
-    object_idx: usize,
-    depth: usize,
-) -> Vec3 {
+    roughness: f32,
+    albedo: &Vec3,
+    sampler: &mut PathSampler,
+) -> Option<Scatter> {
     // eta = eta_from / eta_to
     let eta = if is_inside { ior } else { 1.0 / ior };
+    let transmittance = if is_inside {
+        Vec3::repeat(1.0)
+    } else {
+        *albedo
+    };
+    if roughness > 0.0 {
+        let microfacet = Microfacet {
+            alpha: roughness * roughness,
+        };
+        return scatter_rough_dielectric(
+            ray,
+            point,
+            normal,
+            eta,
+            &microfacet,
+            &transmittance,
+            sampler,
+        );
+    }
 
-    let reflected_ray = get_reflected_ray(&ray.direction, point, normal);
-    let maybe_refracetd_ray = get_refracted_ray(&ray.direction, point, normal, eta);
+    let reflected_ray = get_reflected_ray(ray, point, normal);
+    let maybe_refracetd_ray = get_refracted_ray(ray, point, normal, eta);
     let coeff = schilcks_coeff(eta, -glm::dot(&ray.direction, normal));
 
-    if maybe_refracetd_ray.is_some() && (scene.generator.gen::<f32>() < 1.0 - coeff) {
-        let refracted_ray = maybe_refracetd_ray.unwrap();
-        let mut color = trace_ray(scene, &refracted_ray, depth + 1);
-        if !is_inside {
-            color.component_mul_assign(&scene.objects[object_idx].color);
-        }
-        color
+    Some(
+        match maybe_refracetd_ray.filter(|_| sampler.next_1d() < 1.0 - coeff) {
+            Some(refracted_ray) => Scatter {
+                ray: refracted_ray,
+                weight: transmittance,
+                kind: Bounce::Transmission,
+            },
+            None => Scatter {
+                ray: reflected_ray,
+                weight: Vec3::repeat(1.0),
+                kind: Bounce::Glossy,
+            },
+        },
+    )
+}
+
+// Rough diffuse relative to Lambert, in Fujii's form of Oren-Nayar with
+// the roughness in 0..1: brighter towards the light at grazing angles
+fn oren_nayar(normal: &Vec3, to_eye: &Vec3, to_light: &Vec3, roughness: f32) -> f32 {
+    let cos_eye = glm::dot(normal, to_eye).max(0.0);
+    let cos_light = glm::dot(normal, to_light).max(0.0);
+    let s = glm::dot(to_eye, to_light) - cos_eye * cos_light;
+    let t = if s > 0.0 {
+        cos_eye.max(cos_light).max(MIN_COS)
+    } else {
+        1.0
+    };
+
+    let a = 1.0 / (1.0 + (PI / 2.0 - 2.0 / 3.0) * roughness);
+    let b = roughness * a;
+    a + b * s / t
+}
+
+// GGX reflection, with the light scattered between facets more than once
+// added back as in Turquin 2019
+fn scatter_rough_metal(
+    ray: &Ray,
+    point: &Vec3,
+    normal: &Vec3,
+    fresnel: Option<&Fresnel>,
+    albedo: &Vec3,
+    microfacet: &Microfacet,
+    sampler: &mut PathSampler,
+) -> Option<Scatter> {
+    let to_eye = -ray.direction;
+    let normal = if glm::dot(&to_eye, normal) < 0.0 {
+        -normal
     } else {
-        trace_ray(scene, &reflected_ray, depth + 1)
+        *normal
+    };
+    let m = microfacet.sample(&normal, sampler);
+    let cos_i = glm::dot(&to_eye, &m);
+    let cos_n = glm::dot(&to_eye, &normal);
+    let direction = ray.direction + 2.0 * cos_i * m;
+    if cos_i <= 0.0 || cos_n <= 0.0 || glm::dot(&direction, &normal) <= 0.0 {
+        return None;
     }
+
+    let (reflectance, f0) = match fresnel {
+        Some(fresnel) => (
+            metal_reflectance(fresnel, albedo, cos_i),
+            metal_reflectance(fresnel, albedo, 1.0),
+        ),
+        None => (*albedo, *albedo),
+    };
+    let e = reflection_albedo(cos_n, microfacet.alpha);
+    let compensation = f0.map(|f0| 1.0 + f0 * (1.0 - e) / e);
+    let weight = reflectance.component_mul(&compensation)
+        * microfacet.weight(&normal, &to_eye, &direction, &m);
+
+    Some(Scatter {
+        ray: Ray::new_offset(*point, &normal, direction, &ray.epsilon),
+        weight,
+        kind: Bounce::Glossy,
+    })
 }
 
-fn intersect_with_objects(
-    objects: &[Object<Box<dyn Geometry>>],
+// Walter et al. 2007: a facet normal is sampled, then reflection or
+// refraction through it by the Fresnel term, which leaves the weight
+// |i.m| G / (|i.n| |m.n|) for both. Dividing by the albedo of the lobes
+// adds back the light scattered between facets more than once
+fn scatter_rough_dielectric(
     ray: &Ray,
-    max_dist: f32,
-) -> Option<(usize, RayIntersection)> {
-    let ray_length = glm::length(&ray.direction);
-
-    objects
-        .iter()
-        .enumerate()
-        .filter_map(|(i, object)| object.geometry.intersect(ray).map(|res| (i, res)))
-        .filter_map(|(i, res)| {
-            if res.t * ray_length < max_dist {
-                Some((i, res))
-            } else {
-                None
-            }
-        })
-        .min_by(|(_, a), (_, b)| a.t.partial_cmp(&b.t).unwrap())
+    point: &Vec3,
+    normal: &Vec3,
+    eta: f32,
+    microfacet: &Microfacet,
+    transmittance: &Vec3,
+    sampler: &mut PathSampler,
+) -> Option<Scatter> {
+    let to_eye = -ray.direction;
+    let m = microfacet.sample(normal, sampler);
+    let cos_i = glm::dot(&to_eye, &m);
+    let cos_n = glm::dot(&to_eye, normal);
+    if cos_i <= 0.0 || cos_n <= 0.0 {
+        return None;
+    }
+
+    let coeff = schilcks_coeff(eta, cos_i);
+    let refracted = refract(&ray.direction, &m, eta).filter(|_| sampler.next_1d() < 1.0 - coeff);
+    let (kind, direction, tint) = match refracted {
+        Some(direction) => (Bounce::Transmission, direction, *transmittance),
+        None => (
+            Bounce::Glossy,
+            ray.direction + 2.0 * cos_i * m,
+            Vec3::repeat(1.0),
+        ),
+    };
+    // Facets can send the ray to the wrong side of the surface
+    let is_transmission = matches!(kind, Bounce::Transmission);
+    if (glm::dot(&direction, normal) < 0.0) != is_transmission {
+        return None;
+    }
+
+    let compensation = dielectric_albedo(cos_n, microfacet.alpha, eta);
+    let weight = tint * (microfacet.weight(normal, &to_eye, &direction, &m) / compensation);
+
+    Some(Scatter {
+        ray: Ray::new_offset(*point, normal, direction, &ray.epsilon),
+        weight,
+        kind,
+    })
 }
 
-fn get_reflected_ray(direction: &Vec3, point: &Vec3, normal: &Vec3) -> Ray {
+fn get_reflected_ray(ray: &Ray, point: &Vec3, normal: &Vec3) -> Ray {
+    let direction = &ray.direction;
     let new_dir = direction - 2.0 * normal * glm::dot(direction, normal);
-    Ray::new_shifted(*point, new_dir)
+    Ray::new_offset(*point, normal, new_dir, &ray.epsilon)
+}
+
+fn get_refracted_ray(ray: &Ray, point: &Vec3, normal: &Vec3, eta: f32) -> Option<Ray> {
+    let new_dir = refract(&ray.direction, normal, eta)?;
+    Some(Ray::new_offset(*point, normal, new_dir, &ray.epsilon))
 }
 
-fn get_refracted_ray(direction: &Vec3, point: &Vec3, normal: &Vec3, eta: f32) -> Option<Ray> {
+// The normal faces the incoming direction
+pub fn refract(direction: &Vec3, normal: &Vec3, eta: f32) -> Option<Vec3> {
     assert!((glm::length2(normal) - 1.0) < 1e-5);
     assert!((glm::length2(direction) - 1.0) < 1e-5);
 
@@ -137,13 +822,41 @@ fn get_refracted_ray(direction: &Vec3, point: &Vec3, normal: &Vec3, eta: f32) ->
     }
 
     let cos2 = (1.0 - sin2 * sin2).sqrt();
-    let new_dir = eta * direction + (eta * cos1 - cos2) * normal;
-    Some(Ray::new_shifted(*point, new_dir))
+    Some(eta * direction + (eta * cos1 - cos2) * normal)
 }
 
-fn schilcks_coeff(eta: f32, cos: f32) -> f32 {
+pub fn schilcks_coeff(eta: f32, cos: f32) -> f32 {
     let r0 = (eta - 1.0) / (eta + 1.0);
     let r0 = r0 * r0;
 
     r0 + (1.0 - r0) * (1.0 - cos).powi(5)
 }
+
+fn metal_reflectance(fresnel: &Fresnel, albedo: &Vec3, cos: f32) -> Vec3 {
+    match fresnel {
+        Fresnel::Conductor { eta, k } => {
+            Vec3::from_fn(|i, _| conductor_coeff(Complex::new(eta[i], k[i]), cos))
+        }
+        Fresnel::F82 { tint } => {
+            // The dip below Schlick's curve is largest at cos = 1/7
+            let cos_max = 1.0 / 7.0;
+            let schlick = |f0: f32, cos: f32| f0 + (1.0 - f0) * (1.0 - cos).powi(5);
+            Vec3::from_fn(|i, _| {
+                let at_max = schlick(albedo[i], cos_max);
+                let a = at_max * (1.0 - tint[i]) / (cos_max * (1.0 - cos_max).powi(6));
+                (schlick(albedo[i], cos) - a * cos * (1.0 - cos).powi(6)).clamp(0.0, 1.0)
+            })
+        }
+    }
+}
+
+// Unpolarized reflectance of a conductor with the complex IOR n + ik
+fn conductor_coeff(eta: Complex<f32>, cos: f32) -> f32 {
+    let cos = Complex::from(cos.clamp(0.0, 1.0));
+    let sin2_t = (Complex::from(1.0) - cos * cos) / (eta * eta);
+    let cos_t = (Complex::from(1.0) - sin2_t).sqrt();
+
+    let r_parallel = (eta * cos - cos_t) / (eta * cos + cos_t);
+    let r_perpendicular = (cos - eta * cos_t) / (cos + eta * cos_t);
+    (r_parallel.norm_sqr() + r_perpendicular.norm_sqr()) / 2.0
+}
@@ -0,0 +1,1025 @@
+use glm::Vec3;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use super::TraversalBackend;
+use crate::camera::Frustum;
+use crate::objects::{Aabb, Geometry, Object, RayIntersection};
+use crate::ray::Ray;
+use crate::report::fnv1a;
+
+// Start of the cache files, changed with the format or the build
+const CACHE_MAGIC: &[u8; 8] = b"RTBVH002";
+// Tuning splits leaves visited this many times more than the average one
+// and collapses subtrees entered by less than this fraction of the rays,
+// up to this many objects
+const HOT_LEAF_FACTOR: f32 = 4.0;
+const COLD_FRACTION: f32 = 1e-3;
+const MAX_COLLAPSED: usize = 16;
+
+// Parameters of the SAH build
+#[derive(Clone, Copy)]
+pub struct BvhBuildOptions {
+    // nodes with more objects are always split
+    pub max_leaf: usize,
+    // of visiting a node and of intersecting an object, only the ratio
+    // matters. Smaller nodes become leaves where that is cheaper than the
+    // best split
+    pub traversal_cost: f32,
+    pub intersection_cost: f32,
+    // bins along the widest axis, their borders are the candidate splits
+    pub split_attempts: usize,
+}
+
+impl Default for BvhBuildOptions {
+    fn default() -> Self {
+        Self {
+            max_leaf: 4,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+            split_attempts: 12,
+        }
+    }
+}
+
+impl BvhBuildOptions {
+    pub fn check(&self) {
+        assert!(self.max_leaf >= 1, "the BVH leaf size must be at least 1");
+        assert!(
+            self.traversal_cost >= 0.0 && self.intersection_cost > 0.0,
+            "BVH costs must be positive"
+        );
+        assert!(self.split_attempts >= 2, "the BVH needs at least 2 bins");
+    }
+}
+
+#[derive(Clone, Copy)]
+enum NodeKind {
+    // range of Bvh::objects
+    Leaf { first: u32, count: u32 },
+    Interior { left: u32, right: u32 },
+}
+
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+// Bounds of a child as 8-bit fractions of its parent's bounds. Rounding
+// is conservative, so decoded bounds always contain the real ones
+#[derive(Clone, Copy, Default)]
+struct QuantizedBounds {
+    min: [u8; 3],
+    max: [u8; 3],
+}
+
+struct QuantizedNode {
+    children: [QuantizedBounds; 2],
+    kind: NodeKind,
+}
+
+enum Nodes {
+    Full(Vec<Node>),
+    Quantized(Vec<QuantizedNode>),
+}
+
+// Binned SAH BVH over bounded objects, unbounded ones (planes) are
+// intersected linearly next to it
+pub struct Bvh {
+    nodes: Nodes,
+    bounds: Aabb,
+    objects: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+// The built hierarchy before quantization, as stored in the cache
+struct Tree {
+    nodes: Vec<Node>,
+    objects: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(
+        objects: &[Object<Box<dyn Geometry>>],
+        quantized: bool,
+        options: &BvhBuildOptions,
+    ) -> Self {
+        let (bounded, unbounded) = split_bounded(objects);
+        Self::from_tree(build_tree(bounded, unbounded, options), quantized)
+    }
+
+    // Over the objects that may be in the frustum, for camera rays. The
+    // subtrees outside it are left out rather than skipped
+    pub fn culled(
+        objects: &[Object<Box<dyn Geometry>>],
+        frustum: &Frustum,
+        slack: f32,
+        quantized: bool,
+        options: &BvhBuildOptions,
+    ) -> Self {
+        let (mut bounded, unbounded) = split_bounded(objects);
+        bounded.retain(|(_, aabb)| frustum.overlaps(aabb, slack));
+        Self::from_tree(build_tree(bounded, unbounded, options), quantized)
+    }
+
+    // Bounded and unbounded
+    pub fn n_objects(&self) -> usize {
+        self.objects.len() + self.unbounded.len()
+    }
+
+    // Reads the tree for these objects from the cache directory, or builds
+    // and writes it there. Files are named by a hash of the object bounds
+    // and the build options, the only inputs of the build
+    pub fn cached(
+        objects: &[Object<Box<dyn Geometry>>],
+        quantized: bool,
+        options: &BvhBuildOptions,
+        dir: &str,
+    ) -> Self {
+        let (bounded, unbounded) = split_bounded(objects);
+        let key = cache_key(&bounded, objects.len(), options);
+        let path = Path::new(dir).join(format!("{:016x}.bvh", key));
+
+        let tree = match std::fs::read(&path) {
+            Ok(bytes) => match Tree::decode(&bytes, key, objects.len()) {
+                Some(tree) => {
+                    log::info!("read the BVH from {}", path.display());
+                    Some(tree)
+                }
+                None => {
+                    log::warn!("ignoring the invalid BVH cache {}", path.display());
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let tree = tree.unwrap_or_else(|| {
+            let tree = build_tree(bounded, unbounded, options);
+            let written =
+                std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, tree.encode(key)));
+            if let Err(err) = written {
+                log::warn!("could not write {}: {}", path.display(), err);
+            }
+            tree
+        });
+        Self::from_tree(tree, quantized)
+    }
+
+    // Linear BVH (Lauterbach et al. 2009): objects sorted along a Morton
+    // curve and split at the highest differing bit. Much faster to build
+    // than the SAH one but slower to traverse, for previews
+    pub fn lbvh(objects: &[Object<Box<dyn Geometry>>], options: &BvhBuildOptions) -> Self {
+        let (bounded, unbounded) = split_bounded(objects);
+
+        let centroid_bounds = bounded.iter().fold(Aabb::empty(), |aabb, (_, b)| {
+            let c = centroid(b);
+            aabb.union(&Aabb { min: c, max: c })
+        });
+        let extent = (centroid_bounds.max - centroid_bounds.min).map(|x| x.max(1e-12));
+        let mut keyed = bounded
+            .into_iter()
+            .map(|(i, aabb)| {
+                let p = (centroid(&aabb) - centroid_bounds.min).component_div(&extent);
+                (morton_code(&p), (i, aabb))
+            })
+            .collect::<Vec<_>>();
+        radix_sort(&mut keyed);
+
+        let codes = keyed.iter().map(|(code, _)| *code).collect::<Vec<_>>();
+        let items = keyed.into_iter().map(|(_, item)| item).collect::<Vec<_>>();
+        let mut nodes = Vec::new();
+        if !items.is_empty() {
+            build_morton(&mut nodes, &items, &codes, 0, options.max_leaf);
+        }
+
+        let tree = Tree {
+            nodes,
+            objects: items.into_iter().map(|(i, _)| i).collect(),
+            unbounded,
+        };
+        Self::from_tree(tree, false)
+    }
+
+    // Rebuilds a full BVH from the visit counts of a warm-up render: leaves
+    // visited far more than the average one are split down to single
+    // objects and subtrees rays hardly enter are collapsed into leaves
+    pub fn tuned(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        visits: &[u32],
+        quantized: bool,
+        options: &BvhBuildOptions,
+    ) -> Self {
+        let Nodes::Full(nodes) = &self.nodes else {
+            panic!("only full BVHs can be tuned");
+        };
+        let mut tree = Tree {
+            nodes: Vec::new(),
+            objects: Vec::new(),
+            unbounded: self.unbounded.clone(),
+        };
+        if nodes.is_empty() {
+            return Self::from_tree(tree, quantized);
+        }
+
+        let leaf_visits = nodes
+            .iter()
+            .zip(visits)
+            .filter(|(node, _)| matches!(node.kind, NodeKind::Leaf { .. }))
+            .map(|(_, &v)| v as f32)
+            .collect::<Vec<_>>();
+        let mut tuning = Tuning {
+            old: nodes,
+            old_objects: &self.objects,
+            objects,
+            visits,
+            options: BvhBuildOptions {
+                max_leaf: 1,
+                ..*options
+            },
+            hot: HOT_LEAF_FACTOR * leaf_visits.iter().sum::<f32>() / leaf_visits.len() as f32,
+            cold: COLD_FRACTION * visits[0] as f32,
+            split: 0,
+            collapsed: 0,
+        };
+        tuning.copy(&mut tree, 0);
+
+        log::info!(
+            "tuned the BVH: {} hot leaves split, {} cold subtrees collapsed, {} -> {} nodes",
+            tuning.split,
+            tuning.collapsed,
+            nodes.len(),
+            tree.nodes.len()
+        );
+        Self::from_tree(tree, quantized)
+    }
+
+    fn from_tree(tree: Tree, quantized: bool) -> Self {
+        let bounds = tree
+            .nodes
+            .first()
+            .map_or_else(Aabb::empty, |node| node.bounds);
+        let nodes = if quantized {
+            Nodes::Quantized(quantize(&tree.nodes))
+        } else {
+            Nodes::Full(tree.nodes)
+        };
+
+        Self {
+            nodes,
+            bounds,
+            objects: tree.objects,
+            unbounded: tree.unbounded,
+        }
+    }
+}
+
+struct Tuning<'a> {
+    old: &'a [Node],
+    old_objects: &'a [usize],
+    objects: &'a [Object<Box<dyn Geometry>>],
+    visits: &'a [u32],
+    // for splitting hot leaves
+    options: BvhBuildOptions,
+    hot: f32,
+    cold: f32,
+    split: usize,
+    collapsed: usize,
+}
+
+impl Tuning<'_> {
+    // Adds the old node to the tree, returns its new index
+    fn copy(&mut self, tree: &mut Tree, idx: usize) -> usize {
+        let node = &self.old[idx];
+        let visits = self.visits[idx] as f32;
+        let new_idx = tree.nodes.len();
+        let first = tree.objects.len();
+
+        match node.kind {
+            NodeKind::Leaf {
+                first: old_first,
+                count,
+            } => {
+                let range = old_first as usize..(old_first + count) as usize;
+                if visits > self.hot && count > 1 {
+                    let mut items = self.old_objects[range]
+                        .iter()
+                        .map(|&i| (i, self.objects[i].geometry.bounds().unwrap()))
+                        .collect::<Vec<_>>();
+                    build(&mut tree.nodes, &mut items, first, &self.options);
+                    tree.objects.extend(items.into_iter().map(|(i, _)| i));
+                    self.split += 1;
+                } else {
+                    tree.objects.extend(&self.old_objects[range]);
+                    tree.nodes.push(Node {
+                        bounds: node.bounds,
+                        kind: NodeKind::Leaf {
+                            first: first as u32,
+                            count,
+                        },
+                    });
+                }
+            }
+            NodeKind::Interior { left, right } => {
+                let subtree = self.subtree_objects(idx);
+                if visits < self.cold && subtree.len() <= MAX_COLLAPSED {
+                    tree.nodes.push(Node {
+                        bounds: node.bounds,
+                        kind: NodeKind::Leaf {
+                            first: first as u32,
+                            count: subtree.len() as u32,
+                        },
+                    });
+                    tree.objects.extend(subtree);
+                    self.collapsed += 1;
+                } else {
+                    tree.nodes.push(Node {
+                        bounds: node.bounds,
+                        kind: node.kind,
+                    });
+                    let left = self.copy(tree, left as usize);
+                    let right = self.copy(tree, right as usize);
+                    tree.nodes[new_idx].kind = NodeKind::Interior {
+                        left: left as u32,
+                        right: right as u32,
+                    };
+                }
+            }
+        }
+        new_idx
+    }
+
+    fn subtree_objects(&self, idx: usize) -> Vec<usize> {
+        match self.old[idx].kind {
+            NodeKind::Leaf { first, count } => {
+                self.old_objects[first as usize..(first + count) as usize].to_vec()
+            }
+            NodeKind::Interior { left, right } => {
+                let mut result = self.subtree_objects(left as usize);
+                result.extend(self.subtree_objects(right as usize));
+                result
+            }
+        }
+    }
+}
+
+// Counts the nodes the rays of a warm-up render visit, for Bvh::tuned.
+// Clones share the counts
+#[derive(Clone)]
+pub struct VisitCounter {
+    bvh: Arc<Bvh>,
+    visits: Arc<Vec<AtomicU32>>,
+}
+
+impl VisitCounter {
+    pub fn new(bvh: Arc<Bvh>) -> Self {
+        let n_nodes = match &bvh.nodes {
+            Nodes::Full(nodes) => nodes.len(),
+            Nodes::Quantized(nodes) => nodes.len(),
+        };
+        let visits = (0..n_nodes).map(|_| AtomicU32::new(0)).collect();
+        Self {
+            bvh,
+            visits: Arc::new(visits),
+        }
+    }
+
+    pub fn visits(&self) -> Vec<u32> {
+        self.visits
+            .iter()
+            .map(|v| v.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+impl TraversalBackend for VisitCounter {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)> {
+        self.bvh.traverse(objects, ray, |idx| {
+            self.visits[idx].fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+fn split_bounded(objects: &[Object<Box<dyn Geometry>>]) -> (Vec<(usize, Aabb)>, Vec<usize>) {
+    let mut bounded = Vec::new();
+    let mut unbounded = Vec::new();
+    for (i, obj) in objects.iter().enumerate() {
+        match obj.geometry.bounds() {
+            Some(aabb) => bounded.push((i, aabb)),
+            None => unbounded.push(i),
+        }
+    }
+    (bounded, unbounded)
+}
+
+fn build_tree(
+    mut bounded: Vec<(usize, Aabb)>,
+    unbounded: Vec<usize>,
+    options: &BvhBuildOptions,
+) -> Tree {
+    let mut nodes = Vec::new();
+    if !bounded.is_empty() {
+        build(&mut nodes, &mut bounded, 0, options);
+    }
+    Tree {
+        nodes,
+        objects: bounded.into_iter().map(|(i, _)| i).collect(),
+        unbounded,
+    }
+}
+
+fn cache_key(bounded: &[(usize, Aabb)], n_objects: usize, options: &BvhBuildOptions) -> u64 {
+    let mut bytes = CACHE_MAGIC.to_vec();
+    bytes.extend((n_objects as u64).to_le_bytes());
+    bytes.extend((options.max_leaf as u64).to_le_bytes());
+    bytes.extend(options.traversal_cost.to_le_bytes());
+    bytes.extend(options.intersection_cost.to_le_bytes());
+    bytes.extend((options.split_attempts as u64).to_le_bytes());
+    for (i, aabb) in bounded {
+        bytes.extend((*i as u64).to_le_bytes());
+        for x in aabb.min.iter().chain(aabb.max.iter()) {
+            bytes.extend(x.to_le_bytes());
+        }
+    }
+    fnv1a(&bytes)
+}
+
+// Little endian: the magic, the key, then the nodes (bounds, a kind byte
+// and two indices), the object indices and the unbounded ones, each list
+// after its length
+impl Tree {
+    fn encode(&self, key: u64) -> Vec<u8> {
+        let mut bytes = CACHE_MAGIC.to_vec();
+        bytes.extend(key.to_le_bytes());
+
+        bytes.extend((self.nodes.len() as u32).to_le_bytes());
+        for node in &self.nodes {
+            for x in node.bounds.min.iter().chain(node.bounds.max.iter()) {
+                bytes.extend(x.to_le_bytes());
+            }
+            let (kind, a, b) = match node.kind {
+                NodeKind::Leaf { first, count } => (0u8, first, count),
+                NodeKind::Interior { left, right } => (1u8, left, right),
+            };
+            bytes.push(kind);
+            bytes.extend(a.to_le_bytes());
+            bytes.extend(b.to_le_bytes());
+        }
+        for list in [&self.objects, &self.unbounded] {
+            bytes.extend((list.len() as u32).to_le_bytes());
+            for &i in list {
+                bytes.extend((i as u32).to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    // None unless the file is complete, for this key and the indices are
+    // in range
+    fn decode(bytes: &[u8], key: u64, n_objects: usize) -> Option<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(8)? != CACHE_MAGIC || reader.u64()? != key {
+            return None;
+        }
+
+        let n_nodes = reader.u32()? as usize;
+        let mut nodes = Vec::with_capacity(n_nodes.min(bytes.len()));
+        for _ in 0..n_nodes {
+            let mut bounds = [0.0; 6];
+            for x in &mut bounds {
+                *x = reader.f32()?;
+            }
+            let (kind, a, b) = (reader.take(1)?[0], reader.u32()?, reader.u32()?);
+            let kind = match kind {
+                0 => NodeKind::Leaf { first: a, count: b },
+                1 if (a as usize) < n_nodes && (b as usize) < n_nodes => {
+                    NodeKind::Interior { left: a, right: b }
+                }
+                _ => return None,
+            };
+            nodes.push(Node {
+                bounds: Aabb {
+                    min: Vec3::from_fn(|i, _| bounds[i]),
+                    max: Vec3::from_fn(|i, _| bounds[i + 3]),
+                },
+                kind,
+            });
+        }
+
+        let mut list = || -> Option<Vec<usize>> {
+            let n = reader.u32()? as usize;
+            (0..n)
+                .map(|_| reader.u32().map(|i| i as usize))
+                .filter(|i| i.is_none_or(|i| i < n_objects))
+                .collect::<Option<Vec<_>>>()
+                .filter(|list| list.len() == n)
+        };
+        let objects = list()?;
+        let unbounded = list()?;
+
+        let leaves_in_range = nodes.iter().all(|node| match node.kind {
+            NodeKind::Leaf { first, count } => (first + count) as usize <= objects.len(),
+            NodeKind::Interior { .. } => true,
+        });
+        leaves_in_range.then_some(Tree {
+            nodes,
+            objects,
+            unbounded,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
+
+fn build(
+    nodes: &mut Vec<Node>,
+    items: &mut [(usize, Aabb)],
+    first: usize,
+    options: &BvhBuildOptions,
+) -> usize {
+    let idx = nodes.len();
+    let bounds = items
+        .iter()
+        .fold(Aabb::empty(), |aabb, (_, b)| aabb.union(b));
+
+    let leaf = Node {
+        bounds,
+        kind: NodeKind::Leaf {
+            first: first as u32,
+            count: items.len() as u32,
+        },
+    };
+    if items.len() == 1 {
+        nodes.push(leaf);
+        return idx;
+    }
+
+    let centroid_bounds = items.iter().fold(Aabb::empty(), |aabb, (_, b)| {
+        let c = centroid(b);
+        aabb.union(&Aabb { min: c, max: c })
+    });
+    let (axis, extent) = (centroid_bounds.max - centroid_bounds.min).argmax();
+    if extent <= 0.0 {
+        nodes.push(leaf);
+        return idx;
+    }
+
+    let n_bins = options.split_attempts;
+    let bin_of = |aabb: &Aabb| {
+        let x = (centroid(aabb)[axis] - centroid_bounds.min[axis]) / extent;
+        ((x * n_bins as f32) as usize).min(n_bins - 1)
+    };
+
+    let mut bins = vec![(Aabb::empty(), 0); n_bins];
+    for (_, aabb) in items.iter() {
+        let bin = &mut bins[bin_of(aabb)];
+        bin.0 = bin.0.union(aabb);
+        bin.1 += 1;
+    }
+
+    let split_cost = |split: usize| {
+        let (left, right) = bins.split_at(split);
+        let side_cost = |side: &[(Aabb, usize)]| {
+            let (aabb, n) = side
+                .iter()
+                .fold((Aabb::empty(), 0), |(a, n), (b, m)| (a.union(b), n + m));
+            if n == 0 {
+                0.0
+            } else {
+                aabb.surface_area() * n as f32
+            }
+        };
+        side_cost(left) + side_cost(right)
+    };
+    let best_split = (1..n_bins)
+        .min_by(|&a, &b| split_cost(a).partial_cmp(&split_cost(b)).unwrap())
+        .unwrap();
+
+    // Expected costs of a ray entering the node
+    if items.len() <= options.max_leaf {
+        let leaf_cost = items.len() as f32 * options.intersection_cost;
+        let split_cost = options.traversal_cost
+            + split_cost(best_split) / bounds.surface_area() * options.intersection_cost;
+        if leaf_cost <= split_cost {
+            nodes.push(leaf);
+            return idx;
+        }
+    }
+
+    let mut mid = partition(items, |(_, aabb)| bin_of(aabb) < best_split);
+    if mid == 0 || mid == items.len() {
+        mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |(_, a), (_, b)| {
+            centroid(a)[axis].partial_cmp(&centroid(b)[axis]).unwrap()
+        });
+    }
+
+    nodes.push(leaf);
+    let (left_items, right_items) = items.split_at_mut(mid);
+    let left = build(nodes, left_items, first, options);
+    let right = build(nodes, right_items, first + mid, options);
+    nodes[idx].kind = NodeKind::Interior {
+        left: left as u32,
+        right: right as u32,
+    };
+
+    idx
+}
+
+// Items are sorted by their codes
+fn build_morton(
+    nodes: &mut Vec<Node>,
+    items: &[(usize, Aabb)],
+    codes: &[u32],
+    first: usize,
+    max_leaf: usize,
+) -> usize {
+    let idx = nodes.len();
+    let bounds = items
+        .iter()
+        .fold(Aabb::empty(), |aabb, (_, b)| aabb.union(b));
+    nodes.push(Node {
+        bounds,
+        kind: NodeKind::Leaf {
+            first: first as u32,
+            count: items.len() as u32,
+        },
+    });
+    if items.len() <= max_leaf {
+        return idx;
+    }
+
+    // The codes share all bits above the highest one that differs
+    let (lo, hi) = (codes[0], codes[codes.len() - 1]);
+    let mid = if lo == hi {
+        items.len() / 2
+    } else {
+        let bit = 31 - (lo ^ hi).leading_zeros();
+        codes.partition_point(|code| code & (1 << bit) == 0)
+    };
+
+    let left = build_morton(nodes, &items[..mid], &codes[..mid], first, max_leaf);
+    let right = build_morton(nodes, &items[mid..], &codes[mid..], first + mid, max_leaf);
+    nodes[idx].kind = NodeKind::Interior {
+        left: left as u32,
+        right: right as u32,
+    };
+    idx
+}
+
+// 10 bits per coordinate in 0..1, interleaved
+fn morton_code(p: &Vec3) -> u32 {
+    let spread = |x: f32| {
+        let mut v = (x.clamp(0.0, 1.0) * 1023.0) as u32;
+        v = (v | (v << 16)) & 0x030000ff;
+        v = (v | (v << 8)) & 0x0300f00f;
+        v = (v | (v << 4)) & 0x030c30c3;
+        (v | (v << 2)) & 0x09249249
+    };
+    (spread(p.x) << 2) | (spread(p.y) << 1) | spread(p.z)
+}
+
+// Least significant digit first, a byte at a time
+fn radix_sort<T: Copy>(items: &mut Vec<(u32, T)>) {
+    let mut buffer = items.clone();
+    for shift in (0..32).step_by(8) {
+        let digit = |code: u32| ((code >> shift) & 0xff) as usize;
+        let mut offsets = [0; 257];
+        for (code, _) in items.iter() {
+            offsets[digit(*code) + 1] += 1;
+        }
+        for i in 0..256 {
+            offsets[i + 1] += offsets[i];
+        }
+        for item in items.iter() {
+            let slot = &mut offsets[digit(item.0)];
+            buffer[*slot] = *item;
+            *slot += 1;
+        }
+        std::mem::swap(items, &mut buffer);
+    }
+}
+
+fn centroid(aabb: &Aabb) -> Vec3 {
+    (aabb.min + aabb.max) / 2.0
+}
+
+// Moves items satisfying the predicate to the front, returns their count
+fn partition<T>(items: &mut [T], pred: impl Fn(&T) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+fn quantize(nodes: &[Node]) -> Vec<QuantizedNode> {
+    let mut quantized = nodes
+        .iter()
+        .map(|node| QuantizedNode {
+            children: Default::default(),
+            kind: node.kind,
+        })
+        .collect::<Vec<_>>();
+
+    // Children are encoded relative to the decoded parent bounds, the same
+    // ones the traversal will reconstruct
+    let mut stack = nodes
+        .first()
+        .map(|root| (0, root.bounds))
+        .into_iter()
+        .collect::<Vec<_>>();
+    while let Some((idx, parent)) = stack.pop() {
+        let NodeKind::Interior { left, right } = nodes[idx].kind else {
+            continue;
+        };
+
+        for (k, child) in [left as usize, right as usize].into_iter().enumerate() {
+            let q = encode_bounds(&nodes[child].bounds, &parent);
+            quantized[idx].children[k] = q;
+            stack.push((child, decode_bounds(&q, &parent)));
+        }
+    }
+
+    quantized
+}
+
+fn encode_bounds(aabb: &Aabb, parent: &Aabb) -> QuantizedBounds {
+    let mut q = QuantizedBounds::default();
+    for i in 0..3 {
+        let (lo, hi) = (parent.min[i], parent.max[i]);
+        let x = |v: f32| (v - lo) / (hi - lo) * 255.0;
+
+        let mut min = x(aabb.min[i]).floor().clamp(0.0, 255.0) as u8;
+        while min > 0 && decode(min, lo, hi) > aabb.min[i] {
+            min -= 1;
+        }
+        let mut max = x(aabb.max[i]).ceil().clamp(0.0, 255.0) as u8;
+        while max < 255 && decode(max, lo, hi) < aabb.max[i] {
+            max += 1;
+        }
+
+        q.min[i] = min;
+        q.max[i] = max;
+    }
+    q
+}
+
+fn decode_bounds(q: &QuantizedBounds, parent: &Aabb) -> Aabb {
+    Aabb {
+        min: Vec3::from_fn(|i, _| decode(q.min[i], parent.min[i], parent.max[i])),
+        max: Vec3::from_fn(|i, _| decode(q.max[i], parent.min[i], parent.max[i])),
+    }
+}
+
+fn decode(q: u8, lo: f32, hi: f32) -> f32 {
+    match q {
+        0 => lo,
+        255 => hi,
+        _ => lo + (hi - lo) * (q as f32 / 255.0),
+    }
+}
+
+impl TraversalBackend for Bvh {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)> {
+        self.traverse(objects, ray, |_| {})
+    }
+}
+
+impl Bvh {
+    // Calls visit with every node the ray goes into
+    fn traverse(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+        visit: impl Fn(usize),
+    ) -> Option<(usize, RayIntersection)> {
+        let mut closest: Option<(usize, RayIntersection)> = None;
+        let test = |i: usize, closest: &mut Option<(usize, RayIntersection)>| {
+            let Some(res) = objects[i].geometry.intersect(ray) else {
+                return;
+            };
+            let is_closer = closest.as_ref().is_none_or(|(_, c)| res.t < c.t);
+            if is_closer {
+                *closest = Some((i, res));
+            }
+        };
+        // Entry distance of the box if it is entered before the closest hit
+        let enter = |aabb: &Aabb, closest: &Option<(usize, RayIntersection)>| {
+            let (t_near, _) = aabb.intersect(ray)?;
+            let is_closer = closest.as_ref().is_none_or(|(_, c)| t_near < c.t);
+            is_closer.then_some(t_near)
+        };
+        let is_closer = |t_near: f32, closest: &Option<(usize, RayIntersection)>| {
+            closest.as_ref().is_none_or(|(_, c)| t_near < c.t)
+        };
+
+        for &i in &self.unbounded {
+            test(i, &mut closest);
+        }
+
+        let Some(t_near) = enter(&self.bounds, &closest) else {
+            return closest;
+        };
+
+        let mut stack = vec![(0, self.bounds, t_near)];
+        while let Some((idx, bounds, t_near)) = stack.pop() {
+            if !is_closer(t_near, &closest) {
+                continue;
+            }
+            visit(idx);
+
+            let kind = match &self.nodes {
+                Nodes::Full(nodes) => nodes[idx].kind,
+                Nodes::Quantized(nodes) => nodes[idx].kind,
+            };
+
+            match kind {
+                NodeKind::Leaf { first, count } => {
+                    let range = first as usize..(first + count) as usize;
+                    for &i in &self.objects[range] {
+                        test(i, &mut closest);
+                    }
+                }
+                NodeKind::Interior { left, right } => {
+                    let [a, b] = [left, right].map(|child| {
+                        let child = child as usize;
+                        let child_bounds = match &self.nodes {
+                            Nodes::Full(nodes) => nodes[child].bounds,
+                            Nodes::Quantized(nodes) => {
+                                let k = if child == left as usize { 0 } else { 1 };
+                                decode_bounds(&nodes[idx].children[k], &bounds)
+                            }
+                        };
+                        let t_near = enter(&child_bounds, &closest)?;
+                        Some((child, child_bounds, t_near))
+                    });
+
+                    // The nearer child goes on top of the stack
+                    match (a, b) {
+                        (Some(a), Some(b)) if a.2 < b.2 => stack.extend([b, a]),
+                        (Some(a), Some(b)) => stack.extend([a, b]),
+                        (a, b) => stack.extend(a.or(b)),
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::benchmark::benchmark_scene;
+    use crate::traversal::assert_hits_match_linear;
+
+    // Objects of every leaf with the bounds the traversal sees for the leaf
+    fn leaves(bvh: &Bvh) -> Vec<(usize, Aabb)> {
+        let mut found = Vec::new();
+        let mut stack = vec![(0, bvh.bounds)];
+        while let Some((idx, bounds)) = stack.pop() {
+            let kind = match &bvh.nodes {
+                Nodes::Full(nodes) => nodes[idx].kind,
+                Nodes::Quantized(nodes) => nodes[idx].kind,
+            };
+            match kind {
+                NodeKind::Leaf { first, count } => {
+                    let range = first as usize..(first + count) as usize;
+                    found.extend(bvh.objects[range].iter().map(|&i| (i, bounds)));
+                }
+                NodeKind::Interior { left, right } => {
+                    for (k, child) in [left, right].into_iter().enumerate() {
+                        let child = child as usize;
+                        let child_bounds = match &bvh.nodes {
+                            Nodes::Full(nodes) => nodes[child].bounds,
+                            Nodes::Quantized(nodes) => {
+                                decode_bounds(&nodes[idx].children[k], &bounds)
+                            }
+                        };
+                        stack.push((child, child_bounds));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    // Every object is in exactly one leaf inside its bounds, or among the
+    // unbounded, and rays hit what linear traversal hits
+    fn check_tree(bvh: &Bvh, objects: &[Object<Box<dyn Geometry>>]) {
+        let leaves = leaves(bvh);
+        let mut reached = leaves.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+        reached.extend(&bvh.unbounded);
+        reached.sort();
+        assert_eq!(reached, (0..objects.len()).collect::<Vec<_>>());
+        for (i, leaf) in leaves {
+            let aabb = objects[i].geometry.bounds().unwrap();
+            assert_eq!(leaf.union(&aabb).min, leaf.min);
+            assert_eq!(leaf.union(&aabb).max, leaf.max);
+        }
+
+        assert_hits_match_linear(bvh, objects);
+    }
+
+    fn check_build(options: BvhBuildOptions) {
+        let scene = benchmark_scene();
+        for quantized in [false, true] {
+            check_tree(
+                &Bvh::new(&scene.objects, quantized, &options),
+                &scene.objects,
+            );
+        }
+    }
+
+    #[test]
+    fn lbvh() {
+        let scene = benchmark_scene();
+        for max_leaf in [1, 4, 1 << 20] {
+            let options = BvhBuildOptions {
+                max_leaf,
+                ..Default::default()
+            };
+            check_tree(&Bvh::lbvh(&scene.objects, &options), &scene.objects);
+        }
+    }
+
+    #[test]
+    fn one_object_leaves() {
+        check_build(BvhBuildOptions {
+            max_leaf: 1,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn unlimited_leaves() {
+        check_build(BvhBuildOptions {
+            max_leaf: 1 << 20,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn free_traversal() {
+        check_build(BvhBuildOptions {
+            traversal_cost: 0.0,
+            intersection_cost: 1.0,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn costly_traversal() {
+        check_build(BvhBuildOptions {
+            traversal_cost: 1.0,
+            intersection_cost: 1e-3,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn two_bins() {
+        check_build(BvhBuildOptions {
+            split_attempts: 2,
+            ..Default::default()
+        });
+    }
+}
@@ -0,0 +1,172 @@
+use glm::Vec3;
+
+use super::TraversalBackend;
+use crate::objects::{Aabb, Geometry, Object, RayIntersection};
+use crate::ray::Ray;
+
+const MAX_RESOLUTION: usize = 64;
+
+// Uniform grid over bounded objects, unbounded ones (planes) are
+// intersected linearly next to it
+pub struct Grid {
+    bounds: Aabb,
+    resolution: [usize; 3],
+    cell_size: Vec3,
+    cells: Vec<Vec<usize>>,
+    unbounded: Vec<usize>,
+}
+
+impl Grid {
+    pub fn new(objects: &[Object<Box<dyn Geometry>>]) -> Self {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (i, obj) in objects.iter().enumerate() {
+            match obj.geometry.bounds() {
+                Some(aabb) => bounded.push((i, aabb)),
+                None => unbounded.push(i),
+            }
+        }
+
+        let bounds = bounded
+            .iter()
+            .fold(Aabb::empty(), |aabb, (_, b)| aabb.union(b));
+        let diagonal = bounds.max - bounds.min;
+
+        // About 3 * cbrt(n) cells along the widest axis
+        let cells_per_unit = 3.0 * (bounded.len() as f32).cbrt() / diagonal.max();
+        let resolution = [0, 1, 2].map(|i| {
+            let n = (diagonal[i] * cells_per_unit).round();
+            if n.is_finite() {
+                (n as usize).clamp(1, MAX_RESOLUTION)
+            } else {
+                1
+            }
+        });
+        let cell_size = Vec3::from_fn(|i, _| diagonal[i] / resolution[i] as f32);
+
+        let mut grid = Self {
+            bounds,
+            resolution,
+            cell_size,
+            cells: vec![Vec::new(); resolution[0] * resolution[1] * resolution[2]],
+            unbounded,
+        };
+
+        for (i, aabb) in bounded {
+            let lo = grid.cell_of(&aabb.min);
+            let hi = grid.cell_of(&aabb.max);
+            for x in lo[0]..=hi[0] {
+                for y in lo[1]..=hi[1] {
+                    for z in lo[2]..=hi[2] {
+                        let idx = grid.cell_index(&[x, y, z]);
+                        grid.cells[idx].push(i);
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    fn cell_of(&self, p: &Vec3) -> [usize; 3] {
+        [0, 1, 2].map(|i| {
+            let x = (p[i] - self.bounds.min[i]) / self.cell_size[i];
+            if x.is_finite() {
+                (x.max(0.0) as usize).min(self.resolution[i] - 1)
+            } else {
+                0
+            }
+        })
+    }
+
+    fn cell_index(&self, cell: &[usize; 3]) -> usize {
+        (cell[2] * self.resolution[1] + cell[1]) * self.resolution[0] + cell[0]
+    }
+}
+
+impl TraversalBackend for Grid {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)> {
+        let mut closest: Option<(usize, RayIntersection)> = None;
+        let test = |i: usize, closest: &mut Option<(usize, RayIntersection)>| {
+            let Some(res) = objects[i].geometry.intersect(ray) else {
+                return;
+            };
+            let is_closer = closest.as_ref().is_none_or(|(_, c)| res.t < c.t);
+            if is_closer {
+                *closest = Some((i, res));
+            }
+        };
+
+        for &i in &self.unbounded {
+            test(i, &mut closest);
+        }
+
+        let Some((t_enter, t_exit)) = self.bounds.intersect(ray) else {
+            return closest;
+        };
+
+        // 3D-DDA from "A Fast Voxel Traversal Algorithm" (Amanatides, Woo)
+        let p = ray.origin + t_enter * ray.direction;
+        let mut cell = self.cell_of(&p);
+        let mut next_t = [f32::INFINITY; 3];
+        let mut delta_t = [f32::INFINITY; 3];
+        let mut step = [0_isize; 3];
+
+        for i in 0..3 {
+            let d = ray.direction[i];
+            let cell_min = self.bounds.min[i] + cell[i] as f32 * self.cell_size[i];
+            if d > 0.0 {
+                step[i] = 1;
+                next_t[i] = t_enter + (cell_min + self.cell_size[i] - p[i]) / d;
+                delta_t[i] = self.cell_size[i] / d;
+            } else if d < 0.0 {
+                step[i] = -1;
+                next_t[i] = t_enter + (cell_min - p[i]) / d;
+                delta_t[i] = -self.cell_size[i] / d;
+            }
+        }
+
+        loop {
+            for &i in &self.cells[self.cell_index(&cell)] {
+                test(i, &mut closest);
+            }
+
+            let axis = (0..3)
+                .min_by(|&a, &b| next_t[a].partial_cmp(&next_t[b]).unwrap())
+                .unwrap();
+
+            if closest.as_ref().is_some_and(|(_, c)| c.t <= next_t[axis]) {
+                break;
+            }
+            if next_t[axis] > t_exit {
+                break;
+            }
+
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.resolution[axis] as isize {
+                break;
+            }
+            cell[axis] = next as usize;
+            next_t[axis] += delta_t[axis];
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::benchmark::benchmark_scene;
+    use crate::traversal::assert_hits_match_linear;
+
+    #[test]
+    fn hits_match_linear() {
+        let scene = benchmark_scene();
+        assert_hits_match_linear(&Grid::new(&scene.objects), &scene.objects);
+    }
+}
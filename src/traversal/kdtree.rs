@@ -0,0 +1,292 @@
+use super::TraversalBackend;
+use crate::objects::{Aabb, Geometry, Object, RayIntersection};
+use crate::ray::Ray;
+
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 80.0;
+const EMPTY_BONUS: f32 = 0.5;
+const MAX_LEAF_SIZE: usize = 1;
+
+enum Node {
+    Leaf(Vec<usize>),
+    Interior {
+        axis: usize,
+        split: f32,
+        // children[0] is below the split plane, children[1] is above
+        children: [usize; 2],
+    },
+}
+
+#[derive(Clone, Copy)]
+struct Edge {
+    t: f32,
+    object: usize,
+    is_start: bool,
+}
+
+// SAH kd-tree over bounded objects, unbounded ones (planes) are
+// intersected linearly next to it
+pub struct KdTree {
+    nodes: Vec<Node>,
+    bounds: Aabb,
+    unbounded: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn new(objects: &[Object<Box<dyn Geometry>>]) -> Self {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut object_bounds = Vec::with_capacity(objects.len());
+
+        for (i, obj) in objects.iter().enumerate() {
+            let aabb = obj.geometry.bounds();
+            match aabb {
+                Some(_) => bounded.push(i),
+                None => unbounded.push(i),
+            }
+            object_bounds.push(aabb.unwrap_or_else(Aabb::empty));
+        }
+
+        let bounds = bounded
+            .iter()
+            .fold(Aabb::empty(), |aabb, &i| aabb.union(&object_bounds[i]));
+        let max_depth = (8.0 + 1.3 * (bounded.len().max(1) as f32).log2()).round() as usize;
+
+        let mut tree = Self {
+            nodes: Vec::new(),
+            bounds,
+            unbounded,
+        };
+        tree.build(&object_bounds, &bounds, bounded, max_depth);
+        tree
+    }
+
+    fn build(
+        &mut self,
+        object_bounds: &[Aabb],
+        node_bounds: &Aabb,
+        objects: Vec<usize>,
+        depth: usize,
+    ) -> usize {
+        let idx = self.nodes.len();
+        if objects.len() <= MAX_LEAF_SIZE || depth == 0 {
+            self.nodes.push(Node::Leaf(objects));
+            return idx;
+        }
+
+        let Some((axis, edges, offset)) = find_split(object_bounds, node_bounds, &objects) else {
+            self.nodes.push(Node::Leaf(objects));
+            return idx;
+        };
+
+        let split = edges[offset].t;
+        let below = edges[..offset]
+            .iter()
+            .filter(|e| e.is_start)
+            .map(|e| e.object)
+            .collect::<Vec<_>>();
+        let above = edges[offset + 1..]
+            .iter()
+            .filter(|e| !e.is_start)
+            .map(|e| e.object)
+            .collect::<Vec<_>>();
+
+        let mut below_bounds = *node_bounds;
+        below_bounds.max[axis] = split;
+        let mut above_bounds = *node_bounds;
+        above_bounds.min[axis] = split;
+
+        self.nodes.push(Node::Leaf(Vec::new()));
+        let below = self.build(object_bounds, &below_bounds, below, depth - 1);
+        let above = self.build(object_bounds, &above_bounds, above, depth - 1);
+        self.nodes[idx] = Node::Interior {
+            axis,
+            split,
+            children: [below, above],
+        };
+
+        idx
+    }
+}
+
+// Returns the axis, the sorted edges along it and the index of the
+// splitting edge, or None if no split is cheaper than a leaf
+fn find_split(
+    object_bounds: &[Aabb],
+    node_bounds: &Aabb,
+    objects: &[usize],
+) -> Option<(usize, Vec<Edge>, usize)> {
+    let diagonal = node_bounds.max - node_bounds.min;
+    let total_area = node_bounds.surface_area();
+    let n = objects.len();
+
+    let mut best_cost = INTERSECTION_COST * n as f32;
+    let mut best = None;
+
+    for axis in 0..3 {
+        let mut edges = objects
+            .iter()
+            .flat_map(|&object| {
+                let aabb = &object_bounds[object];
+                [
+                    Edge {
+                        t: aabb.min[axis],
+                        object,
+                        is_start: true,
+                    },
+                    Edge {
+                        t: aabb.max[axis],
+                        object,
+                        is_start: false,
+                    },
+                ]
+            })
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| {
+            a.t.partial_cmp(&b.t)
+                .unwrap()
+                .then(b.is_start.cmp(&a.is_start))
+        });
+
+        let (other1, other2) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut n_below = 0;
+        let mut n_above = n;
+        let mut best_offset = None;
+
+        for (offset, edge) in edges.iter().enumerate() {
+            if !edge.is_start {
+                n_above -= 1;
+            }
+
+            if edge.t > node_bounds.min[axis] && edge.t < node_bounds.max[axis] {
+                let cap = diagonal[other1] * diagonal[other2];
+                let ring = diagonal[other1] + diagonal[other2];
+                let below_area = 2.0 * (cap + (edge.t - node_bounds.min[axis]) * ring);
+                let above_area = 2.0 * (cap + (node_bounds.max[axis] - edge.t) * ring);
+
+                let p_below = below_area / total_area;
+                let p_above = above_area / total_area;
+                let bonus = if n_below == 0 || n_above == 0 {
+                    EMPTY_BONUS
+                } else {
+                    0.0
+                };
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST
+                        * (1.0 - bonus)
+                        * (p_below * n_below as f32 + p_above * n_above as f32);
+
+                if cost < best_cost {
+                    best_cost = cost;
+                    best_offset = Some(offset);
+                }
+            }
+
+            if edge.is_start {
+                n_below += 1;
+            }
+        }
+
+        if let Some(offset) = best_offset {
+            best = Some((axis, edges, offset));
+        }
+    }
+
+    best
+}
+
+impl TraversalBackend for KdTree {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)> {
+        let mut closest: Option<(usize, RayIntersection)> = None;
+        let test = |i: usize, closest: &mut Option<(usize, RayIntersection)>| {
+            let Some(res) = objects[i].geometry.intersect(ray) else {
+                return;
+            };
+            let is_closer = closest.as_ref().is_none_or(|(_, c)| res.t < c.t);
+            if is_closer {
+                *closest = Some((i, res));
+            }
+        };
+
+        for &i in &self.unbounded {
+            test(i, &mut closest);
+        }
+
+        let Some((t_min, t_max)) = self.bounds.intersect(ray) else {
+            return closest;
+        };
+
+        let mut stack = Vec::new();
+        let mut node = 0;
+        let (mut t_min, mut t_max) = (t_min, t_max);
+
+        loop {
+            if closest.as_ref().is_some_and(|(_, c)| c.t < t_min) {
+                break;
+            }
+
+            match &self.nodes[node] {
+                Node::Interior {
+                    axis,
+                    split,
+                    children,
+                } => {
+                    let o = ray.origin[*axis];
+                    let d = ray.direction[*axis];
+                    let t_plane = (split - o) / d;
+
+                    // The first child is the one the interval starts in, a
+                    // plane crossed before that only leads out of it
+                    let start = o + d * ray.t_min;
+                    let below_first = start < *split || (start == *split && d <= 0.0);
+                    let (first, second) = if below_first {
+                        (children[0], children[1])
+                    } else {
+                        (children[1], children[0])
+                    };
+
+                    if t_plane > t_max || t_plane <= ray.t_min {
+                        node = first;
+                    } else if t_plane < t_min {
+                        node = second;
+                    } else {
+                        stack.push((second, t_plane, t_max));
+                        node = first;
+                        t_max = t_plane;
+                    }
+                }
+                Node::Leaf(leaf_objects) => {
+                    for &i in leaf_objects {
+                        test(i, &mut closest);
+                    }
+
+                    let Some((next, next_min, next_max)) = stack.pop() else {
+                        break;
+                    };
+                    node = next;
+                    t_min = next_min;
+                    t_max = next_max;
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::benchmark::benchmark_scene;
+    use crate::traversal::assert_hits_match_linear;
+
+    #[test]
+    fn hits_match_linear() {
+        let scene = benchmark_scene();
+        assert_hits_match_linear(&KdTree::new(&scene.objects), &scene.objects);
+    }
+}
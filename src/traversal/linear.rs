@@ -0,0 +1,19 @@
+use super::TraversalBackend;
+use crate::objects::{Geometry, Object, RayIntersection};
+use crate::ray::Ray;
+
+pub struct Linear;
+
+impl TraversalBackend for Linear {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)> {
+        objects
+            .iter()
+            .enumerate()
+            .filter_map(|(i, object)| object.geometry.intersect(ray).map(|res| (i, res)))
+            .min_by(|(_, a), (_, b)| a.t.partial_cmp(&b.t).unwrap())
+    }
+}
@@ -0,0 +1,126 @@
+mod bvh;
+mod grid;
+mod kdtree;
+mod linear;
+
+pub use bvh::*;
+pub use grid::*;
+pub use kdtree::*;
+pub use linear::*;
+
+use std::str::FromStr;
+
+use crate::objects::{Geometry, Object, RayIntersection};
+use crate::ray::Ray;
+
+// Finds the closest object hit by a ray in its interval. Backends only
+// keep indices, the objects themselves are owned by the scene
+pub trait TraversalBackend: Send + Sync {
+    fn intersect(
+        &self,
+        objects: &[Object<Box<dyn Geometry>>],
+        ray: &Ray,
+    ) -> Option<(usize, RayIntersection)>;
+}
+
+#[derive(Clone, Copy)]
+pub enum Accel {
+    Linear,
+    KdTree,
+    Grid,
+    Bvh,
+    QuantizedBvh,
+    // fast to build, for previews
+    Lbvh,
+}
+
+impl FromStr for Accel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Accel::Linear),
+            "kdtree" => Ok(Accel::KdTree),
+            "grid" => Ok(Accel::Grid),
+            "bvh" => Ok(Accel::Bvh),
+            "qbvh" => Ok(Accel::QuantizedBvh),
+            "lbvh" => Ok(Accel::Lbvh),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Accel {
+    // As given to --accel
+    pub fn name(&self) -> &'static str {
+        match self {
+            Accel::Linear => "linear",
+            Accel::KdTree => "kdtree",
+            Accel::Grid => "grid",
+            Accel::Bvh => "bvh",
+            Accel::QuantizedBvh => "qbvh",
+            Accel::Lbvh => "lbvh",
+        }
+    }
+}
+
+pub fn build_bvh(
+    objects: &[Object<Box<dyn Geometry>>],
+    quantized: bool,
+    options: &BvhBuildOptions,
+    cache_dir: Option<&str>,
+) -> Bvh {
+    match cache_dir {
+        Some(dir) => Bvh::cached(objects, quantized, options, dir),
+        None => Bvh::new(objects, quantized, options),
+    }
+}
+
+// BVHs are read from and written to the cache directory when there is one
+pub fn build_traversal(
+    accel: Accel,
+    objects: &[Object<Box<dyn Geometry>>],
+    options: &BvhBuildOptions,
+    cache_dir: Option<&str>,
+) -> Box<dyn TraversalBackend> {
+    let bvh = |quantized: bool| build_bvh(objects, quantized, options, cache_dir);
+    match accel {
+        Accel::Linear => Box::new(Linear),
+        Accel::KdTree => Box::new(KdTree::new(objects)),
+        Accel::Grid => Box::new(Grid::new(objects)),
+        Accel::Bvh => Box::new(bvh(false)),
+        Accel::QuantizedBvh => Box::new(bvh(true)),
+        Accel::Lbvh => Box::new(Bvh::lbvh(objects, options)),
+    }
+}
+
+// Traces random rays through the objects, a third of them with an
+// interval that starts or ends among the objects, and checks the backend
+// hits what linear traversal hits
+#[cfg(test)]
+fn assert_hits_match_linear(backend: &dyn TraversalBackend, objects: &[Object<Box<dyn Geometry>>]) {
+    use glm::vec3;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    let mut rng = SmallRng::seed_from_u64(1);
+    for k in 0..500 {
+        let mut point = || {
+            vec3(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-1.0..6.0),
+                rng.gen_range(-10.0..10.0),
+            )
+        };
+        let origin = point();
+        let mut ray = Ray::new(origin, point() - origin);
+        if k % 3 == 0 {
+            let t_min = rng.gen_range(0.0..10.0);
+            ray = ray.with_interval(t_min, t_min + rng.gen_range(0.0..10.0));
+        }
+        let hit = |(i, res): (usize, RayIntersection)| (i, res.t);
+        assert_eq!(
+            backend.intersect(objects, &ray).map(hit),
+            Linear.intersect(objects, &ray).map(hit)
+        );
+    }
+}
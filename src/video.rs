@@ -0,0 +1,45 @@
+use std::io::BufWriter;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::image::Image;
+
+// Encodes frames with an ffmpeg process, which has to be on the PATH.
+// Frames go to its stdin as PPM, the codec follows from the file extension
+pub struct Video {
+    ffmpeg: Child,
+    stdin: BufWriter<ChildStdin>,
+}
+
+impl Video {
+    pub fn new(path: &str, fps: f32) -> Self {
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-f",
+                "image2pipe",
+                "-c:v",
+                "ppm",
+            ])
+            .args(["-framerate", &fps.to_string(), "-i", "-"])
+            .args(["-pix_fmt", "yuv420p", path])
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("failed to start ffmpeg");
+        let stdin = BufWriter::new(ffmpeg.stdin.take().unwrap());
+        Self { ffmpeg, stdin }
+    }
+
+    pub fn write_frame(&mut self, image: &Image) {
+        image.write_ppm(&mut self.stdin);
+    }
+
+    // Closes the input and waits for ffmpeg to write the file
+    pub fn finish(self) {
+        let Self { mut ffmpeg, stdin } = self;
+        drop(stdin.into_inner().unwrap());
+        let status = ffmpeg.wait().unwrap();
+        assert!(status.success(), "ffmpeg failed: {}", status);
+    }
+}
@@ -0,0 +1,205 @@
+use glm::Vec3;
+use rayon::prelude::*;
+
+use crate::objects::{Material, RayIntersection};
+use crate::options::Options;
+use crate::profile_scope;
+use crate::ray::Ray;
+use crate::render::CameraSample;
+use crate::scene::Scene;
+use crate::trace::{
+    cached_light, hit_emission, sampled_light, scatter, shadow_density, visible_hit, Bounce,
+    PathState, Radiance,
+};
+
+// Wavefront path tracing (--wavefront): the paths of a sample pass advance
+// together, all of them are intersected, then all of them shaded, instead
+// of one path after another. It traces the same paths as the recursive
+// tracer, which stays the reference, adding up the light along the way
+
+// Paths in flight at once, the rest of the pass waits
+const WAVE_SIZE: usize = 1 << 16;
+
+struct Path {
+    // position in the order of the pass
+    slot: usize,
+    sample: CameraSample,
+    ray: Ray,
+    state: PathState,
+    // the diffuse object the ray leaves, for light linking
+    source: Option<usize>,
+    // decides the light path category, as in Radiance::reflected
+    first_bounce: Option<Bounce>,
+    hit: Option<(usize, RayIntersection, Vec3)>,
+    radiance: Radiance,
+    done: bool,
+}
+
+// One sample of every pixel, in the given order of the pixel indices
+pub fn trace_pass(scene: &Scene, order: &[usize], step: usize, options: &Options) -> Vec<Radiance> {
+    let width = scene.image.width;
+    let mut result = vec![Radiance::default(); order.len()];
+
+    for (wave, radiance) in result.chunks_mut(WAVE_SIZE).enumerate() {
+        let first = wave * WAVE_SIZE;
+        let mut paths = order[first..first + radiance.len()]
+            .par_iter()
+            .enumerate()
+            .map(|(slot, &pixel)| {
+                let sample = CameraSample::new(scene, pixel % width, pixel / width, step, options);
+                Path::new(first + slot, sample)
+            })
+            .collect::<Vec<_>>();
+
+        while !paths.is_empty() {
+            {
+                profile_scope!("extend");
+                paths.par_iter_mut().for_each(|path| path.extend(scene));
+            }
+            {
+                profile_scope!("shade");
+                paths.par_iter_mut().for_each(|path| path.shade(scene));
+            }
+
+            for path in paths.iter().filter(|path| path.done) {
+                radiance[path.slot - first] = path.sample.finish(path.radiance, options);
+            }
+            paths.retain(|path| !path.done);
+        }
+    }
+    result
+}
+
+impl Path {
+    fn new(slot: usize, sample: CameraSample) -> Self {
+        Self {
+            slot,
+            ray: sample.ray.clone(),
+            sample,
+            state: PathState::default(),
+            source: None,
+            first_bounce: None,
+            hit: None,
+            radiance: Radiance::default(),
+            done: false,
+        }
+    }
+
+    fn extend(&mut self, scene: &Scene) {
+        if self.state.is_done(scene) {
+            self.done = true;
+            return;
+        }
+        self.hit = visible_hit(scene, &self.ray, self.state.depth == 0);
+    }
+
+    // As trace_from, with the bounce continuing the path instead of
+    // recursing
+    fn shade(&mut self, scene: &Scene) {
+        if self.done {
+            return;
+        }
+        let depth = self.state.depth;
+        self.sample.sampler.start_bounce(depth);
+
+        let Some((idx, intersection, point)) = self.hit.take() else {
+            // Left transparent to composite the objects and shadows over
+            let transparent = depth == 0 && scene.shadow_catcher;
+            if !(transparent || self.state.light_sampled) {
+                self.add_emitted(&scene.environment.radiance(&self.ray.direction));
+            }
+            self.done = true;
+            return;
+        };
+
+        profile_scope!("shading");
+        let normal = intersection.n;
+        let emitted = if self.state.light_sampled {
+            Vec3::zeros()
+        } else {
+            hit_emission(scene, &self.ray, idx, &intersection, point, self.source)
+        };
+        let sampler = &mut self.sample.sampler;
+        let (material, albedo) = scene.objects[idx].surface_at(&point, &normal, sampler);
+
+        if depth == 0 {
+            if matches!(material, Material::ShadowCatcher) {
+                self.radiance.alpha = shadow_density(scene, &point, &normal, sampler);
+                self.done = true;
+                return;
+            }
+            self.radiance.alpha = 1.0;
+        }
+        self.add_emitted(&emitted);
+
+        let state = &self.state;
+        if let Some(reflected) = cached_light(scene, state, idx, material, &point, &normal, &albedo)
+        {
+            self.add_reflected(&reflected);
+            self.done = true;
+            return;
+        }
+
+        let sampler = &mut self.sample.sampler;
+        let next = scatter(
+            scene,
+            &self.ray,
+            &self.state,
+            idx,
+            &intersection,
+            &point,
+            material,
+            &albedo,
+            sampler,
+        );
+        match next {
+            Some(scatter) => {
+                let next = self.state.bounce(scatter.kind, &scatter.weight);
+                let direct = sampled_light(
+                    scene, &self.ray, idx, &scatter, &next, &point, &normal, &albedo, sampler,
+                );
+                // reflected at this hit, so with its throughput
+                let direct = direct.map(|light| self.state.throughput.component_mul(&light));
+
+                self.first_bounce.get_or_insert(scatter.kind);
+                self.source = matches!(scatter.kind, Bounce::Diffuse).then_some(idx);
+                self.state = PathState {
+                    light_sampled: direct.is_some(),
+                    ..next
+                };
+                self.ray = scatter.ray;
+                // as if emitted at the next hit
+                if let Some(direct) = direct {
+                    self.add_light(direct);
+                }
+            }
+            None => self.done = true,
+        }
+    }
+
+    // Light emitted at the current hit, or coming from the environment
+    fn add_emitted(&mut self, light: &Vec3) {
+        self.add_light(self.state.throughput.component_mul(light));
+    }
+
+    // Light reaching the camera from the current hit, by the category of
+    // the path
+    fn add_light(&mut self, light: Vec3) {
+        let radiance = &mut self.radiance;
+        match self.first_bounce {
+            None => radiance.emitted += light,
+            Some(Bounce::Diffuse) if self.state.depth == 1 => radiance.diffuse_direct += light,
+            Some(Bounce::Diffuse) => radiance.diffuse_indirect += light,
+            Some(_) => radiance.specular += light,
+        }
+    }
+
+    // Light reflected at the current hit without tracing it further
+    fn add_reflected(&mut self, light: &Vec3) {
+        let light = self.state.throughput.component_mul(light);
+        match self.first_bounce {
+            Some(kind) if kind.is_specular() => self.radiance.specular += light,
+            _ => self.radiance.diffuse_indirect += light,
+        }
+    }
+}
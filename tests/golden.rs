@@ -0,0 +1,13 @@
+use raytracing::golden::run_golden;
+use raytracing::options::Options;
+
+// The bundled golden scenes, with every traversal backend
+#[test]
+fn golden_scenes() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/golden");
+    let options = Options::parse(Vec::new());
+    assert!(
+        run_golden(dir, false, &options),
+        "a golden scene is over the tolerance"
+    );
+}